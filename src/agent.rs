@@ -1,10 +1,140 @@
+use crate::config::Limits;
+use crate::events::{self, Event};
+use crate::forwarded;
+use crate::protocol;
+use crate::scheduler::Scheduler;
+use crate::telemetry::{self, SpanKind};
 use anyhow::{Context, Result};
+use md5::Md5;
 use sha2::{Sha256, Digest};
+use std::os::fd::AsRawFd;
 use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::io::{Read, Write};
-use base64::engine::general_purpose::STANDARD_NO_PAD;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
 use base64::Engine;
 
+thread_local! {
+    /// Upstream socket the request handled on this thread is pinned to
+    static PINNED: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    /// Client whose requests are handled on this thread
+    static CLIENT: RefCell<Option<UnixStream>> = const { RefCell::new(None) };
+}
+
+/// Keeps a request's upstream exchanges on one socket until dropped
+pub struct UpstreamPin(());
+
+impl Drop for UpstreamPin {
+    fn drop(&mut self) {
+        PINNED.with(|pinned| pinned.borrow_mut().take());
+    }
+}
+
+/// The upstream socket the request on this thread was pinned to, if
+/// upstream is one that moves
+pub fn pinned_upstream() -> Option<PathBuf> {
+    PINNED.with(|pinned| pinned.borrow().clone())
+}
+
+/// Gives up upstream exchanges when the client on this thread goes away,
+/// until dropped
+pub struct ClientWatch(());
+
+impl Drop for ClientWatch {
+    fn drop(&mut self) {
+        CLIENT.with(|client| client.borrow_mut().take());
+    }
+}
+
+/// Stop waiting for upstream on behalf of `stream` once it disconnects,
+/// for requests handled on this thread until the watch is dropped
+///
+/// A request queued for a pooled connection leaves the queue, and one
+/// waiting for upstream's answer closes its connection, rather than keep
+/// a connection busy for an answer nobody reads.
+pub fn watch_client(stream: &UnixStream) -> ClientWatch {
+    let clone = stream.try_clone().ok();
+    CLIENT.with(|client| *client.borrow_mut() = clone);
+    ClientWatch(())
+}
+
+/// The error upstream exchanges fail with once the watched client is gone
+#[derive(Debug)]
+pub struct ClientGone;
+
+impl std::fmt::Display for ClientGone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("The client disconnected before upstream answered")
+    }
+}
+
+impl std::error::Error for ClientGone {}
+
+/// Whether `error` is, or was caused by, the client going away
+pub fn is_client_gone(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<ClientGone>())
+}
+
+/// Whether the client on this thread closed the connection
+///
+/// Only a hang-up counts, not end of file: a client may shut down its
+/// writing side once it sent its request and still read the answer.
+fn client_gone() -> bool {
+    CLIENT.with(|client| {
+        let Some(stream) = client.borrow().as_ref().map(AsRawFd::as_raw_fd) else {
+            return false;
+        };
+        // Asking for no event leaves hang-ups and errors, which poll
+        // always reports
+        let mut fds = [libc::pollfd { fd: stream, events: 0, revents: 0 }];
+        // SAFETY: `fds` holds one initialized pollfd
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 0) };
+        ready > 0 && fds[0].revents != 0
+    })
+}
+
+/// Wait until `upstream` has an answer to read, failing with
+/// [`ClientGone`] if the client on this thread disconnects first
+fn wait_for_answer(upstream: &UnixStream, timeout: Option<Duration>) -> Result<()> {
+    let Some(client) = CLIENT.with(|client| client.borrow().as_ref().map(AsRawFd::as_raw_fd)) else {
+        return Ok(());
+    };
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let mut fds = [
+            libc::pollfd { fd: upstream.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: client, events: 0, revents: 0 },
+        ];
+        let wait = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()).as_millis().min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        // SAFETY: `fds` holds two initialized pollfds
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, wait) };
+        if ready < 0 {
+            let error = std::io::Error::last_os_error();
+            if error.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error.into());
+        }
+        // Reading reports a timeout or a closed upstream as it always did
+        if ready == 0 || fds[0].revents != 0 {
+            return Ok(());
+        }
+        // The client pollfd asks for no event, so only a hang-up or an
+        // error wakes it
+        if fds[1].revents != 0 {
+            return Err(ClientGone.into());
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SshKey {
     pub key_type: String,
@@ -24,153 +154,600 @@ impl SshKey {
         }
     }
 
+    /// Build a key from a wire-format blob, reading the key type from it
+    pub fn from_key_blob(blob: Vec<u8>, comment: String) -> Self {
+        let key_type = if blob.len() > 4 {
+            let type_len = u32::from_be_bytes([blob[0], blob[1], blob[2], blob[3]]) as usize;
+            if blob.len() >= 4 + type_len {
+                String::from_utf8_lossy(&blob[4..4 + type_len]).to_string()
+            } else {
+                "unknown".to_string()
+            }
+        } else {
+            "unknown".to_string()
+        };
+        Self::from_blob(key_type, blob, comment)
+    }
+
+    /// Parse an OpenSSH public key line (`type base64 [comment]`)
+    pub fn from_public_key_line(line: &str) -> Result<Self> {
+        let mut parts = line.split_whitespace();
+        let key_type = parts.next()
+            .ok_or_else(|| anyhow::anyhow!("Empty public key line"))?;
+        let encoded = parts.next()
+            .ok_or_else(|| anyhow::anyhow!("Public key line has no key data"))?;
+        let comment = parts.collect::<Vec<_>>().join(" ");
+
+        let blob = STANDARD.decode(encoded)
+            .context("Public key data is not valid base64")?;
+
+        Ok(Self::from_blob(key_type.to_string(), blob, comment))
+    }
+
+    /// Parse an `authorized_keys` line, skipping options such as
+    /// `from="..."` before the key type
+    pub fn from_authorized_keys_line(line: &str) -> Result<Self> {
+        let line = line.trim();
+        let is_key_type = |token: &str| {
+            token.starts_with("ssh-") || token.starts_with("ecdsa-") || token.starts_with("sk-")
+        };
+        if line.split_whitespace().next().is_some_and(is_key_type) {
+            return Self::from_public_key_line(line);
+        }
+
+        // Options end at the first whitespace outside double quotes
+        let mut in_quotes = false;
+        let mut escaped = false;
+        let end = line.char_indices()
+            .find(|&(_, c)| {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    '"' => in_quotes = !in_quotes,
+                    c if c.is_whitespace() && !in_quotes => return true,
+                    _ => {}
+                }
+                false
+            })
+            .map(|(i, _)| i)
+            .ok_or_else(|| anyhow::anyhow!("Not a public key line"))?;
+
+        let rest = line[end..].trim_start();
+        if !rest.split_whitespace().next().is_some_and(is_key_type) {
+            anyhow::bail!("Not a fingerprint or public key");
+        }
+        Self::from_public_key_line(rest)
+    }
+
+    /// Format as an OpenSSH public key line
+    pub fn to_public_key_line(&self) -> String {
+        let line = format!("{} {}", self.key_type, STANDARD.encode(&self.blob));
+        if self.comment.is_empty() {
+            line
+        } else {
+            format!("{} {}", line, self.comment)
+        }
+    }
+
+    /// Legacy MD5 fingerprint, e.g. `MD5:12:34:...`
+    pub fn md5_fingerprint(&self) -> String {
+        let hash = Md5::digest(&self.blob);
+        let hex: Vec<String> = hash.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("MD5:{}", hex.join(":"))
+    }
+
+    /// Whether a SHA256 or MD5 fingerprint refers to this key
+    pub fn matches_fingerprint(&self, fingerprint: &str) -> bool {
+        if fingerprint.starts_with("MD5:") {
+            self.md5_fingerprint().eq_ignore_ascii_case(fingerprint)
+        } else {
+            self.fingerprint == fingerprint
+        }
+    }
+
     fn calculate_fingerprint(blob: &[u8]) -> String {
         let mut hasher = Sha256::new();
         hasher.update(blob);
         let hash = hasher.finalize();
         
-        format!("SHA256:{}", STANDARD_NO_PAD.encode(&hash))
+        format!("SHA256:{}", STANDARD_NO_PAD.encode(hash))
     }
 }
 
+/// An open upstream connection and the socket it was made to
+type Connection = (PathBuf, UnixStream);
+
+/// Whether an upstream failure is likely to pass by itself, as while the
+/// agent restarts
+fn is_transient(error: &anyhow::Error) -> bool {
+    use std::io::ErrorKind;
+
+    error.chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|e| matches!(
+            e.kind(),
+            ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof
+        ))
+}
+
+/// A random duration shorter than `max`
+fn jitter(max: Duration) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    // Every RandomState is seeded differently, which is random enough to
+    // spread retries out
+    let random = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    Duration::from_nanos(random % max.as_nanos().min(u64::MAX as u128) as u64)
+}
+
+/// Connection to the upstream agent
+///
+/// Clones share the last identity listing, so every socket of a router
+/// contributes to the same snapshot. They also share a small pool of
+/// persistent upstream connections; the agent protocol answers requests
+/// in order, so each connection carries one request at a time and
+/// clients queue for a free one, taking turns across sockets.
 #[derive(Clone)]
 pub struct Agent {
     upstream_path: String,
+    limits: Limits,
+    last_seen: Arc<Mutex<Option<IdentitySnapshot>>>,
+    connections: Arc<Vec<Mutex<Option<Connection>>>>,
+    next_connection: Arc<AtomicUsize>,
+    /// Hands out `connections` to the sockets' requests in turn
+    scheduler: Arc<Scheduler>,
+    /// Socket whose queue requests wait in for a connection
+    queue: String,
+    /// Whether the last exchange with upstream succeeded
+    healthy: Arc<Mutex<Option<bool>>>,
+    /// How long a request waits for a missing upstream socket to appear
+    upstream_wait: Duration,
+}
+
+/// The identities upstream listed most recently
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentitySnapshot {
+    /// Unix time of the listing
+    pub seen_at: u64,
+    pub keys: Vec<SeenKey>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeenKey {
+    pub fingerprint: String,
+    pub key_type: String,
+    pub comment: String,
 }
 
 impl Agent {
-    pub fn new(upstream_path: String) -> Self {
-        Self { upstream_path }
+    pub fn new(upstream_path: String, limits: Limits) -> Self {
+        let connections: Vec<_> = (0..limits.upstream_connections.max(1))
+            .map(|_| Mutex::new(None))
+            .collect();
+        let scheduler = Arc::new(Scheduler::new(connections.len()));
+        Self {
+            upstream_path,
+            limits,
+            last_seen: Arc::new(Mutex::new(None)),
+            connections: Arc::new(connections),
+            next_connection: Arc::new(AtomicUsize::new(0)),
+            scheduler,
+            queue: String::new(),
+            healthy: Arc::new(Mutex::new(None)),
+            upstream_wait: Duration::ZERO,
+        }
     }
 
-    fn connect(&self) -> Result<UnixStream> {
-        let path = if self.upstream_path.is_empty() {
-            std::env::var("SSH_AUTH_SOCK")
-                .context("SSH_AUTH_SOCK not set and no upstream path provided")?
-        } else {
-            self.upstream_path.clone()
-        };
+    /// Let requests wait up to `wait` for the upstream socket to be
+    /// created, as while the agent is still starting, rather than fail
+    /// right away
+    pub fn with_upstream_wait(mut self, wait: Duration) -> Self {
+        self.upstream_wait = wait;
+        self
+    }
 
-        UnixStream::connect(&path)
-            .with_context(|| format!("Failed to connect to SSH agent at {}", path))
+    /// The socket upstream is configured at, `None` where it moves, as
+    /// with forwarded agents or `SSH_AUTH_SOCK`
+    pub fn fixed_upstream(&self) -> Option<PathBuf> {
+        match self.upstream_path.as_str() {
+            "" | forwarded::FORWARDED => None,
+            path => Some(PathBuf::from(path)),
+        }
     }
 
-    pub fn list_keys(&self) -> Result<Vec<SshKey>> {
-        let mut stream = self.connect()?;
-        
-        // SSH_AGENTC_REQUEST_IDENTITIES
-        let request: [u8; 5] = [0, 0, 0, 1, 11];
-        stream.write_all(&request)?;
-        stream.flush()?;
+    /// A handle with an upstream connection of its own, still sharing the
+    /// identity snapshot
+    ///
+    /// The connection is opened on first use and closed when the handle is
+    /// dropped.
+    pub fn dedicated(&self) -> Self {
+        Self {
+            connections: Arc::new(vec![Mutex::new(None)]),
+            next_connection: Arc::new(AtomicUsize::new(0)),
+            scheduler: Arc::new(Scheduler::new(1)),
+            ..self.clone()
+        }
+    }
 
-        // Read response
-        let mut len_buf = [0u8; 4];
-        stream.read_exact(&mut len_buf)?;
-        let msg_len = u32::from_be_bytes(len_buf);
+    /// A handle whose requests wait for a pooled connection in the queue
+    /// of socket `name`
+    pub fn for_socket(&self, name: &str) -> Self {
+        Self { queue: name.to_string(), ..self.clone() }
+    }
+
+    /// Identities from the most recent successful listing, if any
+    pub fn last_seen(&self) -> Option<IdentitySnapshot> {
+        self.last_seen.lock().unwrap().clone()
+    }
 
-        let mut msg_buf = vec![0u8; msg_len as usize];
-        stream.read_exact(&mut msg_buf)?;
+    /// Whether upstream answered the most recent request, `None` before
+    /// the first one
+    pub fn healthy(&self) -> Option<bool> {
+        *self.healthy.lock().unwrap()
+    }
 
-        // Parse response
-        if msg_buf.is_empty() || msg_buf[0] != 12 {
-            // SSH_AGENT_IDENTITIES_ANSWER
-            return Err(anyhow::anyhow!("Unexpected response from SSH agent"));
+    /// Publish an event when upstream starts or stops answering
+    fn record_health<T>(&self, result: &Result<T>) {
+        let healthy = result.is_ok();
+        let mut state = self.healthy.lock().unwrap();
+        if *state == Some(healthy) {
+            return;
         }
+        *state = Some(healthy);
+        drop(state);
 
-        let mut keys = Vec::new();
-        let mut pos = 1;
+        let error = result.as_ref().err().map(|e| format!("{:#}", e));
+        match &error {
+            Some(e) => log::debug!("Upstream agent stopped answering: {}", e),
+            None => log::debug!("Upstream agent is answering"),
+        }
+        events::publish(Event::Upstream { healthy, error });
+    }
 
-        // Read number of keys
-        if msg_buf.len() < pos + 4 {
-            return Ok(keys);
+    /// Record that upstream listed the same identities again
+    pub fn touch_last_seen(&self) {
+        if let Some(snapshot) = self.last_seen.lock().unwrap().as_mut() {
+            snapshot.seen_at = unix_now();
         }
-        let num_keys = u32::from_be_bytes([
-            msg_buf[pos],
-            msg_buf[pos + 1],
-            msg_buf[pos + 2],
-            msg_buf[pos + 3],
-        ]);
-        pos += 4;
+    }
 
-        for _ in 0..num_keys {
-            if msg_buf.len() < pos + 4 {
-                break;
+    /// Socket to send the next request to
+    ///
+    /// With `upstream = "forwarded"` that is the newest live forwarded
+    /// agent, which changes as ssh sessions come and go.
+    fn upstream_socket(&self) -> Result<PathBuf> {
+        if self.upstream_path == forwarded::FORWARDED {
+            if let Some(pinned) = pinned_upstream() {
+                return Ok(pinned);
             }
+            return forwarded::latest();
+        }
+        if self.upstream_path.is_empty() {
+            return std::env::var_os("SSH_AUTH_SOCK")
+                .map(PathBuf::from)
+                .context("SSH_AUTH_SOCK not set and no upstream path provided");
+        }
+        Ok(PathBuf::from(&self.upstream_path))
+    }
 
-            // Read key blob length
-            let blob_len = u32::from_be_bytes([
-                msg_buf[pos],
-                msg_buf[pos + 1],
-                msg_buf[pos + 2],
-                msg_buf[pos + 3],
-            ]) as usize;
-            pos += 4;
-
-            if msg_buf.len() < pos + blob_len {
-                break;
-            }
+    /// Send every exchange of the request on this thread to the same
+    /// upstream socket until the returned pin is dropped
+    ///
+    /// Only matters with `upstream = "forwarded"`: the key check and the
+    /// signature of one request must not land on different ssh sessions'
+    /// agents, and the audit trail names the one that served it.
+    pub fn pin(&self) -> UpstreamPin {
+        if self.upstream_path == forwarded::FORWARDED {
+            let socket = forwarded::latest().ok();
+            PINNED.with(|pinned| *pinned.borrow_mut() = socket);
+        }
+        UpstreamPin(())
+    }
 
-            // Read key blob
-            let blob = msg_buf[pos..pos + blob_len].to_vec();
-            pos += blob_len;
-
-            // Parse key type from blob
-            let key_type = if blob.len() > 4 {
-                let type_len = u32::from_be_bytes([blob[0], blob[1], blob[2], blob[3]]) as usize;
-                if blob.len() >= 4 + type_len {
-                    String::from_utf8_lossy(&blob[4..4 + type_len]).to_string()
-                } else {
-                    "unknown".to_string()
-                }
-            } else {
-                "unknown".to_string()
-            };
+    /// Connect to `path`, first waiting up to `upstream_wait` for it to
+    /// exist if it does not yet
+    fn connect_or_wait(&self, path: &Path) -> Result<UnixStream> {
+        if !self.upstream_wait.is_zero() && !path.exists() {
+            log::debug!("Waiting up to {} for upstream agent {:?} to appear", humantime::format_duration(self.upstream_wait), path);
+            crate::watch::wait_for_path(path, Some(self.upstream_wait))?;
+        }
+        self.connect(path)
+    }
 
-            // Read comment length
-            if msg_buf.len() < pos + 4 {
-                break;
-            }
-            let comment_len = u32::from_be_bytes([
-                msg_buf[pos],
-                msg_buf[pos + 1],
-                msg_buf[pos + 2],
-                msg_buf[pos + 3],
-            ]) as usize;
-            pos += 4;
-
-            if msg_buf.len() < pos + comment_len {
-                break;
-            }
+    fn connect(&self, path: &Path) -> Result<UnixStream> {
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("Failed to connect to SSH agent at {}", path.display()))?;
+        stream.set_read_timeout(self.limits.per_conn_timeout)?;
+        stream.set_write_timeout(self.limits.per_conn_timeout)?;
 
-            // Read comment
-            let comment = String::from_utf8_lossy(&msg_buf[pos..pos + comment_len]).to_string();
-            pos += comment_len;
+        Ok(stream)
+    }
 
-            keys.push(SshKey::from_blob(key_type, blob, comment));
+    /// Send one length-prefixed request over a pooled connection and read
+    /// the response
+    ///
+    /// A reused connection may have been closed by upstream since its last
+    /// request, so a failure on one is retried once on a fresh connection.
+    /// Any failure drops the connection, since it may be out of sync.
+    fn exchange(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let mut span = telemetry::span("upstream exchange", SpanKind::Client);
+        let result = self.exchange_pooled(request);
+        self.record_health(&result);
+        if let Err(e) = &result {
+            span.error(e);
         }
+        result
+    }
 
-        Ok(keys)
+    fn exchange_pooled(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let Some(_permit) = self.scheduler.acquire(&self.queue, client_gone) else {
+            return Err(ClientGone.into());
+        };
+        // Warming holds connections without a permit, so one may still be
+        // busy for a moment
+        let mut slot = self.connections.iter()
+            .find_map(|c| c.try_lock().ok())
+            .unwrap_or_else(|| {
+                let i = self.next_connection.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+                self.connections[i].lock().unwrap()
+            });
+        self.exchange_on(&mut slot, request)
     }
 
-    pub fn forward_request(&self, request: &[u8]) -> Result<Vec<u8>> {
-        let mut stream = self.connect()?;
-        
+    /// Send a request over the connection in `slot`, opening one if it
+    /// is empty or connected to an upstream that has since moved
+    fn exchange_on(&self, slot: &mut Option<Connection>, request: &[u8]) -> Result<Vec<u8>> {
+        let path = self.upstream_socket()?;
+        match slot.take() {
+            Some((connected, mut stream)) if connected == path => match self.send(&mut stream, request) {
+                Ok(response) => {
+                    *slot = Some((connected, stream));
+                    return Ok(response);
+                }
+                // The answer may still come, so the connection goes
+                Err(e) if is_client_gone(&e) => return Err(e),
+                Err(e) => log::debug!("Reconnecting to upstream agent: {:#}", e),
+            },
+            Some((connected, _)) => log::info!("Upstream agent moved from {:?} to {:?}", connected, path),
+            None => {}
+        }
+
+        let (stream, response) = self.retrying(|| {
+            let mut stream = self.connect_or_wait(&path)?;
+            let response = self.send(&mut stream, request)?;
+            Ok((stream, response))
+        })?;
+        *slot = Some((path, stream));
+        Ok(response)
+    }
+
+    /// Run `attempt` again after failures that are likely to pass, as
+    /// `limits.upstream_retry` allows
+    ///
+    /// Only failures without an answer from upstream are retried: a
+    /// refused connection, or one closed before the response.
+    fn retrying<T>(&self, mut attempt: impl FnMut() -> Result<T>) -> Result<T> {
+        let policy = &self.limits.upstream_retry;
+        let started = Instant::now();
+        let mut delay = policy.base_delay;
+        let mut tries = 1;
+        loop {
+            match attempt() {
+                Err(e) if tries < policy.attempts && is_transient(&e) => {
+                    let wait = delay + jitter(policy.jitter);
+                    if started.elapsed() + wait > policy.deadline {
+                        return Err(e);
+                    }
+                    log::debug!("Upstream agent failed, retrying in {:?}: {:#}", wait, e);
+                    std::thread::sleep(wait);
+                    delay = delay.saturating_mul(2);
+                    tries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn send(&self, stream: &mut UnixStream, request: &[u8]) -> Result<Vec<u8>> {
         stream.write_all(request)?;
         stream.flush()?;
+        wait_for_answer(stream, self.limits.per_conn_timeout)?;
+        self.read_response(stream)
+    }
 
-        // Read response length
+    /// Read one length-prefixed response from the upstream agent, enforcing
+    /// the size limit
+    ///
+    /// The prefix and body share one buffer so the response can be passed
+    /// on to the client as is.
+    fn read_response(&self, stream: &mut UnixStream) -> Result<Vec<u8>> {
         let mut len_buf = [0u8; 4];
         stream.read_exact(&mut len_buf)?;
         let msg_len = u32::from_be_bytes(len_buf);
 
-        // Read response
-        let mut response = vec![0u8; msg_len as usize];
-        stream.read_exact(&mut response)?;
+        if msg_len > self.limits.max_message_size {
+            return Err(anyhow::anyhow!(
+                "Upstream response too large: {} bytes (max: {})",
+                msg_len, self.limits.max_message_size
+            ));
+        }
 
-        // Prepend length
-        let mut full_response = len_buf.to_vec();
-        full_response.extend_from_slice(&response);
+        let mut response = vec![0u8; 4 + msg_len as usize];
+        response[..4].copy_from_slice(&len_buf);
+        stream.read_exact(&mut response[4..])?;
 
-        Ok(full_response)
+        Ok(response)
     }
+
+    /// List every identity held by the upstream agent
+    pub fn list_keys(&self) -> Result<Vec<SshKey>> {
+        // SSH_AGENTC_REQUEST_IDENTITIES
+        let request: [u8; 5] = [0, 0, 0, 1, 11];
+        let response = self.exchange(&request)?;
+
+        self.identities_from_answer(&response[4..])
+    }
+
+    /// Open every pooled connection at once, listing identities over each,
+    /// and return the identities answer
+    ///
+    /// Run on start and reload so the first client neither waits for a
+    /// connection nor for a cold listing.
+    pub fn warm(&self) -> Result<Vec<u8>> {
+        // SSH_AGENTC_REQUEST_IDENTITIES
+        let request: [u8; 5] = [0, 0, 0, 1, 11];
+        let results: Vec<Result<Vec<u8>>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = self.connections.iter()
+                .map(|slot| scope.spawn(move || self.exchange_on(&mut slot.lock().unwrap(), &request)))
+                .collect();
+            handles.into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|_| Err(anyhow::anyhow!("Warming thread panicked"))))
+                .collect()
+        });
+
+        // One answer is enough: every connection goes to the same upstream
+        let mut first_error = None;
+        let mut answer = None;
+        for result in results {
+            match result {
+                Ok(response) if answer.is_none() => answer = Some(response),
+                Ok(_) => {}
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        let result = answer.ok_or_else(|| first_error.unwrap_or_else(|| anyhow::anyhow!("No upstream connections")));
+        self.record_health(&result);
+        let response = result?;
+        if response.len() < 5 {
+            anyhow::bail!("Upstream sent an empty response");
+        }
+        self.identities_from_answer(&response[4..])?;
+        Ok(response)
+    }
+
+    /// Parse an identities answer upstream sent and remember it as the
+    /// latest listing
+    ///
+    /// Lets a forwarded listing double as the snapshot it is filtered
+    /// against, instead of asking upstream a second time.
+    pub fn identities_from_answer(&self, msg_buf: &[u8]) -> Result<Vec<SshKey>> {
+        // Not truncated to max_identities here: sign requests must be
+        // checked against every upstream key, only what is returned to
+        // clients is limited
+        let keys = parse_identities_answer(msg_buf)?;
+
+        *self.last_seen.lock().unwrap() = Some(IdentitySnapshot {
+            seen_at: unix_now(),
+            keys: keys.iter()
+                .map(|k| SeenKey {
+                    fingerprint: k.fingerprint.clone(),
+                    key_type: k.key_type.clone(),
+                    comment: k.comment.clone(),
+                })
+                .collect(),
+        });
+
+        Ok(keys)
+    }
+
+    pub fn forward_request(&self, request: &[u8]) -> Result<Vec<u8>> {
+        self.exchange(request)
+    }
+
+    /// Lock (`ssh-add -x`) or unlock (`ssh-add -X`) the upstream agent
+    /// with `passphrase`
+    pub fn lock(&self, lock: bool, passphrase: &str) -> Result<()> {
+        let mut body = vec![if lock { protocol::SSH_AGENTC_LOCK } else { protocol::SSH_AGENTC_UNLOCK }];
+        protocol::put_string(&mut body, passphrase.as_bytes());
+        let response = self.exchange(&protocol::frame(&body))?;
+        if protocol::message_type(&response) != Some(protocol::SSH_AGENT_SUCCESS) {
+            match lock {
+                true => anyhow::bail!("The upstream agent refused to lock, it may be locked already"),
+                false => anyhow::bail!("The upstream agent refused to unlock, check the passphrase"),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Parse an SSH_AGENT_IDENTITIES_ANSWER message body (without length prefix)
+pub fn parse_identities_answer(msg_buf: &[u8]) -> Result<Vec<SshKey>> {
+    if msg_buf.is_empty() || msg_buf[0] != 12 {
+        // SSH_AGENT_IDENTITIES_ANSWER
+        return Err(anyhow::anyhow!("Unexpected response from SSH agent"));
+    }
+
+    let mut keys = Vec::new();
+    let mut pos = 1;
+
+    // Read number of keys
+    if msg_buf.len() < pos + 4 {
+        return Ok(keys);
+    }
+    let num_keys = u32::from_be_bytes([
+        msg_buf[pos],
+        msg_buf[pos + 1],
+        msg_buf[pos + 2],
+        msg_buf[pos + 3],
+    ]);
+    pos += 4;
+
+    for _ in 0..num_keys {
+        if msg_buf.len() < pos + 4 {
+            break;
+        }
+
+        // Read key blob length
+        let blob_len = u32::from_be_bytes([
+            msg_buf[pos],
+            msg_buf[pos + 1],
+            msg_buf[pos + 2],
+            msg_buf[pos + 3],
+        ]) as usize;
+        pos += 4;
+
+        if msg_buf.len() < pos + blob_len {
+            break;
+        }
+
+        // Read key blob
+        let blob = msg_buf[pos..pos + blob_len].to_vec();
+        pos += blob_len;
+
+        // Read comment length
+        if msg_buf.len() < pos + 4 {
+            break;
+        }
+        let comment_len = u32::from_be_bytes([
+            msg_buf[pos],
+            msg_buf[pos + 1],
+            msg_buf[pos + 2],
+            msg_buf[pos + 3],
+        ]) as usize;
+        pos += 4;
+
+        if msg_buf.len() < pos + comment_len {
+            break;
+        }
+
+        // Read comment
+        let comment = String::from_utf8_lossy(&msg_buf[pos..pos + comment_len]).to_string();
+        pos += comment_len;
+
+        keys.push(SshKey::from_key_blob(blob, comment));
+    }
+
+    Ok(keys)
 }
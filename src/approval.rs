@@ -0,0 +1,724 @@
+use crate::agent::SshKey;
+use crate::context::RequestContext;
+use crate::config::{ApprovalConfig, ConfirmMethod, KeyMeta, NotifyMethod, NtfyConfig, PushoverConfig, WebhookConfig};
+use crate::i18n;
+use crate::privacy;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long to wait for a decision unless the socket sets `confirm_timeout`
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the push notification backends poll for an answer
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Only one prompt is shown on the terminal at a time
+static TERMINAL: Mutex<()> = Mutex::new(());
+
+/// Distinguishes approval requests sent in the same instant
+static REQUEST_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// A sign request waiting for the user's decision
+pub struct ApprovalRequest<'a> {
+    pub context: &'a RequestContext,
+    pub key: &'a SshKey,
+    /// The key's `[keys]` table, if the config has one
+    pub meta: Option<&'a KeyMeta>,
+    /// What the socket is for, from its `description`
+    pub socket_description: Option<&'a str>,
+}
+
+impl ApprovalRequest<'_> {
+    /// One-line description for notifications
+    pub fn summary(&self) -> String {
+        let key = self.key;
+        let socket = self.socket();
+        let client = sanitize(&self.context.client());
+        let fingerprint = privacy::fingerprint(&key.fingerprint);
+        let key_type = sanitize(&key.key_type);
+        // The user's own label says more than the comment upstream gave
+        if let Some(label) = self.meta.and_then(KeyMeta::describe) {
+            return i18n::format("approval-summary-label", &[
+                ("socket", &socket), ("client", &client), ("label", &sanitize(&label)),
+                ("key", &fingerprint), ("type", &key_type),
+            ]);
+        }
+        match privacy::comment(&key.comment) {
+            Some(comment) => i18n::format("approval-summary-comment", &[
+                ("socket", &socket), ("client", &client), ("key", &fingerprint),
+                ("type", &key_type), ("comment", &sanitize(comment)),
+            ]),
+            None => i18n::format("approval-summary", &[
+                ("socket", &socket), ("client", &client), ("key", &fingerprint), ("type", &key_type),
+            ]),
+        }
+    }
+
+    /// The socket's name, followed by its description if it has one
+    fn socket(&self) -> String {
+        match self.socket_description {
+            Some(description) => format!("{} ({})", sanitize(&self.context.socket), sanitize(description)),
+            None => sanitize(&self.context.socket),
+        }
+    }
+}
+
+/// A way of asking the user whether to allow a sign request
+///
+/// Implementations block until the user answers or `timeout` expires;
+/// no answer in time means the request is denied.
+pub trait Approver: Send + Sync {
+    /// Short name used in log lines
+    fn name(&self) -> &'static str;
+
+    fn approve(&self, request: &ApprovalRequest, timeout: Duration) -> Result<bool>;
+}
+
+/// Build the approver for a socket's `confirm` setting
+pub fn approver(method: ConfirmMethod, config: &ApprovalConfig) -> Result<Box<dyn Approver>> {
+    Ok(match method {
+        ConfirmMethod::Terminal => Box::new(Terminal),
+        ConfirmMethod::Dialog => Box::new(Dialog),
+        ConfirmMethod::TouchId => {
+            if !cfg!(target_os = "macos") {
+                anyhow::bail!("confirm = \"touch-id\" is only available on macOS");
+            }
+            Box::new(TouchId)
+        }
+        ConfirmMethod::Webhook => Box::new(Webhook(config.webhook.clone()
+            .context("confirm = \"webhook\" needs an [approval.webhook] section")?)),
+        ConfirmMethod::Ntfy => Box::new(Ntfy(config.ntfy.clone()
+            .context("confirm = \"ntfy\" needs an [approval.ntfy] section")?)),
+        ConfirmMethod::Pushover => Box::new(Pushover(config.pushover.clone()
+            .context("confirm = \"pushover\" needs an [approval.pushover] section")?)),
+    })
+}
+
+pub struct Terminal;
+
+impl Approver for Terminal {
+    fn name(&self) -> &'static str {
+        "terminal"
+    }
+
+    fn approve(&self, request: &ApprovalRequest, timeout: Duration) -> Result<bool> {
+        ask_terminal(request, timeout)
+    }
+}
+
+pub struct Dialog;
+
+impl Approver for Dialog {
+    fn name(&self) -> &'static str {
+        "dialog"
+    }
+
+    fn approve(&self, request: &ApprovalRequest, timeout: Duration) -> Result<bool> {
+        ask_dialog(request, timeout)
+    }
+}
+
+pub struct TouchId;
+
+impl Approver for TouchId {
+    fn name(&self) -> &'static str {
+        "touch-id"
+    }
+
+    fn approve(&self, request: &ApprovalRequest, timeout: Duration) -> Result<bool> {
+        ask_touch_id(request, timeout)
+    }
+}
+
+pub struct Webhook(pub WebhookConfig);
+
+impl Approver for Webhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn approve(&self, request: &ApprovalRequest, timeout: Duration) -> Result<bool> {
+        ask_webhook(request, &self.0, timeout)
+    }
+}
+
+pub struct Ntfy(pub NtfyConfig);
+
+impl Approver for Ntfy {
+    fn name(&self) -> &'static str {
+        "ntfy"
+    }
+
+    fn approve(&self, request: &ApprovalRequest, timeout: Duration) -> Result<bool> {
+        ask_ntfy(request, &self.0, timeout)
+    }
+}
+
+pub struct Pushover(pub PushoverConfig);
+
+impl Approver for Pushover {
+    fn name(&self) -> &'static str {
+        "pushover"
+    }
+
+    fn approve(&self, request: &ApprovalRequest, timeout: Duration) -> Result<bool> {
+        ask_pushover(request, &self.0, timeout)
+    }
+}
+
+/// Tells the user a key was used, for a risk level's `notify` setting
+#[derive(Debug, Clone)]
+pub enum Notifier {
+    Desktop,
+    Ntfy(NtfyConfig),
+}
+
+impl Notifier {
+    pub fn new(method: NotifyMethod, config: &ApprovalConfig) -> Result<Self> {
+        Ok(match method {
+            NotifyMethod::Desktop => Notifier::Desktop,
+            NotifyMethod::Ntfy => Notifier::Ntfy(config.ntfy.clone()
+                .context("notify = \"ntfy\" needs an [approval.ntfy] section")?),
+        })
+    }
+
+    /// Short name used in log lines
+    pub fn name(&self) -> &'static str {
+        match self {
+            Notifier::Desktop => "desktop",
+            Notifier::Ntfy(_) => "ntfy",
+        }
+    }
+
+    /// Show `summary`, as made by [`ApprovalRequest::summary`]
+    pub fn notify(&self, summary: &str) -> Result<()> {
+        let title = i18n::text("notify-title");
+        match self {
+            Notifier::Desktop => notify_desktop(title, summary),
+            Notifier::Ntfy(config) => {
+                let publish = http_agent()
+                    .post(&format!("{}/{}", config.server.trim_end_matches('/'), config.topic))
+                    .set("Title", &header_value(title))
+                    .set("Tags", "key");
+                authorize(publish, config)
+                    .send_string(summary)
+                    .context("Failed to publish ntfy notification")?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Show a desktop notification with `osascript` on macOS and `notify-send`
+/// elsewhere
+fn notify_desktop(title: &str, text: &str) -> Result<()> {
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("osascript");
+        command.args([
+            "-e", "on run argv",
+            "-e", "display notification (item 1 of argv) with title (item 2 of argv)",
+            "-e", "end run",
+            text, title,
+        ]);
+        command
+    } else if which("notify-send") {
+        let mut command = Command::new("notify-send");
+        command.args(["--app-name=ssh-agent-router", title, text]);
+        command
+    } else {
+        anyhow::bail!("No notification program found, install notify-send");
+    };
+
+    match run_with_timeout(&mut command, Duration::from_secs(5))? {
+        Some(status) if status.success() => Ok(()),
+        Some(status) => anyhow::bail!("{:?} failed: {}", command.get_program(), status),
+        None => anyhow::bail!("{:?} did not finish in time", command.get_program()),
+    }
+}
+
+/// Approvals remembered per (key, client process) for a socket
+///
+/// Only approvals are cached: a denial always asks again next time. A
+/// client whose process id cannot be determined is never cached, since
+/// its answer could otherwise be reused by any other process.
+pub struct DecisionCache {
+    ttl: Duration,
+    approved: Mutex<HashMap<(String, u32), Instant>>,
+}
+
+impl DecisionCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            approved: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `fingerprint` was approved for `pid` less than the TTL ago
+    pub fn is_approved(&self, fingerprint: &str, pid: Option<u32>) -> bool {
+        let Some(pid) = pid else {
+            return false;
+        };
+        let mut approved = self.approved.lock().unwrap();
+        approved.retain(|_, at| at.elapsed() < self.ttl);
+        approved.contains_key(&(fingerprint.to_string(), pid))
+    }
+
+    pub fn approve(&self, fingerprint: &str, pid: Option<u32>) {
+        if let Some(pid) = pid {
+            self.approved.lock().unwrap().insert((fingerprint.to_string(), pid), Instant::now());
+        }
+    }
+
+    /// Forget every cached approval, returning how many were dropped
+    pub fn flush(&self) -> usize {
+        let mut approved = self.approved.lock().unwrap();
+        let count = approved.values().filter(|at| at.elapsed() < self.ttl).count();
+        approved.clear();
+        count
+    }
+}
+
+/// Ask on the controlling terminal whether to allow a sign request
+///
+/// Anything other than `y`/`yes` before the timeout denies the request.
+/// Fails when the router has no controlling terminal, e.g. when it runs
+/// as a service.
+pub fn ask_terminal(request: &ApprovalRequest, timeout: Duration) -> Result<bool> {
+    let _guard = TERMINAL.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("No controlling terminal to ask for confirmation")?;
+
+    prompt(&mut tty, request, timeout)
+}
+
+/// Show the confirmation prompt on `tty` and wait for the answer
+pub fn prompt<T: Read + Write + AsRawFd>(tty: &mut T, request: &ApprovalRequest, timeout: Duration) -> Result<bool> {
+    // Comments come from the upstream agent and any client can add keys,
+    // so escape sequences must not reach the terminal
+    let key = request.key;
+    write!(
+        tty,
+        "\nssh-agent-router: {}\n  {}: {} ({}) {}\n",
+        i18n::format("approval-terminal", &[
+            ("socket", &request.socket()),
+            ("client", &sanitize(&request.context.client())),
+        ]),
+        i18n::text("approval-key"),
+        privacy::fingerprint(&key.fingerprint),
+        sanitize(&key.key_type),
+        sanitize(privacy::comment(&key.comment).unwrap_or_default()),
+    )?;
+    if let Some(meta) = request.meta {
+        if let Some(label) = meta.describe() {
+            writeln!(tty, "  {}", sanitize(&label))?;
+        }
+        if let Some(owner) = &meta.owner {
+            writeln!(tty, "  {}: {}", i18n::text("key-owner"), sanitize(owner))?;
+        }
+        if let Some(notes) = &meta.notes {
+            writeln!(tty, "  {}: {}", i18n::text("key-notes"), sanitize(notes))?;
+        }
+    }
+    write!(tty, "{} [y/N] ({}s): ", i18n::text("approval-question"), timeout.as_secs())?;
+    tty.flush()?;
+
+    match read_line(tty, timeout)? {
+        Some(answer) => Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")),
+        None => {
+            writeln!(tty, "\n{}", i18n::text("approval-timed-out"))?;
+            Ok(false)
+        }
+    }
+}
+
+/// Replace control characters, including escape sequences, with `?`
+pub fn sanitize(text: &str) -> String {
+    text.chars().map(|c| if c.is_control() { '?' } else { c }).collect()
+}
+
+/// Read one line from the terminal, or `None` if nothing arrives in time
+fn read_line<T: Read + AsRawFd>(tty: &mut T, timeout: Duration) -> Result<Option<String>> {
+    let deadline = Instant::now() + timeout;
+    let mut line = Vec::new();
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+
+        let mut fds = libc::pollfd {
+            fd: tty.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = remaining.as_millis().min(i32::MAX as u128) as i32;
+        // SAFETY: fds points to one valid pollfd for the duration of the call
+        let ready = unsafe { libc::poll(&mut fds, 1, timeout_ms) };
+        if ready < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err.into());
+        }
+        if ready == 0 {
+            return Ok(None);
+        }
+
+        let mut buf = [0u8; 64];
+        let n = tty.read(&mut buf)?;
+        if n == 0 {
+            return Ok(Some(String::from_utf8_lossy(&line).to_string()));
+        }
+        line.extend_from_slice(&buf[..n]);
+        if let Some(end) = line.iter().position(|b| *b == b'\n') {
+            return Ok(Some(String::from_utf8_lossy(&line[..end]).to_string()));
+        }
+    }
+}
+
+/// Ask with a desktop dialog
+///
+/// Uses `osascript` on macOS and `zenity` or `kdialog` elsewhere, whichever
+/// is installed. The dialog is closed and the request denied on timeout.
+pub fn ask_dialog(request: &ApprovalRequest, timeout: Duration) -> Result<bool> {
+    let _guard = TERMINAL.lock().unwrap_or_else(|e| e.into_inner());
+    let text = format!("{}\n\n{}", request.summary(), i18n::text("approval-question"));
+    let (allow, deny) = (i18n::text("approval-allow"), i18n::text("approval-deny"));
+
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("osascript");
+        command.args([
+            "-e", "on run argv",
+            "-e", "display dialog (item 1 of argv) with title \"ssh-agent-router\" \
+                   buttons {item 3 of argv, item 2 of argv} \
+                   default button (item 3 of argv) cancel button (item 3 of argv)",
+            "-e", "end run",
+            &text, allow, deny,
+        ]);
+        command
+    } else if which("zenity") {
+        let mut command = Command::new("zenity");
+        command.args(["--question", "--title=ssh-agent-router", "--text", &text]);
+        command.args([format!("--ok-label={}", allow), format!("--cancel-label={}", deny)]);
+        command
+    } else if which("kdialog") {
+        let mut command = Command::new("kdialog");
+        command.args(["--title", "ssh-agent-router", "--yes-label", allow, "--no-label", deny, "--yesno", &text]);
+        command
+    } else {
+        anyhow::bail!("No dialog program found, install zenity or kdialog");
+    };
+
+    Ok(run_with_timeout(&mut command, timeout)?.is_some_and(|status| status.success()))
+}
+
+/// Ask for Touch ID through the LocalAuthentication framework
+///
+/// The script runs under `osascript` so the router needs no Objective-C
+/// bindings. Cancelling, failing or not answering in time denies.
+pub fn ask_touch_id(request: &ApprovalRequest, timeout: Duration) -> Result<bool> {
+    const SCRIPT: &str = r#"
+        ObjC.import('LocalAuthentication');
+        function run(argv) {
+            var context = $.LAContext.alloc.init;
+            var done = false, approved = false;
+            // LAPolicyDeviceOwnerAuthenticationWithBiometrics
+            context.evaluatePolicyLocalizedReasonReply(1, argv[0], function (success, error) {
+                approved = success;
+                done = true;
+            });
+            while (!done) {
+                $.NSRunLoop.currentRunLoop.runUntilDate($.NSDate.dateWithTimeIntervalSinceNow(0.1));
+            }
+            if (!approved) {
+                $.exit(1);
+            }
+        }
+    "#;
+
+    let _guard = TERMINAL.lock().unwrap_or_else(|e| e.into_inner());
+    // Reads as one sentence in English: "approve an SSH sign request on ..."
+    let summary = request.summary();
+    let summary = match summary.chars().next() {
+        Some(first) => first.to_lowercase().chain(summary.chars().skip(1)).collect(),
+        None => summary,
+    };
+    let reason = i18n::format("approval-touch-id", &[("summary", &summary)]);
+    let mut command = Command::new("osascript");
+    command.args(["-l", "JavaScript", "-e", SCRIPT, &reason]);
+
+    Ok(run_with_timeout(&mut command, timeout)?.is_some_and(|status| status.success()))
+}
+
+/// Body of the JSON POST sent to the approval webhook
+#[derive(Serialize)]
+struct WebhookRequest<'a> {
+    request_id: &'a str,
+    socket: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socket_description: Option<&'a str>,
+    pid: Option<u32>,
+    uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_path: Option<String>,
+    fingerprint: String,
+    key_type: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_meta: Option<&'a KeyMeta>,
+    summary: String,
+}
+
+/// Expected answer from the approval webhook
+#[derive(Deserialize)]
+struct WebhookResponse {
+    approved: bool,
+}
+
+/// Ask an HTTP endpoint
+///
+/// The endpoint receives the request as JSON and must answer with
+/// `{"approved": true}` or `{"approved": false}` before the timeout.
+pub fn ask_webhook(request: &ApprovalRequest, config: &WebhookConfig, timeout: Duration) -> Result<bool> {
+    let body = WebhookRequest {
+        request_id: &request.context.id,
+        socket: &request.context.socket,
+        socket_description: request.socket_description,
+        pid: request.context.peer.pid,
+        uid: request.context.peer.uid,
+        client_path: privacy::client_path(&request.context.peer),
+        fingerprint: privacy::fingerprint(&request.key.fingerprint),
+        key_type: &request.key.key_type,
+        comment: privacy::comment(&request.key.comment),
+        key_meta: request.meta,
+        summary: request.summary(),
+    };
+
+    let mut post = ureq::AgentBuilder::new()
+        .timeout(timeout)
+        .build()
+        .post(&config.url);
+    if let Some(token) = &config.token {
+        post = post.set("Authorization", &format!("Bearer {}", token));
+    }
+    let body = post
+        .set("Content-Type", "application/json")
+        .send_string(&serde_json::to_string(&body)?)
+        .context("Failed to call approval webhook")?
+        .into_string()?;
+    let response: WebhookResponse = serde_json::from_str(&body)
+        .context("Unexpected response from approval webhook")?;
+
+    Ok(response.approved)
+}
+
+/// Ask through an ntfy push notification with approve/deny action buttons
+///
+/// The buttons publish `approve:<id>` or `deny:<id>` to the response topic,
+/// which is polled until an answer for this request arrives or the timeout
+/// expires. The answer is carried in the notification itself, so anyone who
+/// can read the request topic, or publish to the response topic, can
+/// approve. Both topics should be on a server that requires the access
+/// token.
+pub fn ask_ntfy(request: &ApprovalRequest, config: &NtfyConfig, timeout: Duration) -> Result<bool> {
+    let server = config.server.trim_end_matches('/');
+    let response_url = format!("{}/{}", server, config.response_topic());
+    let id = request_id();
+    let since = unix_time();
+
+    let actions = format!(
+        "http, {approve}, {url}, method=POST, body=approve:{id}, clear=true; \
+         http, {deny}, {url}, method=POST, body=deny:{id}, clear=true",
+        approve = i18n::text("approval-approve"),
+        deny = i18n::text("approval-deny"),
+        url = response_url,
+        id = id
+    );
+    let publish = http_agent()
+        .post(&format!("{}/{}", server, config.topic))
+        .set("Title", &header_value(i18n::text("approval-title")))
+        .set("Priority", "high")
+        .set("Tags", "key")
+        .set("Actions", &header_value(&actions));
+    authorize(publish, config)
+        .send_string(&format!(
+            "{}\n{}",
+            request.summary(),
+            i18n::format("approval-request-id", &[("id", &request.context.id)])
+        ))
+        .context("Failed to publish ntfy approval request")?;
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        std::thread::sleep(POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())));
+
+        let poll = http_agent()
+            .get(&format!("{}/json", response_url))
+            .query("poll", "1")
+            .query("since", &since.to_string());
+        let body = authorize(poll, config)
+            .call()
+            .context("Failed to poll ntfy response topic")?
+            .into_string()?;
+
+        // One JSON message per line
+        for line in body.lines() {
+            let Ok(message) = serde_json::from_str::<serde_json::Value>(line) else {
+                continue;
+            };
+            match message["message"].as_str() {
+                Some(answer) if answer == format!("approve:{}", id) => return Ok(true),
+                Some(answer) if answer == format!("deny:{}", id) => return Ok(false),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Ask through a Pushover emergency notification
+///
+/// Pushover has no deny action: acknowledging the notification approves
+/// the request and letting it time out denies it.
+pub fn ask_pushover(request: &ApprovalRequest, config: &PushoverConfig, timeout: Duration) -> Result<bool> {
+    const API: &str = "https://api.pushover.net/1";
+
+    // Emergency priority repeats the alert every `retry` seconds (minimum 30)
+    // until acknowledged or `expire` passes
+    let expire = timeout.as_secs().clamp(30, 10800).to_string();
+    let body = http_agent()
+        .post(&format!("{}/messages.json", API))
+        .send_form(&[
+            ("token", config.token.as_str()),
+            ("user", config.user.as_str()),
+            ("title", i18n::text("approval-title")),
+            ("message", &format!(
+                "{}\n{}. {}",
+                request.summary(),
+                i18n::format("approval-request-id", &[("id", &request.context.id)]),
+                i18n::text("approval-acknowledge")
+            )),
+            ("priority", "2"),
+            ("retry", "30"),
+            ("expire", &expire),
+        ])
+        .context("Failed to send Pushover approval request")?
+        .into_string()?;
+    let response: serde_json::Value = serde_json::from_str(&body)
+        .context("Unexpected response from Pushover")?;
+    let receipt = response["receipt"].as_str()
+        .ok_or_else(|| anyhow::anyhow!("Pushover did not return a receipt: {}", body))?
+        .to_string();
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        std::thread::sleep(POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())));
+
+        let body = http_agent()
+            .get(&format!("{}/receipts/{}.json", API, receipt))
+            .query("token", &config.token)
+            .call()
+            .context("Failed to poll Pushover receipt")?
+            .into_string()?;
+        let status: serde_json::Value = serde_json::from_str(&body)
+            .context("Unexpected response from Pushover")?;
+        if status["acknowledged"].as_i64() == Some(1) {
+            return Ok(true);
+        }
+    }
+
+    // Stop the alert from repeating once the request has been denied
+    let _ = http_agent()
+        .post(&format!("{}/receipts/{}/cancel.json", API, receipt))
+        .send_form(&[("token", config.token.as_str())]);
+
+    Ok(false)
+}
+
+/// Run a command, killing it if it has not exited when `timeout` expires
+fn run_with_timeout(command: &mut Command, timeout: Duration) -> Result<Option<ExitStatus>> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to run {:?}", command.get_program()))?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Whether `program` is on the PATH
+fn which(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+        .unwrap_or(false)
+}
+
+fn http_agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(10))
+        .build()
+}
+
+fn authorize(request: ureq::Request, config: &NtfyConfig) -> ureq::Request {
+    match &config.token {
+        Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+        None => request,
+    }
+}
+
+/// `text` as an HTTP header value, encoded as in RFC 2047 when it is not
+/// ASCII, which ntfy decodes
+fn header_value(text: &str) -> String {
+    use base64::Engine;
+
+    match text.is_ascii() {
+        true => text.to_string(),
+        false => format!("=?UTF-8?B?{}?=", base64::engine::general_purpose::STANDARD.encode(text)),
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Identifier matching an answer to the request it belongs to
+fn request_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let seq = REQUEST_SEQ.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}{:x}{:x}", nanos, std::process::id(), seq)
+}
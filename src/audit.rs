@@ -0,0 +1,295 @@
+use crate::config::{expand_tilde, AuditSink, KeyMeta};
+use crate::context::RequestContext;
+use crate::policy::DenyReason;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Name the records are logged under by syslog, journald and the unified log
+const IDENTIFIER: &str = "ssh-agent-router";
+
+/// The security/authorization facility, which most hosts keep private
+const LOG_AUTHPRIV: u8 = 10;
+const LOG_NOTICE: u8 = 5;
+const LOG_INFO: u8 = 6;
+
+/// Audit trail for one socket, written to each of its sinks
+///
+/// Kept separate from the log so a noisy socket cannot bury the decisions
+/// made on another one.
+pub struct AuditLog {
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+/// A decision recorded in the audit trail
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum AuditEvent<'a> {
+    /// A client listed identities and saw `exposed` of `total` upstream keys
+    List { exposed: usize, total: usize },
+    /// A client asked to sign with a key, making an SSHSIG signature if
+    /// `namespace` is set
+    Sign {
+        fingerprint: &'a str,
+        comment: &'a str,
+        /// The key's `[keys]` table, if the config has one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        key_meta: Option<&'a KeyMeta>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        namespace: Option<&'a str>,
+        allowed: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<DenyReason>,
+    },
+    /// A client asked to add a key upstream, limited to `destinations`
+    Add {
+        fingerprint: &'a str,
+        comment: &'a str,
+        destinations: Vec<String>,
+        allowed: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<DenyReason>,
+    },
+    /// A client asked to load (`add`) or unload (`remove`) a PKCS#11 token
+    Smartcard {
+        action: &'a str,
+        provider: &'a str,
+        allowed: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<DenyReason>,
+    },
+    /// A client was turned away as it connected, e.g. from a container,
+    /// with what gave it away
+    Connect {
+        detail: &'a str,
+        allowed: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<DenyReason>,
+    },
+}
+
+impl AuditEvent<'_> {
+    /// Syslog severity: refusals stand out from routine decisions
+    fn severity(&self) -> u8 {
+        match self {
+            AuditEvent::Sign { allowed: false, .. }
+            | AuditEvent::Add { allowed: false, .. }
+            | AuditEvent::Smartcard { allowed: false, .. }
+            | AuditEvent::Connect { allowed: false, .. } => LOG_NOTICE,
+            _ => LOG_INFO,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    time: String,
+    id: &'a str,
+    socket: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream: Option<String>,
+    #[serde(flatten)]
+    event: &'a AuditEvent<'a>,
+}
+
+/// Somewhere audit records are delivered
+trait Sink: Send + Sync {
+    /// Deliver one record, given both as JSON and as its fields
+    fn write(&self, line: &str, record: &serde_json::Value, severity: u8) -> Result<()>;
+}
+
+impl AuditLog {
+    /// Append to a single file
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self { sinks: vec![Box::new(FileSink::open(path)?)] })
+    }
+
+    /// Write to every sink in `sinks`
+    pub fn new(sinks: &[AuditSink]) -> Result<Self> {
+        let sinks = sinks.iter()
+            .map(|sink| -> Result<Box<dyn Sink>> {
+                Ok(match sink {
+                    AuditSink::File { path } => Box::new(FileSink::open(&expand_tilde(path))?),
+                    AuditSink::Syslog { socket } => Box::new(DatagramSink::syslog(socket.clone())?),
+                    AuditSink::Journald => Box::new(DatagramSink::journald()?),
+                    AuditSink::Oslog => Box::new(OslogSink::new()?),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { sinks })
+    }
+
+    /// Record `event` in every sink, even if an earlier one fails
+    ///
+    /// `upstream` names the agent socket that served the request when
+    /// upstream is one that moves, such as the newest forwarded agent.
+    pub fn record(&self, context: &RequestContext, upstream: Option<&Path>, event: &AuditEvent) -> Result<()> {
+        let record = AuditRecord {
+            time: humantime::format_rfc3339_seconds(context.time).to_string(),
+            id: &context.id,
+            socket: &context.socket,
+            pid: context.peer.pid,
+            uid: context.peer.uid,
+            upstream: upstream.map(|path| path.to_string_lossy().into_owned()),
+            event,
+        };
+        let line = serde_json::to_string(&record)?;
+        let fields = serde_json::to_value(&record)?;
+
+        let mut errors = Vec::new();
+        for sink in &self.sinks {
+            if let Err(e) = sink.write(&line, &fields, event.severity()) {
+                errors.push(format!("{:#}", e));
+            }
+        }
+        if !errors.is_empty() {
+            anyhow::bail!(errors.join("; "));
+        }
+        Ok(())
+    }
+}
+
+/// JSON lines appended to a file
+struct FileSink {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {:?}", parent))?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Failed to open audit file {:?}", path))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl Sink for FileSink {
+    fn write(&self, line: &str, _record: &serde_json::Value, _severity: u8) -> Result<()> {
+        // One write per record keeps lines whole with several writers
+        self.file.lock().unwrap().write_all(format!("{}\n", line).as_bytes())
+            .with_context(|| format!("Failed to write audit file {:?}", self.path))
+    }
+}
+
+/// Datagrams to the local syslog daemon or to journald
+///
+/// The daemon may start after the router or restart under it, so every
+/// record is sent to the socket path afresh.
+struct DatagramSink {
+    path: PathBuf,
+    format: fn(&str, &serde_json::Value, u8) -> Vec<u8>,
+    socket: UnixDatagram,
+}
+
+impl DatagramSink {
+    fn syslog(path: Option<PathBuf>) -> Result<Self> {
+        let default = if cfg!(target_os = "macos") { "/var/run/syslog" } else { "/dev/log" };
+        Self::new(path.unwrap_or_else(|| PathBuf::from(default)), syslog_message)
+    }
+
+    fn journald() -> Result<Self> {
+        Self::new(PathBuf::from("/run/systemd/journal/socket"), journal_message)
+    }
+
+    fn new(path: PathBuf, format: fn(&str, &serde_json::Value, u8) -> Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            path,
+            format,
+            socket: UnixDatagram::unbound().context("Failed to create datagram socket")?,
+        })
+    }
+}
+
+impl Sink for DatagramSink {
+    fn write(&self, line: &str, record: &serde_json::Value, severity: u8) -> Result<()> {
+        self.socket.send_to(&(self.format)(line, record, severity), &self.path)
+            .with_context(|| format!("Failed to send audit record to {:?}", self.path))?;
+        Ok(())
+    }
+}
+
+/// A BSD syslog message; the daemon adds the time and host
+fn syslog_message(line: &str, _record: &serde_json::Value, severity: u8) -> Vec<u8> {
+    format!("<{}>{}[{}]: {}", LOG_AUTHPRIV * 8 + severity, IDENTIFIER, std::process::id(), line).into_bytes()
+}
+
+/// A journald native protocol message with every record field as a
+/// journal field, e.g. `SSH_AGENT_ROUTER_FINGERPRINT`
+fn journal_message(line: &str, record: &serde_json::Value, severity: u8) -> Vec<u8> {
+    let mut message = Vec::new();
+    journal_field(&mut message, "MESSAGE", line);
+    journal_field(&mut message, "PRIORITY", &severity.to_string());
+    journal_field(&mut message, "SYSLOG_FACILITY", &LOG_AUTHPRIV.to_string());
+    journal_field(&mut message, "SYSLOG_IDENTIFIER", IDENTIFIER);
+
+    for (key, value) in record.as_object().into_iter().flatten() {
+        let value = match value {
+            serde_json::Value::String(s) => s.clone(),
+            serde_json::Value::Array(items) => items.iter()
+                .map(|item| item.as_str().map_or_else(|| item.to_string(), str::to_string))
+                .collect::<Vec<_>>()
+                .join(","),
+            other => other.to_string(),
+        };
+        journal_field(&mut message, &format!("SSH_AGENT_ROUTER_{}", key.to_uppercase()), &value);
+    }
+    message
+}
+
+fn journal_field(message: &mut Vec<u8>, key: &str, value: &str) {
+    message.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        // Values spanning lines are sent length-prefixed instead
+        message.push(b'\n');
+        message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        message.push(b'=');
+    }
+    message.extend_from_slice(value.as_bytes());
+    message.push(b'\n');
+}
+
+/// The macOS unified log, through syslog(3), which macOS stores there
+struct OslogSink;
+
+impl OslogSink {
+    fn new() -> Result<Self> {
+        if !cfg!(target_os = "macos") {
+            anyhow::bail!("The oslog audit sink is only available on macOS");
+        }
+        Ok(Self)
+    }
+}
+
+impl Sink for OslogSink {
+    fn write(&self, line: &str, _record: &serde_json::Value, severity: u8) -> Result<()> {
+        let message = std::ffi::CString::new(line)?;
+        let priority = (LOG_AUTHPRIV as libc::c_int) << 3 | severity as libc::c_int;
+        // SAFETY: both strings are NUL-terminated and outlive the call, and
+        // the format consumes exactly the one argument passed
+        unsafe { libc::syslog(priority, c"%s".as_ptr(), message.as_ptr()) };
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use crate::protocol;
+use anyhow::{Context, Result};
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Writes request/response pairs passing through a socket to a directory
+///
+/// Each pair becomes `<millis>-<socket>-<seq>.request` and `.response`,
+/// both holding the length-prefixed message as seen on the wire with its
+/// secrets zeroed: private keys, PINs and passphrases, session IDs and
+/// other data being signed, and signatures. Unless redacted they still
+/// hold key blobs and user names, so the directory is created 0700 and
+/// the files 0600.
+pub struct Capture {
+    dir: PathBuf,
+    redact: bool,
+    seq: AtomicU64,
+}
+
+impl Capture {
+    pub fn new(dir: PathBuf, redact: bool) -> Result<Self> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("Failed to create capture directory {:?}", dir))?;
+
+        // An existing directory is left alone, but shouldn't be shared
+        let mode = fs::metadata(&dir)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            log::warn!("Capture directory {:?} is accessible by other users (mode {:o})", dir, mode & 0o777);
+        }
+
+        Ok(Self {
+            dir,
+            redact,
+            seq: AtomicU64::new(0),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn record(&self, socket_name: &str, request: &[u8], response: &[u8]) -> Result<()> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let stem = format!("{}-{}-{:06}", millis, socket_name, seq);
+
+        let (request, response) = (protocol::redact_secrets(request), protocol::redact_secrets(response));
+        let (request, response) = if self.redact {
+            (protocol::redact_key_blobs(&request), protocol::redact_key_blobs(&response))
+        } else {
+            (request, response)
+        };
+
+        write_private(&self.dir.join(format!("{}.request", stem)), &request)
+            .context("Failed to write captured request")?;
+        write_private(&self.dir.join(format!("{}.response", stem)), &response)
+            .context("Failed to write captured response")?;
+
+        Ok(())
+    }
+}
+
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data)
+}
+
+/// Read a captured request file back into a length-prefixed message
+pub fn load_request(path: &Path) -> Result<Vec<u8>> {
+    load_message(path, "request")
+}
+
+/// The `.response` captured together with a `.request`, if it exists
+pub fn paired_response(request_path: &Path) -> Option<PathBuf> {
+    let path = request_path.with_extension("response");
+    (path != request_path && path.exists()).then_some(path)
+}
+
+/// Read a captured response file back into a length-prefixed message
+pub fn load_response(path: &Path) -> Result<Vec<u8>> {
+    load_message(path, "response")
+}
+
+fn load_message(path: &Path, kind: &str) -> Result<Vec<u8>> {
+    let message = fs::read(path)
+        .with_context(|| format!("Failed to read captured {} {:?}", kind, path))?;
+
+    if message.len() < 4 {
+        return Err(anyhow::anyhow!("Captured {} {:?} is truncated", kind, path));
+    }
+    let msg_len = u32::from_be_bytes([message[0], message[1], message[2], message[3]]) as usize;
+    if message.len() != 4 + msg_len {
+        return Err(anyhow::anyhow!(
+            "Captured {} {:?} has length prefix {} but holds {} bytes",
+            kind, path, msg_len, message.len() - 4
+        ));
+    }
+
+    Ok(message)
+}
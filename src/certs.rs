@@ -0,0 +1,224 @@
+use crate::agent::SshKey;
+use crate::config::CertificatesConfig;
+use crate::privacy;
+use crate::protocol::Reader;
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long to wait before running the command again for a key it failed
+/// for, so a broken CA does not hold up every listing
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A user certificate fetched for an upstream key
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    pub blob: Vec<u8>,
+    pub comment: String,
+    /// Blob of the key it certifies, which upstream signs with
+    pub key_blob: Vec<u8>,
+    /// Unix time it expires at
+    pub valid_before: u64,
+}
+
+impl Certificate {
+    /// Parse the certificate `line` printed for `key`, checking that it
+    /// is a user certificate for that very key
+    pub fn parse(line: &str, key: &SshKey) -> Result<Self> {
+        let line = line.lines().map(str::trim).find(|line| !line.is_empty() && !line.starts_with('#'))
+            .context("The command printed no certificate")?;
+        let mut parts = line.split_whitespace();
+        let cert_type = parts.next().unwrap_or_default();
+        let blob = STANDARD.decode(parts.next().context("The certificate line has no certificate data")?)
+            .context("The certificate data is not valid base64")?;
+        let comment = parts.collect::<Vec<_>>().join(" ");
+
+        // Key types end in @openssh.com for security keys, whose
+        // certificates are named sk-ssh-ed25519-cert-v01@openssh.com
+        let expected = format!("{}-cert-v01@openssh.com", key.key_type.trim_end_matches("@openssh.com"));
+        let mut reader = Reader::new(&blob);
+        let blob_type = reader.read_string()?;
+        if blob_type != expected.as_bytes() || cert_type != expected {
+            anyhow::bail!("Expected a {} certificate, got {}", expected, cert_type);
+        }
+        reader.read_string()?; // nonce
+
+        // The certified public key follows the nonce, in the same
+        // encoding as the key blob after its type
+        let mut key_reader = Reader::new(&key.blob);
+        key_reader.read_string()?;
+        let key_fields = &key.blob[key_reader.position()..];
+        if reader.read_bytes(key_fields.len())? != key_fields {
+            anyhow::bail!("The certificate is for another key than {}", key.fingerprint);
+        }
+        reader.read_u64()?; // serial
+        if reader.read_u32()? != 1 {
+            anyhow::bail!("The certificate is not a user certificate");
+        }
+        reader.read_string()?; // key id
+        reader.read_string()?; // principals
+        reader.read_u64()?; // valid after
+        let valid_before = reader.read_u64()?;
+
+        Ok(Self { blob, comment, key_blob: key.blob.clone(), valid_before })
+    }
+
+    /// When it expires, `None` for a certificate valid forever
+    pub fn expires(&self) -> Option<SystemTime> {
+        match self.valid_before {
+            u64::MAX => None,
+            secs => UNIX_EPOCH.checked_add(Duration::from_secs(secs)),
+        }
+    }
+
+    /// When to fetch a new one, `refresh_before` it expires
+    pub fn refresh_at(&self, refresh_before: Duration) -> Option<SystemTime> {
+        self.expires().map(|expires| expires.checked_sub(refresh_before).unwrap_or(UNIX_EPOCH))
+    }
+}
+
+/// Certificates fetched with the `[certificates]` command, shared by
+/// every socket so each key's is fetched once
+pub struct CertificateStore {
+    config: CertificatesConfig,
+    certificates: Mutex<HashMap<String, Certificate>>,
+    /// When the command last failed for a key
+    failed: Mutex<HashMap<String, Instant>>,
+    /// Keys the command is running for, so sockets listing at the same
+    /// time do not fetch their own
+    fetching: Mutex<HashSet<String>>,
+    /// Counts certificates fetched, so listings built without one are
+    /// rebuilt once it arrives
+    generation: AtomicU64,
+}
+
+impl CertificateStore {
+    pub fn new(config: CertificatesConfig) -> Self {
+        Self {
+            config,
+            certificates: Mutex::new(HashMap::new()),
+            failed: Mutex::new(HashMap::new()),
+            fetching: Mutex::new(HashSet::new()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn config(&self) -> &CertificatesConfig {
+        &self.config
+    }
+
+    /// The certificate to list with `key` now; `None` for keys not
+    /// configured
+    ///
+    /// When there is none yet or it is due for a refresh, a new one is
+    /// fetched in the background and the previous one returned while it
+    /// is still valid, so a slow command never holds up a listing. The
+    /// [`Self::generation`] changes once the new one is in.
+    pub fn certificate(self: &Arc<Self>, key: &SshKey) -> Option<Certificate> {
+        if !self.config.keys.is_empty() && !self.config.keys.iter().any(|k| key.matches_fingerprint(k)) {
+            return None;
+        }
+        let cached = self.certificates.lock().unwrap().get(&key.fingerprint).cloned();
+        let now = SystemTime::now();
+        if let Some(cert) = cached.as_ref().filter(|cert| cert.refresh_at(self.config.refresh_before).is_none_or(|at| at > now)) {
+            return Some(cert.clone());
+        }
+        let still_valid = cached.filter(|cert| cert.expires().is_none_or(|expires| expires > now));
+        if self.failed.lock().unwrap().get(&key.fingerprint).is_some_and(|at| at.elapsed() < RETRY_INTERVAL) {
+            return still_valid;
+        }
+        if !self.fetching.lock().unwrap().insert(key.fingerprint.clone()) {
+            return still_valid;
+        }
+
+        let (store, key) = (Arc::clone(self), key.clone());
+        std::thread::spawn(move || {
+            match store.fetch(&key) {
+                Ok(cert) => {
+                    let until = match cert.expires() {
+                        Some(expires) => format!("valid until {}", humantime::format_rfc3339_seconds(expires)),
+                        None => "valid forever".to_string(),
+                    };
+                    log::info!("Fetched a certificate for {}, {}", privacy::fingerprint(&key.fingerprint), until);
+                    store.failed.lock().unwrap().remove(&key.fingerprint);
+                    store.certificates.lock().unwrap().insert(key.fingerprint.clone(), cert);
+                    store.generation.fetch_add(1, Ordering::SeqCst);
+                }
+                Err(e) => {
+                    log::warn!("Failed to fetch a certificate for {}: {:#}", privacy::fingerprint(&key.fingerprint), e);
+                    store.failed.lock().unwrap().insert(key.fingerprint.clone(), Instant::now());
+                }
+            }
+            store.fetching.lock().unwrap().remove(&key.fingerprint);
+        });
+        still_valid
+    }
+
+    /// Counter that changes whenever a certificate was fetched
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// The key blob a certificate listed by the router certifies
+    pub fn key_of(&self, cert_blob: &[u8]) -> Option<Vec<u8>> {
+        self.certificates.lock().unwrap().values()
+            .find(|cert| cert.blob == cert_blob)
+            .map(|cert| cert.key_blob.clone())
+    }
+
+    fn fetch(&self, key: &SshKey) -> Result<Certificate> {
+        let line = key.to_public_key_line();
+        let mut child = Command::new("sh")
+            .args(["-c", &self.config.command])
+            .env("SSH_AGENT_ROUTER_KEY", &line)
+            .env("SSH_AGENT_ROUTER_FINGERPRINT", &key.fingerprint)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to run the certificates command")?;
+
+        // Read on threads so a command that prints a lot does not block on
+        // a full pipe while the timeout is checked
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let output = std::thread::spawn(move || {
+            let mut output = String::new();
+            stdout.read_to_string(&mut output).map(|_| output)
+        });
+        let errors = std::thread::spawn(move || {
+            let mut errors = String::new();
+            let _ = stderr.read_to_string(&mut errors);
+            errors
+        });
+        // A command that does not read its input must not fail the fetch
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = writeln!(stdin, "{}", line);
+        }
+
+        let deadline = Instant::now() + self.config.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                anyhow::bail!("The certificates command did not finish within {}", humantime::format_duration(self.config.timeout));
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        };
+        let output = output.join().map_err(|_| anyhow::anyhow!("Failed to read the command output"))??;
+        let errors = errors.join().unwrap_or_default();
+        if !status.success() {
+            anyhow::bail!("The certificates command failed ({}): {}", status, errors.trim());
+        }
+        Certificate::parse(&output, key)
+    }
+}
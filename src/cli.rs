@@ -1,15 +1,57 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use log::LevelFilter;
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(name = "ssh-agent-router")]
 #[command(about = "SSH agent router with key filtering capabilities")]
+#[command(version)]
 #[command(long_about = "Create multiple SSH authentication sockets with usage restrictions from a single upstream SSH agent.\n\nExamples:\n  ssh-agent-router /tmp/work.sock:SHA256:abc123,SHA256:def456 /tmp/personal.sock:SHA256:xyz789\n  ssh-agent-router -- /tmp/work.sock SHA256:abc123,SHA256:def456 -- /tmp/personal.sock SHA256:xyz789")]
 pub struct Cli {
     /// Path to upstream SSH agent socket (default: $SSH_AUTH_SOCK)
     #[arg(long, default_value = "", value_name = "PATH")]
     pub upstream: String,
 
+    /// Log level: error, warn, info, debug or trace (default: info)
+    ///
+    /// At trace level every agent message is logged with a hexdump.
+    #[arg(long, value_name = "LEVEL", value_parser = parse_level)]
+    pub log_level: Option<LevelFilter>,
+
+    /// Zero out key blobs in trace hexdumps
+    #[arg(long)]
+    pub log_redact: bool,
+
+    /// Config file to use instead of the one found by searching
+    /// $SSH_AGENT_ROUTER_CONFIG, the active profile,
+    /// ~/.config/ssh-agent-router/config.toml and /etc/ssh-agent-router/config.toml
+    // A distinct id, since `config` is also a subcommand
+    #[arg(long = "config", id = "config_file", global = true, value_name = "PATH", conflicts_with = "profile_name")]
+    pub config: Option<PathBuf>,
+
+    /// Use profiles/NAME.toml instead of the active profile
+    // A distinct id, since `profile` is also a subcommand
+    #[arg(long = "profile", id = "profile_name", global = true, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Print tables without color; also off when NO_COLOR is set or
+    /// stdout is not a terminal
+    #[arg(long, global = true)]
+    pub no_color: bool,
+
+    /// Relay control requests between stdin/stdout and the running router,
+    /// as `remote` runs over SSH
+    #[arg(long, hide = true)]
+    pub control_json: bool,
+
+    /// Dump every request/response pair into DIR for offline debugging
+    #[arg(long, value_name = "DIR")]
+    pub debug_capture: Option<PathBuf>,
+
+    /// Zero out key blobs in captured messages
+    #[arg(long)]
+    pub capture_redact: bool,
+
     /// Socket configurations in format: path:fingerprint1,fingerprint2,-fingerprint3
     /// Or use space-separated format: path fingerprint1,fingerprint2,-fingerprint3
     /// 
@@ -24,25 +66,275 @@ pub struct Cli {
     pub command: Option<Commands>,
 }
 
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    level.parse()
+        .map_err(|_| format!("invalid log level '{}', expected error, warn, info, debug or trace", level))
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Commands {
+    /// Run the router in the foreground, optionally until it goes idle
+    Run {
+        /// Exit after no client has connected for this long, e.g. `10m`
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+        until_idle: Option<std::time::Duration>,
+
+        /// Take the sockets over from the router already running, which
+        /// exits, so clients never find them missing across an upgrade
+        #[arg(long)]
+        takeover: bool,
+    },
+
     /// List all created sockets
-    ListSocks,
+    ListSocks {
+        /// Show live connection statistics from the running router
+        #[arg(long, short)]
+        verbose: bool,
+
+        /// Show fingerprints in full instead of shortened
+        #[arg(long, short)]
+        long: bool,
+    },
     
-    /// List all available keys from upstream
-    ListKeys,
+    /// List all available keys from upstream and the sockets exposing them
+    ListKeys {
+        /// Show a key-by-socket table instead of a list
+        #[arg(long)]
+        matrix: bool,
+
+        /// Show fingerprints in full instead of shortened
+        #[arg(long, short)]
+        long: bool,
+    },
     
     /// List both sockets and keys
     List,
     
+    /// Show which sockets expose a key and why
+    Which {
+        /// Key fingerprint, e.g. SHA256:abc123
+        #[arg(value_name = "FINGERPRINT", required_unless_present = "pubkey")]
+        fingerprint: Option<String>,
+
+        /// Read the key from a public key file instead
+        #[arg(long, value_name = "FILE", conflicts_with = "fingerprint")]
+        pubkey: Option<PathBuf>,
+    },
+
+    /// Print SHA256 and MD5 fingerprints of public keys, as used in allow/deny lists
+    Fingerprint {
+        /// Public key or authorized_keys file, `-` for stdin
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+    },
+
+    /// Print the public keys visible through a socket
+    Export {
+        /// Socket name or path (default: every configured socket)
+        #[arg(long, value_name = "NAME")]
+        socket: Option<String>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value = "authorized_keys")]
+        format: ExportFormat,
+
+        /// Write one `<socket>.keys` file per socket into DIR instead of printing
+        #[arg(long, value_name = "DIR")]
+        output_dir: Option<PathBuf>,
+    },
+
     /// Show router status
-    Status,
+    Status {
+        /// Show fingerprints in full instead of shortened
+        #[arg(long, short)]
+        long: bool,
+    },
+
+    /// Check the configuration, the upstream agent and socket paths for problems
+    Doctor,
+
+    /// Acknowledge upstream keys so pinning stops reporting or hiding them
+    PinKeys {
+        /// Fingerprints to pin (default: every key upstream lists now)
+        #[arg(value_name = "FINGERPRINT")]
+        fingerprints: Vec<String>,
+    },
+
+    /// Show or reset the uses of count-limited grants
+    Grants {
+        #[command(subcommand)]
+        command: GrantsCommand,
+    },
+
+    /// List profiles or change the active one
+    Profile {
+        #[command(subcommand)]
+        command: ProfileCommand,
+    },
+
+    /// Create or remove temporary sockets in the running router
+    Socket {
+        #[command(subcommand)]
+        command: SocketCommand,
+    },
+
+    /// Run a command with a socket exposing only the keys the project's
+    /// .ssh-agent-router.toml allows
+    Exec {
+        /// Project directory (default: the current one, searching upward)
+        #[arg(long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+
+        /// Command and its arguments
+        #[arg(trailing_var_arg = true, required = true, value_name = "COMMAND")]
+        command: Vec<String>,
+    },
+
+    /// Bind the project's socket and print shell exports for direnv's
+    /// .envrc: eval "$(ssh-agent-router direnv)"
+    Direnv {
+        /// Project directory (default: the current one, searching upward)
+        #[arg(long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
+
+    /// Set up a git repository to sign commits with a socket's key and
+    /// to fetch and push through that socket
+    GitSetup {
+        /// Socket name or path; it must expose exactly one upstream key
+        #[arg(long, value_name = "SOCKET")]
+        socket: String,
+
+        /// Repository to configure
+        #[arg(long, value_name = "DIR", default_value = ".")]
+        repo: PathBuf,
+
+        #[command(flatten)]
+        change: Change,
+    },
+
+    /// Print an ssh_config block sending some hosts through a socket
+    SshConfig {
+        /// Socket name or path
+        #[arg(long, value_name = "SOCKET")]
+        socket: String,
+
+        /// Host patterns, e.g. "*.corp.example,bastion"
+        #[arg(long, value_name = "PATTERNS")]
+        hosts: String,
+
+        /// Write the block to ~/.ssh/config.d/ssh-agent-router.conf,
+        /// replacing the socket's previous one, and include that file
+        /// from ~/.ssh/config
+        #[arg(long)]
+        install: bool,
+
+        #[command(flatten)]
+        change: Change,
+    },
+
+    /// Put a filtering socket in place of another agent's socket until
+    /// interrupted, then put the original back
+    Attach {
+        /// Socket of the agent to filter
+        #[arg(value_name = "SOCKET")]
+        socket: PathBuf,
+
+        /// Fingerprint or pattern of a key to expose (repeatable)
+        #[arg(long, value_name = "FINGERPRINT", required = true)]
+        allow: Vec<String>,
+
+        /// Fingerprint or pattern of a key to hide (repeatable)
+        #[arg(long, value_name = "FINGERPRINT")]
+        deny: Vec<String>,
+    },
+
+    /// Print the running router's state in one line, for status bars
+    Summary {
+        /// Print the full summary as JSON instead
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Print the running router's events as JSON lines until interrupted
+    Subscribe,
+
+    /// Print the running router's most recent events as JSON lines
+    Recent {
+        /// How many events to print, at most 1000
+        #[arg(long, short = 'n', default_value_t = 50)]
+        count: usize,
+    },
+
+    /// Make every socket of the running router refuse everything and
+    /// disconnect all clients, until `resume`
+    Panic {
+        /// Also lock the upstream agent, asking for a passphrase to
+        /// unlock it with (read from stdin when it is not a terminal)
+        #[arg(long)]
+        lock: bool,
+    },
+
+    /// Leave panic mode so sockets apply their policy again
+    Resume {
+        /// Unlock the upstream agent first, asking for its passphrase
+        #[arg(long)]
+        unlock: bool,
+    },
+
+    /// Bind the running router's sockets tagged with a tag again, after
+    /// `disable`
+    Enable {
+        /// Tag from the sockets' `tags`
+        #[arg(long)]
+        tag: String,
+    },
+
+    /// Unbind the running router's sockets tagged with a tag and
+    /// disconnect their clients, until `enable` or a restart
+    Disable {
+        /// Tag from the sockets' `tags`
+        #[arg(long)]
+        tag: String,
+    },
+
+    /// Query routers on other machines over SSH
+    Remote {
+        /// Host to run the query on, as given to ssh (repeatable)
+        #[arg(long, value_name = "HOST", required = true)]
+        host: Vec<String>,
+
+        /// ssh-agent-router on the hosts, if it is not on their PATH
+        #[arg(long, value_name = "PATH", default_value = "ssh-agent-router")]
+        remote_program: String,
+
+        #[command(subcommand)]
+        command: RemoteCommand,
+    },
+
+    /// Remove socket files left behind by a router that did not shut down
+    /// cleanly
+    Clean {
+        /// List the stale sockets without removing them
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Forget cached sign request approvals in the running router
+    FlushApprovals {
+        /// Socket name or path (default: every socket)
+        #[arg(value_name = "SOCKET")]
+        socket: Option<String>,
+    },
     
     /// TUI-based configuration editor
     Config {
         /// Enable enhanced mode
         #[arg(long)]
         enhanced: bool,
+
+        #[command(subcommand)]
+        command: Option<ConfigCommand>,
     },
     
     /// Upgrade the application
@@ -50,13 +342,248 @@ pub enum Commands {
         /// Enable auto-upgrade
         #[arg(long)]
         auto_upgrade: bool,
+
+        #[command(flatten)]
+        change: Change,
     },
     
+    /// Package the latest crash diagnostics, the build and the redacted
+    /// config into a report to attach to an issue
+    Bugreport {
+        /// Write the report to FILE instead of stdout
+        #[arg(long, short, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// Show the version, git commit, build date and compiled-in features
+    Version {
+        /// Print it as JSON instead
+        #[arg(long)]
+        json: bool,
+    },
+
     /// Register auto-start on system boot
-    RegisterAutostart,
+    RegisterAutostart {
+        #[command(flatten)]
+        change: Change,
+    },
     
     /// Unregister auto-start on system boot
-    UnregisterAutostart,
+    UnregisterAutostart {
+        #[command(flatten)]
+        change: Change,
+    },
+
+    /// Keep captured requests as regression fixtures and replay them
+    /// through the filters
+    Corpus {
+        #[command(subcommand)]
+        command: CorpusCommand,
+    },
+
+    /// Check how an agent socket, the router's or any other, handles
+    /// valid and malformed agent protocol messages
+    Conformance {
+        /// Agent socket to check (default: $SSH_AUTH_SOCK)
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+
+        /// How long to wait for each answer
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, default_value = "2s")]
+        timeout: std::time::Duration,
+
+        /// Print the results as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Evaluate a captured request against a socket's filters offline
+    Replay {
+        /// Captured `.request` file written by --debug-capture
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        /// Path of the configured socket whose filters to apply
+        /// (default: the only configured socket)
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+
+        /// Forward the request to the upstream agent; replaying a sign
+        /// request this way creates a real signature
+        #[arg(long)]
+        live: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ProfileCommand {
+    /// List profiles, marking the active one
+    List,
+
+    /// Make a profile active, switching the running router over to it
+    Switch {
+        /// Profile name, or `default` for config.toml
+        #[arg(value_name = "NAME")]
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum GrantsCommand {
+    /// List count-limited grants and how often each was used
+    List,
+
+    /// Make used grants available again
+    Reset {
+        /// Socket name or path (default: every socket)
+        #[arg(value_name = "SOCKET")]
+        socket: Option<String>,
+
+        /// Only the grant with this `key` (default: every grant)
+        #[arg(long, value_name = "KEY")]
+        key: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Check the config file and point out expired sockets
+    Validate,
+    /// Print the configuration the running router uses, with inherited
+    /// and default settings filled in and groups expanded
+    Effective,
+    /// Show how the config file differs from what the running router uses
+    Diff,
+    /// Restore the config file as it was before it was last saved
+    Rollback {
+        #[command(flatten)]
+        change: Change,
+    },
+    /// Rewrite the config file in the current schema version
+    Migrate {
+        #[command(flatten)]
+        change: Change,
+    },
+    /// Check that the config file holds no tokens, keys or email addresses
+    /// before publishing it, e.g. in a dotfile repository
+    CheckPublic,
+    /// Split the config file into one safe to publish and a private file
+    /// it includes, holding tokens and other secrets
+    Scrub {
+        /// Where to write the public file (default: over the config file)
+        #[arg(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+
+        /// Where to write the private file (default: private.toml next to
+        /// the public file)
+        #[arg(long, value_name = "PATH")]
+        private: Option<PathBuf>,
+
+        /// Also replace email addresses and `user@host` key comments with
+        /// placeholders such as user1@example.com, keeping the originals in
+        /// the private file
+        #[arg(long)]
+        placeholders: bool,
+
+        #[command(flatten)]
+        change: Change,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, Copy)]
+pub enum RemoteCommand {
+    /// Show each router's state and its sockets' counters
+    Status,
+    /// List the keys each router's upstream agent listed most recently
+    ListKeys {
+        /// Show fingerprints in full instead of shortened
+        #[arg(long, short)]
+        long: bool,
+    },
+    /// Print the routers' events as JSON lines until interrupted, with the
+    /// host added to each
+    Watch,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CorpusCommand {
+    /// Turn captured `.request` files into anonymized fixtures, replayed
+    /// against the socket's current settings
+    Add {
+        /// Captured `.request` files written by --debug-capture
+        #[arg(value_name = "FILE", required = true)]
+        files: Vec<PathBuf>,
+
+        /// Path of the configured socket whose settings the fixtures keep
+        /// (default: the only configured socket)
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+
+        /// Directory to write the fixtures to (default: corpus in the data
+        /// directory)
+        #[arg(long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
+    /// Replay every fixture and report the ones the filters now answer
+    /// differently
+    Check {
+        /// Directory holding the fixtures (default: corpus in the data
+        /// directory)
+        #[arg(long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
+}
+
+/// Flags of commands that change files or the system, which show the
+/// change and ask before making it
+#[derive(Args, Debug, Clone, Copy, Default)]
+pub struct Change {
+    /// Show what would change without changing anything
+    #[arg(long, conflicts_with = "yes")]
+    pub dry_run: bool,
+
+    /// Make the change without asking, as needed when stdin is not a
+    /// terminal
+    #[arg(long, short)]
+    pub yes: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SocketCommand {
+    /// Bind a socket exposing only the given keys and print its path
+    Create {
+        /// Fingerprint or pattern of a key to expose (repeatable)
+        #[arg(long, value_name = "FINGERPRINT", required = true)]
+        allow: Vec<String>,
+
+        /// Remove the socket after this long, e.g. `1h` (default: when the router stops)
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+        ttl: Option<std::time::Duration>,
+
+        /// Socket name (default: ephemeral-N)
+        #[arg(long, value_name = "NAME")]
+        name: Option<String>,
+
+        /// Socket file (default: in the router's runtime directory)
+        #[arg(long, value_name = "PATH")]
+        path: Option<PathBuf>,
+    },
+
+    /// Unbind a socket created with `socket create`
+    Remove {
+        /// Socket name or path
+        #[arg(value_name = "SOCKET")]
+        socket: String,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// `type base64 comment` lines usable in authorized_keys or GitHub
+    #[value(name = "authorized_keys")]
+    AuthorizedKeys,
+    /// One SHA256 fingerprint per line
+    Fingerprints,
 }
 
 #[derive(Debug, Clone)]
@@ -86,8 +613,8 @@ impl SocketConfig {
             if fp.is_empty() {
                 continue;
             }
-            if fp.starts_with('-') {
-                denied.push(fp[1..].to_string());
+            if let Some(fp) = fp.strip_prefix('-') {
+                denied.push(fp.to_string());
             } else {
                 allowed.push(fp.to_string());
             }
@@ -132,8 +659,8 @@ impl SocketConfig {
                 if fp.is_empty() {
                     continue;
                 }
-                if fp.starts_with('-') {
-                    denied.push(fp[1..].to_string());
+                if let Some(fp) = fp.strip_prefix('-') {
+                    denied.push(fp.to_string());
                 } else {
                     allowed.push(fp.to_string());
                 }
@@ -1,81 +1,1842 @@
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
 use anyhow::{Context, Result};
+use log::LevelFilter;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
+    /// Schema version the file was written for; older files are upgraded
+    /// when loaded
+    #[serde(default = "unversioned")]
+    pub version: u32,
+
+    /// Files merged over this one, e.g. tokens and key comments kept out
+    /// of a public dotfile repository; relative paths are next to this
+    /// file and missing ones are skipped
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<PathBuf>,
+
     /// Path to upstream SSH agent socket
     #[serde(default = "default_upstream")]
     pub upstream: String,
     
+    /// Log level (error, warn, info, debug, trace)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<LevelFilter>,
+
+    /// Zero out key blobs in TRACE hexdumps
+    #[serde(default)]
+    pub log_redact: bool,
+
+    /// What sockets expose when their allow list is empty (default: allow)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<DefaultPolicy>,
+
+    /// Resource limits applied to every socket and the upstream connection
+    #[serde(default)]
+    pub limits: Limits,
+
+    /// Settings for the push notification confirmation backends
+    #[serde(default)]
+    pub approval: ApprovalConfig,
+
+    /// Alert when the upstream key set changes
+    #[serde(default)]
+    pub pinning: PinningConfig,
+
+    /// Named sets of fingerprints, referenced as `@name` in rules and lists
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, Vec<String>>,
+
+    /// What the user knows about keys, by fingerprint, shown in key
+    /// listings, audit records and confirmation prompts
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub keys: BTreeMap<String, KeyMeta>,
+
+    /// How sockets treat keys of each `risk` level, e.g. `[risk.high]`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub risk: BTreeMap<Risk, RiskTier>,
+
+    /// Export traces to an OpenTelemetry collector
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+
+    /// What logs, events and notifications may reveal
+    #[serde(default)]
+    pub privacy: PrivacyConfig,
+
+    /// Language of command output and approval prompts
+    #[serde(default)]
+    pub ui: UiConfig,
+
+    /// Where sockets without their own `audit` list send audit records
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audit: Vec<AuditSink>,
+
+    /// Agent messages sockets without their own list pass on to upstream;
+    /// anything else is refused by the router. Unset passes everything on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_messages: Option<Vec<String>>,
+
     /// Socket configurations
     #[serde(default)]
     pub sockets: Vec<SocketEntry>,
+
+    /// Generate one socket per upstream key, exposing only that key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_key_sockets: Option<PerKeySockets>,
+
+    /// Fetch short-lived certificates for upstream keys and list them
+    /// alongside the keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub certificates: Option<CertificatesConfig>,
+
+    /// Modification time of the file this was loaded from, which socket
+    /// `ttl`s count from
+    #[serde(skip)]
+    pub modified: Option<SystemTime>,
+}
+
+/// Notes on one key from a `[keys."SHA256:..."]` table
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyMeta {
+    /// What the key is for, e.g. "Production deploy key"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Who is responsible for the key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub risk: Option<Risk>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    /// Month (`2025-09`) or day (`2025-09-30`) the key is due to be
+    /// replaced by
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rotate_by: Option<String>,
+}
+
+impl KeyMeta {
+    /// The label with the risk and rotation date, e.g. "Production deploy
+    /// key (high risk, rotate by 2025-09)", `None` if none is set
+    pub fn describe(&self) -> Option<String> {
+        let mut details = Vec::new();
+        if let Some(risk) = self.risk {
+            details.push(crate::i18n::text(risk.message_id()).to_string());
+        }
+        if let Some(rotate_by) = &self.rotate_by {
+            details.push(crate::i18n::format("key-rotate-by", &[("date", rotate_by)]));
+        }
+        match (&self.label, details.is_empty()) {
+            (None, true) => None,
+            (None, false) => Some(details.join(", ")),
+            (Some(label), true) => Some(label.clone()),
+            (Some(label), false) => Some(format!("{} ({})", label, details.join(", "))),
+        }
+    }
+
+    /// Whether `rotate_by` has passed on `today`, given as `YYYY-MM-DD`
+    pub fn rotation_due(&self, today: &str) -> bool {
+        self.rotate_by.as_deref()
+            .is_some_and(|date| today.get(..date.len()).is_some_and(|today| today > date))
+    }
+
+    fn validate(&self, fingerprint: &str) -> Result<()> {
+        if !fingerprint.starts_with("SHA256:") {
+            anyhow::bail!("keys.{:?} must be a SHA256 fingerprint, e.g. [keys.\"SHA256:...\"]", fingerprint);
+        }
+        if let Some(date) = &self.rotate_by {
+            let valid = match date.len() {
+                7 => humantime::parse_rfc3339(&format!("{}-01T00:00:00Z", date)).is_ok(),
+                10 => humantime::parse_rfc3339(&format!("{}T00:00:00Z", date)).is_ok(),
+                _ => false,
+            };
+            if !valid {
+                anyhow::bail!("keys.{:?}.rotate_by {:?} must be a month or day, e.g. \"2025-09\" or \"2025-09-30\"", fingerprint, date);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How much harm a key could do in the wrong hands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Risk {
+    Low,
+    Medium,
+    High,
+}
+
+impl Risk {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Risk::Low => "low",
+            Risk::Medium => "medium",
+            Risk::High => "high",
+        }
+    }
+
+    fn message_id(self) -> &'static str {
+        match self {
+            Risk::Low => "key-risk-low",
+            Risk::Medium => "key-risk-medium",
+            Risk::High => "key-risk-high",
+        }
+    }
+}
+
+/// Defaults for signing with keys of one risk level
+///
+/// Each setting applies on sockets that do not set their own.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RiskTier {
+    /// Ask before every sign request with these keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirm: Option<ConfirmMethod>,
+    /// Where sign decisions for these keys are recorded; `[]` keeps them
+    /// out of the audit trail
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audit: Option<Vec<AuditSink>>,
+    /// Tell the user whenever these keys are used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notify: Option<NotifyMethod>,
+}
+
+/// How to tell the user a key was used, without waiting for an answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyMethod {
+    /// Desktop notification (osascript on macOS, notify-send elsewhere)
+    Desktop,
+    /// Push notification to the `[approval.ntfy]` topic
+    Ntfy,
+}
+
+/// Key and client details that may appear in logs, events, notifications
+/// and webhooks; the audit trail always has them all
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrivacyConfig {
+    /// Show key comments
+    pub comments: bool,
+    /// Show whole fingerprints rather than the start of the hash
+    pub full_fingerprints: bool,
+    /// Show the executable of client processes
+    pub client_paths: bool,
+}
+
+impl PrivacyConfig {
+    pub const SHOW_ALL: Self = Self {
+        comments: true,
+        full_fingerprints: true,
+        client_paths: true,
+    };
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        Self::SHOW_ALL
+    }
+}
+
+/// How the router talks to the user
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UiConfig {
+    /// `en` or `ja`; unset or `auto` follows `LC_ALL`, `LC_MESSAGES` and
+    /// `LANG`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+/// Trace export, available when built with the `otel` feature
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// OTLP/HTTP collector to send spans to, e.g. http://localhost:4318
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub otlp_endpoint: Option<String>,
+
+    /// `service.name` of the exported spans
+    pub service_name: String,
+
+    /// Extra HTTP headers for the collector, e.g. for authentication
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: "ssh-agent-router".to_string(),
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+/// Sockets generated from the upstream key list, one per key
+///
+/// The router lists upstream keys every `interval`, binding a socket for
+/// each new key and unbinding the sockets of keys that went away.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PerKeySockets {
+    /// Directory the sockets are created in
+    pub dir: PathBuf,
+
+    /// Socket file name, with `{comment_slug}`, `{fingerprint_slug}` and
+    /// `{key_type}` replaced for each key (default: `{comment_slug}.sock`)
+    #[serde(default = "default_per_key_pattern")]
+    pub pattern: String,
+
+    /// How often to check upstream for added and removed keys
+    #[serde(default = "default_per_key_interval", with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+/// `[certificates]`: a command, such as `step ssh certificate` or `vault
+/// write ssh/sign/...`, that signs a certificate for an upstream key
+///
+/// The command runs with `sh -c`, gets the key's public key line on stdin
+/// and in `SSH_AGENT_ROUTER_KEY`, its fingerprint in
+/// `SSH_AGENT_ROUTER_FINGERPRINT`, and prints the certificate as an
+/// OpenSSH public key line. Certificates are fetched again `refresh_before`
+/// they expire.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificatesConfig {
+    pub command: String,
+
+    /// Keys to fetch certificates for, by fingerprint or `@group`
+    /// (default: every key)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<String>,
+
+    #[serde(default = "default_cert_refresh_before", with = "humantime_serde")]
+    pub refresh_before: Duration,
+
+    /// How long the command may take before it is killed
+    #[serde(default = "default_cert_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+fn default_cert_refresh_before() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+fn default_cert_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+impl CertificatesConfig {
+    fn validate(&self) -> Result<()> {
+        if self.command.trim().is_empty() {
+            anyhow::bail!("certificates.command must not be empty");
+        }
+        if self.timeout.is_zero() {
+            anyhow::bail!("certificates.timeout must be greater than 0");
+        }
+        Ok(())
+    }
+}
+
+fn default_per_key_pattern() -> String {
+    "{comment_slug}.sock".to_string()
+}
+
+fn default_per_key_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+impl PerKeySockets {
+    /// Socket file for a key, named by `pattern`
+    ///
+    /// A key without a comment is named by its fingerprint instead.
+    pub fn socket_path(&self, fingerprint: &str, key_type: &str, comment: &str) -> PathBuf {
+        // Base64 is case-sensitive, so only the characters unsafe in file
+        // names are changed, as in the URL-safe alphabet
+        let fingerprint_slug: String = fingerprint.strip_prefix("SHA256:").unwrap_or(fingerprint)
+            .chars()
+            .filter(|c| *c != '=')
+            .map(|c| match c {
+                '+' => '-',
+                '/' => '_',
+                c => c,
+            })
+            .collect();
+        let comment_slug = match slug(comment) {
+            comment if comment.is_empty() => fingerprint_slug.clone(),
+            comment => comment,
+        };
+        let name = self.pattern
+            .replace("{comment_slug}", &comment_slug)
+            .replace("{fingerprint_slug}", &fingerprint_slug)
+            .replace("{key_type}", &slug(key_type));
+        expand_tilde(&self.dir).join(name)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !["{comment_slug}", "{fingerprint_slug}"].iter().any(|p| self.pattern.contains(p)) {
+            anyhow::bail!(
+                "per_key_sockets.pattern {:?} needs {{comment_slug}} or {{fingerprint_slug}} to tell keys apart",
+                self.pattern
+            );
+        }
+        if self.pattern.contains('/') {
+            anyhow::bail!("per_key_sockets.pattern {:?} must be a file name, set the directory with dir", self.pattern);
+        }
+        if self.interval.is_zero() {
+            anyhow::bail!("per_key_sockets.interval must be greater than 0");
+        }
+        Ok(())
+    }
+}
+
+/// Lowercase letters, digits, `.` and `_`, with every other run of
+/// characters turned into a single `-`
+fn slug(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() || matches!(c, '.' | '_') {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').trim_start_matches('.').to_string()
+}
+
+/// A destination for audit records
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AuditSink {
+    /// Append one JSON object per line to a file
+    File { path: PathBuf },
+    /// Send to the local syslog daemon under the authpriv facility
+    Syslog {
+        /// Datagram socket of the daemon (default: /dev/log, or
+        /// /var/run/syslog on macOS)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        socket: Option<PathBuf>,
+    },
+    /// Send to systemd-journald with each record field as a journal field
+    Journald,
+    /// Write to the macOS unified log
+    Oslog,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DefaultPolicy {
+    /// An empty allow list exposes every key that is not denied
+    #[default]
+    Allow,
+    /// An empty allow list exposes nothing
+    Deny,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// Maximum size of a single agent message in bytes
+    pub max_message_size: u32,
+
+    /// Maximum concurrent client connections per socket
+    pub max_connections: usize,
+
+    /// Read/write timeout for client and upstream connections (e.g. "30s")
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub per_conn_timeout: Option<Duration>,
+
+    /// Maximum number of identities returned to a client per listing
+    pub max_identities: usize,
+
+    /// Persistent connections to the upstream agent shared by all clients
+    pub upstream_connections: usize,
+
+    /// How long the router holds a request while the upstream socket does
+    /// not exist yet, e.g. before the agent has started; "0s" fails right
+    /// away
+    #[serde(with = "humantime_serde")]
+    pub upstream_wait: Duration,
+
+    /// Retrying connections upstream refuses or drops, as while the agent
+    /// restarts
+    pub upstream_retry: RetryPolicy,
+}
+
+/// How often and how patiently to retry the upstream agent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Tries in all, the first included; 1 turns retrying off
+    pub attempts: u32,
+
+    /// Delay before the first retry, doubled for each one after it
+    #[serde(with = "humantime_serde")]
+    pub base_delay: Duration,
+
+    /// Up to this much is added to each delay at random, so clients that
+    /// failed together do not all retry at once
+    #[serde(with = "humantime_serde")]
+    pub jitter: Duration,
+
+    /// No retry starts later than this after the first try
+    #[serde(with = "humantime_serde")]
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            base_delay: Duration::from_millis(100),
+            jitter: Duration::from_millis(100),
+            deadline: Duration::from_secs(3),
+        }
+    }
+}
+
+impl Limits {
+    /// Zero values would make every client connection fail
+    pub fn validate(&self) -> Result<()> {
+        if self.max_message_size == 0 {
+            anyhow::bail!("limits.max_message_size must be greater than 0");
+        }
+        if self.max_connections == 0 {
+            anyhow::bail!("limits.max_connections must be greater than 0");
+        }
+        if self.per_conn_timeout == Some(Duration::ZERO) {
+            anyhow::bail!("limits.per_conn_timeout must be greater than 0, leave it unset for no timeout");
+        }
+        if self.max_identities == 0 {
+            anyhow::bail!("limits.max_identities must be greater than 0");
+        }
+        if self.upstream_connections == 0 {
+            anyhow::bail!("limits.upstream_connections must be greater than 0");
+        }
+        if self.upstream_retry.attempts == 0 {
+            anyhow::bail!("limits.upstream_retry.attempts must be at least 1, which turns retrying off");
+        }
+        Ok(())
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            // 1MB should be more than enough for SSH agent
+            max_message_size: 1024 * 1024,
+            max_connections: 100,
+            per_conn_timeout: None,
+            max_identities: 1024,
+            upstream_connections: 1,
+            upstream_wait: Duration::from_secs(10),
+            upstream_retry: RetryPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct SocketEntry {
     /// Path to the socket file
     pub path: PathBuf,
+
+    /// Name used to refer to the socket (default: socket file name)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// What the socket is for, e.g. "CI builds only", shown by
+    /// `list-socks`, in confirmation prompts and to clients through the
+    /// stats extension
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// Labels for bringing groups of sockets up or down together with
+    /// `enable --tag` and `disable --tag`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
     
-    /// Allowed key fingerprints (whitelist)
+    /// Allowed key fingerprints or patterns (whitelist), optionally
+    /// limited to a number of signatures
     #[serde(default)]
-    pub allowed: Vec<String>,
+    pub allowed: Vec<AllowedKey>,
     
-    /// Denied key fingerprints (blacklist)
+    /// Denied key fingerprints or patterns (blacklist)
     #[serde(default)]
     pub denied: Vec<String>,
+
+    /// Ordered `+pattern`/`-pattern` rules, first match wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<String>,
+
+    /// What to expose when the allow list is empty, overriding the global default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<DefaultPolicy>,
+
+    /// Files with additional allowed public keys or fingerprints, one per line
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_files: Vec<PathBuf>,
+
+    /// Directory to dump request/response pairs into for debugging
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debug_capture: Option<PathBuf>,
+
+    /// File to write `SSH_AUTH_SOCK=<path>` to while the socket is bound,
+    /// for scripts and systemd's `EnvironmentFile=`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_file: Option<PathBuf>,
+
+    /// Zero out key blobs in captured messages
+    #[serde(default)]
+    pub capture_redact: bool,
+
+    /// Log level for this socket's messages, overriding the global level
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<LevelFilter>,
+
+    /// File to append list and sign decisions to, one JSON object per line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit_file: Option<PathBuf>,
+
+    /// Where to send audit records instead of the global `audit` sinks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit: Option<Vec<AuditSink>>,
+
+    /// Ask before every sign request with an allowed key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confirm: Option<ConfirmMethod>,
+
+    /// How long to wait for a confirmation before denying (default: 30s)
+    #[serde(default, with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub confirm_timeout: Option<Duration>,
+
+    /// Reuse an approval for the same key and client process this long
+    #[serde(default, with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub confirm_cache: Option<Duration>,
+
+    /// Deny or confirm sign requests while they arrive faster than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sign_rate: Option<SignRateLimit>,
+
+    /// Refuse to add keys upstream unless they carry destination
+    /// constraints (`ssh-add -h`)
+    #[serde(default)]
+    pub require_destination_constraints: bool,
+
+    /// Servers keys may log in to, by host key; the first entry whose
+    /// `key` matches decides
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<HostBinding>,
+
+    /// SSHSIG namespaces keys may sign in (`ssh-keygen -Y sign -n`); the
+    /// first entry whose `key` matches decides
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sshsig: Vec<NamespaceBinding>,
+
+    /// Whether clients may load or unload PKCS#11 tokens upstream
+    /// (default: allow)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smartcard: Option<SmartcardPolicy>,
+
+    /// Provider libraries clients may load; empty allows any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub smartcard_providers: Vec<PathBuf>,
+
+    /// Agent messages passed on to upstream, overriding the global
+    /// `allowed_messages`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_messages: Option<Vec<String>>,
+
+    /// Forward legacy SSH protocol 1 requests instead of refusing them.
+    /// Protocol 1 keys are never filtered.
+    #[serde(default)]
+    pub allow_protocol_1: bool,
+
+    /// Refuse clients running in a container, such as one the socket is
+    /// bind-mounted into (Linux only)
+    #[serde(default)]
+    pub deny_containers: bool,
+
+    /// Images whose containers are let through despite `deny_containers`,
+    /// e.g. `ghcr.io/acme/ci-*`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub container_images: Vec<String>,
+
+    /// What to do when the socket's directory could let other users
+    /// replace the socket (default: warn)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir_permissions: Option<DirPermissions>,
+
+    /// What to do with sign requests for keys upstream does not list
+    /// (default: deny)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unknown_key_policy: Option<UnknownKeyPolicy>,
+
+    /// How refused requests are answered (default: failure)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deny_response: Option<DenyResponse>,
+
+    /// How client connections map onto upstream connections (default: shared)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_affinity: Option<UpstreamAffinity>,
+
+    /// Hide keys first seen longer ago than this, overriding
+    /// `pinning.max_key_age`
+    #[serde(default, with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub max_key_age: Option<Duration>,
+
+    /// Remove the socket at this time, e.g. `2025-07-01` (UTC)
+    #[serde(default, with = "expiry", skip_serializing_if = "Option::is_none")]
+    pub expires: Option<SystemTime>,
+
+    /// Remove the socket this long after the config file was last changed
+    #[serde(default, with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<Duration>,
+
+    /// Only bind the socket during these hours in local time, e.g.
+    /// `Mon-Fri 08:00-19:00`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active: Option<String>,
+}
+
+/// An entry of a socket's allow list
+///
+/// Written as a plain fingerprint or pattern, or as a table
+/// `{ key = "SHA256:...", max_uses = 5 }` for a grant that is used up after
+/// that many signatures until `grants reset`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "AllowedKeyRepr", into = "AllowedKeyRepr")]
+pub struct AllowedKey {
+    pub key: String,
+    pub max_uses: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum AllowedKeyRepr {
+    Plain(String),
+    Limited {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_uses: Option<u64>,
+    },
+}
+
+impl From<AllowedKeyRepr> for AllowedKey {
+    fn from(repr: AllowedKeyRepr) -> Self {
+        match repr {
+            AllowedKeyRepr::Plain(key) => Self { key, max_uses: None },
+            AllowedKeyRepr::Limited { key, max_uses } => Self { key, max_uses },
+        }
+    }
+}
+
+impl From<AllowedKey> for AllowedKeyRepr {
+    fn from(allowed: AllowedKey) -> Self {
+        match allowed.max_uses {
+            None => Self::Plain(allowed.key),
+            max_uses => Self::Limited { key: allowed.key, max_uses },
+        }
+    }
+}
+
+impl From<String> for AllowedKey {
+    fn from(key: String) -> Self {
+        Self { key, max_uses: None }
+    }
+}
+
+impl AsRef<str> for AllowedKey {
+    fn as_ref(&self) -> &str {
+        &self.key
+    }
+}
+
+impl std::fmt::Display for AllowedKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.max_uses {
+            Some(max_uses) => write!(f, "{} (max {} uses)", self.key, max_uses),
+            None => write!(f, "{}", self.key),
+        }
+    }
+}
+
+/// Keys that may only log in to servers with one of `host_keys`
+///
+/// The server is recognized from the data being signed, or from the
+/// `session-bind@openssh.com` message ssh sent for the same session, so
+/// keys matching `key` cannot sign anything else.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostBinding {
+    /// Fingerprint, pattern or `@group`, as in `allowed`
+    pub key: String,
+    /// Host key fingerprints or public key lines, e.g. from known_hosts
+    pub host_keys: Vec<String>,
+}
+
+/// Keys that may only make SSHSIG signatures in one of `namespaces`,
+/// e.g. `git` for commit signing
+///
+/// Keys matching `key` cannot log in anywhere unless a `hosts` entry
+/// binds them too.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamespaceBinding {
+    /// Fingerprint, pattern or `@group`, as in `allowed`
+    pub key: String,
+    pub namespaces: Vec<String>,
+}
+
+/// `expires` as a date or RFC 3339 time in UTC
+mod expiry {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::SystemTime;
+
+    pub fn serialize<S: Serializer>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => serializer.serialize_str(&humantime::format_rfc3339_seconds(*time).to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<SystemTime>, D::Error> {
+        let Some(text) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        // A bare date means the start of that day
+        let time = if text.len() == 10 { format!("{}T00:00:00Z", text) } else { text.clone() };
+        humantime::parse_rfc3339_weak(&time)
+            .map(Some)
+            .map_err(|e| serde::de::Error::custom(format!("invalid expiry {:?}: {}", text, e)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmartcardPolicy {
+    /// Forward smartcard requests, limited to `smartcard_providers` if set
+    #[default]
+    Allow,
+    /// Refuse every smartcard request
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownKeyPolicy {
+    /// Pass the request on unfiltered; upstream refuses keys it lacks
+    Forward,
+    /// Refuse it, so the socket cannot be used to probe upstream
+    #[default]
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DirPermissions {
+    /// Bind anyway and log a warning
+    #[default]
+    Warn,
+    /// Refuse to bind the socket
+    Refuse,
+    /// Remove group and world write permission from the directory, or
+    /// refuse if it belongs to someone else
+    Fix,
+    /// Skip the check, for directories shared on purpose
+    Ignore,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DenyResponse {
+    /// A bare SSH_AGENT_FAILURE
+    #[default]
+    Failure,
+    /// SSH_AGENT_EXTENSION_FAILURE saying why, and on which socket
+    ExtensionFailure,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpstreamAffinity {
+    /// Queue requests onto the router's shared upstream connections
+    #[default]
+    Shared,
+    /// Give each client its own upstream connection for its lifetime
+    PerConnection,
+}
+
+/// A socket's signing rate limit, e.g. `{ max = 20, per = "1m" }`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignRateLimit {
+    /// Sign requests allowed within `per`
+    pub max: u32,
+
+    #[serde(with = "humantime_serde")]
+    pub per: Duration,
+
+    /// Ask with this method instead of denying requests over the limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confirm: Option<ConfirmMethod>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfirmMethod {
+    /// Prompt on the router's controlling terminal
+    Terminal,
+    /// Desktop dialog (osascript on macOS, zenity or kdialog elsewhere)
+    Dialog,
+    /// Touch ID on macOS
+    TouchId,
+    /// Ask an HTTP endpoint configured in `[approval.webhook]`
+    Webhook,
+    /// Push notification with approve/deny actions through ntfy
+    Ntfy,
+    /// Pushover emergency notification, approved by acknowledging it
+    Pushover,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PinningConfig {
+    /// Remember upstream fingerprints and warn when keys appear or disappear
+    pub enabled: bool,
+
+    /// Hide keys that appeared since pinning until they are acknowledged
+    /// with `pin-keys`; implies `enabled`
+    pub enforce: bool,
+
+    /// Where pinned fingerprints are kept (default: data dir)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+
+    /// Hide keys first seen longer ago than this on every socket, unless
+    /// a socket allows them by fingerprint; implies `enabled`
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub max_key_age: Option<Duration>,
+}
+
+impl PinningConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled || self.enforce || self.max_key_age.is_some()
+    }
+}
+
+/// Settings for the remote approval backends, shared by all sockets
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ApprovalConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ntfy: Option<NtfyConfig>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pushover: Option<PushoverConfig>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<WebhookConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NtfyConfig {
+    /// ntfy server URL (default: https://ntfy.sh)
+    #[serde(default = "default_ntfy_server")]
+    pub server: String,
+
+    /// Topic the approval requests are published to
+    pub topic: String,
+
+    /// Topic the approve/deny actions publish to (default: `<topic>-response`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_topic: Option<String>,
+
+    /// Access token for protected topics
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushoverConfig {
+    /// Application API token
+    pub token: String,
+
+    /// User or group key receiving the notifications
+    pub user: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// URL receiving a JSON POST for every sign request to approve
+    pub url: String,
+
+    /// Sent as a bearer token so the endpoint can reject other callers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl NtfyConfig {
+    /// Topic the approve/deny actions publish to
+    pub fn response_topic(&self) -> String {
+        self.response_topic.clone()
+            .unwrap_or_else(|| format!("{}-response", self.topic))
+    }
+}
+
+fn default_ntfy_server() -> String {
+    "https://ntfy.sh".to_string()
+}
+
+impl SocketEntry {
+    /// Short name for the socket, derived from the socket file name
+    /// unless set explicitly
+    pub fn name(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        self.path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "socket".to_string())
+    }
+}
+
+/// File in which a project declares the keys it needs
+pub const PROJECT_FILE: &str = ".ssh-agent-router.toml";
+
+/// Keys a project needs, read from [`PROJECT_FILE`] by `exec` and
+/// `direnv` to give the project a socket of its own
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// Socket name (default: `project-` and the directory name)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// Fingerprints, patterns or `@group`s of the keys to expose
+    pub allowed: Vec<String>,
+
+    /// Remove the socket after this long, e.g. "8h" (default: when the
+    /// router stops, or when `exec`'s command exits)
+    #[serde(default, with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<Duration>,
+
+    /// Directory holding the file
+    #[serde(skip)]
+    pub dir: PathBuf,
+}
+
+impl ProjectConfig {
+    /// The project file in `dir` or the nearest directory above it
+    pub fn find(dir: &Path) -> Result<Option<Self>> {
+        for dir in dir.ancestors() {
+            let path = dir.join(PROJECT_FILE);
+            if path.is_file() {
+                return Self::read(&path).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read project file {:?}", path))?;
+        let mut project: Self = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse project file {:?}", path))?;
+        if project.allowed.is_empty() {
+            anyhow::bail!("Project file {:?} allows no keys", path);
+        }
+        project.dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(project)
+    }
+
+    /// Name of the project's socket
+    pub fn socket_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            let dir = self.dir.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            format!("project-{}", slug(&dir))
+        })
+    }
+}
+
+/// Expand a leading `~/` to the home directory
+pub fn expand_tilde(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Merge `overlay` into `base`: tables are merged key by key, `[[sockets]]`
+/// entries with the same `path` are merged and others added, and any
+/// other value in the overlay replaces the one in the base
+pub fn merge_overlay(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => merge_overlay(base, overlay),
+            (Some(toml::Value::Array(sockets)), toml::Value::Array(overlay)) if key == "sockets" => {
+                for entry in overlay {
+                    let same_path = sockets.iter_mut().find(|socket| {
+                        socket.get("path").is_some_and(|path| Some(path) == entry.get("path"))
+                    });
+                    match (same_path, entry) {
+                        (Some(toml::Value::Table(socket)), toml::Value::Table(entry)) => merge_overlay(socket, entry),
+                        (_, entry) => sockets.push(entry),
+                    }
+                }
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Files written before the schema was versioned have the first version
+fn unversioned() -> u32 {
+    1
 }
 
 fn default_upstream() -> String {
     std::env::var("SSH_AUTH_SOCK").unwrap_or_default()
 }
 
+/// Name of the profile stored in `config.toml` itself
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Profile chosen for this process with `--profile` or `profile switch`,
+/// taking precedence over the active profile file
+static PROFILE: RwLock<Option<String>> = RwLock::new(None);
+
+/// Config file given with `--config`, taking precedence over any profile
+static CONFIG_FILE: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Environment variable naming the config file
+pub const CONFIG_ENV: &str = "SSH_AGENT_ROUTER_CONFIG";
+
+/// Schema version of the config this build reads and writes
+pub const CONFIG_VERSION: u32 = 1;
+
+/// A step upgrading a parsed config file by one schema version
+pub type Migration = fn(&mut toml::Table) -> Result<()>;
+
+/// Upgrades from each older schema version, in order: the first entry
+/// turns a version 1 file into version 2, and so on
+///
+/// When a change to the schema would break existing files, bump
+/// [`CONFIG_VERSION`] and append the step rewriting them here.
+pub const MIGRATIONS: &[Migration] = &[];
+
+/// Backups kept of each config file the CLI rewrites
+pub const CONFIG_BACKUPS: usize = 10;
+
+/// System-wide config file, used when the user has none
+pub const SYSTEM_CONFIG_PATH: &str = "/etc/ssh-agent-router/config.toml";
+
+/// How the config file in effect was chosen
+enum ConfigSource {
+    /// `--config` or `$SSH_AGENT_ROUTER_CONFIG`, which must exist
+    Explicit,
+    /// A profile other than the default, which must exist
+    Profile(String),
+    /// The user or system config file, either of which may be missing
+    Search,
+}
+
 impl Config {
-    /// Get the config file path
-    pub fn config_path() -> Result<PathBuf> {
+    fn config_dir() -> Result<PathBuf> {
         let config_dir = dirs::config_dir()
             .context("Failed to get config directory")?
             .join("ssh-agent-router");
-        
+
         fs::create_dir_all(&config_dir)
             .context("Failed to create config directory")?;
-        
-        Ok(config_dir.join("config.toml"))
+
+        Ok(config_dir)
     }
 
-    /// Load config from the default location
+    /// Get the config file path, searched for in this order:
+    ///
+    /// 1. `--config` or `--profile`
+    /// 2. `$SSH_AGENT_ROUTER_CONFIG`
+    /// 3. the profile made active with `profile switch`
+    /// 4. `config.toml` in the user config directory
+    /// 5. [`SYSTEM_CONFIG_PATH`]
+    ///
+    /// With neither of the last two present it is the user's
+    /// `config.toml`, where `save` creates it.
+    pub fn config_path() -> Result<PathBuf> {
+        Ok(Self::locate()?.0)
+    }
+
+    fn locate() -> Result<(PathBuf, ConfigSource)> {
+        if let Some(path) = CONFIG_FILE.read().unwrap().clone() {
+            return Ok((path, ConfigSource::Explicit));
+        }
+        let selected = PROFILE.read().unwrap().clone();
+        if selected.is_none() {
+            if let Some(path) = std::env::var_os(CONFIG_ENV).filter(|p| !p.is_empty()) {
+                return Ok((expand_tilde(Path::new(&path)), ConfigSource::Explicit));
+            }
+        }
+        if let Some(name) = Self::profile()? {
+            return Ok((Self::profile_path(&name)?, ConfigSource::Profile(name)));
+        }
+
+        let user = Self::config_dir()?.join("config.toml");
+        let system = PathBuf::from(SYSTEM_CONFIG_PATH);
+        if !user.exists() && system.exists() {
+            return Ok((system, ConfigSource::Search));
+        }
+        Ok((user, ConfigSource::Search))
+    }
+
+    /// Load `path` instead of any profile for the rest of the process
+    pub fn use_file(path: &Path) {
+        *CONFIG_FILE.write().unwrap() = Some(expand_tilde(path));
+        *PROFILE.write().unwrap() = None;
+    }
+
+    /// Load `name` instead of the active profile for the rest of the process
+    pub fn use_profile(name: &str) {
+        *PROFILE.write().unwrap() = Some(name.to_string());
+        *CONFIG_FILE.write().unwrap() = None;
+    }
+
+    /// The profile in effect, `None` for `config.toml`
+    pub fn profile() -> Result<Option<String>> {
+        let selected = PROFILE.read().unwrap().clone();
+        let name = match selected {
+            Some(name) => Some(name),
+            None => Self::active_profile()?,
+        };
+        Ok(name.filter(|name| name != DEFAULT_PROFILE))
+    }
+
+    /// The profile set with `profile switch`, if any
+    pub fn active_profile() -> Result<Option<String>> {
+        let path = Self::config_dir()?.join("active_profile");
+        match fs::read_to_string(&path) {
+            Ok(name) => Ok(Some(name.trim().to_string()).filter(|name| !name.is_empty())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {:?}", path)),
+        }
+    }
+
+    /// Make `name` the profile used by every later invocation
+    ///
+    /// The file is replaced by a rename so readers never see it half
+    /// written.
+    pub fn set_active_profile(name: &str) -> Result<()> {
+        let path = Self::config_dir()?.join("active_profile");
+        if name == DEFAULT_PROFILE {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(e).with_context(|| format!("Failed to remove {:?}", path))
+                }
+                _ => Ok(()),
+            };
+        }
+
+        Self::profile_path(name)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, format!("{}\n", name))
+            .with_context(|| format!("Failed to write {:?}", tmp))?;
+        fs::rename(&tmp, &path)
+            .with_context(|| format!("Failed to replace {:?}", path))
+    }
+
+    /// File holding profile `name`
+    pub fn profile_path(name: &str) -> Result<PathBuf> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            anyhow::bail!("Invalid profile name {:?}, use letters, digits, '-', '_' and '.'", name);
+        }
+        Ok(Self::config_dir()?.join("profiles").join(format!("{}.toml", name)))
+    }
+
+    /// Names of the profiles in the profiles directory, sorted
+    pub fn profiles() -> Result<Vec<String>> {
+        let dir = Self::config_dir()?.join("profiles");
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", dir)),
+        };
+
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "toml") {
+                if let Some(stem) = path.file_stem() {
+                    names.push(stem.to_string_lossy().to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Get the control socket path used to talk to a running router
+    pub fn control_socket_path() -> Result<PathBuf> {
+        Ok(crate::state::runtime_dir()?.join("control.sock"))
+    }
+
+    /// File the running router holds a lock on, so only one runs at a time
+    pub fn instance_lock_path() -> Result<PathBuf> {
+        Ok(crate::state::runtime_dir()?.join("router.lock"))
+    }
+
+    /// Directory for ephemeral sockets created without a path
+    pub fn ephemeral_socket_dir() -> Result<PathBuf> {
+        Ok(Self::control_socket_path()?.with_file_name("ephemeral"))
+    }
+
+    /// File counting the uses of count-limited grants
+    pub fn grant_uses_path() -> Result<PathBuf> {
+        Ok(crate::state::data_dir()?.join("grant_uses.json"))
+    }
+
+    /// File socket counters are saved to, so they survive restarts
+    pub fn stats_path() -> Result<PathBuf> {
+        Ok(crate::state::state_dir()?.join("stats.json"))
+    }
+
+    /// File holding the pinned upstream fingerprints
+    pub fn pinned_keys_path(&self) -> Result<PathBuf> {
+        if let Some(file) = &self.pinning.file {
+            return Ok(expand_tilde(file));
+        }
+        Ok(crate::state::data_dir()?.join("pinned_keys"))
+    }
+
+    /// Audit sinks for a socket: its own `audit` list or else the global
+    /// one, plus its `audit_file`
+    pub fn audit_sinks(&self, entry: &SocketEntry) -> Vec<AuditSink> {
+        let mut sinks = entry.audit.clone().unwrap_or_else(|| self.audit.clone());
+        if let Some(path) = &entry.audit_file {
+            sinks.push(AuditSink::File { path: path.clone() });
+        }
+        sinks
+    }
+
+    /// Effective default policy for a socket
+    pub fn default_policy(&self, entry: &SocketEntry) -> DefaultPolicy {
+        entry.default.or(self.default).unwrap_or_default()
+    }
+
+    /// Age beyond which a socket hides keys, if any
+    pub fn max_key_age(&self, entry: &SocketEntry) -> Option<Duration> {
+        entry.max_key_age.or(self.pinning.max_key_age)
+    }
+
+    /// Settings a running socket cannot take over on reload, compared to
+    /// tell when a reload has to rebuild the socket instead
+    pub fn fixed_socket_settings(&self, entry: &SocketEntry) -> serde_json::Value {
+        let mut entry = entry.clone();
+        // Filter and listing settings, reloaded in place
+        entry.allowed.clear();
+        entry.denied.clear();
+        entry.rules.clear();
+        entry.default = None;
+        entry.allowed_files.clear();
+        entry.hosts.clear();
+        entry.sshsig.clear();
+        entry.allowed_messages = None;
+        entry.max_key_age = None;
+        entry.description = None;
+        entry.env_file = None;
+        entry.log_level = None;
+        // When the socket is bound, which the router settles itself
+        entry.tags.clear();
+        entry.expires = None;
+        entry.ttl = None;
+        entry.active = None;
+        serde_json::json!({
+            "socket": entry,
+            "limits": self.limits,
+            "approval": self.approval,
+            "risk": self.risk,
+            "audit": self.audit,
+        })
+    }
+
+    /// Agent messages a socket passes on to upstream, `None` for all
+    pub fn allowed_messages<'a>(&'a self, entry: &'a SocketEntry) -> Option<&'a [String]> {
+        entry.allowed_messages.as_deref().or(self.allowed_messages.as_deref())
+    }
+
+    /// Whether neither the socket nor the global config chose a default
+    pub fn default_policy_is_implicit(&self, entry: &SocketEntry) -> bool {
+        entry.default.is_none() && self.default.is_none()
+    }
+
+    /// Find a socket by name or path
+    pub fn find_socket(&self, name_or_path: &str) -> Option<&SocketEntry> {
+        self.sockets.iter()
+            .find(|s| s.name() == name_or_path || s.path == Path::new(name_or_path))
+    }
+
+    /// Load config from the file [`Config::config_path`] finds
     pub fn load() -> Result<Self> {
-        let path = Self::config_path()?;
+        let (path, source) = Self::locate()?;
         
         if !path.exists() {
-            return Ok(Self::default());
+            match source {
+                ConfigSource::Explicit => anyhow::bail!("Config file {:?} not found", path),
+                ConfigSource::Profile(name) => anyhow::bail!("Profile {:?} not found, expected {:?}", name, path),
+                ConfigSource::Search => return Ok(Self::default()),
+            }
         }
-        
-        let content = fs::read_to_string(&path)
-            .context("Failed to read config file")?;
-        
-        toml::from_str(&content)
-            .context("Failed to parse config file")
+
+        Self::load_from(&path)
+    }
+
+    /// Load config from a specific file, without expired sockets
+    pub fn load_from(path: &Path) -> Result<Self> {
+        let mut config = Self::read(path)?;
+        config.validate()?;
+        config.drop_expired(SystemTime::now());
+        Ok(config)
+    }
+
+    /// Parse a config file as it is, without checking it
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+
+        let mut table: toml::Table = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file {:?}", path))?;
+        let from = Self::migrate(&mut table)
+            .with_context(|| format!("Failed to upgrade config file {:?}", path))?;
+        if from < CONFIG_VERSION {
+            log::info!(
+                "Upgraded config file {:?} from version {} to {}, run `config migrate` to rewrite it",
+                path, from, CONFIG_VERSION
+            );
+        }
+        let builtins = crate::template::builtins();
+        Self::merge_includes(&mut table, path, &builtins)?;
+        crate::template::apply(&mut table, &builtins)
+            .with_context(|| format!("Failed to resolve variables in config file {:?}", path))?;
+
+        let mut config: Self = table.try_into()
+            .with_context(|| format!("Failed to parse config file {:?}", path))?;
+        config.modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        Ok(config)
+    }
+
+    /// Merge the files listed in `include` over `table`, read from `path`;
+    /// their paths can use the builtin variables, e.g. `hosts/${hostname}.toml`
+    fn merge_includes(table: &mut toml::Table, path: &Path, builtins: &BTreeMap<String, String>) -> Result<()> {
+        let includes: Vec<PathBuf> = match table.get("include") {
+            Some(value) => value.clone().try_into().context("include must be a list of file paths")?,
+            None => return Ok(()),
+        };
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for include in includes {
+            let include = crate::template::interpolate(&include.to_string_lossy(), builtins)
+                .context("Invalid include path")?;
+            let include = dir.join(expand_tilde(Path::new(&include)));
+            let content = match fs::read_to_string(&include) {
+                Ok(content) => content,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    log::info!("Included config file {:?} not found, skipping it", include);
+                    continue;
+                }
+                Err(e) => return Err(e).with_context(|| format!("Failed to read included config file {:?}", include)),
+            };
+            let overlay: toml::Table = toml::from_str(&content)
+                .with_context(|| format!("Failed to parse included config file {:?}", include))?;
+            if overlay.contains_key("include") {
+                anyhow::bail!("Included config file {:?} cannot include other files", include);
+            }
+            merge_overlay(table, overlay);
+        }
+        Ok(())
+    }
+
+    /// Upgrade a parsed config file to [`CONFIG_VERSION`], returning the
+    /// version it had
+    pub fn migrate(table: &mut toml::Table) -> Result<u32> {
+        Self::migrate_with(table, MIGRATIONS)
+    }
+
+    /// Upgrade `table` with `migrations`, the steps up from version 1
+    pub fn migrate_with(table: &mut toml::Table, migrations: &[Migration]) -> Result<u32> {
+        let from = match table.get("version") {
+            None => unversioned(),
+            Some(toml::Value::Integer(version)) if *version >= 1 => u32::try_from(*version)
+                .map_err(|_| anyhow::anyhow!("Unsupported config version {}", version))?,
+            Some(other) => anyhow::bail!("Invalid config version {}", other),
+        };
+        let latest = migrations.len() as u32 + 1;
+        if from > latest {
+            anyhow::bail!(
+                "Config version {} is newer than this build understands ({}), upgrade ssh-agent-router",
+                from, latest
+            );
+        }
+
+        for (step, migration) in migrations.iter().enumerate().skip(from as usize - 1) {
+            migration(table).with_context(|| format!("Failed to upgrade from version {}", step + 1))?;
+            table.insert("version".to_string(), toml::Value::Integer(step as i64 + 2));
+        }
+        Ok(from)
+    }
+
+    /// Rewrite the config file at `path` in the current schema version,
+    /// returning the version it had, or `None` if it was up to date
+    ///
+    /// A file that only lacks the `version` stamp gets it added at the top
+    /// and is otherwise left as it was, comments included. Upgraded files
+    /// are written out afresh; the old one is kept as a backup.
+    pub fn migrate_file(path: &Path) -> Result<Option<u32>> {
+        let Some((from, content)) = Self::migrated(path)? else {
+            return Ok(None);
+        };
+        Self::write_file(path, &content)?;
+        Ok(Some(from))
+    }
+
+    /// The version the config file at `path` has and what
+    /// [`migrate_file`](Self::migrate_file) would write in its place,
+    /// `None` if it is up to date
+    pub fn migrated(path: &Path) -> Result<Option<(u32, String)>> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+        let mut table: toml::Table = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file {:?}", path))?;
+        let stamped = table.contains_key("version");
+        let from = Self::migrate(&mut table)?;
+
+        let content = match (from < CONFIG_VERSION, stamped) {
+            (true, _) => toml::to_string_pretty(&table).context("Failed to serialize config")?,
+            (false, false) => format!("version = {}\n\n{}", CONFIG_VERSION, content),
+            (false, true) => return Ok(None),
+        };
+        table.try_into::<Self>()
+            .with_context(|| format!("Upgraded config file {:?} does not parse", path))?
+            .validate()?;
+        Ok(Some((from, content)))
+    }
+
+    /// When a socket is due to be removed, the earlier of its `expires`
+    /// and its `ttl` after the config file was changed
+    pub fn socket_expiry(&self, entry: &SocketEntry) -> Option<SystemTime> {
+        let ttl_end = entry.ttl.zip(self.modified).map(|(ttl, modified)| modified + ttl);
+        match (entry.expires, ttl_end) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Whether a socket's `active` schedule lets it be bound at `now`;
+    /// sockets without one are always active
+    pub fn socket_active(&self, entry: &SocketEntry, now: SystemTime) -> bool {
+        let schedule = entry.active.as_deref().and_then(|active| crate::schedule::Schedule::parse(active).ok());
+        schedule.is_none_or(|schedule| schedule.is_active_at(now))
+    }
+
+    /// Sockets whose expiry has passed at `now`
+    pub fn expired_sockets(&self, now: SystemTime) -> Vec<&SocketEntry> {
+        self.sockets.iter()
+            .filter(|s| self.socket_expiry(s).is_some_and(|expiry| expiry <= now))
+            .collect()
+    }
+
+    /// Leave out sockets whose expiry has passed at `now`
+    pub fn drop_expired(&mut self, now: SystemTime) {
+        let expired: Vec<PathBuf> = self.expired_sockets(now).iter().map(|s| s.path.clone()).collect();
+        self.sockets.retain(|socket| {
+            if !expired.contains(&socket.path) {
+                return true;
+            }
+            log::info!("{}: expired, skipping {:?}", socket.name(), socket.path);
+            false
+        });
+    }
+
+    /// The configuration with every setting spelled out the way sockets
+    /// apply it
+    ///
+    /// Inherited and default values are filled in per socket, `~/` is
+    /// expanded in paths and `@group` references are replaced with the
+    /// group's members, except in count-limited grants, whose uses are
+    /// counted per entry.
+    pub fn effective(&self) -> Self {
+        let mut config = self.clone();
+        config.default = Some(self.default.unwrap_or_default());
+        config.sockets = self.sockets.iter()
+            .map(|entry| {
+                let mut socket = entry.clone();
+                socket.path = expand_tilde(&entry.path);
+                socket.name = Some(entry.name());
+                socket.default = Some(self.default_policy(entry));
+                socket.audit = Some(self.audit_sinks(entry).into_iter()
+                    .map(|sink| match sink {
+                        AuditSink::File { path } => AuditSink::File { path: expand_tilde(&path) },
+                        sink => sink,
+                    })
+                    .collect());
+                socket.audit_file = None;
+                socket.max_key_age = self.max_key_age(entry);
+                socket.allowed_messages = self.allowed_messages(entry).map(|messages| {
+                    messages.iter()
+                        .flat_map(|message| match message.as_str() {
+                            "default" => crate::policy::DEFAULT_ALLOWED_MESSAGES.iter().map(|m| m.to_string()).collect(),
+                            _ => vec![message.clone()],
+                        })
+                        .collect()
+                });
+                socket.smartcard = Some(entry.smartcard.unwrap_or_default());
+                socket.unknown_key_policy = Some(entry.unknown_key_policy.unwrap_or_default());
+                socket.deny_response = Some(entry.deny_response.unwrap_or_default());
+                socket.upstream_affinity = Some(entry.upstream_affinity.unwrap_or_default());
+                socket.allowed_files = entry.allowed_files.iter().map(|p| expand_tilde(p)).collect();
+                socket.debug_capture = entry.debug_capture.as_deref().map(expand_tilde);
+                socket.env_file = entry.env_file.as_deref().map(expand_tilde);
+                socket.allowed = entry.allowed.iter()
+                    .flat_map(|allowed| match allowed.max_uses {
+                        Some(_) => vec![allowed.clone()],
+                        None => self.group_members(&allowed.key).into_iter().map(AllowedKey::from).collect(),
+                    })
+                    .collect();
+                socket.denied = entry.denied.iter().flat_map(|key| self.group_members(key)).collect();
+                socket.rules = entry.rules.iter()
+                    .flat_map(|rule| match rule.split_at_checked(1) {
+                        Some((sign @ ("+" | "-"), pattern)) => {
+                            self.group_members(pattern.trim()).into_iter().map(|member| format!("{}{}", sign, member)).collect()
+                        }
+                        _ => vec![rule.clone()],
+                    })
+                    .collect();
+                socket
+            })
+            .collect();
+        if let Some(certificates) = &mut config.certificates {
+            certificates.keys = certificates.keys.iter().flat_map(|key| self.group_members(key)).collect();
+        }
+        config
+    }
+
+    /// Members of `@name`, or `entry` itself if it is not a known group
+    fn group_members(&self, entry: &str) -> Vec<String> {
+        match entry.strip_prefix('@').and_then(|name| self.groups.get(name)) {
+            Some(members) => members.clone(),
+            None => vec![entry.to_string()],
+        }
+    }
+
+    /// Reject settings that would make every connection fail
+    pub fn validate(&self) -> Result<()> {
+        self.limits.validate()?;
+        if let Some(per_key) = &self.per_key_sockets {
+            per_key.validate()?;
+        }
+        if let Some(certificates) = &self.certificates {
+            certificates.validate()?;
+        }
+        if let Some(messages) = &self.allowed_messages {
+            crate::policy::MessageFilter::new(messages)?;
+        }
+        if let Some(language) = self.ui.language.as_deref().filter(|l| *l != "auto") {
+            if crate::i18n::Language::parse(language).is_none() {
+                anyhow::bail!("Unsupported [ui] language {:?}, expected \"en\", \"ja\" or \"auto\"", language);
+            }
+        }
+
+        for (fingerprint, meta) in &self.keys {
+            meta.validate(fingerprint)?;
+        }
+
+        // Names key log targets, audit records and capture files, so two
+        // sockets sharing one would be indistinguishable
+        let mut names = HashSet::new();
+        let mut paths = HashSet::new();
+        let mut env_files = HashSet::new();
+        for socket in &self.sockets {
+            if !paths.insert(&socket.path) {
+                anyhow::bail!("Socket {:?} is configured more than once", socket.path);
+            }
+            if let Some(env_file) = &socket.env_file {
+                if !env_files.insert(expand_tilde(env_file)) {
+                    anyhow::bail!("env_file {:?} is used by more than one socket", env_file);
+                }
+            }
+            let name = socket.name();
+            if !names.insert(name.clone()) {
+                anyhow::bail!(
+                    "Socket name {:?} is used by more than one socket, set a distinct `name` for {:?}",
+                    name, socket.path
+                );
+            }
+            if let Some(messages) = &socket.allowed_messages {
+                crate::policy::MessageFilter::new(messages).with_context(|| format!("Socket {}", name))?;
+            }
+            if let Some(active) = &socket.active {
+                crate::schedule::Schedule::parse(active).with_context(|| format!("Socket {}", name))?;
+            }
+            if !socket.container_images.is_empty() && !socket.deny_containers {
+                anyhow::bail!("Socket {} sets container_images, which only applies with deny_containers = true", name);
+            }
+            // Ages are counted from the dates pinning records
+            if socket.max_key_age.is_some() && !self.pinning.is_enabled() {
+                anyhow::bail!("Socket {} sets max_key_age, which needs [pinning] enabled", name);
+            }
+        }
+        Ok(())
     }
 
     /// Save config to the default location
     pub fn save(&self) -> Result<()> {
-        let path = Self::config_path()?;
+        // The merged settings would otherwise end up in the main file
+        if !self.include.is_empty() {
+            anyhow::bail!("The config includes other files, edit them by hand instead");
+        }
+        // Changes to the system defaults become the user's own config
+        let path = match Self::config_path()? {
+            path if path == Path::new(SYSTEM_CONFIG_PATH) => Self::config_dir()?.join("config.toml"),
+            path => path,
+        };
         let content = toml::to_string_pretty(self)
             .context("Failed to serialize config")?;
-        
-        fs::write(&path, content)
-            .context("Failed to write config file")
+        Self::write_file(&path, &content)
+    }
+
+    /// Replace the config file at `path` with `content`, keeping what it
+    /// held before as a backup
+    ///
+    /// The new file is written next to the old one and renamed over it, so
+    /// a router reloading at the same moment sees one or the other, never
+    /// half a file.
+    pub fn write_file(path: &Path, content: &str) -> Result<()> {
+        if path.exists() {
+            Self::back_up(path)?;
+        }
+        let tmp = path.with_extension("toml.tmp");
+        fs::write(&tmp, content)
+            .with_context(|| format!("Failed to write {:?}", tmp))?;
+        fs::rename(&tmp, path)
+            .with_context(|| format!("Failed to replace config file {:?}", path))
+    }
+
+    /// Directory holding the backups of the config file at `path`
+    pub fn backup_dir(path: &Path) -> PathBuf {
+        path.parent().unwrap_or(Path::new(".")).join("backups")
+    }
+
+    /// Backups of the config file at `path`, oldest first
+    pub fn backups(path: &Path) -> Result<Vec<PathBuf>> {
+        let dir = Self::backup_dir(path);
+        let prefix = format!("{}.", Self::backup_stem(path));
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", dir)),
+        };
+
+        let mut backups: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|backup| {
+                backup.file_name().and_then(|name| name.to_str()).is_some_and(|name| {
+                    name.strip_prefix(&prefix)
+                        .and_then(|rest| rest.strip_suffix(".toml"))
+                        .is_some_and(|time| time.starts_with(|c: char| c.is_ascii_digit()))
+                })
+            })
+            .collect();
+        // Timestamps are fixed width, so names sort by age
+        backups.sort();
+        Ok(backups)
+    }
+
+    /// Restore the newest backup of the config file at `path`, returning
+    /// the backup used
+    ///
+    /// The backup is consumed, so rolling back again goes one further
+    /// version back. What the file held before is kept as
+    /// `<name>.rolled-back.toml` in the backup directory.
+    pub fn rollback(path: &Path) -> Result<PathBuf> {
+        let backup = Self::backups(path)?
+            .pop()
+            .with_context(|| format!("No backups of {:?} in {:?}", path, Self::backup_dir(path)))?;
+        Self::read(&backup)?.validate()
+            .with_context(|| format!("Backup {:?} is not a valid config", backup))?;
+
+        if path.exists() {
+            let replaced = Self::backup_dir(path).join(format!("{}.rolled-back.toml", Self::backup_stem(path)));
+            fs::copy(path, &replaced)
+                .with_context(|| format!("Failed to keep {:?} as {:?}", path, replaced))?;
+        }
+        let tmp = path.with_extension("toml.tmp");
+        fs::copy(&backup, &tmp)
+            .with_context(|| format!("Failed to copy {:?}", backup))?;
+        fs::rename(&tmp, path)
+            .with_context(|| format!("Failed to replace config file {:?}", path))?;
+        fs::remove_file(&backup)
+            .with_context(|| format!("Failed to remove {:?}", backup))?;
+        Ok(backup)
+    }
+
+    /// Copy the config file at `path` into its backup directory and prune
+    /// all but the newest [`CONFIG_BACKUPS`]
+    fn back_up(path: &Path) -> Result<PathBuf> {
+        let dir = Self::backup_dir(path);
+        crate::state::create_private_dir(&dir)?;
+
+        // e.g. config.20250701T120000.123456Z.toml
+        let time = humantime::format_rfc3339_micros(SystemTime::now()).to_string().replace(['-', ':'], "");
+        let backup = dir.join(format!("{}.{}.toml", Self::backup_stem(path), time));
+        fs::copy(path, &backup)
+            .with_context(|| format!("Failed to back up {:?} to {:?}", path, backup))?;
+
+        let backups = Self::backups(path)?;
+        for old in &backups[..backups.len().saturating_sub(CONFIG_BACKUPS)] {
+            if let Err(e) = fs::remove_file(old) {
+                log::warn!("Failed to remove old config backup {:?}: {}", old, e);
+            }
+        }
+        Ok(backup)
+    }
+
+    fn backup_stem(path: &Path) -> String {
+        path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_else(|| "config".to_string())
     }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            version: CONFIG_VERSION,
+            include: Vec::new(),
             upstream: default_upstream(),
+            log_level: None,
+            log_redact: false,
+            default: None,
+            limits: Limits::default(),
+            approval: ApprovalConfig::default(),
+            pinning: PinningConfig::default(),
+            groups: BTreeMap::new(),
+            keys: BTreeMap::new(),
+            risk: BTreeMap::new(),
+            telemetry: TelemetryConfig::default(),
+            privacy: PrivacyConfig::default(),
+            ui: UiConfig::default(),
+            audit: Vec::new(),
+            allowed_messages: None,
             sockets: Vec::new(),
+            per_key_sockets: None,
+            certificates: None,
+            modified: None,
         }
     }
 }
@@ -0,0 +1,135 @@
+use crate::config::{expand_tilde, Config};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A configured socket path that something else also uses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub path: PathBuf,
+    pub problem: String,
+    pub fix: String,
+}
+
+/// Shell startup files that commonly start agents, relative to the home directory
+const SHELL_FILES: &[&str] = &[
+    ".profile",
+    ".bash_profile",
+    ".bashrc",
+    ".zprofile",
+    ".zshenv",
+    ".zshrc",
+    ".config/fish/config.fish",
+];
+
+/// Words on a shell line that start or configure another agent
+const AGENT_WORDS: &[&str] = &["ssh-agent", "gpg-agent", "gpgconf", "gnome-keyring", "1password", "op-ssh-sign"];
+
+/// Find configured socket paths that collide with other agents
+///
+/// With `check_live` a socket file something already listens on is
+/// reported too, since binding there would replace it. Callers that may
+/// run alongside the router leave it off for sockets it serves.
+pub fn check(config: &Config, check_live: impl Fn(&Path) -> bool) -> Vec<Conflict> {
+    let agents = known_agents();
+    let home = dirs::home_dir();
+    let upstream = expand_tilde(Path::new(&config.upstream));
+    let mut conflicts = Vec::new();
+
+    for socket in &config.sockets {
+        let path = expand_tilde(&socket.path);
+
+        if path == upstream {
+            conflicts.push(Conflict {
+                path: socket.path.clone(),
+                problem: "it is the upstream agent socket, so the router would forward to itself".to_string(),
+                fix: "set upstream to the real agent, or pick another path for this socket".to_string(),
+            });
+            continue;
+        }
+
+        for (agent, agent_path) in &agents {
+            if &path == agent_path {
+                conflicts.push(Conflict {
+                    path: socket.path.clone(),
+                    problem: format!("{} uses this path for its SSH agent socket", agent),
+                    fix: format!("pick another path for this socket, or use {} as upstream", agent_path.display()),
+                });
+            }
+        }
+
+        if check_live(&path) && UnixStream::connect(&path).is_ok() {
+            conflicts.push(Conflict {
+                path: socket.path.clone(),
+                problem: "another process is listening on it and would lose its socket".to_string(),
+                fix: "stop the other agent or pick another path for this socket".to_string(),
+            });
+        }
+
+        if let Some(home) = &home {
+            for (file, line) in shell_mentions(home, &path) {
+                conflicts.push(Conflict {
+                    path: socket.path.clone(),
+                    problem: format!("{}:{} starts another agent on it", file.display(), line),
+                    fix: format!("remove that agent from {}, or pick another path for this socket", file.display()),
+                });
+            }
+        }
+    }
+
+    conflicts
+}
+
+/// Default SSH socket paths of other agents that may run on this host
+fn known_agents() -> Vec<(&'static str, PathBuf)> {
+    let mut agents = Vec::new();
+
+    // gpgconf knows the path even when GNUPGHOME or the socket dir moved it
+    if let Ok(output) = Command::new("gpgconf").args(["--list-dirs", "agent-ssh-socket"]).output() {
+        let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if output.status.success() && !path.is_empty() {
+            agents.push(("gpg-agent", PathBuf::from(path)));
+        }
+    }
+    if let Some(runtime) = dirs::runtime_dir() {
+        agents.push(("gpg-agent", runtime.join("gnupg/S.gpg-agent.ssh")));
+        agents.push(("gnome-keyring", runtime.join("keyring/ssh")));
+        agents.push(("gnome-keyring", runtime.join("keyring/.ssh")));
+    }
+    if let Some(home) = dirs::home_dir() {
+        agents.push(("gpg-agent", home.join(".gnupg/S.gpg-agent.ssh")));
+        agents.push(("1Password", home.join(".1password/agent.sock")));
+        agents.push(("1Password", home.join("Library/Group Containers/2BUA8C4S2C.com.1password/t/agent.sock")));
+    }
+
+    agents
+}
+
+/// Lines of shell startup files that start an agent on `path`
+fn shell_mentions(home: &Path, path: &Path) -> Vec<(PathBuf, usize)> {
+    let full = path.to_string_lossy().to_string();
+    let mut spellings = vec![full.clone()];
+    if let Ok(relative) = path.strip_prefix(home) {
+        spellings.push(format!("~/{}", relative.display()));
+        spellings.push(format!("$HOME/{}", relative.display()));
+        spellings.push(format!("${{HOME}}/{}", relative.display()));
+    }
+
+    let mut mentions = Vec::new();
+    for file in SHELL_FILES.iter().map(|f| home.join(f)) {
+        let Ok(content) = std::fs::read_to_string(&file) else {
+            continue;
+        };
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('#') || !spellings.iter().any(|s| line.contains(s.as_str())) {
+                continue;
+            }
+            let line = line.replace("ssh-agent-router", "");
+            if AGENT_WORDS.iter().any(|word| line.contains(word)) {
+                mentions.push((file.clone(), number + 1));
+            }
+        }
+    }
+    mentions
+}
@@ -0,0 +1,391 @@
+use crate::protocol::{self, Reader};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+/// Largest answer read back; real agents stay far below it
+const MAX_ANSWER: usize = 16 * 1024 * 1024;
+
+/// Extension no agent implements, to see how unknown extensions fail
+const UNKNOWN_EXTENSION: &str = "conformance-probe@ssh-agent-router";
+
+/// How an agent did on one check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Pass,
+    /// Allowed by the protocol but unlike OpenSSH's agent, which clients
+    /// may not expect
+    Warn,
+    Fail,
+}
+
+/// The result of one check
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub verdict: Verdict,
+    /// What the agent did, and why that passes or not
+    pub detail: String,
+}
+
+/// What an agent did with a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// It answered with this length-prefixed message
+    Answer(Vec<u8>),
+    /// It closed the connection without answering
+    Closed,
+    /// It had not answered when the timeout ran out
+    Silent,
+}
+
+impl Outcome {
+    fn describe(&self) -> String {
+        match self {
+            Outcome::Answer(answer) => match protocol::message_type(answer) {
+                Some(msg_type) if protocol::message_type_name(msg_type) != "UNKNOWN" => {
+                    protocol::message_type_name(msg_type).to_string()
+                }
+                Some(msg_type) => format!("message {}", msg_type),
+                None => "an empty message".to_string(),
+            },
+            Outcome::Closed => "closed the connection".to_string(),
+            Outcome::Silent => "no answer".to_string(),
+        }
+    }
+
+    fn is(&self, msg_type: u8) -> bool {
+        matches!(self, Outcome::Answer(answer) if protocol::message_type(answer) == Some(msg_type))
+    }
+
+    /// SSH_AGENT_FAILURE, or SSH_AGENT_EXTENSION_FAILURE, which the draft
+    /// allows for extensions and the router sends with `deny_response`
+    fn is_failure(&self) -> bool {
+        self.is(protocol::SSH_AGENT_FAILURE) || self.is(protocol::SSH_AGENT_EXTENSION_FAILURE)
+    }
+}
+
+/// Exercise the agent at `socket` with valid and malformed messages
+///
+/// Every check uses a connection of its own, so one that upsets the agent
+/// does not spoil the next; the last checks that it still answers. The
+/// only request with an effect is a sign request for a key no agent
+/// holds. An error means the agent could not be reached at all.
+pub fn run(socket: &Path, timeout: Duration) -> Result<Vec<Check>> {
+    UnixStream::connect(socket).with_context(|| format!("Failed to connect to agent at {:?}", socket))?;
+    let probe = Probe { socket, timeout };
+    let mut checks = vec![
+        probe.request_identities(),
+        probe.sign_unknown_key(),
+        probe.unknown_message(),
+        probe.unknown_extension(),
+        probe.query(),
+        probe.protocol_1(),
+        probe.split_message(),
+        probe.pipelined(),
+        probe.empty_message(),
+        probe.truncated_string(),
+        probe.truncated_frame(),
+        probe.oversized_length(),
+    ];
+    checks.push(probe.still_answering());
+    Ok(checks)
+}
+
+struct Probe<'a> {
+    socket: &'a Path,
+    timeout: Duration,
+}
+
+impl Probe<'_> {
+    fn connect(&self) -> Result<UnixStream> {
+        let stream = UnixStream::connect(self.socket)
+            .with_context(|| format!("Failed to connect to agent at {:?}", self.socket))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        Ok(stream)
+    }
+
+    /// Send `message` on a new connection and see what comes back
+    fn exchange(&self, message: &[u8]) -> Result<Outcome> {
+        let mut stream = self.connect()?;
+        if let Err(e) = stream.write_all(message) {
+            return closed_or(e);
+        }
+        read_answer(&mut stream)
+    }
+
+    fn request_identities(&self) -> Check {
+        let name = "request-identities";
+        let outcome = match self.exchange(&protocol::frame(&[protocol::SSH_AGENTC_REQUEST_IDENTITIES])) {
+            Ok(outcome) => outcome,
+            Err(e) => return error(name, e),
+        };
+        match &outcome {
+            Outcome::Answer(answer) if outcome.is(protocol::SSH_AGENT_IDENTITIES_ANSWER) => {
+                match parse_identities(answer) {
+                    Ok(count) => pass(name, format!("well-formed answer with {} key(s)", count)),
+                    Err(e) => fail(name, format!("malformed SSH_AGENT_IDENTITIES_ANSWER: {:#}", e)),
+                }
+            }
+            _ => fail(name, format!("{}, expected SSH_AGENT_IDENTITIES_ANSWER", outcome.describe())),
+        }
+    }
+
+    fn sign_unknown_key(&self) -> Check {
+        let name = "sign-unknown-key";
+        let mut key = Vec::new();
+        protocol::put_string(&mut key, b"ssh-ed25519");
+        protocol::put_string(&mut key, &[0; 32]);
+        let mut body = vec![protocol::SSH_AGENTC_SIGN_REQUEST];
+        protocol::put_string(&mut body, &key);
+        protocol::put_string(&mut body, b"ssh-agent-router conformance check");
+        body.extend_from_slice(&0u32.to_be_bytes());
+        self.expect_failure(name, &protocol::frame(&body), "a sign request for a key it does not hold")
+    }
+
+    fn unknown_message(&self) -> Check {
+        let name = "unknown-message";
+        match self.exchange(&protocol::frame(&[250])) {
+            Ok(outcome) if outcome.is(protocol::SSH_AGENT_FAILURE) => {
+                pass(name, "SSH_AGENT_FAILURE for message type 250".to_string())
+            }
+            Ok(Outcome::Closed) => warn(name, "closed the connection instead of answering SSH_AGENT_FAILURE".to_string()),
+            Ok(outcome) => fail(name, format!("{} for message type 250, expected SSH_AGENT_FAILURE", outcome.describe())),
+            Err(e) => error(name, e),
+        }
+    }
+
+    fn unknown_extension(&self) -> Check {
+        let mut body = vec![protocol::SSH_AGENTC_EXTENSION];
+        protocol::put_string(&mut body, UNKNOWN_EXTENSION.as_bytes());
+        self.expect_failure("unknown-extension", &protocol::frame(&body), "an unknown extension")
+    }
+
+    fn query(&self) -> Check {
+        let name = "query-extension";
+        let mut body = vec![protocol::SSH_AGENTC_EXTENSION];
+        protocol::put_string(&mut body, protocol::QUERY_EXTENSION.as_bytes());
+        match self.exchange(&protocol::frame(&body)) {
+            Ok(Outcome::Answer(answer)) if protocol::message_type(&answer) == Some(protocol::SSH_AGENT_SUCCESS) => {
+                match protocol::parse_query_response(&answer) {
+                    Some(names) if names.is_empty() => pass(name, "supported, lists no extensions".to_string()),
+                    Some(names) => pass(name, format!("supported: {}", names.join(", "))),
+                    None => fail(name, "malformed answer to query".to_string()),
+                }
+            }
+            // The extension is optional
+            Ok(outcome) if outcome.is_failure() => pass(name, format!("not supported ({})", outcome.describe())),
+            Ok(outcome) => fail(name, format!("{}, expected SSH_AGENT_SUCCESS or a failure", outcome.describe())),
+            Err(e) => error(name, e),
+        }
+    }
+
+    fn protocol_1(&self) -> Check {
+        let name = "protocol-1";
+        match self.exchange(&protocol::frame(&[protocol::SSH_AGENTC_REQUEST_RSA_IDENTITIES])) {
+            Ok(outcome) if outcome.is(protocol::SSH_AGENT_FAILURE) => pass(name, "refused".to_string()),
+            // SSH_AGENT_RSA_IDENTITIES_ANSWER from an agent that still speaks it
+            Ok(outcome) if outcome.is(2) => warn(name, "still answers SSH protocol 1 requests".to_string()),
+            Ok(outcome) => fail(name, format!("{}, expected SSH_AGENT_FAILURE", outcome.describe())),
+            Err(e) => error(name, e),
+        }
+    }
+
+    fn split_message(&self) -> Check {
+        let name = "split-message";
+        let message = protocol::frame(&[protocol::SSH_AGENTC_REQUEST_IDENTITIES]);
+        let outcome = self.connect().and_then(|mut stream| {
+            // A length arriving in pieces, as it may on a busy system
+            for chunk in [&message[..2], &message[2..]] {
+                if let Err(e) = stream.write_all(chunk) {
+                    return closed_or(e);
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            read_answer(&mut stream)
+        });
+        match outcome {
+            Ok(outcome) if outcome.is(protocol::SSH_AGENT_IDENTITIES_ANSWER) => {
+                pass(name, "reassembled a request sent in pieces".to_string())
+            }
+            Ok(outcome) => fail(name, format!("{} for a request sent in pieces", outcome.describe())),
+            Err(e) => error(name, e),
+        }
+    }
+
+    fn pipelined(&self) -> Check {
+        let name = "pipelined-requests";
+        let message = protocol::frame(&[protocol::SSH_AGENTC_REQUEST_IDENTITIES]);
+        let answers = self.connect().and_then(|mut stream| {
+            stream.write_all(&[message.as_slice(), message.as_slice()].concat())?;
+            Ok([read_answer(&mut stream)?, read_answer(&mut stream)?])
+        });
+        match answers {
+            Ok(answers) if answers.iter().all(|a| a.is(protocol::SSH_AGENT_IDENTITIES_ANSWER)) => {
+                pass(name, "answered two requests sent together, in order".to_string())
+            }
+            Ok([first, second]) => fail(
+                name,
+                format!("{} and {} for two requests sent together", first.describe(), second.describe()),
+            ),
+            Err(e) => error(name, e),
+        }
+    }
+
+    fn empty_message(&self) -> Check {
+        let name = "empty-message";
+        match self.exchange(&0u32.to_be_bytes()) {
+            // OpenSSH's agent waits for the type byte before it looks at
+            // the length
+            Ok(Outcome::Silent) => warn(name, "waits for more after a message without a type".to_string()),
+            outcome => refusal(name, outcome, "a message without a type"),
+        }
+    }
+
+    fn truncated_string(&self) -> Check {
+        // A key blob said to be far longer than the message
+        let mut body = vec![protocol::SSH_AGENTC_SIGN_REQUEST];
+        body.extend_from_slice(&1000u32.to_be_bytes());
+        body.extend_from_slice(b"ssh-ed25519");
+        self.expect_refusal("truncated-string", &protocol::frame(&body), "a string running past the message")
+    }
+
+    fn truncated_frame(&self) -> Check {
+        let name = "truncated-frame";
+        let outcome = self.connect().and_then(|mut stream| {
+            // The length promises more than ever arrives before the client
+            // stops sending
+            let mut message = 100u32.to_be_bytes().to_vec();
+            message.extend_from_slice(&[protocol::SSH_AGENTC_REQUEST_IDENTITIES; 10]);
+            if let Err(e) = stream.write_all(&message) {
+                return closed_or(e);
+            }
+            stream.shutdown(std::net::Shutdown::Write)?;
+            read_answer(&mut stream)
+        });
+        match outcome {
+            Ok(Outcome::Closed) => pass(name, "closed the connection after a partial message".to_string()),
+            Ok(Outcome::Silent) => fail(name, "kept waiting after the client stopped sending".to_string()),
+            Ok(outcome) => warn(name, format!("{} for a partial message", outcome.describe())),
+            Err(e) => error(name, e),
+        }
+    }
+
+    fn oversized_length(&self) -> Check {
+        let name = "oversized-length";
+        let mut message = u32::MAX.to_be_bytes().to_vec();
+        message.push(protocol::SSH_AGENTC_ADD_IDENTITY);
+        match self.exchange(&message) {
+            Ok(Outcome::Closed) => pass(name, "closed the connection on a 4 GiB length".to_string()),
+            Ok(outcome) if outcome.is_failure() => pass(name, format!("{} for a 4 GiB length", outcome.describe())),
+            Ok(Outcome::Silent) => fail(name, "waited for a 4 GiB message instead of refusing it".to_string()),
+            Ok(outcome) => fail(name, format!("{} for a 4 GiB length", outcome.describe())),
+            Err(e) => error(name, e),
+        }
+    }
+
+    fn still_answering(&self) -> Check {
+        let name = "still-answering";
+        match self.exchange(&protocol::frame(&[protocol::SSH_AGENTC_REQUEST_IDENTITIES])) {
+            Ok(outcome) if outcome.is(protocol::SSH_AGENT_IDENTITIES_ANSWER) => {
+                pass(name, "answers normally after the malformed messages".to_string())
+            }
+            Ok(outcome) => fail(name, format!("{} after the malformed messages", outcome.describe())),
+            Err(e) => fail(name, format!("{:#} after the malformed messages", e)),
+        }
+    }
+
+    /// A valid request the agent has to refuse with a failure message
+    fn expect_failure(&self, name: &'static str, message: &[u8], what: &str) -> Check {
+        match self.exchange(message) {
+            Ok(outcome) if outcome.is_failure() => pass(name, format!("{} for {}", outcome.describe(), what)),
+            Ok(outcome) => fail(name, format!("{} for {}, expected SSH_AGENT_FAILURE", outcome.describe(), what)),
+            Err(e) => error(name, e),
+        }
+    }
+
+    /// A malformed request the agent may refuse or hang up on, but must
+    /// not act on or leave unanswered
+    fn expect_refusal(&self, name: &'static str, message: &[u8], what: &str) -> Check {
+        refusal(name, self.exchange(message), what)
+    }
+}
+
+fn refusal(name: &'static str, outcome: Result<Outcome>, what: &str) -> Check {
+    match outcome {
+        Ok(outcome) if outcome.is_failure() || outcome == Outcome::Closed => {
+            pass(name, format!("{} for {}", outcome.describe(), what))
+        }
+        Ok(outcome) => fail(name, format!("{} for {}, expected a failure or a closed connection", outcome.describe(), what)),
+        Err(e) => error(name, e),
+    }
+}
+
+/// Read one length-prefixed answer
+fn read_answer(stream: &mut UnixStream) -> Result<Outcome> {
+    let mut len = [0u8; 4];
+    if let Err(e) = stream.read_exact(&mut len) {
+        return closed_or(e);
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_ANSWER {
+        anyhow::bail!("Agent answered with a {} byte message", len);
+    }
+    let mut answer = vec![0u8; 4 + len];
+    answer[..4].copy_from_slice(&(len as u32).to_be_bytes());
+    stream.read_exact(&mut answer[4..]).context("Agent closed the connection in the middle of an answer")?;
+    Ok(Outcome::Answer(answer))
+}
+
+/// The outcome an I/O error stands for, or the error if it is not one of
+/// the ways an agent hangs up or goes quiet
+fn closed_or(e: std::io::Error) -> Result<Outcome> {
+    match e.kind() {
+        ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe | ErrorKind::ConnectionReset => Ok(Outcome::Closed),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => Ok(Outcome::Silent),
+        _ => Err(e.into()),
+    }
+}
+
+/// Number of keys in a length-prefixed identities answer, checking that
+/// it holds exactly what it says
+fn parse_identities(answer: &[u8]) -> Result<u32> {
+    let mut reader = Reader::new(answer);
+    reader.read_u32()?;
+    reader.read_u8()?;
+    let count = reader.read_u32()?;
+    for i in 0..count {
+        let blob = reader.read_string().with_context(|| format!("key {}", i + 1))?;
+        let mut key = Reader::new(blob);
+        let key_type = key.read_string().with_context(|| format!("key {} has no key type", i + 1))?;
+        if key_type.is_empty() {
+            anyhow::bail!("key {} has an empty key type", i + 1);
+        }
+        reader.read_string().with_context(|| format!("comment of key {}", i + 1))?;
+    }
+    if reader.position() != answer.len() {
+        anyhow::bail!("{} bytes after the last key", answer.len() - reader.position());
+    }
+    Ok(count)
+}
+
+fn pass(name: &'static str, detail: String) -> Check {
+    Check { name, verdict: Verdict::Pass, detail }
+}
+
+fn warn(name: &'static str, detail: String) -> Check {
+    Check { name, verdict: Verdict::Warn, detail }
+}
+
+fn fail(name: &'static str, detail: String) -> Check {
+    Check { name, verdict: Verdict::Fail, detail }
+}
+
+fn error(name: &'static str, e: anyhow::Error) -> Check {
+    fail(name, format!("{:#}", e))
+}
@@ -0,0 +1,181 @@
+use crate::config::SocketEntry;
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::fmt;
+use std::sync::LazyLock;
+
+/// Container IDs in cgroup paths, e.g. `docker-<id>.scope` under systemd
+/// or `/docker/<id>` with cgroup v1
+static RUNTIME_ID: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(docker|libpod|cri-containerd|crio)[-/]([0-9a-f]{12,64})").unwrap()
+});
+static KUBEPODS_ID: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"kubepods.*[/-]([0-9a-f]{64})").unwrap());
+static LXC_NAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"lxc(?:\.payload\.|/)([^/.]+)").unwrap());
+
+/// A container a client process runs in, as far as the host can tell
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Container {
+    /// `docker`, `podman`, `containerd`, `cri-o`, `kubernetes` or `lxc`,
+    /// or `unknown` when only the namespaces give it away
+    pub runtime: String,
+    /// Container ID or name from the cgroup path
+    pub id: Option<String>,
+    /// Image the container was started from, when the runtime says
+    pub image: Option<String>,
+}
+
+impl fmt::Display for Container {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.runtime.as_str() {
+            "unknown" => write!(f, "container of an unknown runtime")?,
+            runtime => write!(f, "{} container", runtime)?,
+        }
+        if let Some(id) = &self.id {
+            write!(f, " {}", id.get(..12).unwrap_or(id))?;
+        }
+        if let Some(image) = &self.image {
+            write!(f, " ({})", image)?;
+        }
+        Ok(())
+    }
+}
+
+/// The runtime and ID of the container a `/proc/<pid>/cgroup` file puts a
+/// process in, `None` outside any container runtime's cgroups
+pub fn parse_cgroup(cgroup: &str) -> Option<Container> {
+    for line in cgroup.lines() {
+        let path = line.splitn(3, ':').nth(2).unwrap_or(line);
+        if let Some(found) = RUNTIME_ID.captures(path) {
+            let runtime = match &found[1] {
+                "libpod" => "podman",
+                "cri-containerd" => "containerd",
+                "crio" => "cri-o",
+                runtime => runtime,
+            };
+            return Some(Container { runtime: runtime.to_string(), id: Some(found[2].to_string()), image: None });
+        }
+        if let Some(found) = KUBEPODS_ID.captures(path) {
+            return Some(Container { runtime: "kubernetes".to_string(), id: Some(found[1].to_string()), image: None });
+        }
+        if let Some(found) = LXC_NAME.captures(path) {
+            return Some(Container { runtime: "lxc".to_string(), id: Some(found[1].to_string()), image: None });
+        }
+    }
+    None
+}
+
+/// Which containers a socket lets in, from its `deny_containers` and
+/// `container_images`
+#[derive(Debug, Clone)]
+pub struct ContainerPolicy {
+    images: Vec<Regex>,
+}
+
+impl ContainerPolicy {
+    /// `None` for sockets that do not care where clients run
+    pub fn from_entry(entry: &SocketEntry) -> Result<Option<Self>> {
+        if !entry.deny_containers {
+            return Ok(None);
+        }
+        let images = entry.container_images.iter()
+            .map(|image| {
+                Regex::new(&crate::policy::glob_to_regex(image))
+                    .with_context(|| format!("Invalid container image pattern {:?}", image))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Some(Self { images }))
+    }
+
+    /// Why the client with process ID `pid` is refused, `None` if it is let
+    /// in
+    pub fn refusal(&self, pid: Option<u32>) -> Option<String> {
+        let Some(pid) = pid else {
+            return Some("cannot tell whether the client runs in a container".to_string());
+        };
+        let container = match detect(pid, !self.images.is_empty()) {
+            Ok(None) => return None,
+            Ok(Some(container)) => container,
+            Err(e) => return Some(format!("cannot tell whether the client runs in a container: {:#}", e)),
+        };
+        let image_allowed = container.image.as_deref()
+            .is_some_and(|image| self.images.iter().any(|pattern| pattern.is_match(image)));
+        (!image_allowed).then(|| format!("client runs in a {}", container))
+    }
+}
+
+/// The container process `pid` runs in, `None` if it runs beside the
+/// router
+///
+/// A process is in a container when its cgroup belongs to a container
+/// runtime other than the router's own, or when both its mount and PID
+/// namespaces differ from the router's; a private mount namespace alone,
+/// as systemd gives services with `PrivateTmp=`, does not count. With
+/// `image`, the runtime is asked which image the container runs.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn detect(pid: u32, image: bool) -> Result<Option<Container>> {
+    let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid))
+        .with_context(|| format!("Failed to read the cgroup of pid {}", pid))?;
+    let own = std::fs::read_to_string("/proc/self/cgroup").ok().and_then(|own| parse_cgroup(&own));
+    let namespace = |pid: &str, kind: &str| std::fs::read_link(format!("/proc/{}/ns/{}", pid, kind)).ok();
+    let pid_str = pid.to_string();
+    let own_namespaces = ["mnt", "pid"].map(|kind| namespace("self", kind));
+    let namespaces = ["mnt", "pid"].map(|kind| namespace(&pid_str, kind));
+    if namespaces.iter().any(Option::is_none) {
+        anyhow::bail!("Failed to read the namespaces of pid {}", pid);
+    }
+
+    let mut container = match parse_cgroup(&cgroup) {
+        // The router runs in the same container
+        Some(container) if own.as_ref().is_some_and(|own| own.id == container.id) => return Ok(None),
+        Some(container) => container,
+        None if namespaces != own_namespaces => Container { runtime: "unknown".to_string(), ..Default::default() },
+        None => return Ok(None),
+    };
+    if image {
+        container.image = image_of(pid, &container);
+    }
+    Ok(Some(container))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn detect(_pid: u32, _image: bool) -> Result<Option<Container>> {
+    Ok(None)
+}
+
+/// The image of a container, from podman's `/run/.containerenv` inside it
+/// or from the Docker API
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn image_of(pid: u32, container: &Container) -> Option<String> {
+    if let Ok(env) = std::fs::read_to_string(format!("/proc/{}/root/run/.containerenv", pid)) {
+        let image = env.lines()
+            .find_map(|line| line.strip_prefix("image="))
+            .map(|image| image.trim_matches('"').to_string());
+        if image.is_some() {
+            return image;
+        }
+    }
+    match (container.runtime.as_str(), &container.id) {
+        ("docker", Some(id)) => docker_image(id).map_err(|e| log::debug!("Docker image of {}: {:#}", id, e)).ok(),
+        _ => None,
+    }
+}
+
+/// Ask the Docker daemon which image container `id` runs
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn docker_image(id: &str) -> Result<String> {
+    use std::io::{Read, Write};
+
+    let path = std::env::var("DOCKER_HOST").ok()
+        .and_then(|host| host.strip_prefix("unix://").map(str::to_string))
+        .unwrap_or_else(|| "/var/run/docker.sock".to_string());
+    let mut stream = std::os::unix::net::UnixStream::connect(&path)
+        .with_context(|| format!("Failed to connect to {}", path))?;
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
+    write!(stream, "GET /containers/{}/json HTTP/1.0\r\nHost: docker\r\n\r\n", id)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (_, body) = response.split_once("\r\n\r\n").context("Invalid response from Docker")?;
+    let details: serde_json::Value = serde_json::from_str(body).context("Invalid response from Docker")?;
+    details["Config"]["Image"].as_str().map(str::to_string).context("Docker did not report an image")
+}
@@ -0,0 +1,66 @@
+use crate::peer::PeerCred;
+use crate::privacy;
+use crate::protocol::{self, SessionBind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+static CONNECTIONS: AtomicU64 = AtomicU64::new(1);
+
+/// Number a new client connection, unique within the running router
+pub fn next_connection() -> u64 {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed)
+}
+
+/// What is known about one client request while it passes through a socket
+///
+/// Built once per request in the client handler and handed to the filter,
+/// approval backends and audit trail so none of them has to work it out
+/// again.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    /// Correlation ID, `<connection>.<request>`, shared by the log lines,
+    /// audit records and approval requests of this request; connection 0
+    /// is a request that did not come from a client, such as a replay
+    pub id: String,
+    /// Name of the socket the request arrived on
+    pub socket: String,
+    /// Credentials of the client process
+    pub peer: PeerCred,
+    /// When the request arrived
+    pub time: SystemTime,
+    /// Agent message number of the request
+    pub message_type: Option<u8>,
+    /// Host bindings announced earlier on the same connection
+    pub session_binds: Vec<SessionBind>,
+}
+
+impl RequestContext {
+    pub fn new(socket: &str, peer: PeerCred, request: &[u8]) -> Self {
+        Self {
+            id: "0.0".to_string(),
+            socket: socket.to_string(),
+            peer,
+            time: SystemTime::now(),
+            message_type: protocol::message_type(request),
+            session_binds: Vec::new(),
+        }
+    }
+
+    /// Number the request as the `request`th on client connection
+    /// `connection`
+    pub fn with_id(mut self, connection: u64, request: u64) -> Self {
+        self.id = format!("{}.{}", connection, request);
+        self
+    }
+
+    /// Attach the host bindings seen so far on the connection
+    pub fn with_session_binds(mut self, binds: &[SessionBind]) -> Self {
+        self.session_binds = binds.to_vec();
+        self
+    }
+
+    /// Short description of the client for prompts and log lines
+    pub fn client(&self) -> String {
+        privacy::client(&self.peer)
+    }
+}
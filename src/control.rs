@@ -0,0 +1,545 @@
+use crate::agent::IdentitySnapshot;
+use crate::config::{Config, SocketEntry};
+use crate::events;
+use crate::fdpass;
+use crate::router::Router;
+use crate::state;
+use crate::stats::StatsSnapshot;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Request sent to a running router over the control socket
+///
+/// The wire format is one JSON object per line in each direction.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum ControlRequest {
+    /// Connection counters for every socket
+    Stats,
+    /// Identities the upstream agent listed most recently
+    Upstream,
+    /// Forget cached approvals on one socket, by name or path, or on all
+    FlushApprovals {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        socket: Option<String>,
+    },
+    /// Load a profile and serve its sockets instead of the current ones
+    SwitchProfile { profile: String },
+    /// Bind a temporary socket exposing only `allowed`
+    CreateSocket {
+        allowed: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        /// Socket file (default: in the ephemeral socket directory)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<PathBuf>,
+        /// Remove the socket after this long (default: when the router stops)
+        #[serde(default, with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+        ttl: Option<Duration>,
+    },
+    /// Unbind an ephemeral socket, by name or path
+    RemoveSocket { socket: String },
+    /// Stream events as JSON lines until the client disconnects
+    Subscribe,
+    /// The last `count` events, kept whether or not anyone subscribed
+    Recent { count: usize },
+    /// Pass the configured sockets' listening sockets to the router
+    /// asking, then exit
+    Handover,
+    /// Compact state for status bars to poll
+    Summary,
+    /// Configuration the sockets are running with
+    Config,
+    /// Refuse everything on every socket and disconnect all clients,
+    /// locking the upstream agent with `lock` if given
+    Panic {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lock: Option<String>,
+    },
+    /// Leave panic mode, unlocking the upstream agent with `unlock` first
+    /// if given
+    Resume {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        unlock: Option<String>,
+    },
+    /// Bind the configured sockets tagged `tag` again
+    Enable { tag: String },
+    /// Unbind the configured sockets tagged `tag` and disconnect their
+    /// clients, leaving every other socket alone
+    Disable { tag: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "kebab-case")]
+pub enum ControlResponse {
+    Stats { sockets: Vec<SocketStatus> },
+    Flushed { approvals: usize },
+    Upstream { snapshot: Option<IdentitySnapshot> },
+    Switched { sockets: Vec<String> },
+    Created { name: String, path: PathBuf },
+    Removed { name: String },
+    /// Followed by one event per line for the rest of the connection
+    Subscribed,
+    /// JSON lines as `subscribe` streams them, oldest first
+    Recent { events: Vec<String> },
+    /// Sent with the listening sockets of `sockets`, in that order
+    HandedOver { sockets: Vec<PathBuf> },
+    Summary { summary: Summary },
+    Config { config: Box<Config> },
+    Panicked { connections: usize, locked: bool },
+    Resumed { was_panicking: bool, unlocked: bool },
+    Enabled { sockets: Vec<String> },
+    Disabled { sockets: Vec<String>, connections: usize },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SocketStatus {
+    pub name: String,
+    pub path: PathBuf,
+    pub stats: StatsSnapshot,
+    /// Created over the control socket rather than configured
+    #[serde(default)]
+    pub ephemeral: bool,
+    /// Unix time at which an ephemeral socket is removed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+/// State of a running router at a glance
+///
+/// Meant to be polled by status bars, so fields are only ever added.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Summary {
+    pub upstream: UpstreamHealth,
+    /// Keys upstream listed most recently, if it has been asked yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_keys: Option<usize>,
+    /// Connected clients over all sockets
+    pub active_connections: u64,
+    pub sockets: Vec<SocketSummary>,
+    /// The most recent sign request refused on any socket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_denied: Option<LastDenied>,
+    /// Every socket refuses every request until `resume`
+    #[serde(default)]
+    pub panic: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamHealth {
+    /// The last request to the upstream agent succeeded
+    Ok,
+    /// The last request to the upstream agent failed
+    Down,
+    /// No request has reached the upstream agent yet
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SocketSummary {
+    pub name: String,
+    pub active_connections: u64,
+    pub denied_signs: u64,
+    #[serde(default)]
+    pub ephemeral: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LastDenied {
+    pub socket: String,
+    pub fingerprint: String,
+    /// Unix time of the request
+    pub at: u64,
+}
+
+/// Listener for the control socket of a running router
+pub struct ControlServer {
+    path: PathBuf,
+}
+
+impl ControlServer {
+    pub fn start(router: Arc<Router>) -> Result<Self> {
+        Self::start_at(Config::control_socket_path()?, router)
+    }
+
+    /// Listen on `path` instead of the default control socket location
+    pub fn start_at(path: PathBuf, router: Arc<Router>) -> Result<Self> {
+        state::create_parent_dir(&path)?;
+        if path.exists() {
+            // A socket left behind by a crashed router refuses connections;
+            // one that still answers belongs to a router that is running
+            if UnixStream::connect(&path).is_ok() {
+                anyhow::bail!("Another router is already running (control socket at {:?})", path);
+            }
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove stale control socket at {:?}", path))?;
+        }
+
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Failed to bind control socket at {:?}", path))?;
+        // Only the owner may talk to the router
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let router = Arc::clone(&router);
+                        std::thread::spawn(move || {
+                            if let Err(e) = handle_connection(&router, stream) {
+                                log::debug!("Control connection error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        log::error!("Control connection error: {}", e);
+                    }
+                }
+            }
+        });
+
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn handle_connection(router: &Arc<Router>, stream: UnixStream) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    let reader = BufReader::new(stream);
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(ControlRequest::Subscribe) => return stream_events(writer),
+            Ok(ControlRequest::Handover) => return hand_over(router, writer),
+            Ok(request) => dispatch(router, request),
+            Err(e) => ControlResponse::Error {
+                message: format!("Invalid control request: {}", e),
+            },
+        };
+
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Hand the connection over to the event stream
+fn stream_events(mut writer: UnixStream) -> Result<()> {
+    let events = events::subscribe();
+    serde_json::to_writer(&mut writer, &ControlResponse::Subscribed)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+
+    // A subscriber that went away is noticed on the next event
+    for line in events {
+        writer.write_all(line.as_bytes())?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Send the listening sockets along with the response, which ends the
+/// connection
+fn hand_over(router: &Router, writer: UnixStream) -> Result<()> {
+    let listeners = router.hand_over();
+    let sockets = listeners.iter().map(|(path, _)| path.clone()).collect();
+    let mut line = serde_json::to_vec(&ControlResponse::HandedOver { sockets })?;
+    line.push(b'\n');
+
+    let fds: Vec<_> = listeners.iter().map(|(_, listener)| listener.as_raw_fd()).collect();
+    let mut chunks = fds.chunks(fdpass::MAX_FDS);
+    fdpass::send(&writer, &line, chunks.next().unwrap_or_default())?;
+    for chunk in chunks {
+        fdpass::send(&writer, b"\n", chunk)?;
+    }
+    log::info!("Handed {} sockets over to a new router, exiting", listeners.len());
+    Ok(())
+}
+
+fn summary(router: &Router) -> Summary {
+    let mut summary = Summary {
+        upstream: match router.agent().healthy() {
+            Some(true) => UpstreamHealth::Ok,
+            Some(false) => UpstreamHealth::Down,
+            None => UpstreamHealth::Unknown,
+        },
+        upstream_keys: router.agent().last_seen().map(|snapshot| snapshot.keys.len()),
+        active_connections: 0,
+        sockets: Vec::new(),
+        last_denied: None,
+        panic: router.in_panic(),
+    };
+
+    for socket in router.sockets() {
+        let stats = socket.stats().snapshot();
+        summary.active_connections += stats.active;
+        if let Some(denied) = stats.last_denied.clone() {
+            if summary.last_denied.as_ref().is_none_or(|last| denied.at >= last.at) {
+                summary.last_denied = Some(LastDenied {
+                    socket: socket.name(),
+                    fingerprint: denied.fingerprint,
+                    at: denied.at,
+                });
+            }
+        }
+        summary.sockets.push(SocketSummary {
+            name: socket.name(),
+            active_connections: stats.active,
+            denied_signs: stats.denied_sign_count(),
+            ephemeral: router.is_ephemeral(&socket),
+        });
+    }
+    summary
+}
+
+fn dispatch(router: &Arc<Router>, request: ControlRequest) -> ControlResponse {
+    match request {
+        ControlRequest::Stats => ControlResponse::Stats {
+            sockets: router.sockets()
+                .iter()
+                .map(|socket| SocketStatus {
+                    name: socket.name(),
+                    path: socket.path().clone(),
+                    stats: socket.stats().snapshot(),
+                    ephemeral: router.is_ephemeral(socket),
+                    expires: router.expires(socket),
+                })
+                .collect(),
+        },
+        ControlRequest::Upstream => ControlResponse::Upstream {
+            snapshot: router.agent().last_seen(),
+        },
+        ControlRequest::FlushApprovals { socket } => {
+            let sockets: Vec<_> = router.sockets()
+                .into_iter()
+                .filter(|s| socket.as_deref().is_none_or(|name| s.name() == name || s.path().as_os_str() == name))
+                .collect();
+            if let (Some(name), true) = (&socket, sockets.is_empty()) {
+                return ControlResponse::Error {
+                    message: format!("No socket named {:?}", name),
+                };
+            }
+            ControlResponse::Flushed {
+                approvals: sockets.iter().map(|s| s.flush_approvals()).sum(),
+            }
+        }
+        ControlRequest::SwitchProfile { profile } => {
+            // Later reloads (SIGHUP) stay on the new profile
+            Config::use_profile(&profile);
+            match Config::load().and_then(|config| router.reload(&config)) {
+                Ok(()) => ControlResponse::Switched {
+                    sockets: router.sockets().iter().map(|s| s.name()).collect(),
+                },
+                Err(e) => ControlResponse::Error {
+                    message: format!("Failed to switch to profile {:?}: {:#}", profile, e),
+                },
+            }
+        }
+        ControlRequest::CreateSocket { allowed, name, path, ttl } => {
+            let entry = SocketEntry {
+                path: path.unwrap_or_default(),
+                name,
+                allowed: allowed.into_iter().map(Into::into).collect(),
+                ..Default::default()
+            };
+            match router.add_ephemeral(entry, ttl) {
+                Ok(socket) => ControlResponse::Created {
+                    name: socket.name(),
+                    path: socket.path().clone(),
+                },
+                Err(e) => ControlResponse::Error {
+                    message: format!("Failed to create socket: {:#}", e),
+                },
+            }
+        }
+        ControlRequest::Summary => ControlResponse::Summary { summary: summary(router) },
+        ControlRequest::Config => ControlResponse::Config { config: Box::new(router.config()) },
+        ControlRequest::Subscribe => ControlResponse::Error {
+            message: "subscribe takes over the connection".to_string(),
+        },
+        ControlRequest::Handover => ControlResponse::Error {
+            message: "handover takes over the connection".to_string(),
+        },
+        ControlRequest::Recent { count } => ControlResponse::Recent { events: events::recent(count) },
+        ControlRequest::RemoveSocket { socket } => match router.remove_ephemeral(&socket) {
+            Ok(socket) => ControlResponse::Removed { name: socket.name() },
+            Err(e) => ControlResponse::Error { message: format!("{:#}", e) },
+        },
+        ControlRequest::Panic { lock } => {
+            // Sockets are shut first; locking upstream may take a while
+            let connections = router.panic();
+            if let Some(passphrase) = &lock {
+                if let Err(e) = router.agent().lock(true, passphrase) {
+                    return ControlResponse::Error {
+                        message: format!("Sockets now refuse everything, but locking upstream failed: {:#}", e),
+                    };
+                }
+                log::warn!("Panic mode: locked the upstream agent");
+            }
+            ControlResponse::Panicked { connections, locked: lock.is_some() }
+        }
+        ControlRequest::Resume { unlock } => {
+            if let Some(passphrase) = &unlock {
+                if let Err(e) = router.agent().lock(false, passphrase) {
+                    return ControlResponse::Error {
+                        message: format!("Still in panic mode, unlocking upstream failed: {:#}", e),
+                    };
+                }
+            }
+            ControlResponse::Resumed { was_panicking: router.resume(), unlocked: unlock.is_some() }
+        }
+        ControlRequest::Enable { tag } => match router.enable_tag(&tag) {
+            Ok(sockets) => ControlResponse::Enabled { sockets },
+            Err(e) => ControlResponse::Error { message: format!("{:#}", e) },
+        },
+        ControlRequest::Disable { tag } => match router.disable_tag(&tag) {
+            Ok((sockets, connections)) => ControlResponse::Disabled { sockets, connections },
+            Err(e) => ControlResponse::Error { message: format!("{:#}", e) },
+        },
+    }
+}
+
+/// Subscribe to the running router's events, returning a reader that
+/// yields one JSON event per line
+pub fn subscribe() -> Result<BufReader<UnixStream>> {
+    subscribe_at(&Config::control_socket_path()?)
+}
+
+pub fn subscribe_at(path: &Path) -> Result<BufReader<UnixStream>> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Router is not running (no control socket at {:?})", path))?;
+
+    serde_json::to_writer(&mut stream, &ControlRequest::Subscribe)?;
+    stream.write_all(b"\n")?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    match read_response(&mut reader)? {
+        ControlResponse::Subscribed => Ok(reader),
+        response => Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+    }
+}
+
+/// Ask the running router for its listening sockets, which it exits
+/// after handing over; `None` if no router is running
+pub fn take_over() -> Result<Option<Vec<(PathBuf, UnixListener)>>> {
+    take_over_at(&Config::control_socket_path()?)
+}
+
+pub fn take_over_at(path: &Path) -> Result<Option<Vec<(PathBuf, UnixListener)>>> {
+    let Ok(mut stream) = UnixStream::connect(path) else {
+        return Ok(None);
+    };
+    serde_json::to_writer(&mut stream, &ControlRequest::Handover)?;
+    stream.write_all(b"\n")?;
+    stream.flush()?;
+
+    // Read with recvmsg only: a buffered read would drop the descriptors
+    let mut data = Vec::new();
+    let mut fds = Vec::new();
+    let mut buf = [0u8; 8192];
+    let receive = |data: &mut Vec<u8>, fds: &mut Vec<_>, buf: &mut [u8]| -> Result<()> {
+        let (n, received) = fdpass::recv(&stream, buf)?;
+        if n == 0 {
+            anyhow::bail!("The router closed the connection during the handover");
+        }
+        data.extend_from_slice(&buf[..n]);
+        fds.extend(received);
+        Ok(())
+    };
+    while !data.contains(&b'\n') {
+        receive(&mut data, &mut fds, &mut buf)?;
+    }
+    let end = data.iter().position(|&b| b == b'\n').unwrap_or(data.len());
+    let sockets = match read_response(&mut &data[..=end])? {
+        ControlResponse::HandedOver { sockets } => sockets,
+        response => anyhow::bail!("Unexpected response from router: {:?}", response),
+    };
+    while fds.len() < sockets.len() {
+        receive(&mut data, &mut fds, &mut buf)?;
+    }
+    Ok(Some(sockets.into_iter().zip(fds.into_iter().map(UnixListener::from)).collect()))
+}
+
+/// Send a request to the running router and wait for its response
+pub fn request(request: &ControlRequest) -> Result<ControlResponse> {
+    request_at(&Config::control_socket_path()?, request)
+}
+
+/// Send a request to the router listening on `path`
+pub fn request_at(path: &Path, request: &ControlRequest) -> Result<ControlResponse> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Router is not running (no control socket at {:?})", path))?;
+
+    serde_json::to_writer(&mut stream, request)?;
+    stream.write_all(b"\n")?;
+    stream.flush()?;
+
+    read_response(&mut BufReader::new(stream))
+}
+
+/// Read the router's answer to a request, turning an error it reports
+/// into an `Err`
+pub fn read_response(reader: &mut impl BufRead) -> Result<ControlResponse> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        anyhow::bail!("The router closed the connection without answering");
+    }
+
+    match serde_json::from_str(&line).context("Invalid response from router")? {
+        ControlResponse::Error { message } => Err(anyhow::anyhow!(message)),
+        response => Ok(response),
+    }
+}
+
+/// Pass control requests from stdin to the running router and its answers
+/// to stdout until either side stops, which lets `remote` reach a router
+/// over SSH
+pub fn relay() -> Result<()> {
+    let path = Config::control_socket_path()?;
+    let stream = match UnixStream::connect(&path) {
+        Ok(stream) => stream,
+        Err(e) => {
+            // Answered as the router would, so the other end can tell why
+            let message = format!("Router is not running (no control socket at {:?}): {}", path, e);
+            println!("{}", serde_json::to_string(&ControlResponse::Error { message })?);
+            return Ok(());
+        }
+    };
+
+    let mut requests = stream.try_clone()?;
+    std::thread::spawn(move || {
+        let _ = std::io::copy(&mut std::io::stdin().lock(), &mut requests);
+        // Without more requests the router closes the connection once it
+        // has answered, unless it is streaming events
+        let _ = requests.shutdown(std::net::Shutdown::Write);
+    });
+    std::io::copy(&mut &stream, &mut std::io::stdout().lock())?;
+    Ok(())
+}
@@ -0,0 +1,524 @@
+use crate::agent::{self, Agent, SshKey};
+use crate::config::{expand_tilde, AllowedKey, Config, DefaultPolicy, SocketEntry};
+use crate::context::RequestContext;
+use crate::protocol::{self, Reader};
+use crate::socket::FilteredSocket;
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+
+/// Numbers the fake upstream sockets of fixtures replayed at once
+static REPLAYS: AtomicU64 = AtomicU64::new(0);
+
+/// Host names and patterns such as `build.corp.internal` or `*.corp.internal`
+static HOST: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"[A-Za-z0-9*?][A-Za-z0-9*?-]*(\.[A-Za-z0-9*?-]+)*\.[A-Za-z*?][A-Za-z0-9*?-]*").unwrap()
+});
+
+/// Domains reserved for documentation, which placeholders are at
+const PLACEHOLDER_DOMAINS: &[&str] = &["example.com", "example.org", "example.net"];
+
+/// Socket settings naming the fixture's own socket, kept as they are
+const SOCKET_KEYS: &[&str] = &["path", "name"];
+
+/// A request and the router's answer to it, with everything needed to
+/// replay it through the filter pipeline: the socket's settings and what
+/// upstream had to say
+///
+/// Fixtures are anonymized when recorded. Keys are replaced by stand-ins
+/// of the same type, numbered in the order they turn up, consistently
+/// across the request, upstream's keys and the settings; email addresses
+/// in key comments and settings become `user1@example.com` and host names
+/// and patterns `host1.example.net` and so on; host keys in the settings
+/// become the fingerprints of their stand-ins; user names, session IDs and
+/// signatures are blanked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fixture {
+    /// What the fixture was made from, such as a capture file name
+    pub source: String,
+
+    /// Length-prefixed request, base64
+    pub request: String,
+
+    /// The router's answer when the fixture was recorded, base64
+    pub response: String,
+
+    /// Whether the request itself reached upstream, rather than being
+    /// answered by the router
+    pub forwarded: bool,
+
+    /// Upstream's answer to the forwarded request, base64
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_response: Option<String>,
+
+    /// Global `default` the socket falls back on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<DefaultPolicy>,
+
+    /// Global `allowed_messages`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_messages: Option<Vec<String>>,
+
+    /// Keys upstream listed
+    #[serde(default)]
+    pub upstream_keys: Vec<FixtureKey>,
+
+    /// Key groups the socket's settings refer to
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, Vec<String>>,
+
+    /// The socket's settings, without the ones that need files, prompts
+    /// or state
+    pub socket: SocketEntry,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FixtureKey {
+    /// Public key blob, base64
+    pub blob: String,
+    pub comment: String,
+}
+
+/// What the filter pipeline did with a fixture's request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replayed {
+    pub response: Vec<u8>,
+    pub forwarded: bool,
+}
+
+impl Fixture {
+    /// Turn a captured request into a fixture for the socket `entry`,
+    /// with upstream listing `upstream_keys`
+    ///
+    /// The fixture records what the pipeline answers now. The second
+    /// value says whether that matches `captured`, the answer given when
+    /// the request was captured; it may not if the settings or upstream's
+    /// keys changed since, or the request needed a confirmation, which
+    /// fixtures leave out.
+    pub fn record(
+        source: &str,
+        request: &[u8],
+        captured: Option<&[u8]>,
+        entry: &SocketEntry,
+        config: &Config,
+        upstream_keys: &[SshKey],
+    ) -> Result<(Self, bool)> {
+        let mut anonymizer = Anonymizer::default();
+        let request = anonymizer.request(request)?;
+        let upstream_keys: Vec<FixtureKey> = upstream_keys.iter()
+            .map(|key| FixtureKey {
+                blob: STANDARD.encode(anonymizer.key(&key.blob)),
+                comment: anonymizer.comment(&key.comment),
+            })
+            .collect();
+        let captured = captured.map(|response| anonymizer.response(response)).transpose()?;
+
+        let mut allowed = entry.allowed.clone();
+        for path in &entry.allowed_files {
+            let keys = crate::policy::read_allowed_file(&expand_tilde(path))?;
+            allowed.extend(keys.into_iter().map(|key| AllowedKey { key, max_uses: None }));
+        }
+        // Only what decides the answer is kept
+        let socket = SocketEntry {
+            path: PathBuf::from(format!("{}.sock", entry.name())),
+            name: Some(entry.name()),
+            description: None,
+            allowed,
+            allowed_files: Vec::new(),
+            debug_capture: None,
+            env_file: None,
+            capture_redact: false,
+            log_level: None,
+            audit_file: None,
+            audit: None,
+            confirm: None,
+            confirm_timeout: None,
+            confirm_cache: None,
+            sign_rate: None,
+            max_key_age: None,
+            active: None,
+            ..entry.clone()
+        };
+
+        let mut fixture = Self {
+            source: source.to_string(),
+            request: STANDARD.encode(&request),
+            response: String::new(),
+            forwarded: false,
+            // Upstream answered the request with what the client got,
+            // unless the router refused it, when it never asks
+            upstream_response: Some(STANDARD.encode(
+                captured.clone().unwrap_or_else(|| protocol::frame(&[protocol::SSH_AGENT_FAILURE])),
+            )),
+            default: config.default,
+            allowed_messages: config.allowed_messages.clone(),
+            upstream_keys,
+            groups: config.groups.clone(),
+            socket: anonymizer.settings(&socket)?,
+        };
+        for keys in fixture.groups.values_mut() {
+            *keys = keys.iter().map(|key| anonymizer.text(key)).collect();
+        }
+
+        let replayed = fixture.replay()?;
+        fixture.response = STANDARD.encode(&replayed.response);
+        fixture.forwarded = replayed.forwarded;
+        if !replayed.forwarded {
+            fixture.upstream_response = None;
+        }
+        let matches = captured.is_none_or(|captured| captured == replayed.response);
+        Ok((fixture, matches))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read fixture {:?}", path))?;
+        toml::from_str(&content).with_context(|| format!("Failed to parse fixture {:?}", path))
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self).context("Failed to serialize fixture")
+    }
+
+    /// Run the request through a socket with the fixture's settings, in
+    /// front of a fake upstream agent answering as the recorded one did
+    pub fn replay(&self) -> Result<Replayed> {
+        let request = decode(&self.request, "request")?;
+        let keys = self.upstream_keys.iter()
+            .map(|key| Ok(SshKey::from_key_blob(decode(&key.blob, "upstream key")?, key.comment.clone())))
+            .collect::<Result<Vec<_>>>()?;
+        let answer = match &self.upstream_response {
+            Some(answer) => decode(answer, "upstream response")?,
+            None => protocol::frame(&[protocol::SSH_AGENT_FAILURE]),
+        };
+
+        let upstream = FakeUpstream::start(keys, answer)?;
+        let config = Config {
+            upstream: upstream.path.to_string_lossy().to_string(),
+            default: self.default,
+            allowed_messages: self.allowed_messages.clone(),
+            groups: self.groups.clone(),
+            sockets: vec![self.socket.clone()],
+            ..Default::default()
+        };
+        config.validate().context("Invalid settings in fixture")?;
+        let agent = Agent::new(config.upstream.clone(), config.limits.clone());
+        let socket = FilteredSocket::new(&self.socket, agent, &config)?;
+        let context = RequestContext::new(&socket.name(), Default::default(), &request);
+        let response = socket.process_request(&request, &context)?;
+        Ok(Replayed { response, forwarded: upstream.forwarded.load(Ordering::SeqCst) })
+    }
+
+    /// How the pipeline's answer now differs from the recorded one, `None`
+    /// if it does not
+    pub fn check(&self) -> Result<Option<String>> {
+        let expected = Replayed { response: decode(&self.response, "response")?, forwarded: self.forwarded };
+        let replayed = self.replay()?;
+        if replayed == expected {
+            return Ok(None);
+        }
+        Ok(Some(format!("expected {}, got {}", describe(&expected), describe(&replayed))))
+    }
+}
+
+/// Fixture files in `dir`, by name
+pub fn fixtures(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read fixture directory {:?}", dir))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn decode(data: &str, what: &str) -> Result<Vec<u8>> {
+    STANDARD.decode(data).with_context(|| format!("Invalid base64 in fixture {}", what))
+}
+
+fn describe(replayed: &Replayed) -> String {
+    let message = protocol::message_type(&replayed.response).map_or("an empty message", protocol::message_type_name);
+    let keys = match protocol::message_type(&replayed.response) {
+        Some(protocol::SSH_AGENT_IDENTITIES_ANSWER) => agent::parse_identities_answer(&replayed.response[4..])
+            .map(|keys| format!(" with {} key(s)", keys.len()))
+            .unwrap_or_default(),
+        _ => String::new(),
+    };
+    let forwarded = if replayed.forwarded { "forwarded" } else { "not forwarded" };
+    format!("{}{} ({})", message, keys, forwarded)
+}
+
+/// Consistent stand-ins for the keys and addresses in a fixture
+#[derive(Default)]
+struct Anonymizer {
+    /// Stand-in blob for each real key blob
+    keys: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Placeholder for each email address
+    names: BTreeMap<String, String>,
+    /// Placeholder for each host name or pattern
+    hosts: BTreeMap<String, String>,
+}
+
+impl Anonymizer {
+    /// A key of the same type whose key data only tells which key of the
+    /// recording it stands in for, so nothing of the real key is left to
+    /// match against known keys
+    fn key(&mut self, blob: &[u8]) -> Vec<u8> {
+        let next = self.keys.len() + 1;
+        self.keys.entry(blob.to_vec())
+            .or_insert_with(|| {
+                let key_type = Reader::new(blob).read_string().unwrap_or_default();
+                let mut stand_in = Vec::new();
+                protocol::put_string(&mut stand_in, key_type);
+                protocol::put_string(&mut stand_in, &Sha256::digest(format!("fixture key {}", next)));
+                stand_in
+            })
+            .clone()
+    }
+
+    fn comment(&mut self, comment: &str) -> String {
+        let comment = crate::scrub::replace_emails_in(comment, &mut self.names);
+        self.hosts(&comment)
+    }
+
+    /// `text` with every host name and pattern replaced by a placeholder,
+    /// the same one for each
+    fn hosts(&mut self, text: &str) -> String {
+        HOST.replace_all(text, |found: &regex::Captures| {
+            let name = &found[0];
+            if PLACEHOLDER_DOMAINS.iter().any(|domain| name == *domain || name.ends_with(&format!(".{}", domain))) {
+                return name.to_string();
+            }
+            let next = self.hosts.len() + 1;
+            self.hosts.entry(name.to_string()).or_insert_with(|| format!("host{}.example.net", next)).clone()
+        }).into_owned()
+    }
+
+    /// `text` from the settings with the fingerprints of replaced keys
+    /// rewritten to match, and addresses and host names replaced as in
+    /// key comments
+    fn text(&mut self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (real, stand_in) in &self.keys {
+            let (real, stand_in) = (SshKey::from_key_blob(real.clone(), String::new()), SshKey::from_key_blob(stand_in.clone(), String::new()));
+            text = text.replace(&real.fingerprint, &stand_in.fingerprint)
+                .replace(&real.md5_fingerprint(), &stand_in.md5_fingerprint());
+        }
+        self.comment(&text)
+    }
+
+    fn settings(&mut self, entry: &SocketEntry) -> Result<SocketEntry> {
+        // Host key lines name the hosts and hold their real keys
+        let mut entry = entry.clone();
+        for binding in &mut entry.hosts {
+            for host_key in &mut binding.host_keys {
+                if let Ok(key) = SshKey::from_authorized_keys_line(host_key) {
+                    let stand_in = self.key(&key.blob);
+                    *host_key = SshKey::from_key_blob(stand_in, String::new()).fingerprint;
+                }
+            }
+        }
+
+        let mut value = toml::Value::try_from(&entry).context("Failed to serialize socket settings")?;
+        if let toml::Value::Table(table) = &mut value {
+            for (name, item) in table.iter_mut() {
+                if !SOCKET_KEYS.contains(&name.as_str()) {
+                    self.rewrite(item);
+                }
+            }
+        }
+        value.try_into().context("Failed to anonymize socket settings")
+    }
+
+    fn rewrite(&mut self, value: &mut toml::Value) {
+        match value {
+            toml::Value::String(text) => *text = self.text(text),
+            toml::Value::Array(items) => items.iter_mut().for_each(|item| self.rewrite(item)),
+            toml::Value::Table(table) => table.iter_mut().for_each(|(_, item)| self.rewrite(item)),
+            _ => {}
+        }
+    }
+
+    /// A length-prefixed request with its keys, user name, session and
+    /// signatures replaced; requests carrying secrets are refused
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = Reader::new(request);
+        reader.read_u32()?;
+        let msg_type = reader.read_u8()?;
+        if let Some(secret) = protocol::secret_in(msg_type) {
+            anyhow::bail!("{} holds {}", protocol::message_type_name(msg_type), secret);
+        }
+        let mut body = vec![msg_type];
+        match msg_type {
+            protocol::SSH_AGENTC_SIGN_REQUEST => {
+                let blob = reader.read_string()?;
+                let data = reader.read_string()?;
+                let stand_in = self.key(blob);
+                protocol::put_string(&mut body, &stand_in);
+                let data = self.sign_data(data)?;
+                protocol::put_string(&mut body, &data);
+            }
+            protocol::SSH_AGENTC_REMOVE_IDENTITY => {
+                let stand_in = self.key(reader.read_string()?);
+                protocol::put_string(&mut body, &stand_in);
+            }
+            protocol::SSH_AGENTC_EXTENSION if protocol::parse_session_bind(request).is_some() => {
+                protocol::put_string(&mut body, reader.read_string()?);
+                let host_key = self.key(reader.read_string()?);
+                protocol::put_string(&mut body, &host_key);
+                protocol::put_string(&mut body, &vec![0; reader.read_string()?.len()]);
+                let signature = protocol::blank_signature(reader.read_string()?);
+                protocol::put_string(&mut body, &signature);
+            }
+            _ => return Ok(request.to_vec()),
+        }
+        // Flags and whatever else follows are kept as they are
+        body.extend_from_slice(&request[reader.position()..]);
+        Ok(protocol::frame(&body))
+    }
+
+    /// Data to sign with the user name and session blanked from logins,
+    /// and anything but logins and SSHSIG data blanked entirely
+    fn sign_data(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        if protocol::sshsig_namespace(data).is_some() {
+            // A hash of the message, no more
+            return Ok(data.to_vec());
+        }
+        let Some(auth) = protocol::parse_userauth(data) else {
+            return Ok(vec![0; data.len()]);
+        };
+        let mut reader = Reader::new(data);
+        let mut out = Vec::new();
+        protocol::put_string(&mut out, &vec![0; reader.read_string()?.len()]);
+        out.push(reader.read_u8()?);
+        reader.read_string()?;
+        protocol::put_string(&mut out, b"user");
+        for _ in 0..2 {
+            // Service and method
+            protocol::put_string(&mut out, reader.read_string()?);
+        }
+        out.push(reader.read_u8()?);
+        protocol::put_string(&mut out, reader.read_string()?);
+        let key = self.key(reader.read_string()?);
+        protocol::put_string(&mut out, &key);
+        if auth.host_key.is_some() {
+            let host_key = self.key(reader.read_string()?);
+            protocol::put_string(&mut out, &host_key);
+        }
+        out.extend_from_slice(&data[reader.position()..]);
+        Ok(out)
+    }
+
+    /// A length-prefixed answer with its keys and signatures replaced
+    fn response(&mut self, response: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = Reader::new(response);
+        reader.read_u32()?;
+        let msg_type = reader.read_u8()?;
+        let mut body = vec![msg_type];
+        match msg_type {
+            protocol::SSH_AGENT_IDENTITIES_ANSWER => {
+                let count = reader.read_u32()?;
+                body.extend_from_slice(&count.to_be_bytes());
+                for _ in 0..count {
+                    let key = self.key(reader.read_string()?);
+                    let comment = self.comment(&String::from_utf8_lossy(reader.read_string()?));
+                    protocol::put_string(&mut body, &key);
+                    protocol::put_string(&mut body, comment.as_bytes());
+                }
+            }
+            protocol::SSH_AGENT_SIGN_RESPONSE => {
+                let signature = protocol::blank_signature(reader.read_string()?);
+                protocol::put_string(&mut body, &signature);
+            }
+            _ => return Ok(response.to_vec()),
+        }
+        Ok(protocol::frame(&body))
+    }
+}
+
+
+/// An upstream agent on a temporary socket listing fixed keys and giving
+/// every other request the same answer
+struct FakeUpstream {
+    path: PathBuf,
+    forwarded: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+}
+
+impl FakeUpstream {
+    fn start(keys: Vec<SshKey>, answer: Vec<u8>) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "ssh-agent-router-corpus-{}-{}.sock",
+            std::process::id(),
+            REPLAYS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).with_context(|| format!("Failed to bind {:?}", path))?;
+        let forwarded = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let mut identities = vec![protocol::SSH_AGENT_IDENTITIES_ANSWER];
+        identities.extend_from_slice(&(keys.len() as u32).to_be_bytes());
+        for key in &keys {
+            protocol::put_string(&mut identities, &key.blob);
+            protocol::put_string(&mut identities, key.comment.as_bytes());
+        }
+        let identities = protocol::frame(&identities);
+
+        let (seen, stopped) = (forwarded.clone(), stop.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let (identities, answer, seen) = (identities.clone(), answer.clone(), seen.clone());
+                std::thread::spawn(move || serve(stream, &identities, &answer, &seen));
+            }
+        });
+        Ok(Self { path, forwarded, stop })
+    }
+}
+
+impl Drop for FakeUpstream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the listener so it sees the stop flag
+        let _ = UnixStream::connect(&self.path);
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn serve(mut stream: UnixStream, identities: &[u8], answer: &[u8], forwarded: &AtomicBool) {
+    loop {
+        let mut len = [0u8; 4];
+        if stream.read_exact(&mut len).is_err() {
+            return;
+        }
+        let mut body = vec![0u8; u32::from_be_bytes(len) as usize];
+        if stream.read_exact(&mut body).is_err() {
+            return;
+        }
+        let response = match body.first() {
+            Some(&protocol::SSH_AGENTC_REQUEST_IDENTITIES) => identities,
+            _ => {
+                forwarded.store(true, Ordering::SeqCst);
+                answer
+            }
+        };
+        if stream.write_all(response).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use crate::config::Config;
+use crate::exit::Failure;
+use crate::version::BuildInfo;
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Redacted config the router runs with, kept ready for a crash since
+/// serializing it while panicking could panic again
+static CONFIG: Mutex<Option<String>> = Mutex::new(None);
+static SOCKETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Files of a diagnostics bundle, in the order a bug report shows them
+const BUNDLE_FILES: &[&str] = &["panic.txt", "sockets.txt", "config.toml", "log.txt"];
+
+/// Directory crash bundles are written to
+pub fn crash_dir() -> Result<PathBuf> {
+    Ok(crate::state::state_dir()?.join("crashes"))
+}
+
+/// `config` without secrets and with email addresses replaced, as
+/// `config scrub --placeholders` leaves the public file
+pub fn redacted_config(config: &Config) -> Result<String> {
+    let table = toml::Table::try_from(config.effective()).context("Failed to serialize config")?;
+    let mut public = crate::scrub::scrub(&table, true, "").public;
+    public.remove("include");
+    toml::to_string_pretty(&public).context("Failed to serialize config")
+}
+
+/// Remember the configuration for the next crash bundle
+pub fn record_config(config: &Config) {
+    let redacted = redacted_config(config).unwrap_or_else(|e| format!("# {:#}\n", e));
+    *CONFIG.lock().unwrap_or_else(PoisonError::into_inner) = Some(redacted);
+}
+
+/// Remember the sockets being served for the next crash bundle
+pub fn record_sockets(sockets: Vec<String>) {
+    *SOCKETS.lock().unwrap_or_else(PoisonError::into_inner) = sockets;
+}
+
+/// Whether launchd or systemd runs the router, and restarts it if it exits
+/// with a failure
+fn under_service_manager() -> bool {
+    // launchd names its jobs' service; shells in Terminal get "0"
+    std::env::var_os("INVOCATION_ID").is_some()
+        || std::env::var("XPC_SERVICE_NAME").is_ok_and(|name| name != "0")
+}
+
+/// Write a diagnostics bundle when the router panics and, under a service
+/// manager, exit so it is restarted
+///
+/// A panic on a connection's thread would otherwise leave the router
+/// running in whatever state the panic left behind.
+pub fn install_panic_hook() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default(info);
+        let thread = std::thread::current();
+        let panic = format!(
+            "thread '{}' {}\n\n{}",
+            thread.name().unwrap_or("<unnamed>"),
+            info,
+            std::backtrace::Backtrace::force_capture()
+        );
+        match crash_dir().and_then(|dir| write_bundle(&dir, &panic)) {
+            Ok(bundle) => eprintln!(
+                "Diagnostics written to {:?}; `ssh-agent-router bugreport` packages them for an issue",
+                bundle
+            ),
+            Err(e) => eprintln!("Failed to write diagnostics: {:#}", e),
+        }
+        if under_service_manager() {
+            std::process::exit(Failure::Crashed.code().into());
+        }
+    }));
+}
+
+/// Write a bundle for `panic` with the recent log, the recorded config
+/// and sockets into a new directory under `dir`, returning its path
+pub fn write_bundle(dir: &Path, panic: &str) -> Result<PathBuf> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let bundle = dir.join(format!("crash-{}-{}", now.as_secs(), std::process::id()));
+    crate::state::create_private_dir(&bundle)?;
+
+    let config = CONFIG.lock().unwrap_or_else(PoisonError::into_inner).clone();
+    let sockets = SOCKETS.lock().unwrap_or_else(PoisonError::into_inner).join("\n");
+    let mut log = crate::logging::recent().join("\n");
+    log.push('\n');
+    let files = [
+        ("panic.txt", format!("{}\n{}\n", BuildInfo::current().summary(), panic)),
+        ("sockets.txt", sockets + "\n"),
+        ("config.toml", config.unwrap_or_else(|| "# No configuration loaded yet\n".to_string())),
+        ("log.txt", log),
+    ];
+    for (name, content) in files {
+        let path = bundle.join(name);
+        std::fs::write(&path, content).with_context(|| format!("Failed to write {:?}", path))?;
+    }
+    Ok(bundle)
+}
+
+/// The most recent bundle in `dir`, if any
+pub fn latest_bundle(dir: &Path) -> Result<Option<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", dir)),
+    };
+    let mut bundles = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with("crash-") {
+            bundles.push((entry.metadata()?.modified()?, entry.path()));
+        }
+    }
+    Ok(bundles.into_iter().max().map(|(_, path)| path))
+}
+
+/// A Markdown report to paste into an issue: the build, the system, the
+/// redacted config and the files of `bundle`
+pub fn bug_report(config: Option<&Config>, bundle: Option<&Path>) -> Result<String> {
+    let mut report = String::from("## ssh-agent-router bug report\n\n");
+    let info = BuildInfo::current();
+    writeln!(report, "- Version: {}", info.summary())?;
+    writeln!(report, "- Features: {}", if info.features.is_empty() { "none".to_string() } else { info.features.join(", ") })?;
+    writeln!(report, "- System: {} {}", std::env::consts::OS, std::env::consts::ARCH)?;
+
+    if let Some(config) = config {
+        writeln!(report, "\n### Configuration\n\n```toml\n{}```", redacted_config(config)?)?;
+    }
+
+    match bundle {
+        None => report.push_str("\nNo crash diagnostics found.\n"),
+        Some(bundle) => {
+            let name = bundle.file_name().unwrap_or_default().to_string_lossy();
+            writeln!(report, "\n### Crash {}", name)?;
+            for file in BUNDLE_FILES {
+                let path = bundle.join(file);
+                let content = match std::fs::read_to_string(&path) {
+                    Ok(content) => content,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", path)),
+                };
+                let lang = if file.ends_with(".toml") { "toml" } else { "text" };
+                let content = content.trim_end();
+                writeln!(report, "\n<details><summary>{}</summary>\n\n```{}\n{}\n```\n\n</details>", file, lang, content)?;
+            }
+        }
+    }
+    Ok(report)
+}
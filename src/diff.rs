@@ -0,0 +1,89 @@
+/// Lines of context kept around each change
+const CONTEXT: usize = 3;
+
+/// How a line of the old text relates to the new one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Line-by-line difference between `old` and `new`, along their longest
+/// common subsequence
+///
+/// Config files are short, so the quadratic table is fine.
+pub fn lines<'a>(old: &'a str, new: &'a str) -> Vec<Line<'a>> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // common[i][j]: length of the common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut diff = Vec::new();
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            diff.push(Line::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            diff.push(Line::Removed(old[i]));
+            i += 1;
+        } else {
+            diff.push(Line::Added(new[j]));
+            j += 1;
+        }
+    }
+    diff.extend(old[i..].iter().map(|line| Line::Removed(line)));
+    diff.extend(new[j..].iter().map(|line| Line::Added(line)));
+    diff
+}
+
+/// The changes from `old` to `new` as `-`/`+` lines with a little
+/// context, or `None` if they are the same
+///
+/// Unchanged stretches too long to show are replaced with `...`.
+pub fn unified(old: &str, new: &str) -> Option<String> {
+    let diff = lines(old, new);
+    let changed: Vec<usize> = diff.iter()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Line::Same(_)))
+        .map(|(i, _)| i)
+        .collect();
+    if changed.is_empty() {
+        return None;
+    }
+
+    let near_change = |i: usize| changed.iter().any(|&c| c.abs_diff(i) <= CONTEXT);
+    let mut out = String::new();
+    let mut skipped = false;
+    for (i, line) in diff.iter().enumerate() {
+        let (sign, text) = match line {
+            Line::Same(_) if !near_change(i) => {
+                skipped = true;
+                continue;
+            }
+            Line::Same(text) => (' ', text),
+            Line::Removed(text) => ('-', text),
+            Line::Added(text) => ('+', text),
+        };
+        if std::mem::take(&mut skipped) && !out.is_empty() {
+            out.push_str("...\n");
+        }
+        out.push(sign);
+        out.push(' ');
+        out.push_str(text);
+        out.push('\n');
+    }
+    Some(out)
+}
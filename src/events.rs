@@ -0,0 +1,83 @@
+use serde::Serialize;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Events a subscriber may fall behind by before it misses some
+const BACKLOG: usize = 1024;
+
+/// Events kept for `recent`, whether or not anyone subscribed
+pub const RECENT_EVENTS: usize = 1000;
+
+/// Something that happened in the router, streamed to `subscribe` clients
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// A client connected to a socket
+    Connected { socket: String, id: String, client: String },
+    /// A client closed its connection after `requests` requests
+    Disconnected { socket: String, id: String, requests: u64 },
+    /// A request was allowed or denied, as recorded in the audit trail
+    Decision { socket: String, id: String, decision: serde_json::Value },
+    /// The upstream agent started or stopped answering
+    Upstream {
+        healthy: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct EventRecord<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// One JSON line per event for each subscriber
+static SUBSCRIBERS: Mutex<Vec<SyncSender<String>>> = Mutex::new(Vec::new());
+
+/// The last `RECENT_EVENTS` JSON lines, oldest first
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Receive every event published from now on, one JSON line each
+///
+/// A subscriber that falls more than a backlog behind misses events
+/// rather than slowing the sockets down. Dropping the receiver
+/// unsubscribes.
+pub fn subscribe() -> Receiver<String> {
+    let (sender, receiver) = mpsc::sync_channel(BACKLOG);
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    receiver
+}
+
+/// The last `count` events published, oldest first, one JSON line each
+pub fn recent(count: usize) -> Vec<String> {
+    let recent = RECENT.lock().unwrap();
+    recent.iter().skip(recent.len().saturating_sub(count)).cloned().collect()
+}
+
+/// Send `event` to every subscriber and keep it for `recent`
+pub fn publish(event: Event) {
+    let record = EventRecord {
+        time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        event: &event,
+    };
+    let line = match serde_json::to_string(&record) {
+        Ok(line) => line,
+        Err(e) => {
+            log::warn!("Failed to encode event: {}", e);
+            return;
+        }
+    };
+    {
+        let mut recent = RECENT.lock().unwrap();
+        if recent.len() == RECENT_EVENTS {
+            recent.pop_front();
+        }
+        recent.push_back(line.clone());
+    }
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    subscribers.retain(|subscriber| !matches!(subscriber.try_send(line.clone()), Err(TrySendError::Disconnected(_))));
+}
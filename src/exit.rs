@@ -0,0 +1,57 @@
+use std::fmt;
+
+/// Why a command failed, as the exit status scripts and health checks can
+/// branch on
+///
+/// Attached to an error as context; the command exits with the code of
+/// the outermost one, or 1 for errors without one. 2 is left to clap for
+/// usage errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The config file is missing, unreadable or invalid
+    Config,
+    /// The upstream agent did not answer
+    Upstream,
+    /// The sockets' policies refuse the key or request
+    Denied,
+    /// Auto-start is not registered with the service manager
+    NotRegistered,
+    /// Some configured sockets could not be served
+    Sockets,
+    /// The router panicked while run by a service manager, which restarts
+    /// it on this status
+    Crashed,
+}
+
+impl Failure {
+    pub fn code(self) -> u8 {
+        match self {
+            Failure::Config => 3,
+            Failure::Upstream => 4,
+            Failure::Denied => 5,
+            Failure::NotRegistered => 6,
+            Failure::Sockets => 7,
+            Failure::Crashed => 8,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Failure::Config => "configuration error",
+            Failure::Upstream => "upstream agent unreachable",
+            Failure::Denied => "denied by policy",
+            Failure::NotRegistered => "auto-start is not registered",
+            Failure::Sockets => "not every socket could be served",
+            Failure::Crashed => "the router crashed",
+        })
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Exit status for a command that failed with `error`
+pub fn code(error: &anyhow::Error) -> u8 {
+    error.downcast_ref::<Failure>().map_or(1, |failure| failure.code())
+}
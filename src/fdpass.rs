@@ -0,0 +1,113 @@
+use anyhow::Result;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+/// Most descriptors one message carries; Linux refuses more than 253
+pub const MAX_FDS: usize = 250;
+
+/// Send `data` over `stream` with `fds` attached, at most [`MAX_FDS`]
+pub fn send(stream: &UnixStream, data: &[u8], fds: &[RawFd]) -> Result<()> {
+    anyhow::ensure!(!data.is_empty(), "Descriptors need at least one byte to travel with");
+    anyhow::ensure!(fds.len() <= MAX_FDS, "Too many descriptors for one message: {}", fds.len());
+
+    let fds_len = std::mem::size_of_val(fds);
+    // SAFETY: CMSG_SPACE only computes a size
+    let space = unsafe { libc::CMSG_SPACE(fds_len as u32) } as usize;
+    let mut control = vec![0u8; if fds.is_empty() { 0 } else { space }];
+    let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut _, iov_len: data.len() };
+    // SAFETY: msghdr is plain data; every pointer set below stays valid
+    // for the sendmsg call
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = control.len() as _;
+        // SAFETY: `control` has room for one header with `fds_len` bytes
+        // of data, as CMSG_SPACE computed
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len as u32) as _;
+            std::ptr::copy_nonoverlapping(fds.as_ptr().cast::<u8>(), libc::CMSG_DATA(cmsg), fds_len);
+        }
+    }
+
+    let mut sent = 0;
+    while sent < data.len() {
+        // SAFETY: `msg` points at `iov` and `control`, both alive here
+        let n = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) };
+        if n < 0 {
+            let error = std::io::Error::last_os_error();
+            if error.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error.into());
+        }
+        // The descriptors went with the first bytes; the rest of the data
+        // is plain
+        sent += n as usize;
+        iov = libc::iovec { iov_base: data[sent..].as_ptr() as *mut _, iov_len: data.len() - sent };
+        msg.msg_iov = &mut iov;
+        msg.msg_control = std::ptr::null_mut();
+        msg.msg_controllen = 0;
+    }
+    Ok(())
+}
+
+/// Receive into `buf`, returning how many bytes were read and the
+/// descriptors that came with them
+pub fn recv(stream: &UnixStream, buf: &mut [u8]) -> Result<(usize, Vec<OwnedFd>)> {
+    // SAFETY: CMSG_SPACE only computes a size
+    let space = unsafe { libc::CMSG_SPACE((MAX_FDS * std::mem::size_of::<RawFd>()) as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() };
+    // SAFETY: msghdr is plain data; every pointer set below stays valid
+    // for the recvmsg call
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = control.len() as _;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let flags = 0;
+    let n = loop {
+        // SAFETY: `msg` points at `buf` and `control`, both alive here
+        let n = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, flags) };
+        if n >= 0 {
+            break n as usize;
+        }
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            return Err(error.into());
+        }
+    };
+
+    let mut fds = Vec::new();
+    // SAFETY: the kernel filled in `control` up to msg_controllen, and the
+    // CMSG macros stay within it
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg);
+                let count = ((*cmsg).cmsg_len as usize - (data as usize - cmsg as usize)) / std::mem::size_of::<RawFd>();
+                for i in 0..count {
+                    let fd = std::ptr::read_unaligned(data.cast::<RawFd>().add(i));
+                    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                    fds.push(OwnedFd::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        anyhow::bail!("Received more descriptors than fit in one message");
+    }
+    Ok((n, fds))
+}
@@ -0,0 +1,71 @@
+use anyhow::Result;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// `upstream` value that follows the newest agent forwarded to this host
+pub const FORWARDED: &str = "forwarded";
+
+/// Where sshd creates the sockets of forwarded agents
+const FORWARD_DIR: &str = "/tmp";
+
+/// Sockets of agents forwarded by ssh sessions of this user, newest first
+///
+/// sshd puts each one at `/tmp/ssh-XXXXXXXXXX/agent.<pid>`. Sockets of
+/// sessions that have ended are included; [`latest`] skips them.
+pub fn sockets() -> Vec<PathBuf> {
+    // SAFETY: geteuid cannot fail and has no preconditions
+    sockets_in(Path::new(FORWARD_DIR), unsafe { libc::geteuid() })
+}
+
+/// Forwarded agent sockets owned by `uid` in `dir`, newest first
+pub fn sockets_in(dir: &Path, uid: u32) -> Vec<PathBuf> {
+    let mut found: Vec<(SystemTime, PathBuf)> = Vec::new();
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    for session in entries.filter_map(|e| e.ok()) {
+        if !session.file_name().to_string_lossy().starts_with("ssh-") {
+            continue;
+        }
+        // Only directories the user owns, so another user cannot plant
+        // a socket for the router to pick up
+        let owned = |path: &Path| fs::symlink_metadata(path).is_ok_and(|m| m.uid() == uid);
+        if !owned(&session.path()) || !session.file_type().is_ok_and(|t| t.is_dir()) {
+            continue;
+        }
+        let Ok(sockets) = fs::read_dir(session.path()) else {
+            continue;
+        };
+        for socket in sockets.filter_map(|e| e.ok()) {
+            if !socket.file_name().to_string_lossy().starts_with("agent.") {
+                continue;
+            }
+            let Ok(metadata) = fs::symlink_metadata(socket.path()) else {
+                continue;
+            };
+            if metadata.file_type().is_socket() && metadata.uid() == uid {
+                found.push((metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH), socket.path()));
+            }
+        }
+    }
+    found.sort_by(|a, b| b.cmp(a));
+    found.into_iter().map(|(_, path)| path).collect()
+}
+
+/// The newest forwarded agent socket that still accepts connections
+pub fn latest() -> Result<PathBuf> {
+    latest_of(sockets())
+}
+
+/// The first of `sockets` that accepts connections
+pub fn latest_of(sockets: Vec<PathBuf>) -> Result<PathBuf> {
+    if sockets.is_empty() {
+        anyhow::bail!("No forwarded agent found in {}/ssh-*/, log in with ssh -A", FORWARD_DIR);
+    }
+    sockets.into_iter()
+        .find(|path| UnixStream::connect(path).is_ok())
+        .ok_or_else(|| anyhow::anyhow!("Every forwarded agent in {}/ssh-*/ is gone, log in again with ssh -A", FORWARD_DIR))
+}
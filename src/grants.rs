@@ -0,0 +1,168 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use crate::state;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Socket name to grant to the number of signatures made with it
+pub type Uses = BTreeMap<String, BTreeMap<String, u64>>;
+
+/// How often each count-limited grant (`allowed = [{ key, max_uses }]`)
+/// has been used, kept across restarts
+///
+/// `grants reset` rewrites the file, which the router picks up the next
+/// time it checks a grant.
+pub struct GrantUses {
+    path: Option<PathBuf>,
+    state: Mutex<UsesState>,
+}
+
+#[derive(Default)]
+struct UsesState {
+    uses: Uses,
+    /// Modification time of the file when it was last read
+    loaded: Option<SystemTime>,
+    /// Bumped whenever the counters may have changed
+    generation: u64,
+}
+
+impl GrantUses {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let grants = Self {
+            path: Some(path),
+            state: Mutex::new(UsesState::default()),
+        };
+        grants.refresh(&mut grants.state.lock().unwrap())?;
+        Ok(grants)
+    }
+
+    /// Counters that are forgotten when the process exits
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            state: Mutex::new(UsesState::default()),
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Signatures made with `grant` on `socket`
+    pub fn used(&self, socket: &str, grant: &str) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = self.refresh(&mut state) {
+            log::warn!("Failed to read grant uses: {:#}", e);
+        }
+        state.uses.get(socket).and_then(|grants| grants.get(grant)).copied().unwrap_or(0)
+    }
+
+    /// Take one use of `grant` on `socket` if fewer than `max_uses` were
+    /// taken, returning the new total
+    pub fn try_use(&self, socket: &str, grant: &str, max_uses: u64) -> Result<Option<u64>> {
+        let mut state = self.state.lock().unwrap();
+        self.refresh(&mut state)?;
+        let used = state.uses.entry(socket.to_string()).or_default().entry(grant.to_string()).or_default();
+        if *used >= max_uses {
+            return Ok(None);
+        }
+        *used += 1;
+        let used = *used;
+        state.generation += 1;
+        self.save(&mut state)?;
+        Ok(Some(used))
+    }
+
+    /// Give back a use taken for a signature upstream did not make
+    pub fn release(&self, socket: &str, grant: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.refresh(&mut state)?;
+        if let Some(used) = state.uses.get_mut(socket).and_then(|grants| grants.get_mut(grant)) {
+            *used = used.saturating_sub(1);
+            state.generation += 1;
+            self.save(&mut state)?;
+        }
+        Ok(())
+    }
+
+    /// Forget the uses of `grant`, of every grant on `socket`, or of
+    /// everything, returning how many counters were cleared
+    pub fn reset(&self, socket: Option<&str>, grant: Option<&str>) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        self.refresh(&mut state)?;
+
+        let mut cleared = 0;
+        for (name, grants) in state.uses.iter_mut() {
+            if socket.is_some_and(|socket| socket != name) {
+                continue;
+            }
+            let before = grants.len();
+            grants.retain(|key, _| grant.is_some_and(|grant| grant != key));
+            cleared += before - grants.len();
+        }
+        state.uses.retain(|_, grants| !grants.is_empty());
+        state.generation += 1;
+        self.save(&mut state)?;
+        Ok(cleared)
+    }
+
+    /// Every counter, by socket and grant
+    pub fn uses(&self) -> Uses {
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = self.refresh(&mut state) {
+            log::warn!("Failed to read grant uses: {:#}", e);
+        }
+        state.uses.clone()
+    }
+
+    /// Counter that changes whenever the uses do, including when
+    /// `grants reset` rewrote the file
+    pub fn generation(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = self.refresh(&mut state) {
+            log::warn!("Failed to read grant uses: {:#}", e);
+        }
+        state.generation
+    }
+
+    /// Re-read the file if something else changed it
+    fn refresh(&self, state: &mut UsesState) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified == state.loaded {
+            return Ok(());
+        }
+
+        state.uses = match modified {
+            Some(_) => {
+                let content = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read grant uses {:?}", path))?;
+                serde_json::from_str(&content)
+                    .with_context(|| format!("Failed to parse grant uses {:?}", path))?
+            }
+            None => Uses::new(),
+        };
+        state.loaded = modified;
+        state.generation += 1;
+        Ok(())
+    }
+
+    fn save(&self, state: &mut UsesState) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        state::create_parent_dir(path)?;
+
+        // Written to a temporary file first so readers never see half a file
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&state.uses)?)
+            .with_context(|| format!("Failed to write grant uses {:?}", tmp))?;
+        fs::rename(&tmp, path).with_context(|| format!("Failed to write grant uses {:?}", path))?;
+        state.loaded = fs::metadata(path).and_then(|m| m.modified()).ok();
+        Ok(())
+    }
+}
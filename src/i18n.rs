@@ -0,0 +1,178 @@
+use std::fmt::Display;
+use std::sync::RwLock;
+
+/// A language the messages are translated into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    En,
+    Ja,
+}
+
+impl Language {
+    /// The language of a `[ui] language` value or a locale such as
+    /// `ja_JP.UTF-8`; `None` for languages without a translation
+    pub fn parse(name: &str) -> Option<Self> {
+        let code = name.split(['_', '-', '.', '@']).next().unwrap_or_default();
+        match code.to_ascii_lowercase().as_str() {
+            "en" | "c" | "posix" => Some(Language::En),
+            "ja" => Some(Language::Ja),
+            _ => None,
+        }
+    }
+
+    /// The language of the locale, looked up like gettext does: `LC_ALL`,
+    /// then `LC_MESSAGES`, then `LANG`. English if none has a translation
+    pub fn from_env() -> Self {
+        ["LC_ALL", "LC_MESSAGES", "LANG"].iter()
+            .filter_map(|name| std::env::var(name).ok())
+            .find(|value| !value.is_empty())
+            .and_then(|value| Self::parse(&value))
+            .unwrap_or(Language::En)
+    }
+}
+
+/// Language set from the config, which takes precedence over the locale
+static LANGUAGE: RwLock<Option<Language>> = RwLock::new(None);
+
+/// Use the `[ui] language` from the config, or the locale when it is
+/// unset or `auto`
+pub fn set(language: Option<&str>) {
+    *LANGUAGE.write().unwrap() = language.and_then(Language::parse);
+}
+
+/// The language messages are shown in
+pub fn language() -> Language {
+    LANGUAGE.read().unwrap().unwrap_or_else(Language::from_env)
+}
+
+/// Every message by id, in English and Japanese
+///
+/// `{name}` placeholders are filled in by [`format`]; a translation must
+/// use the same placeholders as the English text, in any order.
+pub const CATALOG: &[(&str, &str, &str)] = &[
+    // Approval prompts and notifications
+    ("approval-summary",
+     "Sign request on socket {socket} from {client} with {key} ({type})",
+     "ソケット {socket} で {client} から {key} ({type}) による署名要求"),
+    ("approval-summary-comment",
+     "Sign request on socket {socket} from {client} with {key} ({type}, {comment})",
+     "ソケット {socket} で {client} から {key} ({type}, {comment}) による署名要求"),
+    ("approval-summary-label",
+     "Sign request on socket {socket} from {client} with {label}: {key} ({type})",
+     "ソケット {socket} で {client} から {label}: {key} ({type}) による署名要求"),
+    ("approval-terminal",
+     "sign request on socket {socket} from {client}",
+     "ソケット {socket} で {client} から署名要求"),
+    ("approval-key", "key", "鍵"),
+    ("approval-question", "Allow?", "許可しますか?"),
+    ("approval-timed-out", "timed out, denied", "時間切れのため拒否しました"),
+    ("approval-allow", "Allow", "許可"),
+    ("approval-deny", "Deny", "拒否"),
+    ("approval-approve", "Approve", "承認"),
+    ("approval-touch-id", "approve an SSH {summary}", "SSH の{summary}を承認"),
+    ("approval-title", "ssh-agent-router approval", "ssh-agent-router 承認要求"),
+    ("approval-request-id", "Request {id}", "要求 {id}"),
+    ("notify-title", "ssh-agent-router: key used", "ssh-agent-router: 鍵が使われました"),
+    ("approval-acknowledge", "Acknowledge to approve.", "承認するには確認してください。"),
+    // Confirmation before changing files
+    ("change-dry-run", "Dry run, nothing was changed", "ドライランのため何も変更していません"),
+    ("change-declined", "Nothing was changed", "何も変更していません"),
+    ("change-needs-yes",
+     "{question} Pass --yes to confirm when stdin is not a terminal",
+     "{question} 標準入力が端末でない場合は --yes で確認してください"),
+    ("change-apply-git", "Apply these settings to {repo}?", "これらの設定を {repo} に適用しますか?"),
+    ("change-write", "Write these changes?", "これらの変更を書き込みますか?"),
+    ("change-restore", "Restore {path} from {backup}?", "{path} を {backup} から復元しますか?"),
+    ("change-migrate", "Rewrite {path} in version {version}?", "{path} をバージョン {version} の形式で書き直しますか?"),
+    // list-socks
+    ("sockets-configured", "Configured sockets:", "設定済みのソケット:"),
+    ("sockets-ephemeral", "Ephemeral sockets:", "一時ソケット:"),
+    ("sockets-served", "served", "提供中"),
+    ("sockets-not-served", "not served", "未提供"),
+    ("statistics-unavailable", "Statistics unavailable: {error}", "統計情報を取得できません: {error}"),
+    // list-keys
+    ("keys-available", "Available keys from upstream:", "上流エージェントの鍵:"),
+    ("keys-none", "(none)", "(なし)"),
+    ("keys-too-old", "{socket} (hidden as too old)", "{socket} (古いため非表示)"),
+    ("keys-ago", "{age} ago", "{age}前"),
+    ("key-risk-low", "low risk", "低リスク"),
+    ("key-risk-medium", "medium risk", "中リスク"),
+    ("key-risk-high", "high risk", "高リスク"),
+    ("key-rotate-by", "rotate by {date}", "{date} までに更新"),
+    ("key-rotation-overdue", "overdue", "期限切れ"),
+    ("key-owner", "owner", "管理者"),
+    ("key-notes", "notes", "メモ"),
+    // status
+    ("status-title", "SSH Agent Router Status", "SSH Agent Router の状態"),
+    ("status-upstream", "Upstream: {upstream}", "上流エージェント: {upstream}"),
+    ("status-sockets", "Configured sockets: {count}", "設定済みのソケット: {count}"),
+    ("status-router", "Router: {state}", "ルーター: {state}"),
+    ("status-running", "running", "実行中"),
+    ("status-not-running", "not running", "停止中"),
+    ("status-upstream-state", "Upstream status: {state}", "上流エージェントの状態: {state}"),
+    ("status-connected", "Connected", "接続済み"),
+    ("status-error", "Error", "エラー"),
+    ("status-keys", "Available keys: {count}", "利用可能な鍵: {count}"),
+    ("status-last-seen", "Last seen by the running router {age} ago:", "実行中のルーターが {age}前に確認した鍵:"),
+    ("status-unreachable", "(none) - not reachable from any socket", "(なし) - どのソケットからも使えません"),
+    ("status-last-used", "{age} ago via {socket}", "{age}前 ({socket} 経由)"),
+    ("status-never-used", "never", "未使用"),
+    // Table headers
+    ("column-name", "NAME", "名前"),
+    ("column-path", "PATH", "パス"),
+    ("column-description", "DESCRIPTION", "説明"),
+    ("column-allowed", "ALLOWED", "許可"),
+    ("column-denied", "DENIED", "拒否"),
+    ("column-expires-in", "EXPIRES IN", "残り時間"),
+    ("column-state", "STATE", "状態"),
+    ("column-active", "ACTIVE", "接続中"),
+    ("column-accepted", "ACCEPTED", "受付"),
+    ("column-rejected", "REJECTED", "拒絶"),
+    ("column-requests", "REQUESTS", "要求"),
+    ("column-denied-signs", "DENIED SIGNS", "拒否した署名"),
+    ("column-errors", "ERRORS", "エラー"),
+    ("column-upstream-errors", "UPSTREAM ERRORS", "上流エラー"),
+    ("column-bytes-in", "BYTES IN", "受信バイト"),
+    ("column-bytes-out", "BYTES OUT", "送信バイト"),
+    ("column-key", "KEY", "鍵"),
+    ("column-type", "TYPE", "種類"),
+    ("column-comment", "COMMENT", "コメント"),
+    ("column-label", "LABEL", "ラベル"),
+    ("column-first-seen", "FIRST SEEN", "初回確認"),
+    ("column-sockets", "SOCKETS", "ソケット"),
+    ("column-last-used", "LAST USED", "最終使用"),
+    ("column-check", "CHECK", "検査"),
+    ("column-result", "RESULT", "結果"),
+    ("column-detail", "DETAIL", "詳細"),
+];
+
+/// The text of message `id` in the current language
+///
+/// Unknown ids are returned as they are, so a missing entry shows up in
+/// the output rather than taking the command down.
+pub fn text(id: &'static str) -> &'static str {
+    let language = language();
+    match CATALOG.iter().find(|(key, _, _)| *key == id) {
+        Some((_, en, ja)) => match language {
+            Language::En => en,
+            Language::Ja => ja,
+        },
+        None => id,
+    }
+}
+
+/// Message `id` with its `{name}` placeholders replaced by `args`
+pub fn format(id: &'static str, args: &[(&str, &dyn Display)]) -> String {
+    args.iter().fold(text(id).to_string(), |message, (name, value)| {
+        message.replace(&format!("{{{}}}", name), &value.to_string())
+    })
+}
+
+/// The placeholders in a message, e.g. `["socket", "client"]`
+pub fn placeholders(message: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = message.split('{').skip(1)
+        .filter_map(|rest| rest.split_once('}').map(|(name, _)| name))
+        .collect();
+    names.sort_unstable();
+    names
+}
@@ -0,0 +1,222 @@
+use crate::agent::SshKey;
+use crate::protocol::{self, Reader};
+use anyhow::Result;
+use std::fmt;
+
+/// Constraint numbers from draft-miller-ssh-agent
+const SSH_AGENT_CONSTRAIN_LIFETIME: u8 = 1;
+const SSH_AGENT_CONSTRAIN_CONFIRM: u8 = 2;
+const SSH_AGENT_CONSTRAIN_EXTENSION: u8 = 255;
+
+pub const RESTRICT_DESTINATION_EXTENSION: &str = "restrict-destination-v00@openssh.com";
+
+/// A key a client asked the upstream agent to add
+#[derive(Debug, Clone)]
+pub struct AddedIdentity {
+    /// Public half of the key
+    pub key: SshKey,
+    pub constraints: Vec<Constraint>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Constraint {
+    /// Remove the key after this many seconds
+    Lifetime(u32),
+    /// Confirm every use of the key
+    Confirm,
+    /// Only usable on these hops (`ssh-add -h`)
+    Destinations(Vec<DestinationConstraint>),
+    /// FIDO authenticator middleware to use
+    SkProvider(String),
+    /// An extension the router does not decode; parsing stops here
+    Extension(String),
+}
+
+/// One permitted hop of a destination-constrained key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DestinationConstraint {
+    pub from: Hop,
+    pub to: Hop,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hop {
+    pub user: String,
+    pub host: String,
+    /// Fingerprints of the host keys, and whether each is a CA
+    pub host_keys: Vec<(String, bool)>,
+}
+
+impl AddedIdentity {
+    /// Destination constraints, empty if the key may be used anywhere
+    pub fn destinations(&self) -> Vec<&DestinationConstraint> {
+        self.constraints
+            .iter()
+            .flat_map(|c| match c {
+                Constraint::Destinations(d) => d.iter().collect(),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Hop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.user.is_empty(), self.host.is_empty()) {
+            (_, true) => write!(f, "(origin)"),
+            (true, false) => write!(f, "{}", self.host),
+            (false, false) => write!(f, "{}@{}", self.user, self.host),
+        }
+    }
+}
+
+impl fmt::Display for DestinationConstraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} > {}", self.from, self.to)
+    }
+}
+
+/// Parse a length-prefixed SSH_AGENTC_ADD_IDENTITY or ADD_ID_CONSTRAINED
+///
+/// Fails for key types whose private key layout is unknown, since the
+/// constraints can only be found after skipping it.
+pub fn parse_add_identity(message: &[u8]) -> Result<AddedIdentity> {
+    let mut reader = Reader::new(message);
+    reader.read_u32()?;
+    let msg_type = reader.read_u8()?;
+    if msg_type != protocol::SSH_AGENTC_ADD_IDENTITY && msg_type != protocol::SSH_AGENTC_ADD_ID_CONSTRAINED {
+        anyhow::bail!("Not an add identity request");
+    }
+
+    let key_type = String::from_utf8_lossy(reader.read_string()?).to_string();
+    let blob = read_key(&mut reader, &key_type)?;
+    let comment = String::from_utf8_lossy(reader.read_string()?).to_string();
+    let key = SshKey::from_blob(key_type, blob, comment);
+
+    let mut constraints = Vec::new();
+    while reader.position() < message.len() {
+        match reader.read_u8()? {
+            SSH_AGENT_CONSTRAIN_LIFETIME => constraints.push(Constraint::Lifetime(reader.read_u32()?)),
+            SSH_AGENT_CONSTRAIN_CONFIRM => constraints.push(Constraint::Confirm),
+            SSH_AGENT_CONSTRAIN_EXTENSION => {
+                let name = String::from_utf8_lossy(reader.read_string()?).to_string();
+                match name.as_str() {
+                    RESTRICT_DESTINATION_EXTENSION => {
+                        constraints.push(Constraint::Destinations(read_destinations(reader.read_string()?)?));
+                    }
+                    "sk-provider@openssh.com" => {
+                        let provider = String::from_utf8_lossy(reader.read_string()?).to_string();
+                        constraints.push(Constraint::SkProvider(provider));
+                    }
+                    _ => {
+                        // The data length is extension specific
+                        constraints.push(Constraint::Extension(name));
+                        break;
+                    }
+                }
+            }
+            other => anyhow::bail!("Unknown key constraint {}", other),
+        }
+    }
+
+    Ok(AddedIdentity { key, constraints })
+}
+
+/// Skip the private key fields and return the public key blob
+fn read_key(reader: &mut Reader, key_type: &str) -> Result<Vec<u8>> {
+    let mut blob = Vec::new();
+
+    // Certificates carry the public key in the certificate itself
+    if key_type.ends_with("-cert-v01@openssh.com") {
+        blob = reader.read_string()?.to_vec();
+        let private_fields = match key_type {
+            t if t.starts_with("ssh-ed25519") => 2,
+            t if t.starts_with("ssh-rsa") => 4,
+            t if t.starts_with("ecdsa-sha2-") => 1,
+            t if t.starts_with("ssh-dss") => 1,
+            _ => anyhow::bail!("Cannot parse private key of type {}", key_type),
+        };
+        for _ in 0..private_fields {
+            reader.read_string()?;
+        }
+        return Ok(blob);
+    }
+
+    protocol::put_string(&mut blob, key_type.as_bytes());
+    match key_type {
+        "ssh-ed25519" => {
+            protocol::put_string(&mut blob, reader.read_string()?);
+            reader.read_string()?;
+        }
+        "ssh-rsa" => {
+            // Private order is n, e, d, iqmp, p, q; the public blob is e, n
+            let n = reader.read_string()?;
+            let e = reader.read_string()?;
+            protocol::put_string(&mut blob, e);
+            protocol::put_string(&mut blob, n);
+            for _ in 0..4 {
+                reader.read_string()?;
+            }
+        }
+        "ssh-dss" => {
+            for _ in 0..4 {
+                protocol::put_string(&mut blob, reader.read_string()?);
+            }
+            reader.read_string()?;
+        }
+        t if t.starts_with("ecdsa-sha2-") => {
+            protocol::put_string(&mut blob, reader.read_string()?);
+            protocol::put_string(&mut blob, reader.read_string()?);
+            reader.read_string()?;
+        }
+        "sk-ssh-ed25519@openssh.com" => {
+            protocol::put_string(&mut blob, reader.read_string()?);
+            protocol::put_string(&mut blob, reader.read_string()?);
+            reader.read_u8()?;
+            reader.read_string()?;
+            reader.read_string()?;
+        }
+        "sk-ecdsa-sha2-nistp256@openssh.com" => {
+            for _ in 0..3 {
+                protocol::put_string(&mut blob, reader.read_string()?);
+            }
+            reader.read_u8()?;
+            reader.read_string()?;
+            reader.read_string()?;
+        }
+        _ => anyhow::bail!("Cannot parse private key of type {}", key_type),
+    }
+
+    Ok(blob)
+}
+
+/// Decode the body of a restrict-destination constraint
+fn read_destinations(data: &[u8]) -> Result<Vec<DestinationConstraint>> {
+    let mut reader = Reader::new(data);
+    let mut destinations = Vec::new();
+
+    while reader.position() < data.len() {
+        let constraint = reader.read_string()?;
+        let mut inner = Reader::new(constraint);
+        let from = read_hop(inner.read_string()?)?;
+        let to = read_hop(inner.read_string()?)?;
+        destinations.push(DestinationConstraint { from, to });
+    }
+
+    Ok(destinations)
+}
+
+fn read_hop(data: &[u8]) -> Result<Hop> {
+    let mut reader = Reader::new(data);
+    let user = String::from_utf8_lossy(reader.read_string()?).to_string();
+    let host = String::from_utf8_lossy(reader.read_string()?).to_string();
+    reader.read_string()?; // reserved
+
+    let mut host_keys = Vec::new();
+    while reader.position() < data.len() {
+        let key = SshKey::from_key_blob(reader.read_string()?.to_vec(), String::new());
+        host_keys.push((key.fingerprint, reader.read_u8()? != 0));
+    }
+
+    Ok(Hop { user, host, host_keys })
+}
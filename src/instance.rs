@@ -0,0 +1,69 @@
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Held by the running router so a second one started by accident fails
+/// instead of taking over its sockets
+///
+/// The lock is an flock on a file in the runtime directory, which the
+/// system releases when the process exits, however it exits. The file
+/// holds the PID of the router holding it.
+pub struct InstanceLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    /// Take the lock at `path`, or fail naming the router that has it
+    pub fn acquire(path: &Path) -> Result<Self> {
+        crate::state::create_parent_dir(path)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Failed to open {:?}", path))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                let holder = match pid.trim() {
+                    "" => "Another router".to_string(),
+                    pid => format!("Another router (PID {})", pid),
+                };
+                let control = crate::config::Config::control_socket_path()
+                    .map_or_else(|_| String::new(), |control| format!(" with control socket {:?}", control));
+                anyhow::bail!("{} is already running{}; stop it first, or use `ssh-agent-router status` to check on it", holder, control);
+            }
+            Err(TryLockError::Error(e)) => return Err(e).with_context(|| format!("Failed to lock {:?}", path)),
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id()).with_context(|| format!("Failed to write {:?}", path))?;
+        Ok(Self { _file: file, path: path.to_path_buf() })
+    }
+
+    /// Take the lock at `path`, waiting up to `timeout` for the router
+    /// holding it to exit
+    pub fn wait(path: &Path, timeout: Duration) -> Result<Self> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match Self::acquire(path) {
+                Err(_) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(50)),
+                result => return result,
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
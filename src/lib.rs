@@ -2,3 +2,44 @@ pub mod cli;
 pub mod config;
 pub mod agent;
 pub mod socket;
+pub mod capture;
+pub mod certs;
+pub mod protocol;
+pub mod logging;
+pub mod control;
+pub mod conflicts;
+pub mod conformance;
+pub mod corpus;
+pub mod diagnostics;
+pub mod diff;
+pub mod router;
+pub mod stats;
+pub mod policy;
+pub mod audit;
+pub mod approval;
+pub mod peer;
+pub mod container;
+pub mod context;
+pub mod events;
+pub mod fdpass;
+pub mod exit;
+pub mod forwarded;
+pub mod i18n;
+pub mod grants;
+pub mod pinning;
+pub mod privacy;
+pub mod rate;
+pub mod remote;
+pub mod schedule;
+pub mod scheduler;
+pub mod scrub;
+pub mod sshconfig;
+pub mod state;
+pub mod sweep;
+pub mod table;
+pub mod identity;
+pub mod instance;
+pub mod telemetry;
+pub mod template;
+pub mod watch;
+pub mod version;
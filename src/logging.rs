@@ -0,0 +1,152 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError, RwLock};
+
+use crate::protocol;
+
+static LOGGER: StderrLogger = StderrLogger;
+static REDACT: AtomicBool = AtomicBool::new(false);
+static LEVEL: RwLock<LevelFilter> = RwLock::new(LevelFilter::Info);
+static SOCKET_LEVELS: RwLock<BTreeMap<String, LevelFilter>> = RwLock::new(BTreeMap::new());
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Log lines kept for crash diagnostics, TRACE lines excepted
+const RECENT_LINES: usize = 500;
+
+/// Prefix of the log target used for a socket's messages
+const SOCKET_TARGET: &str = "socket:";
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Sockets with their own level override the global one
+        let level = metadata.target()
+            .strip_prefix(SOCKET_TARGET)
+            .and_then(|name| SOCKET_LEVELS.read().unwrap().get(name).copied())
+            .unwrap_or_else(|| *LEVEL.read().unwrap());
+        metadata.level() <= level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let line = format!("[{}] {}", record.level(), record.args());
+            eprintln!("{}", line);
+            // TRACE dumps whole messages, which bug reports must not carry
+            if record.level() == log::Level::Trace {
+                return;
+            }
+            let mut recent = RECENT.lock().unwrap_or_else(PoisonError::into_inner);
+            if recent.len() == RECENT_LINES {
+                recent.pop_front();
+            }
+            recent.push_back(line);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the stderr logger at the given level
+pub fn init(level: LevelFilter) {
+    // Only fails if a logger is already installed, which is fine
+    let _ = log::set_logger(&LOGGER);
+    set_level(level);
+}
+
+pub fn set_level(level: LevelFilter) {
+    *LEVEL.write().unwrap() = level;
+    update_max_level();
+}
+
+/// Override the level for one socket's messages, `None` to use the global level
+pub fn set_socket_level(socket_name: &str, level: Option<LevelFilter>) {
+    let mut levels = SOCKET_LEVELS.write().unwrap();
+    match level {
+        Some(level) => levels.insert(socket_name.to_string(), level),
+        None => levels.remove(socket_name),
+    };
+    drop(levels);
+    update_max_level();
+}
+
+/// The most recent log lines, oldest first
+pub fn recent() -> Vec<String> {
+    RECENT.lock().unwrap_or_else(PoisonError::into_inner).iter().cloned().collect()
+}
+
+/// Log target for messages about a socket, e.g. `log::info!(target: &target, ...)`
+pub fn socket_target(socket_name: &str) -> String {
+    format!("{}{}", SOCKET_TARGET, socket_name)
+}
+
+/// The `log` macros skip anything above the max level, so it has to
+/// cover the most verbose socket
+fn update_max_level() {
+    let global = *LEVEL.read().unwrap();
+    let max = SOCKET_LEVELS.read().unwrap().values().copied().fold(global, Ord::max);
+    log::set_max_level(max);
+}
+
+/// Zero out key blobs in traced messages
+pub fn set_redact(redact: bool) {
+    REDACT.store(redact, Ordering::Relaxed);
+}
+
+/// Log a length-prefixed agent message at TRACE level with its decoded type
+pub fn trace_message(socket_name: &str, id: &str, direction: &str, message: &[u8]) {
+    let target = socket_target(socket_name);
+    if !log::log_enabled!(target: &target, log::Level::Trace) {
+        return;
+    }
+
+    let type_name = protocol::message_type(message)
+        .map(protocol::message_type_name)
+        .unwrap_or("EMPTY");
+    // Private keys, passphrases and signatures are never dumped
+    let message = protocol::redact_secrets(message);
+    let dump = if REDACT.load(Ordering::Relaxed) {
+        hexdump(&protocol::redact_key_blobs(&message))
+    } else {
+        hexdump(&message)
+    };
+
+    log::trace!(
+        target: &target,
+        "{} [{}] {} {} ({} bytes)\n{}",
+        socket_name,
+        id,
+        direction,
+        type_name,
+        message.len().saturating_sub(4),
+        dump
+    );
+}
+
+/// Format bytes as offset, hex and ASCII columns, 16 bytes per line
+pub fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x}  ", i * 16);
+        for j in 0..16 {
+            match chunk.get(j) {
+                Some(byte) => { let _ = write!(out, "{:02x} ", byte); }
+                None => out.push_str("   "),
+            }
+            if j == 7 {
+                out.push(' ');
+            }
+        }
+        out.push(' ');
+        for byte in chunk {
+            out.push(if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' });
+        }
+        out.push('\n');
+    }
+
+    out.pop();
+    out
+}
@@ -1,31 +1,109 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
-use ssh_agent_router::cli::{Cli, Commands, SocketConfig};
-use ssh_agent_router::config::{self, Config};
-use ssh_agent_router::agent::Agent;
-use ssh_agent_router::socket::FilteredSocket;
+use log::LevelFilter;
+use ssh_agent_router::cli::{Change, Cli, Commands, ConfigCommand, CorpusCommand, ExportFormat, GrantsCommand, ProfileCommand, RemoteCommand, SocketCommand, SocketConfig};
+use ssh_agent_router::config::{self, expand_tilde, AuditSink, Config, DirPermissions};
+use ssh_agent_router::agent::{Agent, SeenKey, SshKey};
+use ssh_agent_router::capture;
+use ssh_agent_router::conflicts;
+use ssh_agent_router::conformance::{self, Verdict};
+use ssh_agent_router::corpus::{self, Fixture};
+use ssh_agent_router::context::RequestContext;
+use ssh_agent_router::exit::{self, Failure};
+use ssh_agent_router::control::{self, ControlRequest, ControlResponse, ControlServer, Summary, UpstreamHealth};
+use ssh_agent_router::diagnostics;
+use ssh_agent_router::diff;
+use ssh_agent_router::forwarded;
+use ssh_agent_router::i18n;
+use ssh_agent_router::instance::InstanceLock;
+use ssh_agent_router::logging;
+use ssh_agent_router::grants::GrantUses;
+use ssh_agent_router::pinning::KeyPins;
+use ssh_agent_router::privacy;
+use ssh_agent_router::protocol;
+use ssh_agent_router::policy::KeyFilter;
+use ssh_agent_router::remote::Remote;
+use ssh_agent_router::router::Router;
+use ssh_agent_router::scrub;
+use ssh_agent_router::socket::{self, FilteredSocket};
+use ssh_agent_router::sshconfig;
+use ssh_agent_router::state;
+use ssh_agent_router::stats::SavedStats;
+use ssh_agent_router::sweep;
+use ssh_agent_router::table::{self, Style, Table};
+use ssh_agent_router::telemetry;
+use std::io::{BufRead, Write};
+use std::process::ExitCode;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// How often a running router writes its counters out, so a crash loses
+/// little
+const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the router being taken over has to exit
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(exit::code(&e))
+        }
+    }
+}
+
+async fn run() -> Result<()> {
     let cli = Cli::parse();
+    logging::init(cli.log_level.unwrap_or(LevelFilter::Info));
+    if let Some(profile) = &cli.profile {
+        Config::use_profile(profile);
+    }
+    if let Some(path) = &cli.config {
+        Config::use_file(path);
+    }
+
+    if cli.control_json {
+        return control::relay();
+    }
+
+    // `run` is the foreground mode below with a few extra knobs
+    let mut until_idle = None;
+    let mut takeover = false;
+    let command = match cli.command {
+        Some(Commands::Run { until_idle: idle, takeover: take }) => {
+            until_idle = idle;
+            takeover = take;
+            None
+        }
+        command => command,
+    };
 
     // Handle subcommands
-    if let Some(command) = cli.command {
-        return handle_command(command).await;
+    if let Some(command) = command {
+        return handle_command(command, table::use_color(cli.no_color)).await;
     }
 
+    // A panic while serving leaves diagnostics behind for `bugreport`
+    diagnostics::install_panic_hook();
+
     // Load configuration
-    let config = if cli.sockets.is_empty() {
+    let from_file = cli.sockets.is_empty();
+    let mut config = if from_file {
         // No arguments provided, load from config file
-        Config::load()?
+        load_config()?
     } else {
         // Use command-line arguments
-        let mut cfg = Config::default();
-        cfg.upstream = if cli.upstream.is_empty() {
-            std::env::var("SSH_AUTH_SOCK").unwrap_or_default()
-        } else {
-            cli.upstream.clone()
+        let mut cfg = Config {
+            upstream: if cli.upstream.is_empty() {
+                std::env::var("SSH_AUTH_SOCK").unwrap_or_default()
+            } else {
+                cli.upstream.clone()
+            },
+            ..Default::default()
         };
 
         // Try to parse as space-separated format first
@@ -42,85 +120,459 @@ async fn main() -> Result<()> {
         for socket_cfg in socket_configs {
             cfg.sockets.push(config::SocketEntry {
                 path: socket_cfg.path,
-                allowed: socket_cfg.allowed_fingerprints,
+                allowed: socket_cfg.allowed_fingerprints.into_iter().map(Into::into).collect(),
                 denied: socket_cfg.denied_fingerprints,
+                ..Default::default()
             });
         }
+        cfg.validate().context(Failure::Config)?;
         cfg
     };
 
-    if config.sockets.is_empty() {
+    // Command-line logging flags take precedence over the config file
+    if cli.log_level.is_none() {
+        if let Some(level) = config.log_level {
+            logging::set_level(level);
+        }
+    }
+    logging::set_redact(cli.log_redact || config.log_redact);
+    privacy::set(&config.privacy);
+
+    // --debug-capture applies to every socket without its own capture dir
+    if let Some(dir) = &cli.debug_capture {
+        for socket in &mut config.sockets {
+            if socket.debug_capture.is_none() {
+                socket.debug_capture = Some(dir.clone());
+                socket.capture_redact |= cli.capture_redact;
+            }
+        }
+    }
+
+    if config.sockets.is_empty() && config.per_key_sockets.is_none() {
         eprintln!("No sockets configured. Use --help for usage information.");
         eprintln!("Or run 'ssh-agent-router config' to create a configuration.");
         return Ok(());
     }
 
+    telemetry::init(&config.telemetry)?;
+    diagnostics::record_config(&config);
+
+    // A second router would replace the first one's sockets; with
+    // --takeover the running one hands them over and exits instead
+    let lock_path = Config::instance_lock_path()?;
+    let _instance = if takeover {
+        take_over(&lock_path)?
+    } else {
+        InstanceLock::acquire(&lock_path).context(Failure::Sockets)?
+    };
+
+    // Binding replaces whatever is at a socket path, so say so up front
+    for conflict in conflicts::check(&config, |path| !socket::is_inherited(path)) {
+        log::warn!("Socket {:?}: {}; {}", conflict.path, conflict.problem, conflict.fix);
+    }
+
+    // Sockets of a router that crashed would otherwise pile up in the
+    // ephemeral and per-key directories
+    match sweep::candidates(&config) {
+        Ok(candidates) => {
+            for path in sweep::sweep(&candidates, &[], false) {
+                log::info!("Removed stale socket {:?}", path);
+            }
+        }
+        Err(e) => log::warn!("Failed to look for stale sockets: {:#}", e),
+    }
+
     // Start the router
     println!("Starting SSH Agent Router");
     println!("Upstream: {}", config.upstream);
     println!("Configured sockets: {}", config.sockets.len());
 
-    let agent = Agent::new(config.upstream.clone());
+    let agent = Agent::new(config.upstream.clone(), config.limits.clone())
+        .with_upstream_wait(config.limits.upstream_wait);
+    let missing_upstream = agent.fixed_upstream().filter(|path| !path.exists());
+    let mut router = Router::new(agent);
+    if config.pinning.is_enabled() {
+        let pins = KeyPins::open(config.pinned_keys_path()?, config.pinning.enforce)?;
+        println!("Pinning upstream keys in: {:?}", pins.path());
+        router = router.with_pins(pins);
+    }
+    // Losing the counters is no reason not to start
+    let saved_stats = Config::stats_path().and_then(SavedStats::open).unwrap_or_else(|e| {
+        log::warn!("Starting with fresh statistics: {:#}", e);
+        SavedStats::in_memory()
+    });
+    let router = Arc::new(
+        router.with_grants(GrantUses::open(Config::grant_uses_path()?)?)
+            .with_saved_stats(saved_stats)
+            .with_config(config.clone()),
+    );
 
     // Create all filtered sockets
-    let mut sockets = Vec::new();
-    for socket_entry in config.sockets {
-        let filtered_socket = Arc::new(FilteredSocket::new(
-            socket_entry.path.clone(),
-            socket_entry.allowed,
-            socket_entry.denied,
-            agent.clone(),
-        ));
-        
+    let now = std::time::SystemTime::now();
+    for socket_entry in &config.sockets {
+        if !config.socket_active(socket_entry, now) {
+            println!(
+                "Socket {:?} is outside its active hours ({}), binding it later",
+                socket_entry.path, socket_entry.active.as_deref().unwrap_or_default()
+            );
+            continue;
+        }
         println!("Starting socket: {:?}", socket_entry.path);
-        filtered_socket.start().await?;
-        sockets.push(filtered_socket);
+        if let Some(dir) = &socket_entry.debug_capture {
+            println!("  Capturing requests to: {:?}", dir);
+        }
+        for sink in config.audit_sinks(socket_entry) {
+            match sink {
+                AuditSink::File { path } => println!("  Auditing to: {:?}", path),
+                sink => println!("  Auditing to: {:?}", sink),
+            }
+        }
+        let socket = router.add_socket(socket_entry, &config).await.context(Failure::Sockets)?;
+        socket.warn_if_allow_all(socket_entry, &config);
     }
 
+    match missing_upstream {
+        Some(path) => {
+            println!("Upstream {:?} does not exist yet, waiting for it to appear", path);
+            watch_upstream(Arc::clone(&router), path);
+        }
+        None => warm(Arc::clone(&router)),
+    }
+
+    // Sockets the old router served that the configuration dropped
+    for path in socket::remove_unused_inherited() {
+        println!("Removed socket no longer configured: {:?}", path);
+    }
+
+    // The control socket is a convenience, the router works without it
+    let control = match ControlServer::start(Arc::clone(&router)) {
+        Ok(control) => {
+            println!("Control socket: {:?}", control.path());
+            Some(control)
+        }
+        Err(e) => {
+            log::warn!("Control socket unavailable: {:#}", e);
+            None
+        }
+    };
+
     println!("\nSSH Agent Router is running in foreground mode.");
-    println!("Press Ctrl+C to stop.");
+    println!("Press Ctrl+C to stop, send SIGHUP to reload the configuration.");
+    if let Some(idle) = until_idle {
+        println!("Exiting after {} without client activity.", humantime::format_duration(idle));
+    }
 
-    // Keep running
-    tokio::signal::ctrl_c().await?;
+    // Keep running, reloading the configuration on SIGHUP
+    let started = Instant::now();
+    let mut hangup = signal(SignalKind::hangup())?;
+    // Service managers stop the router with SIGTERM, which gets the same
+    // clean shutdown as Ctrl+C so the counters are saved
+    let mut terminate = signal(SignalKind::terminate())?;
+    // Checks for expired sockets and, with --until-idle, for idleness
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let mut last_activity = (router.activity(), Instant::now());
+    let mut next_key_sync = Instant::now();
+    let mut next_stats_save = Instant::now() + STATS_SAVE_INTERVAL;
+    let mut next_sweep = Instant::now() + sweep::SWEEP_INTERVAL;
+    loop {
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                result?;
+                break;
+            }
+            _ = terminate.recv() => break,
+            _ = tick.tick() => {
+                router.remove_expired();
+                router.apply_schedules();
+                diagnostics::record_sockets(router.sockets().iter()
+                    .map(|socket| format!("{} {}", socket.name(), socket.path().display()))
+                    .collect());
+                if router.handed_over() {
+                    println!("\nHanded the sockets over to a new router");
+                    break;
+                }
+                if Instant::now() >= next_stats_save {
+                    next_stats_save = Instant::now() + STATS_SAVE_INTERVAL;
+                    if let Err(e) = router.save_stats() {
+                        log::warn!("Failed to save statistics: {:#}", e);
+                    }
+                }
+                if Instant::now() >= next_sweep {
+                    next_sweep = Instant::now() + sweep::SWEEP_INTERVAL;
+                    let router = Arc::clone(&router);
+                    tokio::task::spawn_blocking(move || match router.sweep_stale_sockets() {
+                        Ok(removed) => removed.iter().for_each(|path| log::info!("Removed stale socket {:?}", path)),
+                        Err(e) => log::warn!("Failed to look for stale sockets: {:#}", e),
+                    });
+                }
+                if let Some(interval) = router.per_key_interval().filter(|_| Instant::now() >= next_key_sync) {
+                    next_key_sync = Instant::now() + interval;
+                    let router = Arc::clone(&router);
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = router.sync_per_key_sockets() {
+                            log::warn!("{:#}", e);
+                        }
+                    });
+                }
+                let Some(until_idle) = until_idle else {
+                    continue;
+                };
+                let activity = router.activity();
+                if activity.is_none() || activity != last_activity.0 {
+                    last_activity = (activity, Instant::now());
+                } else if last_activity.1.elapsed() >= until_idle {
+                    println!("\nNo client activity for {}", humantime::format_duration(until_idle));
+                    break;
+                }
+            }
+            _ = hangup.recv() => {
+                log::info!("Received SIGHUP, reloading");
+                // Sockets from the command line are rebuilt from the same
+                // entries
+                let result = if from_file {
+                    Config::load().and_then(|config| router.reload(&config))
+                } else {
+                    router.reload(&config)
+                };
+                match result {
+                    Ok(()) => {
+                        diagnostics::record_config(&router.config());
+                        warm(Arc::clone(&router));
+                    }
+                    Err(e) => log::error!("Failed to reload configuration, keeping the running sockets: {:#}", e),
+                }
+                next_key_sync = Instant::now();
+            }
+        }
+    }
     println!("\nShutting down...");
+    print_summary(started.elapsed(), &router);
+    if let Err(e) = router.save_stats() {
+        log::warn!("Failed to save statistics: {:#}", e);
+    }
+    drop(control);
+    router.shutdown();
 
     Ok(())
 }
 
-async fn handle_command(command: Commands) -> Result<()> {
+/// Ask upstream for its keys the moment its socket appears, which tells
+/// subscribers it is online and warms the listing for the first client
+fn watch_upstream(router: Arc<Router>, path: std::path::PathBuf) {
+    std::thread::spawn(move || {
+        if let Err(e) = ssh_agent_router::watch::wait_for_path(&path, None) {
+            log::warn!("Failed to watch for upstream agent {:?}: {:#}", path, e);
+            return;
+        }
+        log::info!("Upstream agent appeared at {:?}", path);
+        if let Err(e) = router.warm() {
+            log::warn!("Upstream agent at {:?} is not answering yet: {:#}", path, e);
+        }
+    });
+}
+
+/// Take the listening sockets from the running router, if any, and its
+/// instance lock once it has exited
+fn take_over(lock_path: &std::path::Path) -> Result<InstanceLock> {
+    match control::take_over().context("Failed to take over from the running router")? {
+        Some(listeners) => {
+            println!("Took over {} sockets from the running router", listeners.len());
+            socket::inherit(listeners);
+        }
+        None => println!("No router is running, starting normally"),
+    }
+    InstanceLock::wait(lock_path, TAKEOVER_TIMEOUT)
+        .context("The running router did not exit after handing its sockets over")
+        .context(Failure::Sockets)
+}
+
+/// Connect to upstream and build the sockets' listings in the background,
+/// so the first client is not the one waiting for them
+fn warm(router: Arc<Router>) {
+    std::thread::spawn(move || match router.warm() {
+        Ok(keys) => log::info!("Warmed upstream connections, {} keys listed", keys),
+        Err(e) => log::warn!("Failed to warm upstream connections: {:#}", e),
+    });
+}
+
+async fn handle_command(command: Commands, color: bool) -> Result<()> {
     match command {
-        Commands::ListSocks => {
-            let config = Config::load()?;
-            println!("Configured sockets:");
-            for (i, socket) in config.sockets.iter().enumerate() {
-                println!("  {}. {:?}", i + 1, socket.path);
-                if !socket.allowed.is_empty() {
-                    println!("     Allowed: {}", socket.allowed.join(", "));
+        Commands::Run { .. } => unreachable!("run is handled by main"),
+        Commands::ListSocks { verbose, long } => {
+            let config = load_config()?;
+
+            // Live counters are only available from a running router
+            let live_stats = if verbose {
+                match control::request(&ControlRequest::Stats) {
+                    Ok(ControlResponse::Stats { sockets }) => Some(sockets),
+                    Ok(_) => None,
+                    Err(e) => {
+                        println!("{}", i18n::format("statistics-unavailable", &[("error", &format_args!("{:#}", e))]));
+                        None
+                    }
                 }
-                if !socket.denied.is_empty() {
-                    println!("     Denied: {}", socket.denied.join(", "));
+            } else {
+                None
+            };
+
+            let fingerprint = |fp: &str| if long { fp.to_string() } else { table::short_fingerprint(fp) };
+            let described = config.sockets.iter().any(|socket| socket.description.is_some());
+            let mut header = ["column-name", "column-path"].map(i18n::text).to_vec();
+            if described {
+                header.push(i18n::text("column-description"));
+            }
+            header.extend(["column-allowed", "column-denied", "column-expires-in"].map(i18n::text));
+            if live_stats.is_some() {
+                header.extend([
+                    "column-state", "column-active", "column-accepted", "column-rejected", "column-requests",
+                    "column-denied-signs", "column-errors", "column-upstream-errors", "column-bytes-in", "column-bytes-out",
+                ].map(i18n::text));
+            }
+            let mut sockets = Table::new(&header, color);
+            for socket in &config.sockets {
+                let allowed = socket.allowed.iter().map(|a| match a.max_uses {
+                    Some(max_uses) => format!("{} (max {} uses)", fingerprint(&a.key), max_uses),
+                    None => fingerprint(&a.key),
+                });
+                let mut row = vec![
+                    (socket.name(), Style::Bold),
+                    (socket.path.display().to_string(), Style::Plain),
+                ];
+                if described {
+                    row.push((socket.description.clone().unwrap_or_default(), Style::Plain));
                 }
+                row.extend([
+                    (allowed.collect::<Vec<_>>().join(", "), Style::Plain),
+                    (socket.denied.iter().map(|d| fingerprint(d)).collect::<Vec<_>>().join(", "), Style::Plain),
+                    (config.socket_expiry(socket).map_or_else(String::new, |expiry| {
+                        format_remaining(expiry.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs())
+                    }), Style::Plain),
+                ]);
+                if let Some(live_stats) = &live_stats {
+                    match live_stats.iter().find(|s| s.path == socket.path) {
+                        Some(status) => {
+                            let stats = &status.stats;
+                            row.push((i18n::text("sockets-served").to_string(), Style::Green));
+                            row.extend([
+                                stats.active, stats.accepted, stats.rejected, stats.requests, stats.denied_sign_count(),
+                                stats.errors, stats.upstream_errors, stats.bytes_in, stats.bytes_out,
+                            ].map(|n| (n.to_string(), Style::Plain)));
+                        }
+                        None => row.push((i18n::text("sockets-not-served").to_string(), Style::Yellow)),
+                    }
+                }
+                sockets.styled_row(row);
+            }
+            println!("{}", i18n::text("sockets-configured"));
+            print!("{}", sockets.render());
+
+            let header = ["column-name", "column-path", "column-expires-in", "column-accepted", "column-requests"].map(i18n::text);
+            let mut ephemeral = Table::new(&header, color);
+            for status in live_stats.iter().flatten().filter(|s| s.ephemeral) {
+                ephemeral.row([
+                    status.name.clone(),
+                    status.path.display().to_string(),
+                    status.expires.map(format_remaining).unwrap_or_default(),
+                    status.stats.accepted.to_string(),
+                    status.stats.requests.to_string(),
+                ]);
+            }
+            if !ephemeral.is_empty() {
+                println!("{}", i18n::text("sockets-ephemeral"));
+                print!("{}", ephemeral.render());
             }
         }
-        Commands::ListKeys => {
-            let config = Config::load()?;
-            let agent = Agent::new(config.upstream);
-            let keys = agent.list_keys()?;
+        Commands::ListKeys { matrix, long } => {
+            let config = load_config()?;
+            let agent = Agent::new(config.upstream.clone(), config.limits.clone());
+            let keys = agent.list_keys().context(Failure::Upstream)?;
+
+            // Evaluate every socket's filter against every key
+            let filters = config.sockets.iter()
+                .map(|s| Ok((s.name(), KeyFilter::from_entry(s, &config)?)))
+                .collect::<Result<Vec<(String, KeyFilter)>>>()?;
+
+            if matrix {
+                print_exposure_matrix(&keys, &filters, long, color);
+                return Ok(());
+            }
             
-            println!("Available keys from upstream:");
-            for (i, key) in keys.iter().enumerate() {
-                println!("  {}. {} ({})", i + 1, key.fingerprint, key.key_type);
-                println!("     Comment: {}", key.comment);
+            // First-seen dates come from the pin file
+            let pins = if config.pinning.is_enabled() {
+                Some(KeyPins::open(config.pinned_keys_path()?, false)?)
+            } else {
+                None
+            };
+            let now = std::time::SystemTime::now();
+
+            let today = humantime::format_rfc3339_seconds(now).to_string();
+            let today = &today[..10];
+
+            let mut header = ["column-key", "column-type", "column-comment"].map(i18n::text).to_vec();
+            if !config.keys.is_empty() {
+                header.push(i18n::text("column-label"));
+            }
+            if pins.is_some() {
+                header.push(i18n::text("column-first-seen"));
             }
+            header.push(i18n::text("column-sockets"));
+            let mut rows = Table::new(&header, color);
+            for key in &keys {
+                let first_seen = pins.as_ref().and_then(|pins| pins.first_seen(&key.fingerprint));
+                let too_old = |filter: &KeyFilter| first_seen
+                    .and_then(|first_seen| filter.hidden_after(key, first_seen))
+                    .is_some_and(|time| time <= now);
+                let exposed_by: Vec<String> = filters.iter()
+                    .filter(|(_, filter)| filter.is_allowed(key) && !too_old(filter))
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                let aged_out: Vec<String> = filters.iter()
+                    .filter(|(_, filter)| filter.is_allowed(key) && too_old(filter))
+                    .map(|(name, _)| i18n::format("keys-too-old", &[("socket", name)]))
+                    .collect();
+                let style = match (exposed_by.is_empty(), aged_out.is_empty()) {
+                    (true, true) => Style::Dim,
+                    (true, false) => Style::Yellow,
+                    (false, _) => Style::Plain,
+                };
+                let sockets = [exposed_by, aged_out].concat();
+
+                let mut row = vec![
+                    (if long { key.fingerprint.clone() } else { table::short_fingerprint(&key.fingerprint) }, Style::Bold),
+                    (key.key_type.clone(), Style::Plain),
+                    (key.comment.clone(), Style::Plain),
+                ];
+                if !config.keys.is_empty() {
+                    let meta = config.keys.get(&key.fingerprint);
+                    row.push(match meta {
+                        Some(meta) if meta.rotation_due(today) => (
+                            format!("{}, {}", meta.describe().unwrap_or_default(), i18n::text("key-rotation-overdue")),
+                            Style::Yellow,
+                        ),
+                        Some(meta) => (meta.describe().unwrap_or_default(), Style::Plain),
+                        None => (String::new(), Style::Plain),
+                    });
+                }
+                if pins.is_some() {
+                    let age = first_seen.map(|first_seen| {
+                        i18n::format("keys-ago", &[("age", &format_key_age(now.duration_since(first_seen).unwrap_or_default()))])
+                    });
+                    row.push((age.unwrap_or_default(), Style::Plain));
+                }
+                row.push((if sockets.is_empty() { i18n::text("keys-none").to_string() } else { sockets.join(", ") }, style));
+                rows.styled_row(row);
+            }
+            println!("{}", i18n::text("keys-available"));
+            print!("{}", rows.render());
         }
         Commands::List => {
             // List sockets
-            let config = Config::load()?;
+            let config = load_config()?;
             println!("Configured sockets:");
             for (i, socket) in config.sockets.iter().enumerate() {
                 println!("  {}. {:?}", i + 1, socket.path);
                 if !socket.allowed.is_empty() {
-                    println!("     Allowed: {}", socket.allowed.join(", "));
+                    println!("     Allowed: {}", socket.allowed.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "));
                 }
                 if !socket.denied.is_empty() {
                     println!("     Denied: {}", socket.denied.join(", "));
@@ -130,8 +582,8 @@ async fn handle_command(command: Commands) -> Result<()> {
             println!();
             
             // List keys
-            let agent = Agent::new(config.upstream);
-            let keys = agent.list_keys()?;
+            let agent = Agent::new(config.upstream, config.limits);
+            let keys = agent.list_keys().context(Failure::Upstream)?;
             
             println!("Available keys from upstream:");
             for (i, key) in keys.iter().enumerate() {
@@ -139,33 +591,938 @@ async fn handle_command(command: Commands) -> Result<()> {
                 println!("     Comment: {}", key.comment);
             }
         }
-        Commands::Status => {
-            let config = Config::load()?;
-            println!("SSH Agent Router Status");
-            println!("======================");
-            println!("Upstream: {}", config.upstream);
-            println!("Configured sockets: {}", config.sockets.len());
-            
+        Commands::Which { fingerprint, pubkey } => {
+            let config = load_config()?;
+
+            let key = match (fingerprint, pubkey) {
+                (_, Some(path)) => {
+                    let content = std::fs::read_to_string(&path)
+                        .with_context(|| format!("Failed to read {:?}", path))?;
+                    SshKey::from_public_key_line(content.trim())?
+                }
+                (Some(fingerprint), None) => {
+                    // Prefer the upstream key so comment and type are known
+                    let agent = Agent::new(config.upstream.clone(), config.limits.clone());
+                    let upstream_key = match agent.list_keys() {
+                        Ok(keys) => keys.into_iter().find(|k| k.matches_fingerprint(&fingerprint)),
+                        Err(e) => {
+                            log::warn!("Could not reach the upstream agent: {:#}", e);
+                            None
+                        }
+                    };
+                    upstream_key.unwrap_or_else(|| {
+                        log::warn!(
+                            "Key not found upstream, comment:/type: patterns and MD5 entries \
+                             cannot be checked. Use --pubkey for exact results."
+                        );
+                        SshKey {
+                            key_type: "unknown".to_string(),
+                            blob: Vec::new(),
+                            comment: String::new(),
+                            fingerprint,
+                        }
+                    })
+                }
+                (None, None) => unreachable!("clap requires a fingerprint or --pubkey"),
+            };
+
+            println!("Key: {} ({})", key.fingerprint, key.key_type);
+            if !key.comment.is_empty() {
+                println!("Comment: {}", key.comment);
+            }
+            if let Some(meta) = config.keys.get(&key.fingerprint) {
+                if let Some(label) = meta.describe() {
+                    println!("Label: {}", label);
+                }
+                if let Some(owner) = &meta.owner {
+                    println!("Owner: {}", owner);
+                }
+                if let Some(notes) = &meta.notes {
+                    println!("Notes: {}", notes);
+                }
+            }
+
+            let mut exposed = 0;
+            for socket in &config.sockets {
+                let filter = KeyFilter::from_entry(socket, &config)?;
+                let verdict = filter.evaluate(&key);
+                if verdict.is_allowed() {
+                    exposed += 1;
+                }
+                println!(
+                    "  {:<7} {:?} ({}): {}{}",
+                    if verdict.is_allowed() { "exposed" } else { "hidden" },
+                    socket.path,
+                    socket.name(),
+                    verdict.reason(),
+                    verdict.deny_reason().map(|code| format!(" [{}]", code)).unwrap_or_default()
+                );
+                if let Some(hosts) = filter.bound_hosts(&key).filter(|_| verdict.is_allowed()) {
+                    println!("          only logs in to {}", hosts.join(", "));
+                }
+                if let Some(namespaces) = filter.bound_namespaces(&key).filter(|_| verdict.is_allowed()) {
+                    println!("          only signs in SSHSIG namespaces {}", namespaces.join(", "));
+                }
+            }
+
+            if exposed == 0 {
+                println!("Not exposed by any configured socket.");
+                return Err(Failure::Denied.into());
+            }
+        }
+        Commands::Fingerprint { file } => {
+            let content = if file.as_os_str() == "-" {
+                std::io::read_to_string(std::io::stdin())?
+            } else {
+                std::fs::read_to_string(&file)
+                    .with_context(|| format!("Failed to read {:?}", file))?
+            };
+
+            // Report bad lines and carry on with the rest
+            let mut failed = 0;
+            for (i, line) in content.lines().map(str::trim).enumerate() {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let key = match SshKey::from_authorized_keys_line(line) {
+                    Ok(key) => key,
+                    Err(e) => {
+                        eprintln!("line {}: {:#}", i + 1, e);
+                        failed += 1;
+                        continue;
+                    }
+                };
+                let comment = if key.comment.is_empty() { "no comment" } else { key.comment.as_str() };
+                println!("{} {} ({})", key.fingerprint, comment, key.key_type);
+                println!("{} {} ({})", key.md5_fingerprint(), comment, key.key_type);
+            }
+            if failed > 0 {
+                anyhow::bail!("{} line(s) could not be parsed", failed);
+            }
+        }
+        Commands::Export { socket, format, output_dir } => {
+            let config = load_config()?;
+            let sockets: Vec<&config::SocketEntry> = match &socket {
+                Some(name) => vec![config.find_socket(name)
+                    .ok_or_else(|| anyhow::anyhow!("No configured socket named {:?}", name))?],
+                None => config.sockets.iter().collect(),
+            };
+
+            let agent = Agent::new(config.upstream.clone(), config.limits.clone());
+            let keys = agent.list_keys()?;
+
+            if let Some(dir) = &output_dir {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("Failed to create directory {:?}", dir))?;
+            }
+
+            for entry in sockets {
+                let filter = KeyFilter::from_entry(entry, &config)?;
+                let lines: Vec<String> = keys.iter()
+                    .filter(|k| filter.is_allowed(k))
+                    .map(|k| match format {
+                        ExportFormat::AuthorizedKeys => k.to_public_key_line(),
+                        ExportFormat::Fingerprints => k.fingerprint.clone(),
+                    })
+                    .collect();
+
+                match &output_dir {
+                    Some(dir) => {
+                        let path = dir.join(format!("{}.keys", entry.name()));
+                        let mut content = lines.join("\n");
+                        if !content.is_empty() {
+                            content.push('\n');
+                        }
+                        std::fs::write(&path, content)
+                            .with_context(|| format!("Failed to write {:?}", path))?;
+                        println!("Wrote {} keys to {:?}", lines.len(), path);
+                    }
+                    None => {
+                        if socket.is_none() {
+                            println!("# {}", entry.name());
+                        }
+                        for line in lines {
+                            println!("{}", line);
+                        }
+                    }
+                }
+            }
+        }
+        Commands::Status { long } => {
+            let config = load_config()?;
+            let title = i18n::text("status-title");
+            println!("{}", title);
+            println!("{}", "=".repeat(table::width(title)));
+            println!("{}", i18n::format("status-upstream", &[("upstream", &config.upstream)]));
+            println!("{}", i18n::format("status-sockets", &[("count", &config.sockets.len())]));
+
+            // Last-used times and the last listing come from a running router
+            let live_stats = match control::request(&ControlRequest::Stats) {
+                Ok(ControlResponse::Stats { sockets }) => Some(sockets),
+                _ => None,
+            };
+            let state = match live_stats.is_some() {
+                true => table::paint(i18n::text("status-running"), Style::Green, color),
+                false => table::paint(i18n::text("status-not-running"), Style::Yellow, color),
+            };
+            println!("{}", i18n::format("status-router", &[("state", &state)]));
+
             // Try to connect to upstream
-            let agent = Agent::new(config.upstream.clone());
-            match agent.list_keys() {
+            let agent = Agent::new(config.upstream.clone(), config.limits.clone());
+            let mut unreachable = false;
+            let keys: Vec<SeenKey> = match agent.list_keys() {
                 Ok(keys) => {
-                    println!("Upstream status: Connected");
-                    println!("Available keys: {}", keys.len());
+                    let state = table::paint(i18n::text("status-connected"), Style::Green, color);
+                    println!("{}", i18n::format("status-upstream-state", &[("state", &state)]));
+                    println!("{}", i18n::format("status-keys", &[("count", &keys.len())]));
+                    keys.iter()
+                        .map(|k| SeenKey {
+                            fingerprint: k.fingerprint.clone(),
+                            key_type: k.key_type.clone(),
+                            comment: k.comment.clone(),
+                        })
+                        .collect()
                 }
                 Err(e) => {
-                    println!("Upstream status: Error - {}", e);
+                    let state = table::paint(i18n::text("status-error"), Style::Red, color);
+                    println!("{} - {}", i18n::format("status-upstream-state", &[("state", &state)]), e);
+                    unreachable = true;
+                    match control::request(&ControlRequest::Upstream) {
+                        Ok(ControlResponse::Upstream { snapshot: Some(snapshot) }) => {
+                            println!("{}", i18n::format("status-last-seen", &[("age", &format_age(snapshot.seen_at))]));
+                            snapshot.keys
+                        }
+                        _ => return Err(Failure::Upstream.into()),
+                    }
+                }
+            };
+
+            let filters = config.sockets.iter()
+                .map(|s| Ok((s.name(), KeyFilter::from_entry(s, &config)?)))
+                .collect::<Result<Vec<(String, KeyFilter)>>>()?;
+
+            let mut header = ["column-key", "column-type", "column-comment", "column-sockets"].map(i18n::text).to_vec();
+            if live_stats.is_some() {
+                header.push(i18n::text("column-last-used"));
+            }
+            let mut rows = Table::new(&header, color);
+            for key in &keys {
+                // Filters only look at the fingerprint, type and comment
+                let probe = SshKey {
+                    key_type: key.key_type.clone(),
+                    blob: Vec::new(),
+                    comment: key.comment.clone(),
+                    fingerprint: key.fingerprint.clone(),
+                };
+                let exposed_by: Vec<&str> = filters.iter()
+                    .filter(|(_, filter)| filter.is_allowed(&probe))
+                    .map(|(name, _)| name.as_str())
+                    .collect();
+
+                let mut row = vec![
+                    (if long { key.fingerprint.clone() } else { table::short_fingerprint(&key.fingerprint) }, Style::Bold),
+                    (key.key_type.clone(), Style::Plain),
+                    (key.comment.clone(), Style::Plain),
+                    match exposed_by.is_empty() {
+                        true => (i18n::text("status-unreachable").to_string(), Style::Yellow),
+                        false => (exposed_by.join(", "), Style::Plain),
+                    },
+                ];
+                if let Some(live_stats) = &live_stats {
+                    let last_used = live_stats.iter()
+                        .filter_map(|s| s.stats.last_signed.get(&key.fingerprint).map(|t| (*t, &s.name)))
+                        .max();
+                    row.push(match last_used {
+                        Some((time, socket)) => (
+                            i18n::format("status-last-used", &[("age", &format_age(time)), ("socket", socket)]),
+                            Style::Plain,
+                        ),
+                        None => (i18n::text("status-never-used").to_string(), Style::Dim),
+                    });
+                }
+                rows.styled_row(row);
+            }
+            println!();
+            print!("{}", rows.render());
+            if unreachable {
+                return Err(Failure::Upstream.into());
+            }
+        }
+        Commands::Doctor => {
+            let mut problems = 0;
+
+            let path = Config::config_path()?;
+            let config = match Config::load() {
+                Ok(config) => {
+                    println!("ok   Config {:?}: {} socket(s)", path, config.sockets.len());
+                    config
+                }
+                Err(e) => {
+                    println!("FAIL Config {:?}: {:#}", path, e);
+                    return Err(anyhow::anyhow!("1 problem found").context(Failure::Config));
+                }
+            };
+
+            if config.upstream == forwarded::FORWARDED {
+                let sockets = forwarded::sockets();
+                let in_use = forwarded::latest_of(sockets.clone()).ok();
+                for socket in &sockets {
+                    let state = match std::os::unix::net::UnixStream::connect(socket) {
+                        Ok(_) if in_use.as_ref() == Some(socket) => "in use",
+                        Ok(_) => "live",
+                        Err(_) => "session ended",
+                    };
+                    println!("     Forwarded agent {:?}: {}", socket, state);
+                }
+            }
+            let agent = Agent::new(config.upstream.clone(), config.limits.clone());
+            let mut failure = None;
+            match agent.list_keys() {
+                Ok(keys) => println!("ok   Upstream {}: {} key(s)", config.upstream, keys.len()),
+                Err(e) => {
+                    println!("FAIL Upstream {}: {:#}", config.upstream, e);
+                    problems += 1;
+                    failure = Some(Failure::Upstream);
+                }
+            }
+
+            // Sockets the running router serves are expected to be live
+            let served: Vec<_> = match control::request(&ControlRequest::Stats) {
+                Ok(ControlResponse::Stats { sockets }) => sockets.into_iter().map(|s| s.path).collect(),
+                _ => Vec::new(),
+            };
+            let conflicts = conflicts::check(&config, |path| !served.iter().any(|p| p == path));
+            if conflicts.is_empty() {
+                println!("ok   No socket path conflicts with other agents");
+            }
+            for conflict in &conflicts {
+                println!("FAIL Socket {:?}: {}", conflict.path, conflict.problem);
+                println!("     Fix: {}", conflict.fix);
+            }
+            problems += conflicts.len();
+            if !conflicts.is_empty() {
+                failure = failure.or(Some(Failure::Sockets));
+            }
+
+            // Directories other users could replace sockets in
+            for socket in &config.sockets {
+                let action = socket.dir_permissions.unwrap_or_default();
+                let Some(dir) = socket.path.parent().filter(|dir| dir.is_dir() && action != DirPermissions::Ignore) else {
+                    continue;
+                };
+                if let Err(e) = state::check_private_dir(dir, DirPermissions::Refuse) {
+                    println!("FAIL Socket {:?}: {:#}", socket.path, e);
+                    problems += 1;
+                    failure = failure.or(Some(Failure::Sockets));
+                }
+            }
+
+            if let Some(failure) = failure {
+                return Err(anyhow::anyhow!("{} problem(s) found", problems).context(failure));
+            }
+        }
+        Commands::Profile { command: ProfileCommand::List } => {
+            let current = Config::profile()?.unwrap_or_else(|| config::DEFAULT_PROFILE.to_string());
+            let mut names = vec![config::DEFAULT_PROFILE.to_string()];
+            names.extend(Config::profiles()?.into_iter().filter(|n| n != config::DEFAULT_PROFILE));
+            for name in names {
+                let marker = if name == current { "*" } else { " " };
+                println!("{} {}", marker, name);
+            }
+        }
+        Commands::Profile { command: ProfileCommand::Switch { name } } => {
+            // Refuse a profile that would not load before anything changes
+            if name != config::DEFAULT_PROFILE {
+                let path = Config::profile_path(&name)?;
+                if !path.exists() {
+                    anyhow::bail!("Profile {:?} not found, expected {:?}", name, path);
+                }
+                Config::load_from(&path)?;
+            }
+            Config::set_active_profile(&name)?;
+            println!("Active profile: {}", name);
+
+            match control::request(&ControlRequest::SwitchProfile { profile: name }) {
+                Ok(ControlResponse::Switched { sockets }) => {
+                    println!("The running router now serves: {}", sockets.join(", "));
+                }
+                Ok(response) => return Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+                Err(_) if control::request(&ControlRequest::Stats).is_err() => {
+                    println!("The router is not running, it will use this profile when started");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Commands::Socket { command: SocketCommand::Create { allow, ttl, name, path } } => {
+            // A relative path would resolve against the router's directory
+            let path = path.map(|p| std::path::absolute(expand_tilde(&p))).transpose()?;
+            match control::request(&ControlRequest::CreateSocket { allowed: allow, name, path, ttl })? {
+                // Only the path on stdout, for SSH_AUTH_SOCK=$(...)
+                ControlResponse::Created { name, path } => {
+                    eprintln!("Created socket {}", name);
+                    println!("{}", path.display());
+                }
+                response => return Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+            }
+        }
+        Commands::Socket { command: SocketCommand::Remove { socket } } => {
+            match control::request(&ControlRequest::RemoveSocket { socket })? {
+                ControlResponse::Removed { name } => println!("Removed socket {}", name),
+                response => return Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+            }
+        }
+        Commands::Exec { dir, command } => {
+            let project = find_project(dir)?;
+            // Named per process so several runs in one project can overlap
+            let name = format!("{}-{}", project.socket_name(), std::process::id());
+            let request = ControlRequest::CreateSocket {
+                allowed: project.allowed.clone(),
+                name: Some(name),
+                path: None,
+                ttl: project.ttl,
+            };
+            let (name, path) = match control::request(&request)? {
+                ControlResponse::Created { name, path } => (name, path),
+                response => return Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+            };
+
+            let status = std::process::Command::new(&command[0])
+                .args(&command[1..])
+                .env("SSH_AUTH_SOCK", &path)
+                .spawn()
+                .map(|mut child| {
+                    // Ctrl+C is the command's to handle; the socket is
+                    // removed once it exits
+                    // SAFETY: only replaces the disposition of SIGINT
+                    unsafe { libc::signal(libc::SIGINT, libc::SIG_IGN) };
+                    child.wait()
+                });
+            if let Err(e) = control::request(&ControlRequest::RemoveSocket { socket: name.clone() }) {
+                log::warn!("Failed to remove socket {}: {:#}", name, e);
+            }
+            let status = status.and_then(|status| status)
+                .with_context(|| format!("Failed to run {:?}", command[0]))?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        Commands::Direnv { dir } => {
+            let project = find_project(dir)?;
+            let name = project.socket_name();
+            // Replaced each time, so changes to the project file apply
+            let _ = control::request(&ControlRequest::RemoveSocket { socket: name.clone() });
+            let request = ControlRequest::CreateSocket {
+                allowed: project.allowed.clone(),
+                name: Some(name),
+                path: None,
+                ttl: project.ttl,
+            };
+            match control::request(&request)? {
+                ControlResponse::Created { name, path } => {
+                    eprintln!("ssh-agent-router: socket {} exposes {}", name, project.allowed.join(", "));
+                    println!("export SSH_AUTH_SOCK={}", shell_quote(&path.to_string_lossy()));
+                    // direnv reloads the environment when this file changes
+                    println!("watch_file {}", shell_quote(&project.dir.join(config::PROJECT_FILE).to_string_lossy()));
                 }
+                response => return Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
             }
         }
-        Commands::Config { enhanced } => {
+        Commands::GitSetup { socket, repo, change } => {
+            let config = load_config()?;
+            let entry = config.find_socket(&socket)
+                .with_context(|| format!("No socket named {:?} in the configuration", socket))?;
+            let path = std::path::absolute(expand_tilde(&entry.path))?;
+
+            let filter = KeyFilter::from_entry(entry, &config)?;
+            let agent = Agent::new(config.upstream.clone(), config.limits.clone());
+            let keys: Vec<SshKey> = agent.list_keys()?.into_iter().filter(|k| filter.is_allowed(k)).collect();
+            let key = match keys.as_slice() {
+                [key] => key,
+                [] => anyhow::bail!("Socket {} exposes no upstream key to sign with", entry.name()),
+                keys => anyhow::bail!(
+                    "Socket {} exposes {} keys, git signs with one; give it a socket allowing only that key",
+                    entry.name(), keys.len()
+                ),
+            };
+
+            // ssh-keygen signs with whatever SSH_AUTH_SOCK names, so git
+            // runs it through a wrapper pointing at the socket
+            let git_dir = git(&repo, &["rev-parse", "--absolute-git-dir"])?;
+            let program = std::path::Path::new(git_dir.trim()).join("ssh-agent-router-sign");
+            let script = format!(
+                "#!/bin/sh\n# Written by ssh-agent-router git-setup\nSSH_AUTH_SOCK={} exec ssh-keygen \"$@\"\n",
+                shell_quote(&path.to_string_lossy())
+            );
+            let settings = [
+                ("gpg.format", "ssh".to_string()),
+                ("user.signingkey", format!("key::{}", key.to_public_key_line())),
+                ("gpg.ssh.program", program.to_string_lossy().into_owned()),
+                ("commit.gpgsign", "true".to_string()),
+                ("core.sshCommand", format!("ssh -o IdentityAgent={}", shell_quote(&path.to_string_lossy()))),
+            ];
+            print_file_change(&program, &read_if_exists(&program)?, &script);
+            for (name, value) in &settings {
+                println!("{} = {}", name, value);
+            }
+            if !confirm_change(&change, &i18n::format("change-apply-git", &[("repo", &format_args!("{:?}", repo))]))? {
+                return Ok(());
+            }
+
+            std::fs::write(&program, script).with_context(|| format!("Failed to write {:?}", program))?;
+            std::fs::set_permissions(&program, std::os::unix::fs::PermissionsExt::from_mode(0o755))?;
+            for (name, value) in &settings {
+                git(&repo, &["config", "--local", name, value])?;
+            }
+            println!("Commits in {:?} are now signed with {} through socket {}", repo, key.fingerprint, entry.name());
+        }
+        Commands::SshConfig { socket, hosts, install, change } => {
+            let config = load_config()?;
+            let entry = config.find_socket(&socket)
+                .with_context(|| format!("No socket named {:?} in the configuration", socket))?;
+            let path = std::path::absolute(expand_tilde(&entry.path))?;
+            let block = sshconfig::block(&entry.name(), &hosts, &path);
+            if !install {
+                print!("{}", block);
+                return Ok(());
+            }
+
+            let ssh_dir = dirs::home_dir().context("Failed to get home directory")?.join(".ssh");
+            let include = ssh_dir.join(sshconfig::INCLUDE_FILE);
+            let old_blocks = read_if_exists(&include)?;
+            let blocks = sshconfig::replace_block(&old_blocks, &entry.name(), &block);
+            print_file_change(&include, &old_blocks, &blocks);
+
+            // Included first, so its blocks apply before any in the file
+            let ssh_config = ssh_dir.join("config");
+            let old_config = read_if_exists(&ssh_config)?;
+            let tilde = format!("~/.ssh/{}", sshconfig::INCLUDE_FILE);
+            let add_include = !sshconfig::includes(&old_config, sshconfig::INCLUDE_FILE) && !sshconfig::includes(&old_config, &tilde);
+            let config_with_include = format!("Include {}\n\n{}", sshconfig::INCLUDE_FILE, old_config);
+            if add_include {
+                print_file_change(&ssh_config, &old_config, &config_with_include);
+            }
+            if !confirm_change(&change, i18n::text("change-write"))? {
+                return Ok(());
+            }
+
+            state::create_parent_dir(&include)?;
+            write_replacing(&include, &blocks)?;
+            println!("Wrote socket {} for {} to {:?}", entry.name(), hosts, include);
+            if add_include {
+                write_replacing(&ssh_config, &config_with_include)?;
+                println!("Added Include {} to the top of {:?}", sshconfig::INCLUDE_FILE, ssh_config);
+            }
+        }
+        Commands::Attach { socket, allow, deny } => {
+            let path = std::path::absolute(expand_tilde(&socket))?;
+            std::os::unix::net::UnixStream::connect(&path)
+                .with_context(|| format!("No agent is listening on {:?}", path))?;
+            let file_name = path.file_name().context("Socket path has no file name")?.to_string_lossy();
+            let original = path.with_file_name(format!(".{}.attached", file_name));
+            if original.exists() {
+                anyhow::bail!("{:?} exists, is another attach running or did one crash? Move it back to {:?}", original, path);
+            }
+
+            let config = Config {
+                upstream: original.to_string_lossy().into_owned(),
+                sockets: vec![config::SocketEntry {
+                    path: path.clone(),
+                    allowed: allow.into_iter().map(Into::into).collect(),
+                    denied: deny,
+                    ..Default::default()
+                }],
+                per_key_sockets: None,
+                ..load_config()?
+            };
+            config.validate()?;
+
+            // Clients already connected keep talking to the agent itself;
+            // new ones find the router at the familiar path
+            std::fs::rename(&path, &original)
+                .with_context(|| format!("Failed to move {:?} aside", path))?;
+            let restore = RestoreOnDrop { from: original.clone(), to: path.clone() };
+            println!("Moved {:?} to {:?}", path, original);
+
+            let agent = Agent::new(config.upstream.clone(), config.limits.clone());
+            let router = Router::new(agent).with_config(config.clone());
+            router.add_socket(&config.sockets[0], &config).await?;
+            println!("Filtering {:?}, press Ctrl+C to detach", path);
+
+            let mut terminate = signal(SignalKind::terminate())?;
+            let mut hangup = signal(SignalKind::hangup())?;
+            tokio::select! {
+                result = tokio::signal::ctrl_c() => result?,
+                _ = terminate.recv() => {}
+                _ = hangup.recv() => {}
+            }
+            router.shutdown();
+            drop(restore);
+            println!("\nRestored {:?}", path);
+        }
+        Commands::Summary { json } => {
+            let summary = match control::request(&ControlRequest::Summary)? {
+                ControlResponse::Summary { summary } => summary,
+                response => return Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+            };
+            if json {
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+                return Ok(());
+            }
+
+            println!("{}", summary_line(&summary));
+        }
+        Commands::Subscribe => {
+            let mut stdout = std::io::stdout();
+            for line in control::subscribe()?.lines() {
+                // Flush each event so pipes see it as it happens
+                writeln!(stdout, "{}", line?)?;
+                stdout.flush()?;
+            }
+        }
+        Commands::Recent { count } => {
+            let events = match control::request(&ControlRequest::Recent { count })? {
+                ControlResponse::Recent { events } => events,
+                response => return Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+            };
+            for event in events {
+                println!("{}", event);
+            }
+        }
+        Commands::Remote { host, remote_program, command } => {
+            let remotes: Vec<Remote> = host.iter().map(|host| Remote::new(host, &remote_program)).collect();
+            if let RemoteCommand::Watch = command {
+                return watch_remotes(remotes);
+            }
+            let mut failed = 0;
+            for remote in &remotes {
+                if remotes.len() > 1 {
+                    println!("{}", table::paint(&format!("== {} ==", remote.host), Style::Bold, color));
+                }
+                if let Err(e) = show_remote(remote, command, color) {
+                    eprintln!("{}: {:#}", remote.host, e);
+                    failed += 1;
+                }
+            }
+            if failed > 0 {
+                return Err(anyhow::anyhow!("{} of {} host(s) could not be queried", failed, remotes.len()));
+            }
+        }
+        Commands::Panic { lock } => {
+            let lock = match lock {
+                true => Some(read_passphrase("Passphrase to lock the upstream agent with: ")?),
+                false => None,
+            };
+            match control::request(&ControlRequest::Panic { lock })? {
+                ControlResponse::Panicked { connections, locked } => {
+                    println!("Panic mode: every socket refuses all requests, closed {} connection(s)", connections);
+                    if locked {
+                        println!("Locked the upstream agent");
+                    }
+                    println!("Run `ssh-agent-router resume` to restore normal policy");
+                }
+                response => return Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+            }
+        }
+        Commands::Resume { unlock } => {
+            let unlock = match unlock {
+                true => Some(read_passphrase("Passphrase to unlock the upstream agent: ")?),
+                false => None,
+            };
+            match control::request(&ControlRequest::Resume { unlock })? {
+                ControlResponse::Resumed { was_panicking, unlocked } => {
+                    if unlocked {
+                        println!("Unlocked the upstream agent");
+                    }
+                    match was_panicking {
+                        true => println!("Left panic mode, sockets apply their policy again"),
+                        false => println!("The router was not in panic mode"),
+                    }
+                }
+                response => return Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+            }
+        }
+        Commands::Enable { tag } => {
+            match control::request(&ControlRequest::Enable { tag: tag.clone() })? {
+                ControlResponse::Enabled { sockets } if sockets.is_empty() => {
+                    println!("Sockets tagged {:?} are already enabled", tag);
+                }
+                ControlResponse::Enabled { sockets } => println!("Enabled {}", sockets.join(", ")),
+                response => return Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+            }
+        }
+        Commands::Disable { tag } => {
+            match control::request(&ControlRequest::Disable { tag: tag.clone() })? {
+                ControlResponse::Disabled { sockets, .. } if sockets.is_empty() => {
+                    println!("Sockets tagged {:?} are already disabled", tag);
+                }
+                ControlResponse::Disabled { sockets, connections } => {
+                    println!("Disabled {}, closed {} connection(s)", sockets.join(", "), connections);
+                    println!("Run `ssh-agent-router enable --tag {}` to bring them back", tag);
+                }
+                response => return Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+            }
+        }
+        Commands::Clean { dry_run } => {
+            let config = load_config()?;
+            let stale = sweep::sweep(&sweep::candidates(&config)?, &[], dry_run);
+            for path in &stale {
+                println!("{} {}", if dry_run { "Stale" } else { "Removed" }, path.display());
+            }
+            if stale.is_empty() {
+                println!("No stale sockets found");
+            }
+        }
+        Commands::FlushApprovals { socket } => {
+            match control::request(&ControlRequest::FlushApprovals { socket })? {
+                ControlResponse::Flushed { approvals } => println!("Flushed {} cached approval(s)", approvals),
+                response => return Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+            }
+        }
+        Commands::Grants { command: GrantsCommand::List } => {
+            let config = load_config()?;
+            let grants = GrantUses::open(Config::grant_uses_path()?)?;
+            let mut any = false;
+            for socket in &config.sockets {
+                for allowed in &socket.allowed {
+                    let Some(max_uses) = allowed.max_uses else {
+                        continue;
+                    };
+                    let used = grants.used(&socket.name(), &allowed.key);
+                    let state = if used >= max_uses { ", used up" } else { "" };
+                    println!("{}: {} used {} of {} times{}", socket.name(), allowed.key, used, max_uses, state);
+                    any = true;
+                }
+            }
+            if !any {
+                println!("No count-limited grants configured");
+            }
+        }
+        Commands::Grants { command: GrantsCommand::Reset { socket, key } } => {
+            let config = load_config()?;
+            // Sockets are counted by name, which a path resolves to
+            let socket = socket.map(|s| config.find_socket(&s).map_or(s, |entry| entry.name()));
+            let grants = GrantUses::open(Config::grant_uses_path()?)?;
+            let cleared = grants.reset(socket.as_deref(), key.as_deref())?;
+            println!("Reset {} grant counter(s)", cleared);
+        }
+        Commands::PinKeys { fingerprints } => {
+            let config = load_config()?;
+            let agent = Agent::new(config.upstream.clone(), config.limits.clone());
+            let keys = agent.list_keys()?;
+
+            let selected: Vec<&SshKey> = if fingerprints.is_empty() {
+                keys.iter().collect()
+            } else {
+                fingerprints.iter()
+                    .map(|fp| keys.iter()
+                        .find(|k| &k.fingerprint == fp || &k.md5_fingerprint() == fp)
+                        .ok_or_else(|| anyhow::anyhow!("Key {} is not listed by the upstream agent", fp)))
+                    .collect::<Result<_>>()?
+            };
+
+            let pins = KeyPins::open(config.pinned_keys_path()?, false)?;
+            let added = pins.pin(&selected)?;
+            for fingerprint in &added {
+                println!("Pinned {}", fingerprint);
+            }
+            println!("{} key(s) newly pinned, {} pinned in {:?}", added.len(), pins.pinned().len(), pins.path());
+        }
+        Commands::Config { command: Some(ConfigCommand::Validate), .. } => {
+            let path = Config::config_path()?;
+            if !path.exists() {
+                return Err(anyhow::anyhow!("Config file {:?} not found", path).context(Failure::Config));
+            }
+            let config = match Config::read(&path).and_then(|config| config.validate().map(|()| config)) {
+                Ok(config) => {
+                    println!("ok   Config {:?}: {} socket(s)", path, config.sockets.len());
+                    config
+                }
+                Err(e) => {
+                    println!("FAIL Config {:?}: {:#}", path, e);
+                    return Err(anyhow::anyhow!("1 problem found").context(Failure::Config));
+                }
+            };
+
+            let mut problems = 0;
+            let now = std::time::SystemTime::now();
+            for socket in &config.sockets {
+                if let Err(e) = KeyFilter::from_entry(socket, &config) {
+                    println!("FAIL Socket {}: {:#}", socket.name(), e);
+                    problems += 1;
+                }
+                match config.socket_expiry(socket) {
+                    Some(expiry) if expiry <= now => {
+                        println!(
+                            "FAIL Socket {}: expired at {}, so it is skipped; remove it from the config",
+                            socket.name(), humantime::format_rfc3339_seconds(expiry)
+                        );
+                        problems += 1;
+                    }
+                    Some(expiry) => {
+                        let remaining = expiry.duration_since(now).unwrap_or_default();
+                        println!(
+                            "ok   Socket {}: expires in {}",
+                            socket.name(), humantime::format_duration(Duration::from_secs(remaining.as_secs()))
+                        );
+                    }
+                    None => {}
+                }
+            }
+
+            if problems > 0 {
+                return Err(anyhow::anyhow!("{} problem(s) found", problems).context(Failure::Config));
+            }
+        }
+        Commands::Config { command: Some(ConfigCommand::Effective), .. } => {
+            let config = match control::request(&ControlRequest::Config) {
+                Ok(ControlResponse::Config { config }) => *config,
+                Ok(ControlResponse::Error { message }) => return Err(anyhow::anyhow!(message)),
+                Ok(response) => return Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+                Err(e) => {
+                    eprintln!("{:#}; showing the config file instead", e);
+                    load_config()?
+                }
+            };
+            print!("{}", toml::to_string(&config.effective()).context("Failed to format config")?);
+        }
+        Commands::Config { command: Some(ConfigCommand::Diff), .. } => {
+            let running = match control::request(&ControlRequest::Config)? {
+                ControlResponse::Config { config } => config.effective(),
+                ControlResponse::Error { message } => return Err(anyhow::anyhow!(message)),
+                response => return Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+            };
+            let path = Config::config_path()?;
+            let mut on_disk = load_config()?;
+            // Without `upstream` in the file it comes from SSH_AUTH_SOCK,
+            // which differs between this shell and the router
+            let sets_upstream = std::fs::read_to_string(&path).ok()
+                .and_then(|content| content.parse::<toml::Table>().ok())
+                .is_some_and(|table| table.contains_key("upstream"));
+            if !sets_upstream {
+                on_disk.upstream = running.upstream.clone();
+            }
+
+            let running = toml::to_string(&running).context("Failed to format config")?;
+            let on_disk = toml::to_string(&on_disk.effective()).context("Failed to format config")?;
+            match diff::unified(&running, &on_disk) {
+                None => println!("The router is running with {:?} as it is", path),
+                Some(diff) => {
+                    println!("--- running router");
+                    println!("+++ {}", path.display());
+                    print!("{}", diff);
+                    println!("Send SIGHUP to the router to load the file");
+                }
+            }
+        }
+        Commands::Config { command: Some(ConfigCommand::Rollback { change }), .. } => {
+            let path = Config::config_path()?;
+            let backup = Config::backups(&path)?
+                .pop()
+                .with_context(|| format!("No backups of {:?} in {:?}", path, Config::backup_dir(&path)))?;
+            let restored = std::fs::read_to_string(&backup).with_context(|| format!("Failed to read {:?}", backup))?;
+            print_file_change(&path, &read_if_exists(&path)?, &restored);
+            if !confirm_change(&change, &i18n::format("change-restore", &[
+                ("path", &format_args!("{:?}", path)),
+                ("backup", &format_args!("{:?}", backup)),
+            ]))? {
+                return Ok(());
+            }
+            let backup = Config::rollback(&path)?;
+            println!("Restored {:?} from {:?}", path, backup);
+            let left = Config::backups(&path)?.len();
+            println!("{} older backup(s) left; send SIGHUP to the router to load the file", left);
+        }
+        Commands::Config { command: Some(ConfigCommand::Migrate { change }), .. } => {
+            let path = Config::config_path()?;
+            let Some((from, content)) = Config::migrated(&path)? else {
+                println!("{:?} is already at version {}", path, config::CONFIG_VERSION);
+                return Ok(());
+            };
+            print_file_change(&path, &read_if_exists(&path)?, &content);
+            if !confirm_change(&change, &i18n::format("change-migrate", &[
+                ("path", &format_args!("{:?}", path)),
+                ("version", &config::CONFIG_VERSION),
+            ]))? {
+                return Ok(());
+            }
+            Config::write_file(&path, &content)?;
+            if from == config::CONFIG_VERSION {
+                println!("Added version = {} to {:?}", from, path);
+            } else {
+                println!("Rewrote {:?} from version {} to {}", path, from, config::CONFIG_VERSION);
+                println!("The previous file is in {:?}", Config::backup_dir(&path));
+            }
+        }
+        Commands::Config { command: Some(ConfigCommand::CheckPublic), .. } => {
+            let path = Config::config_path()?;
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read config file {:?}", path))
+                .context(Failure::Config)?;
+            let findings = scrub::check(&content)
+                .with_context(|| format!("Failed to check {:?}", path))
+                .context(Failure::Config)?;
+            for finding in &findings {
+                let level = if finding.secret { "FAIL" } else { "warn" };
+                println!("{} {}: {}", level, finding.key, finding.problem);
+            }
+            let secrets = findings.iter().filter(|finding| finding.secret).count();
+            if secrets > 0 {
+                return Err(anyhow::anyhow!(
+                    "{} secret(s) in {:?}; move them to a private file with `config scrub`", secrets, path
+                ).context(Failure::Config));
+            }
+            match findings.len() {
+                0 => println!("ok   {:?} is safe to publish", path),
+                n => println!("ok   {:?} holds no secrets, but {} personal detail(s); `config scrub --placeholders` replaces them", path, n),
+            }
+        }
+        Commands::Config { command: Some(ConfigCommand::Scrub { output, private, placeholders, change }), .. } => {
+            let path = Config::config_path()?;
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read config file {:?}", path))?;
+            let table: toml::Table = toml::from_str(&content)
+                .with_context(|| format!("Failed to parse config file {:?}", path))?;
+            let output = output.unwrap_or_else(|| path.clone());
+            let dir = output.parent().unwrap_or(std::path::Path::new("")).to_path_buf();
+            let private = private.unwrap_or_else(|| dir.join("private.toml"));
+            // Includes are relative to the including file
+            let include = match private.strip_prefix(&dir) {
+                Ok(name) if !dir.as_os_str().is_empty() => name.display().to_string(),
+                _ => std::path::absolute(&private)?.display().to_string(),
+            };
+
+            let scrubbed = scrub::scrub(&table, placeholders, &include);
+            if scrubbed.private.is_empty() {
+                println!("Nothing in {:?} to keep private", path);
+                return Ok(());
+            }
+            let mut private_table: toml::Table = toml::from_str(&read_if_exists(&private)?)
+                .with_context(|| format!("Failed to parse {:?}", private))?;
+            config::merge_overlay(&mut private_table, scrubbed.private);
+            let public_content = toml::to_string_pretty(&scrubbed.public).context("Failed to serialize config")?;
+            let private_content = toml::to_string_pretty(&private_table).context("Failed to serialize config")?;
+
+            print_file_change(&output, &read_if_exists(&output)?, &public_content);
+            print_file_change(&private, &read_if_exists(&private)?, &private_content);
+            if !confirm_change(&change, i18n::text("change-write"))? {
+                return Ok(());
+            }
+            // The private file first, so the public one never includes a
+            // file that is not there
+            write_replacing(&private, &private_content)?;
+            Config::write_file(&output, &public_content)?;
+            println!("Wrote {:?} to publish and {:?} to keep private", output, private);
+            let left = scrub::check(&public_content)?.len();
+            if left > 0 {
+                println!("{} personal detail(s) left in {:?}; `config check-public` lists them", left, output);
+            }
+        }
+        Commands::Config { enhanced, command: None } => {
             println!("Configuration editor");
             if enhanced {
                 println!("Enhanced mode enabled");
             }
             
-            let config = Config::load()?;
-            let agent = Agent::new(config.upstream.clone());
+            let config = load_config()?;
+            let agent = Agent::new(config.upstream.clone(), config.limits.clone());
             
             // Show current configuration
             println!("\nCurrent configuration:");
@@ -190,14 +1547,50 @@ async fn handle_command(command: Commands) -> Result<()> {
             println!("For now, please edit the configuration file manually at:");
             println!("{:?}", Config::config_path()?);
         }
-        Commands::Upgrade { auto_upgrade } => {
+        Commands::Bugreport { output } => {
+            let bundle = diagnostics::latest_bundle(&diagnostics::crash_dir()?)?;
+            // A broken config is often what is being reported
+            let config = load_config().map_err(|e| eprintln!("Leaving out the configuration: {:#}", e)).ok();
+            let report = diagnostics::bug_report(config.as_ref(), bundle.as_deref())?;
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, report).with_context(|| format!("Failed to write {:?}", path))?;
+                    println!("Wrote bug report to {:?}", path);
+                    match bundle {
+                        Some(bundle) => println!("Included crash diagnostics from {:?}", bundle),
+                        None => println!("No crash diagnostics found"),
+                    }
+                }
+                None => print!("{}", report),
+            }
+        }
+        Commands::Version { json } => {
+            let info = ssh_agent_router::version::BuildInfo::current();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&info)?);
+                return Ok(());
+            }
+            println!("{}", info.summary());
+            let features = if info.features.is_empty() { "none".to_string() } else { info.features.join(", ") };
+            println!("Features: {}", features);
+            println!("Extensions: {}", info.extensions.join(", "));
+        }
+        Commands::Upgrade { auto_upgrade, change } => {
+            if change.dry_run {
+                println!("Dry run: self-upgrade is not implemented yet, so nothing would change");
+                return Ok(());
+            }
             println!("Upgrade command");
             if auto_upgrade {
                 println!("Auto-upgrade enabled");
             }
             println!("Note: Self-upgrade functionality is planned for future releases.");
         }
-        Commands::RegisterAutostart => {
+        Commands::RegisterAutostart { change } => {
+            if change.dry_run {
+                println!("Dry run: auto-start registration is not implemented yet, so nothing would be registered");
+                return Ok(());
+            }
             println!("Register auto-start");
             println!("Note: Auto-start registration is planned for future releases.");
             #[cfg(target_os = "macos")]
@@ -207,11 +1600,609 @@ async fn handle_command(command: Commands) -> Result<()> {
             #[cfg(target_os = "windows")]
             println!("On Windows, this will use the Task Scheduler.");
         }
-        Commands::UnregisterAutostart => {
+        Commands::UnregisterAutostart { change } => {
+            if change.dry_run {
+                println!("Dry run: auto-start registration is not implemented yet, so nothing would be removed");
+                return Err(Failure::NotRegistered.into());
+            }
             println!("Unregister auto-start");
             println!("Note: Auto-start unregistration is planned for future releases.");
+            // Nothing can have been registered yet
+            return Err(Failure::NotRegistered.into());
+        }
+        Commands::Corpus { command: CorpusCommand::Add { files, socket, dir } } => {
+            let config = load_config()?;
+            let entry = configured_socket(&config, socket.as_deref())?;
+            let dir = match dir {
+                Some(dir) => dir,
+                None => state::data_dir()?.join("corpus"),
+            };
+            state::create_private_dir(&dir)?;
+            let upstream_keys = Agent::new(config.upstream.clone(), config.limits.clone())
+                .list_keys()
+                .context(Failure::Upstream)?;
+
+            for file in &files {
+                let request = capture::load_request(file)?;
+                let captured = capture::paired_response(file).map(|path| capture::load_response(&path)).transpose()?;
+                let source = file.file_name().unwrap_or_default().to_string_lossy().to_string();
+                let (fixture, matches) = Fixture::record(&source, &request, captured.as_deref(), entry, &config, &upstream_keys)
+                    .with_context(|| format!("Cannot make a fixture of {:?}", file))?;
+                let path = dir.join(file.with_extension("toml").file_name().unwrap_or_default());
+                std::fs::write(&path, fixture.to_toml()?).with_context(|| format!("Failed to write {:?}", path))?;
+                println!("Wrote {:?}", path);
+                if !matches {
+                    println!(
+                        "     The filters now answer differently than when {:?} was captured; the fixture keeps the current answer",
+                        file
+                    );
+                }
+            }
+        }
+        Commands::Corpus { command: CorpusCommand::Check { dir } } => {
+            let dir = match dir {
+                Some(dir) => dir,
+                None => state::data_dir()?.join("corpus"),
+            };
+            let paths = corpus::fixtures(&dir)?;
+            let mut failed = 0;
+            for path in &paths {
+                let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                match Fixture::load(path).and_then(|fixture| fixture.check()) {
+                    Ok(None) => println!("ok   {}", name),
+                    Ok(Some(difference)) => {
+                        println!("FAIL {}: {}", name, difference);
+                        failed += 1;
+                    }
+                    Err(e) => {
+                        println!("FAIL {}: {:#}", name, e);
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                anyhow::bail!("{} of {} fixtures failed", failed, paths.len());
+            }
+            println!("{} fixture(s) replayed", paths.len());
+        }
+        Commands::Conformance { socket, timeout, json } => {
+            let socket = match socket {
+                Some(socket) => socket,
+                None => std::env::var_os("SSH_AUTH_SOCK")
+                    .map(Into::into)
+                    .context("SSH_AUTH_SOCK is not set, choose a socket with --socket")?,
+            };
+            let checks = conformance::run(&socket, timeout)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&checks)?);
+            } else {
+                println!("Agent protocol conformance of {:?}", socket);
+                let header = ["column-check", "column-result", "column-detail"].map(i18n::text);
+                let mut rows = Table::new(&header, color);
+                for check in &checks {
+                    let (verdict, style) = match check.verdict {
+                        Verdict::Pass => ("pass", Style::Green),
+                        Verdict::Warn => ("warn", Style::Yellow),
+                        Verdict::Fail => ("FAIL", Style::Red),
+                    };
+                    rows.styled_row([
+                        (check.name.to_string(), Style::Plain),
+                        (verdict.to_string(), style),
+                        (check.detail.clone(), Style::Plain),
+                    ]);
+                }
+                print!("{}", rows.render());
+            }
+            let failed = checks.iter().filter(|check| check.verdict == Verdict::Fail).count();
+            if failed > 0 {
+                anyhow::bail!("{} of {} checks failed", failed, checks.len());
+            }
+        }
+        Commands::Replay { file, socket, live } => {
+            let config = load_config()?;
+            let entry = configured_socket(&config, socket.as_deref())?;
+
+            let request = capture::load_request(&file)?;
+            println!("Replaying {:?} through {:?}", file, entry.path);
+            print_message("Request", &request);
+
+            if live {
+                replay_live(&request, entry, &config)?;
+            } else {
+                replay_offline(&file, &request, entry, &config)?;
+            }
         }
     }
     
     Ok(())
 }
+
+fn print_exposure_matrix(keys: &[SshKey], filters: &[(String, KeyFilter)], long: bool, color: bool) {
+    let mut header = vec![i18n::text("column-key"), i18n::text("column-type")];
+    header.extend(filters.iter().map(|(name, _)| name.as_str()));
+    let mut rows = Table::new(&header, color);
+    for key in keys {
+        let mut row = vec![
+            (if long { key.fingerprint.clone() } else { table::short_fingerprint(&key.fingerprint) }, Style::Bold),
+            (key.key_type.clone(), Style::Plain),
+        ];
+        row.extend(filters.iter().map(|(_, filter)| match filter.is_allowed(key) {
+            true => ("✓".to_string(), Style::Green),
+            false => ("-".to_string(), Style::Dim),
+        }));
+        rows.styled_row(row);
+    }
+    print!("{}", rows.render());
+}
+
+/// Evaluate a captured request against the socket's filters without
+/// contacting the upstream agent
+/// The configured socket at `path`, or the only one there is
+fn configured_socket<'a>(config: &'a Config, path: Option<&std::path::Path>) -> Result<&'a config::SocketEntry> {
+    match path {
+        Some(path) => config.sockets.iter()
+            .find(|s| s.path == path)
+            .ok_or_else(|| anyhow::anyhow!("No configured socket at {:?}", path)),
+        None if config.sockets.len() == 1 => Ok(&config.sockets[0]),
+        None => Err(anyhow::anyhow!("{} sockets configured, choose one with --socket", config.sockets.len())),
+    }
+}
+
+fn replay_offline(file: &std::path::Path, request: &[u8], entry: &config::SocketEntry, config: &Config) -> Result<()> {
+    let filter = KeyFilter::from_entry(entry, config)?;
+
+    match protocol::message_type(request) {
+        Some(protocol::SSH_AGENTC_SIGN_REQUEST) => {
+            let mut reader = protocol::Reader::new(request);
+            reader.read_u32()?;
+            reader.read_u8()?;
+            let blob = reader.read_string()?;
+            if blob.iter().all(|b| *b == 0) {
+                println!("Key blob was redacted at capture time, cannot evaluate the filter");
+                return Ok(());
+            }
+
+            let key = SshKey::from_key_blob(blob.to_vec(), String::new());
+            let verdict = filter.evaluate(&key);
+            println!(
+                "Sign with {} ({}): {} ({}){}",
+                key.fingerprint,
+                key.key_type,
+                if verdict.is_allowed() { "allowed" } else { "denied" },
+                verdict.reason(),
+                verdict.deny_reason().map(|code| format!(" [{}]", code)).unwrap_or_default()
+            );
+            if !verdict.is_allowed() {
+                return Err(Failure::Denied.into());
+            }
+        }
+        Some(protocol::SSH_AGENTC_REQUEST_IDENTITIES) => {
+            // The captured response holds the keys the client saw back then
+            let Some(response_path) = capture::paired_response(file) else {
+                println!("No captured response next to the request; use --live to ask the upstream agent");
+                return Ok(());
+            };
+            let response = capture::load_response(&response_path)?;
+            if protocol::message_type(&response) != Some(protocol::SSH_AGENT_IDENTITIES_ANSWER) {
+                print_message("Captured response", &response);
+                return Ok(());
+            }
+
+            let keys = ssh_agent_router::agent::parse_identities_answer(&response[4..])?;
+            println!("Keys in captured response {:?}:", response_path);
+            for (i, key) in keys.iter().enumerate() {
+                let verdict = filter.evaluate(key);
+                println!(
+                    "  {}. {} ({}) {}: {}{}",
+                    i + 1,
+                    key.fingerprint,
+                    key.key_type,
+                    if verdict.is_allowed() { "exposed" } else { "hidden" },
+                    verdict.reason(),
+                    verdict.deny_reason().map(|code| format!(" [{}]", code)).unwrap_or_default()
+                );
+            }
+        }
+        _ => println!("Not filtered, would be forwarded unchanged"),
+    }
+
+    Ok(())
+}
+
+/// Send a captured request through the socket's pipeline to the upstream agent
+fn replay_live(request: &[u8], entry: &config::SocketEntry, config: &Config) -> Result<()> {
+    if protocol::message_type(request) == Some(protocol::SSH_AGENTC_SIGN_REQUEST) {
+        let mut reader = protocol::Reader::new(request);
+        reader.read_u32()?;
+        reader.read_u8()?;
+        if reader.read_string()?.iter().all(|b| *b == 0) {
+            return Err(anyhow::anyhow!("Key blob was redacted at capture time, refusing to forward"));
+        }
+    }
+
+    let mut entry = entry.clone();
+    entry.debug_capture = None;
+    let agent = Agent::new(config.upstream.clone(), config.limits.clone());
+    let filtered_socket = FilteredSocket::new(&entry, agent, config)?;
+
+    let response = filtered_socket.process_request(request, &RequestContext::new(&entry.name(), Default::default(), request))?;
+    print_message("Response", &response);
+    if let Some(reason) = protocol::failure_reason(&response) {
+        println!("  {}", reason);
+    }
+    if matches!(
+        protocol::message_type(&response),
+        Some(protocol::SSH_AGENT_FAILURE | protocol::SSH_AGENT_EXTENSION_FAILURE)
+    ) {
+        return Err(Failure::Denied.into());
+    }
+
+    if protocol::message_type(&response) == Some(protocol::SSH_AGENT_IDENTITIES_ANSWER) {
+        let keys = ssh_agent_router::agent::parse_identities_answer(&response[4..])?;
+        for (i, key) in keys.iter().enumerate() {
+            println!("  {}. {} ({})", i + 1, key.fingerprint, key.key_type);
+        }
+    }
+
+    Ok(())
+}
+
+/// The config file, failing with [`Failure::Config`]
+fn load_config() -> Result<Config> {
+    let config = Config::load().context(Failure::Config)?;
+    i18n::set(config.ui.language.as_deref());
+    Ok(config)
+}
+
+/// The project file governing `dir`, or the current directory
+fn find_project(dir: Option<std::path::PathBuf>) -> Result<config::ProjectConfig> {
+    let dir = match dir {
+        Some(dir) => std::path::absolute(expand_tilde(&dir))?,
+        None => std::env::current_dir().context("Failed to get the current directory")?,
+    };
+    config::ProjectConfig::find(&dir)?
+        .with_context(|| format!("No {} in {:?} or above", config::PROJECT_FILE, dir))
+}
+
+/// Run git in `repo`, returning its output
+fn git(repo: &std::path::Path, args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .output()
+        .context("Failed to run git")?;
+    if !output.status.success() {
+        anyhow::bail!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Moves a socket file back to where `attach` found it, however the
+/// command ends
+struct RestoreOnDrop {
+    from: std::path::PathBuf,
+    to: std::path::PathBuf,
+}
+
+impl Drop for RestoreOnDrop {
+    fn drop(&mut self) {
+        // Renaming over the router's socket replaces it in one step
+        if let Err(e) = std::fs::rename(&self.from, &self.to) {
+            eprintln!("Failed to move {:?} back to {:?}: {}", self.from, self.to, e);
+        }
+    }
+}
+
+/// Contents of `path`, empty if there is no such file
+/// The running router's state in one line, for `summary`
+fn summary_line(summary: &Summary) -> String {
+    let upstream = match (summary.upstream, summary.upstream_keys) {
+        (UpstreamHealth::Ok, Some(keys)) => format!("upstream ok ({} keys)", keys),
+        (UpstreamHealth::Ok, None) => "upstream ok".to_string(),
+        (UpstreamHealth::Down, _) => "upstream down".to_string(),
+        (UpstreamHealth::Unknown, _) => "upstream not used yet".to_string(),
+    };
+    let mut line = format!(
+        "{} sockets, {} connected, {}",
+        summary.sockets.len(), summary.active_connections, upstream
+    );
+    if summary.panic {
+        line.insert_str(0, "PANIC, ");
+    }
+    if let Some(denied) = &summary.last_denied {
+        line.push_str(&format!(
+            ", denied {} on {} {} ago",
+            denied.fingerprint, denied.socket, format_age(denied.at)
+        ));
+    }
+    line
+}
+
+/// Print what `remote status` or `remote list-keys` asks a remote router
+fn show_remote(remote: &Remote, command: RemoteCommand, color: bool) -> Result<()> {
+    let unexpected = |response| anyhow::anyhow!("Unexpected response from router: {:?}", response);
+    match command {
+        RemoteCommand::Status => {
+            let [summary, stats] = <[ControlResponse; 2]>::try_from(
+                remote.requests(&[ControlRequest::Summary, ControlRequest::Stats])?
+            ).expect("one response per request");
+            let summary = match summary {
+                ControlResponse::Summary { summary } => summary,
+                response => return Err(unexpected(response)),
+            };
+            let sockets = match stats {
+                ControlResponse::Stats { sockets } => sockets,
+                response => return Err(unexpected(response)),
+            };
+            println!("{}", summary_line(&summary));
+            let header = [
+                "column-name", "column-path", "column-active", "column-accepted", "column-requests",
+                "column-denied-signs", "column-errors", "column-upstream-errors",
+            ].map(i18n::text);
+            let mut rows = Table::new(&header, color);
+            for status in &sockets {
+                let stats = &status.stats;
+                let mut row = vec![(status.name.clone(), Style::Bold), (status.path.display().to_string(), Style::Plain)];
+                row.extend([
+                    stats.active, stats.accepted, stats.requests, stats.denied_sign_count(), stats.errors, stats.upstream_errors,
+                ].map(|n| (n.to_string(), Style::Plain)));
+                rows.styled_row(row);
+            }
+            print!("{}", rows.render());
+        }
+        RemoteCommand::ListKeys { long } => {
+            let [upstream, stats] = <[ControlResponse; 2]>::try_from(
+                remote.requests(&[ControlRequest::Upstream, ControlRequest::Stats])?
+            ).expect("one response per request");
+            let snapshot = match upstream {
+                ControlResponse::Upstream { snapshot: Some(snapshot) } => snapshot,
+                ControlResponse::Upstream { snapshot: None } => {
+                    println!("The router has not listed its upstream agent's keys yet");
+                    return Ok(());
+                }
+                response => return Err(unexpected(response)),
+            };
+            let sockets = match stats {
+                ControlResponse::Stats { sockets } => sockets,
+                response => return Err(unexpected(response)),
+            };
+            println!("{}", i18n::format("status-last-seen", &[("age", &format_age(snapshot.seen_at))]));
+            let header = ["column-key", "column-type", "column-comment", "column-last-used"].map(i18n::text);
+            let mut rows = Table::new(&header, color);
+            for key in &snapshot.keys {
+                let last_used = sockets.iter()
+                    .filter_map(|s| s.stats.last_signed.get(&key.fingerprint).map(|t| (*t, &s.name)))
+                    .max();
+                rows.styled_row([
+                    (if long { key.fingerprint.clone() } else { table::short_fingerprint(&key.fingerprint) }, Style::Bold),
+                    (key.key_type.clone(), Style::Plain),
+                    (key.comment.clone(), Style::Plain),
+                    match last_used {
+                        Some((time, socket)) => (
+                            i18n::format("status-last-used", &[("age", &format_age(time)), ("socket", socket)]),
+                            Style::Plain,
+                        ),
+                        None => (i18n::text("status-never-used").to_string(), Style::Dim),
+                    },
+                ]);
+            }
+            print!("{}", rows.render());
+        }
+        RemoteCommand::Watch => unreachable!("watched by watch_remotes"),
+    }
+    Ok(())
+}
+
+/// Print the events of routers on several hosts as they happen, each with
+/// a `host` field, until every connection is gone
+fn watch_remotes(remotes: Vec<Remote>) -> Result<()> {
+    let (sender, receiver) = std::sync::mpsc::channel::<String>();
+    let hosts = remotes.len();
+    for remote in remotes {
+        let sender = sender.clone();
+        std::thread::spawn(move || {
+            let watch = || -> Result<()> {
+                let mut stream = remote.subscribe()?;
+                for line in (&mut stream.reader).lines() {
+                    let mut event: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&line?)
+                        .context("Invalid event from router")?;
+                    event.insert("host".to_string(), remote.host.clone().into());
+                    if sender.send(serde_json::to_string(&event)?).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            };
+            match watch() {
+                Ok(()) => eprintln!("{}: connection closed", remote.host),
+                Err(e) => eprintln!("{}: {:#}", remote.host, e),
+            }
+        });
+    }
+    drop(sender);
+
+    let mut stdout = std::io::stdout();
+    for line in receiver {
+        // Flush each event so pipes see it as it happens
+        writeln!(stdout, "{}", line)?;
+        stdout.flush()?;
+    }
+    Err(anyhow::anyhow!("Lost the connection to {} host(s)", hosts))
+}
+
+fn read_if_exists(path: &std::path::Path) -> Result<String> {
+    match std::fs::read_to_string(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        result => result.with_context(|| format!("Failed to read {:?}", path)),
+    }
+}
+
+/// Replace `path` with `content` in one step, keeping its permissions
+/// (`0600` for a new file)
+fn write_replacing(path: &std::path::Path, content: &str) -> Result<()> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let mode = std::fs::metadata(path).map(|m| m.permissions().mode() & 0o777).unwrap_or(0o600);
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&tmp)
+        .with_context(|| format!("Failed to write {:?}", tmp))?;
+    file.write_all(content.as_bytes()).with_context(|| format!("Failed to write {:?}", tmp))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to replace {:?}", path))
+}
+
+/// `text` in single quotes for a POSIX shell
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// Print how a file changes, as a diff against what it holds now
+fn print_file_change(path: &std::path::Path, old: &str, new: &str) {
+    match diff::unified(old, new) {
+        None => println!("{:?} would not change", path),
+        Some(diff) => {
+            println!("--- {}", path.display());
+            println!("+++ {}", path.display());
+            print!("{}", diff);
+        }
+    }
+}
+
+/// Whether to make a change just shown to the user: never with
+/// `--dry-run`, always with `--yes`, and otherwise if they agree on the
+/// terminal
+fn confirm_change(change: &Change, question: &str) -> Result<bool> {
+    use std::io::IsTerminal;
+
+    if change.dry_run {
+        println!("{}", i18n::text("change-dry-run"));
+        return Ok(false);
+    }
+    if change.yes {
+        return Ok(true);
+    }
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!(i18n::format("change-needs-yes", &[("question", &question)]));
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer).context("Failed to read the answer")?;
+    let agreed = matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes");
+    if !agreed {
+        println!("{}", i18n::text("change-declined"));
+    }
+    Ok(agreed)
+}
+
+/// Ask for a passphrase on the terminal without echoing it, or read one
+/// line from stdin when it is not a terminal
+fn read_passphrase(prompt: &str) -> Result<String> {
+    use std::os::fd::AsRawFd;
+
+    let stdin = std::io::stdin();
+    let fd = stdin.as_raw_fd();
+    let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+    // SAFETY: tcgetattr only writes to the termios it is given
+    let saved = match unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } {
+        0 => Some(unsafe { termios.assume_init() }),
+        _ => None,
+    };
+
+    if let Some(saved) = saved {
+        eprint!("{}", prompt);
+        let mut quiet = saved;
+        quiet.c_lflag &= !libc::ECHO;
+        // SAFETY: fd is stdin, and quiet is a valid termios copied from it
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &quiet) };
+    }
+    let mut line = String::new();
+    let read = stdin.lock().read_line(&mut line);
+    if let Some(saved) = saved {
+        // SAFETY: restores the settings read above
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+        eprintln!();
+    }
+    read.context("Failed to read the passphrase")?;
+
+    let passphrase = line.trim_end_matches(['\r', '\n']).to_string();
+    if passphrase.is_empty() {
+        anyhow::bail!("The passphrase is empty");
+    }
+    Ok(passphrase)
+}
+
+fn print_message(label: &str, message: &[u8]) {
+    match protocol::message_type(message) {
+        Some(msg_type) => println!(
+            "{}: {} ({}), {} bytes",
+            label,
+            protocol::message_type_name(msg_type),
+            msg_type,
+            message.len() - 4
+        ),
+        None => println!("{}: empty message", label),
+    }
+}
+
+/// Time elapsed since a unix timestamp, rounded to seconds
+fn format_age(unix_secs: u64) -> String {
+    humantime::format_duration(Duration::from_secs(unix_now().saturating_sub(unix_secs))).to_string()
+}
+
+/// Time left until a unix timestamp, rounded to seconds
+fn format_remaining(unix_secs: u64) -> String {
+    humantime::format_duration(Duration::from_secs(unix_secs.saturating_sub(unix_now()))).to_string()
+}
+
+/// A key's age in whole days, or in minutes when younger than a day
+fn format_key_age(age: Duration) -> String {
+    match age.as_secs() / 86400 {
+        0 => humantime::format_duration(Duration::from_secs(age.as_secs() / 60 * 60)).to_string(),
+        1 => "1 day".to_string(),
+        days => format!("{} days", days),
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Print what happened while the router was running, and log it
+fn print_summary(uptime: Duration, router: &Router) {
+    let uptime = humantime::format_duration(Duration::from_secs(uptime.as_secs()));
+    println!("\nSummary (uptime {}):", uptime);
+    log::info!("Uptime {}", uptime);
+
+    for socket in router.sockets() {
+        let stats = socket.stats().snapshot();
+        println!(
+            "  {}: {} requests, {} connections, {} denied signs, {} upstream errors",
+            socket.name(),
+            stats.requests,
+            stats.accepted,
+            stats.denied_sign_count(),
+            stats.upstream_errors
+        );
+        for (fingerprint, count) in &stats.denied_signs {
+            println!("    denied {} x{}", privacy::fingerprint(fingerprint), count);
+        }
+
+        log::info!(
+            "{}: requests={} connections={} denied_signs={} upstream_errors={} denied={:?}",
+            socket.name(),
+            stats.requests,
+            stats.accepted,
+            stats.denied_sign_count(),
+            stats.upstream_errors,
+            stats.denied_signs
+        );
+    }
+}
@@ -0,0 +1,108 @@
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+use std::os::unix::io::AsRawFd;
+use std::fmt;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+/// Credentials of the client process on the other end of a Unix socket
+///
+/// Fields are `None` when the platform cannot tell.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerCred {
+    pub pid: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// Short description of the client for prompts and log lines
+impl fmt::Display for PeerCred {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.pid, self.uid) {
+            (Some(pid), Some(uid)) => write!(f, "pid {} (uid {})", pid, uid),
+            (Some(pid), None) => write!(f, "pid {}", pid),
+            (None, Some(uid)) => write!(f, "uid {}", uid),
+            (None, None) => write!(f, "unknown client"),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn peer_cred(stream: &UnixStream) -> PeerCred {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: cred and len describe a valid, writable ucred
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return PeerCred::default();
+    }
+
+    PeerCred {
+        pid: (cred.pid > 0).then_some(cred.pid as u32),
+        uid: Some(cred.uid),
+        gid: Some(cred.gid),
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub fn peer_cred(stream: &UnixStream) -> PeerCred {
+    let fd = stream.as_raw_fd();
+
+    let mut pid: libc::pid_t = 0;
+    let mut len = std::mem::size_of::<libc::pid_t>() as libc::socklen_t;
+    // SAFETY: pid and len describe a valid, writable pid_t
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_LOCAL,
+            libc::LOCAL_PEERPID,
+            &mut pid as *mut libc::pid_t as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    // SAFETY: uid and gid are valid, writable locations
+    let ids = unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } == 0;
+
+    PeerCred {
+        pid: (ret == 0 && pid > 0).then_some(pid as u32),
+        uid: ids.then_some(uid),
+        gid: ids.then_some(gid),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios")))]
+pub fn peer_cred(_stream: &UnixStream) -> PeerCred {
+    PeerCred::default()
+}
+
+/// Executable of a process, if the platform reports it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn process_path(pid: u32) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{}/exe", pid)).ok()
+}
+
+/// Executable of a process, if the platform reports it
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub fn process_path(pid: u32) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut buf = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+    // SAFETY: buf is valid and writable for the size passed
+    let len = unsafe { libc::proc_pidpath(pid as libc::c_int, buf.as_mut_ptr() as *mut libc::c_void, buf.len() as u32) };
+    (len > 0).then(|| PathBuf::from(std::ffi::OsStr::from_bytes(&buf[..len as usize])))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios")))]
+pub fn process_path(_pid: u32) -> Option<PathBuf> {
+    None
+}
@@ -0,0 +1,256 @@
+use crate::agent::SshKey;
+use crate::privacy;
+use crate::state;
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Upstream fingerprints remembered across restarts
+///
+/// The first time the upstream agent is seen its keys are pinned as they
+/// are. After that a key that appears or disappears is logged once. Without
+/// `enforce` a new key is pinned right after the warning; with it, the key
+/// stays hidden until acknowledged with `pin-keys`. Each pin records when
+/// the key was first seen, which `max_key_age` is measured from.
+pub struct KeyPins {
+    path: PathBuf,
+    enforce: bool,
+    state: Mutex<PinState>,
+}
+
+/// A pinned key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pin {
+    pub comment: String,
+    /// When the key was pinned, `None` for pins written before dates were
+    /// recorded until the upstream agent lists the key again
+    pub first_seen: Option<SystemTime>,
+}
+
+impl Pin {
+    fn new(comment: &str) -> Self {
+        Self { comment: comment.to_string(), first_seen: Some(SystemTime::now()) }
+    }
+}
+
+#[derive(Default)]
+struct PinState {
+    /// Pinned fingerprint to its pin
+    pinned: BTreeMap<String, Pin>,
+    /// Modification time of the pin file when it was last read
+    loaded: Option<SystemTime>,
+    /// Whether the pin file existed when it was last read
+    exists: bool,
+    /// Unpinned keys already warned about
+    reported_new: BTreeSet<String>,
+    /// Pinned keys already reported missing
+    reported_missing: BTreeSet<String>,
+    /// Bumped whenever the pinned set may have changed
+    generation: u64,
+}
+
+impl KeyPins {
+    pub fn open(path: PathBuf, enforce: bool) -> Result<Self> {
+        let pins = Self {
+            path,
+            enforce,
+            state: Mutex::new(PinState::default()),
+        };
+        pins.refresh(&mut pins.state.lock().unwrap())?;
+        Ok(pins)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Compare the keys upstream just listed against the pinned set
+    pub fn observe(&self, keys: &[SshKey]) {
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = self.refresh(&mut state) {
+            log::warn!("Failed to read pinned keys: {:#}", e);
+        }
+
+        // Trust on first use
+        if !state.exists {
+            state.pinned = keys.iter().map(|k| (k.fingerprint.clone(), Pin::new(&k.comment))).collect();
+            state.generation += 1;
+            log::info!("Pinned {} upstream key(s) in {:?}", keys.len(), self.path);
+            self.save(&mut state);
+            return;
+        }
+
+        let mut changed = false;
+        for key in keys {
+            if let Some(pin) = state.pinned.get_mut(&key.fingerprint) {
+                // Undated pins are dated from the first listing after the upgrade
+                if pin.first_seen.is_none() {
+                    pin.first_seen = Some(SystemTime::now());
+                    changed = true;
+                }
+                continue;
+            }
+            if state.reported_new.insert(key.fingerprint.clone()) {
+                if self.enforce {
+                    log::warn!(
+                        "New upstream key appeared: {}, hidden until acknowledged with `pin-keys`",
+                        privacy::key(&key.fingerprint, &key.comment)
+                    );
+                } else {
+                    log::warn!("New upstream key appeared: {}", privacy::key(&key.fingerprint, &key.comment));
+                }
+            }
+            if !self.enforce {
+                state.pinned.insert(key.fingerprint.clone(), Pin::new(&key.comment));
+                changed = true;
+            }
+        }
+
+        let listed: BTreeSet<&str> = keys.iter().map(|k| k.fingerprint.as_str()).collect();
+        let PinState { pinned, reported_missing, .. } = &mut *state;
+        for (fingerprint, pin) in pinned.iter() {
+            if listed.contains(fingerprint.as_str()) {
+                reported_missing.remove(fingerprint);
+            } else if reported_missing.insert(fingerprint.clone()) {
+                log::warn!("Pinned upstream key disappeared: {}", privacy::key(fingerprint, &pin.comment));
+            }
+        }
+
+        if changed {
+            state.generation += 1;
+            self.save(&mut state);
+        }
+    }
+
+    /// Whether a key may be exposed
+    pub fn allows(&self, key: &SshKey) -> bool {
+        if !self.enforce {
+            return true;
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = self.refresh(&mut state) {
+            log::warn!("Failed to read pinned keys: {:#}", e);
+        }
+        state.pinned.contains_key(&key.fingerprint)
+    }
+
+    /// Pin keys, returning the fingerprints that were not pinned before
+    pub fn pin(&self, keys: &[&SshKey]) -> Result<Vec<String>> {
+        let mut state = self.state.lock().unwrap();
+        self.refresh(&mut state)?;
+
+        let mut added = Vec::new();
+        for key in keys {
+            if !state.pinned.contains_key(&key.fingerprint) {
+                state.pinned.insert(key.fingerprint.clone(), Pin::new(&key.comment));
+                added.push(key.fingerprint.clone());
+            }
+            state.reported_new.remove(&key.fingerprint);
+        }
+        write_pins(&self.path, &state.pinned)?;
+        state.exists = true;
+        state.loaded = modified(&self.path);
+        state.generation += 1;
+        Ok(added)
+    }
+
+    /// Counter that changes whenever the pinned set does, including when
+    /// `pin-keys` rewrote the pin file
+    pub fn generation(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = self.refresh(&mut state) {
+            log::warn!("Failed to read pinned keys: {:#}", e);
+        }
+        state.generation
+    }
+
+    /// Pinned fingerprints and their pins
+    pub fn pinned(&self) -> BTreeMap<String, Pin> {
+        self.state.lock().unwrap().pinned.clone()
+    }
+
+    /// When a key was first seen upstream, if it is pinned and dated
+    pub fn first_seen(&self, fingerprint: &str) -> Option<SystemTime> {
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = self.refresh(&mut state) {
+            log::warn!("Failed to read pinned keys: {:#}", e);
+        }
+        state.pinned.get(fingerprint)?.first_seen
+    }
+
+    /// Re-read the pin file if `pin-keys` changed it
+    fn refresh(&self, state: &mut PinState) -> Result<()> {
+        let modified = modified(&self.path);
+        if state.exists && modified == state.loaded {
+            return Ok(());
+        }
+
+        state.exists = self.path.exists();
+        state.loaded = modified;
+        if state.exists {
+            state.pinned = read_pins(&self.path)?;
+        }
+        state.generation += 1;
+        Ok(())
+    }
+
+    fn save(&self, state: &mut PinState) {
+        match write_pins(&self.path, &state.pinned) {
+            Ok(()) => {
+                state.exists = true;
+                state.loaded = modified(&self.path);
+            }
+            Err(e) => log::warn!("Failed to save pinned keys: {:#}", e),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// One `fingerprint first-seen comment` line per key, where older files
+/// lack the first-seen time
+fn read_pins(path: &Path) -> Result<BTreeMap<String, Pin>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read pinned keys {:?}", path))?;
+
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (fingerprint, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim_start();
+            let (first, comment) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let pin = match humantime::parse_rfc3339(first) {
+                Ok(time) => Pin { comment: comment.trim().to_string(), first_seen: Some(time) },
+                Err(_) => Pin { comment: rest.to_string(), first_seen: None },
+            };
+            (fingerprint.to_string(), pin)
+        })
+        .collect())
+}
+
+fn write_pins(path: &Path, pinned: &BTreeMap<String, Pin>) -> Result<()> {
+    state::create_parent_dir(path)?;
+
+    let mut content = String::from("# Upstream keys pinned by ssh-agent-router\n");
+    for (fingerprint, pin) in pinned {
+        let comment = pin.comment.replace(|c: char| c.is_control(), " ");
+        let line = match pin.first_seen {
+            Some(time) => format!("{} {} {}", fingerprint, humantime::format_rfc3339_seconds(time), comment),
+            None => format!("{} {}", fingerprint, comment),
+        };
+        content.push_str(line.trim_end());
+        content.push('\n');
+    }
+
+    // Written to a temporary file first so readers never see half a file
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content).with_context(|| format!("Failed to write pinned keys {:?}", tmp))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to write pinned keys {:?}", path))
+}
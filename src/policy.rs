@@ -0,0 +1,616 @@
+use crate::agent::SshKey;
+use crate::protocol;
+use crate::config::{expand_tilde, Config, DefaultPolicy, HostBinding, NamespaceBinding, SocketEntry};
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Allow/deny rules deciding which keys a socket exposes
+///
+/// Ordered `rules` are checked first and the first match wins. Keys no rule
+/// matches fall through to the `denied`/`allowed` lists and then the default
+/// policy.
+#[derive(Debug, Clone)]
+pub struct KeyFilter {
+    rules: Vec<Rule>,
+    allowed: KeySet,
+    /// An allow list is configured, even if its files or groups are empty
+    has_allow_list: bool,
+    denied: KeySet,
+    default: DefaultPolicy,
+    /// Hide keys first seen longer ago than this
+    max_key_age: Option<Duration>,
+    /// `max_uses` of count-limited allow list entries, by entry
+    max_uses: HashMap<String, u64>,
+    /// Keys bound to server host keys, first match wins
+    hosts: Vec<HostRule>,
+    /// Keys bound to SSHSIG namespaces, first match wins
+    namespaces: Vec<(KeySet, Vec<String>)>,
+}
+
+/// A parsed entry of a socket's `hosts`
+#[derive(Debug, Clone)]
+struct HostRule {
+    keys: KeySet,
+    /// SHA256 fingerprints of the host keys
+    host_keys: Vec<String>,
+}
+
+/// One parsed entry of a socket's `rules`, e.g. `+@work` or `-*`
+#[derive(Debug, Clone)]
+struct Rule {
+    allow: bool,
+    text: String,
+    matcher: Matcher,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    /// `*` matches every key
+    Any,
+    /// Fingerprints and patterns, including the members of a group
+    Keys(KeySet),
+}
+
+/// Fingerprints and compiled patterns from an allow/deny list
+///
+/// Plain fingerprints are looked up directly, everything else is compiled
+/// into a [`Pattern`] once when the configuration is loaded. Each entry
+/// remembers where it came from, e.g. `@work` or an allowed file, so
+/// verdicts can name it.
+#[derive(Debug, Clone, Default)]
+struct KeySet {
+    fingerprints: HashMap<String, String>,
+    patterns: Vec<(Pattern, String)>,
+}
+
+/// A list entry and the configuration entry it came from
+type Sourced = (String, String);
+
+/// A glob or regex matched against one attribute of a key
+///
+/// Entries look like `comment:*@work.example.com`, `type:sk-*`,
+/// `fp:SHA256:AAA*` or `re:^SHA256:AAA`. A field prefix followed by `re:`
+/// takes a regex instead of a glob, e.g. `comment:re:^ci-\d+$`.
+#[derive(Debug, Clone)]
+struct Pattern {
+    field: Field,
+    regex: Regex,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    Fingerprint,
+    Comment,
+    Type,
+}
+
+impl KeyFilter {
+    pub fn new(allowed: &[String], denied: &[String], default: DefaultPolicy) -> Result<Self> {
+        let sourced = |entries: &[String]| entries.iter().map(|e| (e.clone(), e.clone())).collect::<Vec<_>>();
+        Self::from_sourced(&sourced(allowed), &sourced(denied), default)
+    }
+
+    fn from_sourced(allowed: &[Sourced], denied: &[Sourced], default: DefaultPolicy) -> Result<Self> {
+        Ok(Self {
+            rules: Vec::new(),
+            allowed: KeySet::parse(allowed)?,
+            has_allow_list: !allowed.is_empty(),
+            denied: KeySet::parse(denied)?,
+            default,
+            max_key_age: None,
+            max_uses: HashMap::new(),
+            hosts: Vec::new(),
+            namespaces: Vec::new(),
+        })
+    }
+
+    /// Add ordered first-match rules, resolving `@group` references
+    pub fn with_rules(mut self, rules: &[String], groups: &BTreeMap<String, Vec<String>>) -> Result<Self> {
+        for text in rules {
+            self.rules.push(Rule::parse(text, groups)?);
+        }
+        Ok(self)
+    }
+
+    /// Build the filter for a socket, reading its `allowed_files`
+    pub fn from_entry(entry: &SocketEntry, config: &Config) -> Result<Self> {
+        let mut allowed = expand_groups(&entry.allowed, &config.groups)?;
+        for path in &entry.allowed_files {
+            let source = path.display().to_string();
+            allowed.extend(read_allowed_file(&expand_tilde(path))?.into_iter().map(|fp| (fp, source.clone())));
+        }
+        let denied = expand_groups(&entry.denied, &config.groups)?;
+
+        let mut filter = Self::from_sourced(&allowed, &denied, config.default_policy(entry))
+            .and_then(|filter| filter.with_rules(&entry.rules, &config.groups))
+            .with_context(|| format!("Invalid filter for socket {}", entry.name()))?;
+
+        // Allowed files that are empty must not turn the socket into allow-all
+        filter.has_allow_list |= !entry.allowed.is_empty() || !entry.allowed_files.is_empty();
+        filter.max_key_age = config.max_key_age(entry);
+        filter.max_uses = entry.allowed.iter()
+            .filter_map(|allowed| Some((allowed.key.clone(), allowed.max_uses?)))
+            .collect();
+        for binding in &entry.hosts {
+            let rule = HostRule::parse(binding, &config.groups)
+                .with_context(|| format!("Invalid hosts entry for {:?} on socket {}", binding.key, entry.name()))?;
+            filter.hosts.push(rule);
+        }
+        for binding in &entry.sshsig {
+            let keys = parse_namespace_binding(binding, &config.groups)
+                .with_context(|| format!("Invalid sshsig entry for {:?} on socket {}", binding.key, entry.name()))?;
+            filter.namespaces.push((keys, binding.namespaces.clone()));
+        }
+        Ok(filter)
+    }
+
+    /// Fingerprints of the host keys `key` may log in to, or `None` if
+    /// it is not bound to any
+    pub fn bound_hosts(&self, key: &SshKey) -> Option<&[String]> {
+        self.hosts.iter().find(|rule| rule.keys.find(key).is_some()).map(|rule| rule.host_keys.as_slice())
+    }
+
+    /// SSHSIG namespaces `key` may sign in, or `None` if it is not bound
+    /// to any
+    pub fn bound_namespaces(&self, key: &SshKey) -> Option<&[String]> {
+        self.namespaces.iter().find(|(keys, _)| keys.find(key).is_some()).map(|(_, namespaces)| namespaces.as_slice())
+    }
+
+    /// The count-limited grant exposing a key and its `max_uses`, if the
+    /// key is allowed through one
+    pub fn grant(&self, key: &SshKey) -> Option<(String, u64)> {
+        match self.evaluate(key) {
+            Verdict::AllowList { source } => self.max_uses.get(&source).map(|max_uses| (source, *max_uses)),
+            _ => None,
+        }
+    }
+
+    /// When a key first seen at `first_seen` becomes too old to expose,
+    /// or `None` if it never does
+    ///
+    /// Keys allowed by their own fingerprint, rather than through a
+    /// pattern, group, file or the default policy, are kept regardless.
+    pub fn hidden_after(&self, key: &SshKey, first_seen: SystemTime) -> Option<SystemTime> {
+        let max_key_age = self.max_key_age?;
+        let explicit = match self.evaluate(key) {
+            Verdict::AllowList { source } => key.matches_fingerprint(&source),
+            Verdict::Rule { rule, allow: true, .. } => key.matches_fingerprint(rule[1..].trim()),
+            _ => false,
+        };
+        (!explicit).then(|| first_seen + max_key_age)
+    }
+
+    /// Whether every key that is not explicitly denied is exposed
+    pub fn is_allow_all(&self) -> bool {
+        // The first catch-all rule decides for everything after it
+        if let Some(rule) = self.rules.iter().find(|r| matches!(r.matcher, Matcher::Any)) {
+            return rule.allow;
+        }
+        !self.has_allow_list && self.default == DefaultPolicy::Allow
+    }
+
+    pub fn is_allowed(&self, key: &SshKey) -> bool {
+        self.evaluate(key).is_allowed()
+    }
+
+    /// Decide whether a key is exposed and which rule is responsible
+    pub fn evaluate(&self, key: &SshKey) -> Verdict {
+        // Ordered rules take precedence, first match wins
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.matches(key) {
+                return Verdict::Rule {
+                    index,
+                    rule: rule.text.clone(),
+                    allow: rule.allow,
+                };
+            }
+        }
+
+        // If in denied list, reject
+        if let Some(source) = self.denied.find(key) {
+            return Verdict::DenyList { source: source.to_string() };
+        }
+
+        // Without an allow list, fall back to the default policy
+        if !self.has_allow_list {
+            return match self.default {
+                DefaultPolicy::Allow => Verdict::DefaultAllow,
+                DefaultPolicy::Deny => Verdict::DefaultDeny,
+            };
+        }
+
+        // Otherwise, must be in allowed list
+        match self.allowed.find(key) {
+            Some(source) => Verdict::AllowList { source: source.to_string() },
+            None => Verdict::NotInAllowList,
+        }
+    }
+}
+
+impl Rule {
+    /// Parse `+pattern` or `-pattern`, where the pattern is `*`, `@group`
+    /// or anything an allow/deny list accepts
+    fn parse(text: &str, groups: &BTreeMap<String, Vec<String>>) -> Result<Self> {
+        let (allow, pattern) = if let Some(rest) = text.strip_prefix('+') {
+            (true, rest)
+        } else if let Some(rest) = text.strip_prefix('-') {
+            (false, rest)
+        } else {
+            anyhow::bail!("Rule {:?} must start with '+' (allow) or '-' (deny)", text);
+        };
+
+        let matcher = match pattern.trim() {
+            "" => anyhow::bail!("Rule {:?} has no pattern", text),
+            "*" => Matcher::Any,
+            pattern => Matcher::Keys(KeySet::parse(&expand_groups(&[pattern.to_string()], groups)?)?),
+        };
+
+        Ok(Self {
+            allow,
+            text: text.to_string(),
+            matcher,
+        })
+    }
+
+    fn matches(&self, key: &SshKey) -> bool {
+        match &self.matcher {
+            Matcher::Any => true,
+            Matcher::Keys(keys) => keys.find(key).is_some(),
+        }
+    }
+}
+
+impl HostRule {
+    fn parse(binding: &HostBinding, groups: &BTreeMap<String, Vec<String>>) -> Result<Self> {
+        if binding.host_keys.is_empty() {
+            anyhow::bail!("host_keys is empty");
+        }
+        let host_keys = binding.host_keys.iter()
+            .map(|host_key| match host_key.starts_with("SHA256:") {
+                true => Ok(host_key.clone()),
+                false => SshKey::from_authorized_keys_line(host_key)
+                    .map(|key| key.fingerprint)
+                    .with_context(|| format!("{:?} is not a SHA256 fingerprint or public key", host_key)),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            keys: KeySet::parse(&expand_groups(&[&binding.key], groups)?)?,
+            host_keys,
+        })
+    }
+}
+
+fn parse_namespace_binding(binding: &NamespaceBinding, groups: &BTreeMap<String, Vec<String>>) -> Result<KeySet> {
+    if binding.namespaces.is_empty() {
+        anyhow::bail!("namespaces is empty");
+    }
+    KeySet::parse(&expand_groups(&[&binding.key], groups)?)
+}
+
+impl KeySet {
+    fn parse(entries: &[Sourced]) -> Result<Self> {
+        let mut set = Self::default();
+        for (entry, source) in entries {
+            match Pattern::parse(entry)? {
+                Some(pattern) => set.patterns.push((pattern, source.clone())),
+                None => {
+                    set.fingerprints.insert(entry.clone(), source.clone());
+                }
+            }
+        }
+        Ok(set)
+    }
+
+    /// Where the entry matching `key` came from, if any
+    ///
+    /// Fingerprints may be SHA256 or MD5.
+    fn find(&self, key: &SshKey) -> Option<&str> {
+        if let Some(source) = self.fingerprints.get(&key.fingerprint) {
+            return Some(source);
+        }
+        self.fingerprints.iter()
+            .find(|(fp, _)| fp.starts_with("MD5:") && key.matches_fingerprint(fp))
+            .map(|(_, source)| source.as_str())
+            .or_else(|| self.patterns.iter().find(|(p, _)| p.matches(key)).map(|(_, source)| source.as_str()))
+    }
+}
+
+impl Pattern {
+    /// Parse a list entry, returning `None` for a plain fingerprint
+    fn parse(entry: &str) -> Result<Option<Self>> {
+        let (field, pattern) = if let Some(rest) = entry.strip_prefix("comment:") {
+            (Field::Comment, rest)
+        } else if let Some(rest) = entry.strip_prefix("type:") {
+            (Field::Type, rest)
+        } else if let Some(rest) = entry.strip_prefix("fingerprint:").or_else(|| entry.strip_prefix("fp:")) {
+            (Field::Fingerprint, rest)
+        } else if entry.starts_with("re:") || entry.contains(['*', '?']) {
+            // Fingerprints never contain glob characters
+            (Field::Fingerprint, entry)
+        } else {
+            return Ok(None);
+        };
+
+        let regex = match pattern.strip_prefix("re:") {
+            Some(re) => Regex::new(re),
+            None => Regex::new(&glob_to_regex(pattern)),
+        }
+        .with_context(|| format!("Invalid pattern {:?}", entry))?;
+
+        Ok(Some(Self { field, regex }))
+    }
+
+    fn matches(&self, key: &SshKey) -> bool {
+        match self.field {
+            Field::Fingerprint => {
+                self.regex.is_match(&key.fingerprint) || self.regex.is_match(&key.md5_fingerprint())
+            }
+            Field::Comment => self.regex.is_match(&key.comment),
+            Field::Type => self.regex.is_match(&key.key_type),
+        }
+    }
+}
+
+/// Translate a glob with `*` and `?` into an anchored regex
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// Replace `@name` entries with the members of that group, keeping the
+/// group name as their source
+fn expand_groups<S: AsRef<str>>(entries: &[S], groups: &BTreeMap<String, Vec<String>>) -> Result<Vec<Sourced>> {
+    let mut expanded = Vec::new();
+    for entry in entries.iter().map(AsRef::as_ref) {
+        match entry.strip_prefix('@') {
+            Some(name) => {
+                let members = groups.get(name)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown group @{}", name))?;
+                expanded.extend(members.iter().map(|member| (member.clone(), entry.to_string())));
+            }
+            None => expanded.push((entry.to_string(), entry.to_string())),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Outcome of evaluating a key against a socket's filter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Matched an entry of `rules` (`index` is zero-based)
+    Rule { index: usize, rule: String, allow: bool },
+    /// Listed in `allowed`, directly, through a group or an allowed file
+    AllowList { source: String },
+    /// `allowed` is empty so every key not denied is exposed
+    DefaultAllow,
+    /// `allowed` is empty and the default policy is deny
+    DefaultDeny,
+    /// Listed in `denied`, directly or through a group
+    DenyList { source: String },
+    /// `allowed` is non-empty and does not list the key
+    NotInAllowList,
+}
+
+/// Machine-readable reason a request was refused, recorded in the audit
+/// trail and event stream as `reason`
+///
+/// Codes are only ever added, so tools can match on them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenyReason {
+    /// Matched a `-` entry of `rules`
+    DeniedByRule,
+    /// Listed in `denied`
+    DeniedList,
+    /// `allowed` is non-empty and does not list the key
+    NotInAllowList,
+    /// `allowed` is empty and the default policy is deny
+    DefaultDeny,
+    /// Pinning is enforced and the key is not pinned
+    Unpinned,
+    /// First seen longer ago than `max_key_age`
+    KeyTooOld,
+    /// The key's count-limited grant is used up
+    GrantUsedUp,
+    /// A login to a server outside the key's `hosts`
+    HostNotAllowed,
+    /// An SSHSIG signature outside the key's `sshsig` namespaces, or data
+    /// a bound key may not sign
+    NamespaceNotAllowed,
+    /// Sign requests arrive faster than `sign_rate` allows
+    RateLimit,
+    /// The user declined or did not answer a confirmation
+    ConfirmationDeclined,
+    /// The upstream agent does not list the key
+    UnknownKey,
+    /// The router is in panic mode
+    Panic,
+    /// An added key lacks destination constraints the socket requires
+    MissingDestinationConstraints,
+    /// The socket refuses smartcard requests
+    SmartcardDenied,
+    /// The smartcard provider is not in `smartcard_providers`
+    ProviderNotAllowed,
+    /// The client runs in a container and the socket has `deny_containers`
+    Container,
+}
+
+impl DenyReason {
+    /// The code as written in audit records, e.g. `not-in-allow-list`
+    pub fn code(&self) -> &'static str {
+        match self {
+            DenyReason::DeniedByRule => "denied-by-rule",
+            DenyReason::DeniedList => "denied-list",
+            DenyReason::NotInAllowList => "not-in-allow-list",
+            DenyReason::DefaultDeny => "default-deny",
+            DenyReason::Unpinned => "unpinned",
+            DenyReason::KeyTooOld => "key-too-old",
+            DenyReason::GrantUsedUp => "grant-used-up",
+            DenyReason::HostNotAllowed => "host-not-allowed",
+            DenyReason::NamespaceNotAllowed => "namespace-not-allowed",
+            DenyReason::RateLimit => "rate-limit",
+            DenyReason::ConfirmationDeclined => "confirmation-declined",
+            DenyReason::UnknownKey => "unknown-key",
+            DenyReason::Panic => "panic",
+            DenyReason::MissingDestinationConstraints => "missing-destination-constraints",
+            DenyReason::SmartcardDenied => "smartcard-denied",
+            DenyReason::ProviderNotAllowed => "provider-not-allowed",
+            DenyReason::Container => "container",
+        }
+    }
+}
+
+impl Serialize for DenyReason {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.code())
+    }
+}
+
+impl std::fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl Verdict {
+    /// Why the key is hidden, `None` if it is exposed
+    pub fn deny_reason(&self) -> Option<DenyReason> {
+        match self {
+            Verdict::Rule { allow: false, .. } => Some(DenyReason::DeniedByRule),
+            Verdict::DefaultDeny => Some(DenyReason::DefaultDeny),
+            Verdict::DenyList { .. } => Some(DenyReason::DeniedList),
+            Verdict::NotInAllowList => Some(DenyReason::NotInAllowList),
+            _ => None,
+        }
+    }
+
+    pub fn is_allowed(&self) -> bool {
+        match self {
+            Verdict::Rule { allow, .. } => *allow,
+            verdict => matches!(verdict, Verdict::AllowList { .. } | Verdict::DefaultAllow),
+        }
+    }
+
+    /// Short explanation of the rule responsible for the verdict
+    pub fn reason(&self) -> String {
+        match self {
+            Verdict::Rule { index, rule, .. } => format!("matched rule {} ({})", index + 1, rule),
+            Verdict::AllowList { source } => format!("listed in allowed ({})", source),
+            Verdict::DefaultAllow => "allowed is empty, all keys not denied are exposed".to_string(),
+            Verdict::DefaultDeny => "allowed is empty and default is deny".to_string(),
+            Verdict::DenyList { source } => format!("listed in denied ({})", source),
+            Verdict::NotInAllowList => "not listed in allowed".to_string(),
+        }
+    }
+}
+
+/// What `allowed_messages = ["default"]` stands for: listing keys,
+/// signing and announcing the host a connection is for
+pub const DEFAULT_ALLOWED_MESSAGES: &[&str] = &["request-identities", "sign-request", "extension:session-bind@openssh.com"];
+
+/// Agent message types a socket passes on to upstream
+///
+/// Entries name a request the way `SSH_AGENTC_<NAME>` does, in lower case
+/// with dashes (`sign-request`, `add-identity`), or give its number.
+/// `extension` lets every extension through and `extension:<name>` only
+/// that one; `default` stands for [`DEFAULT_ALLOWED_MESSAGES`].
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilter {
+    types: Vec<u8>,
+    extensions: Vec<String>,
+    all_extensions: bool,
+}
+
+impl MessageFilter {
+    pub fn new(entries: &[String]) -> Result<Self> {
+        let mut filter = Self::default();
+        for entry in entries {
+            if entry == "default" {
+                for entry in DEFAULT_ALLOWED_MESSAGES {
+                    filter.add(entry)?;
+                }
+            } else {
+                filter.add(entry)?;
+            }
+        }
+        Ok(filter)
+    }
+
+    fn add(&mut self, entry: &str) -> Result<()> {
+        if entry == "extension" || entry == protocol::SSH_AGENTC_EXTENSION.to_string() {
+            self.all_extensions = true;
+        } else if let Some(name) = entry.strip_prefix("extension:") {
+            self.extensions.push(name.to_string());
+        } else {
+            let msg_type = entry.parse::<u8>().ok()
+                .or_else(|| (0..=u8::MAX).find(|&t| message_name(t).is_some_and(|name| name == entry)))
+                .ok_or_else(|| anyhow::anyhow!("Unknown agent message {:?} in allowed_messages", entry))?;
+            self.types.push(msg_type);
+        }
+        Ok(())
+    }
+
+    /// Whether a length-prefixed request may reach upstream
+    pub fn permits(&self, request: &[u8]) -> bool {
+        match protocol::message_type(request) {
+            Some(protocol::SSH_AGENTC_EXTENSION) => {
+                self.all_extensions
+                    || protocol::extension_name(request)
+                        .is_some_and(|name| self.extensions.iter().any(|allowed| allowed.as_bytes() == name))
+            }
+            Some(msg_type) => self.types.contains(&msg_type),
+            None => false,
+        }
+    }
+}
+
+/// `SSH_AGENTC_SIGN_REQUEST` as `sign-request`, `None` for numbers that
+/// are not client requests
+fn message_name(msg_type: u8) -> Option<String> {
+    protocol::message_type_name(msg_type)
+        .strip_prefix("SSH_AGENTC_")
+        .filter(|_| msg_type != protocol::SSH_AGENTC_EXTENSION)
+        .map(|name| name.to_lowercase().replace('_', "-"))
+}
+
+/// Read fingerprints from an allow list file
+///
+/// Each line is either a fingerprint or a public key in authorized_keys
+/// format (options before the key type are skipped). Blank lines and
+/// lines starting with `#` are ignored.
+pub fn read_allowed_file(path: &Path) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read allowed file {:?}", path))?;
+
+    let mut fingerprints = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with("SHA256:") || line.starts_with("MD5:") {
+            // Allow trailing comments after a fingerprint
+            let fingerprint = line.split_whitespace().next().unwrap_or(line);
+            fingerprints.push(fingerprint.to_string());
+            continue;
+        }
+
+        let key = SshKey::from_authorized_keys_line(line)
+            .with_context(|| format!("{:?} line {}", path, i + 1))?;
+        fingerprints.push(key.fingerprint);
+    }
+
+    Ok(fingerprints)
+}
@@ -0,0 +1,73 @@
+use crate::config::PrivacyConfig;
+use crate::peer::{self, PeerCred};
+use std::sync::RwLock;
+
+/// Characters of a fingerprint's hash kept when fingerprints are shortened
+const SHORT_FINGERPRINT: usize = 8;
+
+static PRIVACY: RwLock<PrivacyConfig> = RwLock::new(PrivacyConfig::SHOW_ALL);
+
+/// Choose what logs, events and notifications reveal from now on
+pub fn set(config: &PrivacyConfig) {
+    *PRIVACY.write().unwrap() = config.clone();
+}
+
+/// A fingerprint as it may be shown, e.g. `SHA256:uZG6Jmeg...`
+pub fn fingerprint(fingerprint: &str) -> String {
+    if PRIVACY.read().unwrap().full_fingerprints {
+        return fingerprint.to_string();
+    }
+    shorten_fingerprint(fingerprint)
+}
+
+/// The algorithm and first characters of a fingerprint's hash
+pub fn shorten_fingerprint(fingerprint: &str) -> String {
+    let (algorithm, hash) = fingerprint.split_once(':').unwrap_or(("", fingerprint));
+    let short: String = hash.chars().take(SHORT_FINGERPRINT).collect();
+    match algorithm {
+        "" => format!("{}...", short),
+        algorithm => format!("{}:{}...", algorithm, short),
+    }
+}
+
+/// A key as `fingerprint (comment)`, or just the fingerprint
+pub fn key(fingerprint_: &str, comment_: &str) -> String {
+    match comment(comment_) {
+        Some(comment) => format!("{} ({})", fingerprint(fingerprint_), comment),
+        None => fingerprint(fingerprint_),
+    }
+}
+
+/// A key comment, or `None` when comments are hidden or empty
+pub fn comment(comment: &str) -> Option<&str> {
+    Some(comment).filter(|c| !c.is_empty() && PRIVACY.read().unwrap().comments)
+}
+
+/// Short description of a client, with its executable when allowed
+pub fn client(peer: &PeerCred) -> String {
+    match client_path(peer) {
+        Some(path) => format!("{} {}", peer, path),
+        None => peer.to_string(),
+    }
+}
+
+/// Executable of a client process when client paths may be shown
+pub fn client_path(peer: &PeerCred) -> Option<String> {
+    if !PRIVACY.read().unwrap().client_paths {
+        return None;
+    }
+    peer::process_path(peer.pid?).map(|path| path.display().to_string())
+}
+
+/// Hide what may not be shown in a record with `fingerprint` and
+/// `comment` fields, such as an audit event
+pub fn redact_record(record: &mut serde_json::Value) {
+    if let Some(serde_json::Value::String(fp)) = record.get_mut("fingerprint") {
+        *fp = fingerprint(fp);
+    }
+    if let Some(object) = record.as_object_mut() {
+        if object.get("comment").and_then(|c| c.as_str()).is_some_and(|c| comment(c).is_none()) {
+            object.remove("comment");
+        }
+    }
+}
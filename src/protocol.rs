@@ -0,0 +1,435 @@
+use anyhow::Result;
+
+// Legacy SSH protocol 1 requests, removed from OpenSSH in 7.6
+pub const SSH_AGENTC_REQUEST_RSA_IDENTITIES: u8 = 1;
+pub const SSH_AGENTC_RSA_CHALLENGE: u8 = 3;
+pub const SSH_AGENTC_ADD_RSA_IDENTITY: u8 = 7;
+pub const SSH_AGENTC_REMOVE_RSA_IDENTITY: u8 = 8;
+pub const SSH_AGENTC_REMOVE_ALL_RSA_IDENTITIES: u8 = 9;
+pub const SSH_AGENTC_ADD_RSA_ID_CONSTRAINED: u8 = 24;
+
+// Message numbers from draft-miller-ssh-agent
+pub const SSH_AGENT_FAILURE: u8 = 5;
+pub const SSH_AGENT_SUCCESS: u8 = 6;
+pub const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+pub const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+pub const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+pub const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+pub const SSH_AGENTC_ADD_IDENTITY: u8 = 17;
+pub const SSH_AGENTC_REMOVE_IDENTITY: u8 = 18;
+pub const SSH_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
+pub const SSH_AGENTC_ADD_SMARTCARD_KEY: u8 = 20;
+pub const SSH_AGENTC_REMOVE_SMARTCARD_KEY: u8 = 21;
+pub const SSH_AGENTC_LOCK: u8 = 22;
+pub const SSH_AGENTC_UNLOCK: u8 = 23;
+pub const SSH_AGENTC_ADD_ID_CONSTRAINED: u8 = 25;
+pub const SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED: u8 = 26;
+pub const SSH_AGENTC_EXTENSION: u8 = 27;
+pub const SSH_AGENT_EXTENSION_FAILURE: u8 = 28;
+
+/// Human readable name of an agent message number
+pub fn message_type_name(msg_type: u8) -> &'static str {
+    match msg_type {
+        SSH_AGENTC_REQUEST_RSA_IDENTITIES => "SSH_AGENTC_REQUEST_RSA_IDENTITIES",
+        SSH_AGENTC_RSA_CHALLENGE => "SSH_AGENTC_RSA_CHALLENGE",
+        SSH_AGENTC_ADD_RSA_IDENTITY => "SSH_AGENTC_ADD_RSA_IDENTITY",
+        SSH_AGENTC_REMOVE_RSA_IDENTITY => "SSH_AGENTC_REMOVE_RSA_IDENTITY",
+        SSH_AGENTC_REMOVE_ALL_RSA_IDENTITIES => "SSH_AGENTC_REMOVE_ALL_RSA_IDENTITIES",
+        SSH_AGENTC_ADD_RSA_ID_CONSTRAINED => "SSH_AGENTC_ADD_RSA_ID_CONSTRAINED",
+        SSH_AGENT_FAILURE => "SSH_AGENT_FAILURE",
+        SSH_AGENT_SUCCESS => "SSH_AGENT_SUCCESS",
+        SSH_AGENTC_REQUEST_IDENTITIES => "SSH_AGENTC_REQUEST_IDENTITIES",
+        SSH_AGENT_IDENTITIES_ANSWER => "SSH_AGENT_IDENTITIES_ANSWER",
+        SSH_AGENTC_SIGN_REQUEST => "SSH_AGENTC_SIGN_REQUEST",
+        SSH_AGENT_SIGN_RESPONSE => "SSH_AGENT_SIGN_RESPONSE",
+        SSH_AGENTC_ADD_IDENTITY => "SSH_AGENTC_ADD_IDENTITY",
+        SSH_AGENTC_REMOVE_IDENTITY => "SSH_AGENTC_REMOVE_IDENTITY",
+        SSH_AGENTC_REMOVE_ALL_IDENTITIES => "SSH_AGENTC_REMOVE_ALL_IDENTITIES",
+        SSH_AGENTC_ADD_SMARTCARD_KEY => "SSH_AGENTC_ADD_SMARTCARD_KEY",
+        SSH_AGENTC_REMOVE_SMARTCARD_KEY => "SSH_AGENTC_REMOVE_SMARTCARD_KEY",
+        SSH_AGENTC_LOCK => "SSH_AGENTC_LOCK",
+        SSH_AGENTC_UNLOCK => "SSH_AGENTC_UNLOCK",
+        SSH_AGENTC_ADD_ID_CONSTRAINED => "SSH_AGENTC_ADD_ID_CONSTRAINED",
+        SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED => "SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED",
+        SSH_AGENTC_EXTENSION => "SSH_AGENTC_EXTENSION",
+        SSH_AGENT_EXTENSION_FAILURE => "SSH_AGENT_EXTENSION_FAILURE",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Vendor extension answered by the router itself with the socket's stats
+pub const STATS_EXTENSION: &str = "stats@ssh-agent-router";
+
+/// Standard extension listing the extensions an agent supports
+pub const QUERY_EXTENSION: &str = "query";
+
+/// Extensions the router answers itself rather than forwarding
+pub const EXTENSIONS: &[&str] = &[QUERY_EXTENSION, STATS_EXTENSION];
+
+/// Prepend the length to a message body
+pub fn frame(body: &[u8]) -> Vec<u8> {
+    let mut message = (body.len() as u32).to_be_bytes().to_vec();
+    message.extend_from_slice(body);
+    message
+}
+
+/// Append a length-prefixed string
+pub fn put_string(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+/// SSH_AGENT_EXTENSION_FAILURE carrying a human readable reason
+///
+/// The draft defines no body for this message, so the reason is extra
+/// data only tools that look for it will show. OpenSSH still fails the
+/// operation, but reports it as "invalid format" for anything other than
+/// an extension request.
+pub fn extension_failure(reason: &str) -> Vec<u8> {
+    let mut body = vec![SSH_AGENT_EXTENSION_FAILURE];
+    put_string(&mut body, reason.as_bytes());
+    frame(&body)
+}
+
+/// The reason attached to a length-prefixed SSH_AGENT_EXTENSION_FAILURE
+pub fn failure_reason(message: &[u8]) -> Option<String> {
+    let mut reader = Reader::new(message);
+    reader.read_u32().ok()?;
+    if reader.read_u8().ok()? != SSH_AGENT_EXTENSION_FAILURE {
+        return None;
+    }
+    reader.read_string().ok().map(|r| String::from_utf8_lossy(r).to_string())
+}
+
+/// Name of a length-prefixed SSH_AGENTC_EXTENSION request
+pub fn extension_name(message: &[u8]) -> Option<&[u8]> {
+    let mut reader = Reader::new(message);
+    reader.read_u32().ok()?;
+    if reader.read_u8().ok()? != SSH_AGENTC_EXTENSION {
+        return None;
+    }
+    reader.read_string().ok()
+}
+
+/// Extension names in a length-prefixed reply to the `query` extension
+///
+/// `None` unless the reply is SSH_AGENT_SUCCESS followed by strings.
+pub fn parse_query_response(message: &[u8]) -> Option<Vec<String>> {
+    let mut reader = Reader::new(message);
+    let len = reader.read_u32().ok()? as usize;
+    if reader.read_u8().ok()? != SSH_AGENT_SUCCESS || message.len() != 4 + len {
+        return None;
+    }
+
+    let mut names = Vec::new();
+    while reader.position() < message.len() {
+        names.push(String::from_utf8_lossy(reader.read_string().ok()?).to_string());
+    }
+    Some(names)
+}
+
+/// Whether a request belongs to the SSH protocol 1 agent protocol
+pub fn is_protocol_1(msg_type: u8) -> bool {
+    matches!(
+        msg_type,
+        SSH_AGENTC_REQUEST_RSA_IDENTITIES
+            | SSH_AGENTC_RSA_CHALLENGE
+            | SSH_AGENTC_ADD_RSA_IDENTITY
+            | SSH_AGENTC_REMOVE_RSA_IDENTITY
+            | SSH_AGENTC_REMOVE_ALL_RSA_IDENTITIES
+            | SSH_AGENTC_ADD_RSA_ID_CONSTRAINED
+    )
+}
+
+/// Message type of a length-prefixed message, if present
+pub fn message_type(message: &[u8]) -> Option<u8> {
+    message.get(4).copied()
+}
+
+/// Cursor over the wire encoding used by the agent protocol
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        let byte = *self.buf
+            .get(self.pos)
+            .ok_or_else(|| anyhow::anyhow!("Truncated agent message"))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Read `len` bytes as they are
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        self.take(len)
+    }
+
+    pub fn read_string(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.pos < len {
+            return Err(anyhow::anyhow!("Truncated agent message"));
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+/// Zero out key blobs in a length-prefixed identities answer or sign request
+///
+/// Lengths are preserved so the redacted message still parses.
+pub fn redact_key_blobs(message: &[u8]) -> Vec<u8> {
+    let mut redacted = message.to_vec();
+    // Malformed messages are redacted up to the point parsing failed
+    let _ = zero_key_blobs(message, &mut redacted);
+    redacted
+}
+
+fn zero_key_blobs(message: &[u8], redacted: &mut [u8]) -> Result<()> {
+    let mut reader = Reader::new(message);
+    reader.read_u32()?;
+
+    match reader.read_u8()? {
+        SSH_AGENT_IDENTITIES_ANSWER => {
+            let num_keys = reader.read_u32()?;
+            for _ in 0..num_keys {
+                let start = reader.position();
+                reader.read_string()?;
+                redacted[start + 4..reader.position()].fill(0);
+                reader.read_string()?;
+            }
+        }
+        SSH_AGENTC_SIGN_REQUEST => {
+            let start = reader.position();
+            reader.read_string()?;
+            redacted[start + 4..reader.position()].fill(0);
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// What a message of `msg_type` holds that must never be written out: the
+/// private key of keys being added, or a PIN or passphrase
+pub fn secret_in(msg_type: u8) -> Option<&'static str> {
+    match msg_type {
+        SSH_AGENTC_ADD_IDENTITY
+        | SSH_AGENTC_ADD_ID_CONSTRAINED
+        | SSH_AGENTC_ADD_RSA_IDENTITY
+        | SSH_AGENTC_ADD_RSA_ID_CONSTRAINED => Some("a private key"),
+        SSH_AGENTC_ADD_SMARTCARD_KEY
+        | SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED
+        | SSH_AGENTC_REMOVE_SMARTCARD_KEY
+        | SSH_AGENTC_LOCK
+        | SSH_AGENTC_UNLOCK => Some("a PIN or passphrase"),
+        _ => None,
+    }
+}
+
+/// Zero out the secrets in a length-prefixed message: the whole body of
+/// messages [`secret_in`] names, the session ID of logins and any other
+/// data being signed except SSHSIG hashes, and signatures
+///
+/// The message type and lengths are preserved so the redacted message
+/// still parses.
+pub fn redact_secrets(message: &[u8]) -> Vec<u8> {
+    let mut redacted = message.to_vec();
+    if zero_secrets(message, &mut redacted).is_err() {
+        // What could not be parsed is not kept
+        redacted.iter_mut().skip(5).for_each(|byte| *byte = 0);
+    }
+    redacted
+}
+
+fn zero_secrets(message: &[u8], redacted: &mut [u8]) -> Result<()> {
+    let mut reader = Reader::new(message);
+    reader.read_u32()?;
+    let msg_type = reader.read_u8()?;
+    if secret_in(msg_type).is_some() {
+        redacted[reader.position()..].fill(0);
+        return Ok(());
+    }
+
+    match msg_type {
+        SSH_AGENTC_SIGN_REQUEST => {
+            reader.read_string()?;
+            let start = reader.position() + 4;
+            let data = reader.read_string()?;
+            if sshsig_namespace(data).is_some() {
+                // A hash of the message, no more
+            } else if parse_userauth(data).is_some() {
+                let session_id = Reader::new(data).read_string()?;
+                redacted[start + 4..start + 4 + session_id.len()].fill(0);
+            } else {
+                redacted[start..start + data.len()].fill(0);
+            }
+        }
+        SSH_AGENT_SIGN_RESPONSE => {
+            let start = reader.position() + 4;
+            let blob = reader.read_string()?;
+            redacted[start..start + blob.len()].copy_from_slice(&blank_signature(blob));
+        }
+        SSH_AGENTC_EXTENSION if parse_session_bind(message).is_some() => {
+            reader.read_string()?; // name
+            reader.read_string()?; // host key
+            for _ in 0..2 {
+                // Session ID and the host's signature over it
+                let start = reader.position() + 4;
+                let field = reader.read_string()?;
+                redacted[start..start + field.len()].fill(0);
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// A signature blob with the algorithm kept and the signature zeroed
+pub fn blank_signature(blob: &[u8]) -> Vec<u8> {
+    let mut reader = Reader::new(blob);
+    let (Ok(algorithm), Ok(signature)) = (reader.read_string(), reader.read_string()) else {
+        return vec![0; blob.len()];
+    };
+    let mut out = Vec::with_capacity(blob.len());
+    put_string(&mut out, algorithm);
+    put_string(&mut out, &vec![0; signature.len()]);
+    // Security key signatures carry flags and a counter after it
+    out.resize(blob.len(), 0);
+    out
+}
+
+/// Host binding announced by OpenSSH 8.9+ through `session-bind@openssh.com`
+///
+/// ssh sends one after key exchange with each host it connects to, so a
+/// connection through a jump host carries one binding per hop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionBind {
+    /// Public key blob of the server host key
+    pub host_key: Vec<u8>,
+    /// Exchange hash identifying the SSH session
+    pub session_id: Vec<u8>,
+    /// Whether the agent is being forwarded to this host
+    pub forwarding: bool,
+}
+
+/// Parse a length-prefixed `session-bind@openssh.com` extension request
+///
+/// Returns `None` for any other message. The host key signature over the
+/// session id is not verified here; upstream checks it and answers
+/// SSH_AGENT_SUCCESS only for a valid one.
+pub fn parse_session_bind(message: &[u8]) -> Option<SessionBind> {
+    let mut reader = Reader::new(message);
+    reader.read_u32().ok()?;
+    if reader.read_u8().ok()? != SSH_AGENTC_EXTENSION
+        || reader.read_string().ok()? != b"session-bind@openssh.com"
+    {
+        return None;
+    }
+
+    let host_key = reader.read_string().ok()?.to_vec();
+    let session_id = reader.read_string().ok()?.to_vec();
+    reader.read_string().ok()?; // signature
+    let forwarding = reader.read_u8().ok()? != 0;
+
+    Some(SessionBind { host_key, session_id, forwarding })
+}
+
+/// Key blob a length-prefixed SSH_AGENTC_SIGN_REQUEST asks to sign with
+pub fn sign_request_key(message: &[u8]) -> Option<&[u8]> {
+    let mut reader = Reader::new(message);
+    reader.read_u32().ok()?;
+    if reader.read_u8().ok()? != SSH_AGENTC_SIGN_REQUEST {
+        return None;
+    }
+    reader.read_string().ok()
+}
+
+/// Data to be signed, from a length-prefixed SSH_AGENTC_SIGN_REQUEST
+pub fn sign_request_data(message: &[u8]) -> Option<&[u8]> {
+    let mut reader = Reader::new(message);
+    reader.read_u32().ok()?;
+    if reader.read_u8().ok()? != SSH_AGENTC_SIGN_REQUEST {
+        return None;
+    }
+    reader.read_string().ok()?; // key blob
+    reader.read_string().ok()
+}
+
+/// SSH_MSG_USERAUTH_REQUEST, which ssh signs to log in with a key
+const SSH_MSG_USERAUTH_REQUEST: u8 = 50;
+
+/// Public key authentication request a client asked to sign
+///
+/// This is what ssh signs when logging in to a server, as described in
+/// RFC 4252 section 7.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserAuth {
+    /// Exchange hash identifying the SSH session
+    pub session_id: Vec<u8>,
+    pub user: String,
+    /// Public key blob of the server host key, sent by OpenSSH 8.9+ with
+    /// the `publickey-hostbound-v00@openssh.com` method
+    pub host_key: Option<Vec<u8>>,
+}
+
+/// Parse the data of a sign request as a public key authentication
+/// request, returning `None` for anything else, such as SSHSIG data
+pub fn parse_userauth(data: &[u8]) -> Option<UserAuth> {
+    let mut reader = Reader::new(data);
+    let session_id = reader.read_string().ok()?.to_vec();
+    if reader.read_u8().ok()? != SSH_MSG_USERAUTH_REQUEST {
+        return None;
+    }
+    let user = String::from_utf8_lossy(reader.read_string().ok()?).to_string();
+    reader.read_string().ok()?; // service
+    let method = reader.read_string().ok()?;
+    if method != b"publickey" && method != b"publickey-hostbound-v00@openssh.com" {
+        return None;
+    }
+    if reader.read_u8().ok()? == 0 {
+        return None;
+    }
+    reader.read_string().ok()?; // algorithm
+    reader.read_string().ok()?; // public key
+    let host_key = match method {
+        b"publickey" => None,
+        _ => Some(reader.read_string().ok()?.to_vec()),
+    };
+
+    Some(UserAuth { session_id, user, host_key })
+}
+
+/// Namespace of an SSHSIG signature being made, e.g. `git` or `file`
+///
+/// `ssh-keygen -Y sign` has the agent sign a blob starting with the
+/// `SSHSIG` magic and the namespace; `None` for any other data.
+pub fn sshsig_namespace(data: &[u8]) -> Option<String> {
+    let rest = data.strip_prefix(b"SSHSIG")?;
+    let namespace = Reader::new(rest).read_string().ok()?;
+    Some(String::from_utf8_lossy(namespace).to_string())
+}
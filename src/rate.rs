@@ -0,0 +1,66 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Sign requests seen on a socket within a sliding window
+///
+/// Requests refused for exceeding the rate count too, so a process that
+/// keeps hammering a socket stays over the limit until it backs off.
+pub struct SignRateWindow {
+    max: u32,
+    per: Duration,
+    state: Mutex<WindowState>,
+}
+
+#[derive(Default)]
+struct WindowState {
+    recent: VecDeque<Instant>,
+    over: bool,
+}
+
+/// Where a sign request leaves the rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignRate {
+    /// At most `max` requests within the window
+    Normal,
+    /// Above the limit; `started` for the request that crossed it
+    Exceeded { started: bool },
+    /// Back at or below the limit after exceeding it
+    Dropped,
+}
+
+impl SignRateWindow {
+    pub fn new(max: u32, per: Duration) -> Self {
+        Self {
+            max,
+            per,
+            state: Mutex::new(WindowState::default()),
+        }
+    }
+
+    /// Requests allowed and the window they are counted in
+    pub fn limit(&self) -> (u32, Duration) {
+        (self.max, self.per)
+    }
+
+    /// Count a sign request made at `now`
+    pub fn record(&self, now: Instant) -> SignRate {
+        let mut state = self.state.lock().unwrap();
+        while state.recent.front().is_some_and(|t| now.duration_since(*t) >= self.per) {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(now);
+        // Older requests cannot change whether the limit is exceeded
+        if state.recent.len() > self.max as usize + 1 {
+            state.recent.pop_front();
+        }
+
+        let over = state.recent.len() > self.max as usize;
+        let was_over = std::mem::replace(&mut state.over, over);
+        match (was_over, over) {
+            (_, true) => SignRate::Exceeded { started: !was_over },
+            (true, false) => SignRate::Dropped,
+            (false, false) => SignRate::Normal,
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use crate::control::{self, ControlRequest, ControlResponse};
+use anyhow::{Context, Result};
+use std::io::{BufReader, Write};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+/// A router on another machine, reached by running this program there
+/// with `--control-json` over SSH
+///
+/// Requests and answers are the control socket's JSON lines, so the remote
+/// router is queried exactly like a local one.
+#[derive(Debug, Clone)]
+pub struct Remote {
+    /// Host as given to ssh, e.g. `mybox` or `admin@mybox`
+    pub host: String,
+    /// Program to run on the host, found on its `PATH` unless a path
+    program: String,
+}
+
+/// Answers streamed from a remote router; the ssh connection is closed
+/// when this is dropped
+pub struct RemoteStream {
+    child: Child,
+    pub reader: BufReader<ChildStdout>,
+}
+
+impl Drop for RemoteStream {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Remote {
+    pub fn new(host: &str, program: &str) -> Self {
+        Self { host: host.to_string(), program: program.to_string() }
+    }
+
+    /// Send `request` to the remote router and wait for its response
+    pub fn request(&self, request: &ControlRequest) -> Result<ControlResponse> {
+        Ok(self.requests(std::slice::from_ref(request))?.remove(0))
+    }
+
+    /// Send several requests over one connection, so the host is logged in
+    /// to once, and return the responses in order
+    pub fn requests(&self, requests: &[ControlRequest]) -> Result<Vec<ControlResponse>> {
+        let mut stream = self.send(requests)?;
+        requests.iter()
+            .map(|_| control::read_response(&mut stream.reader))
+            .collect::<Result<_>>()
+            .context("Failed to query the router")
+    }
+
+    /// Subscribe to the remote router's events, one JSON line each
+    pub fn subscribe(&self) -> Result<RemoteStream> {
+        let mut stream = self.send(&[ControlRequest::Subscribe])?;
+        match control::read_response(&mut stream.reader).context("Failed to query the router")? {
+            ControlResponse::Subscribed => Ok(stream),
+            response => Err(anyhow::anyhow!("Unexpected response from router: {:?}", response)),
+        }
+    }
+
+    fn send(&self, requests: &[ControlRequest]) -> Result<RemoteStream> {
+        // ssh prints its own errors, such as a refused login, on stderr
+        let mut child = Command::new("ssh")
+            .args(["-T", "-e", "none", &self.host, "--", &self.program, "--control-json"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("Failed to run ssh")?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        for request in requests {
+            serde_json::to_writer(&mut stdin, request)?;
+            stdin.write_all(b"\n")?;
+        }
+        // The end of the requests lets the relay stop after answering
+        drop(stdin);
+
+        let reader = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        Ok(RemoteStream { child, reader })
+    }
+}
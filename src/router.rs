@@ -0,0 +1,680 @@
+use crate::agent::Agent;
+use crate::certs::CertificateStore;
+use crate::config::{Config, SocketEntry};
+use crate::grants::GrantUses;
+use crate::pinning::KeyPins;
+use crate::socket::FilteredSocket;
+use crate::state;
+use crate::stats::SavedStats;
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A socket created at runtime rather than from the configuration
+struct Ephemeral {
+    socket: Arc<FilteredSocket>,
+    /// Unix time at which the socket is removed
+    expires: Option<u64>,
+}
+
+/// The set of filtered sockets served by a running router
+///
+/// Shared with the control socket so it can inspect live sockets.
+pub struct Router {
+    agent: Agent,
+    pins: Option<Arc<KeyPins>>,
+    grants: Arc<GrantUses>,
+    /// Certificates from `[certificates]`, shared by every socket
+    certificates: RwLock<Option<Arc<CertificateStore>>>,
+    /// Counters of configured and generated sockets from earlier runs
+    saved_stats: SavedStats,
+    /// Set while in panic mode, shared with every socket
+    lockdown: Arc<AtomicBool>,
+    /// Set once the configured sockets went to a router taking over
+    handed_over: AtomicBool,
+    sockets: RwLock<Vec<Arc<FilteredSocket>>>,
+    ephemeral: RwLock<Vec<Ephemeral>>,
+    next_ephemeral: AtomicUsize,
+    /// Sockets generated from `per_key_sockets` and the entries they were
+    /// built from
+    per_key: RwLock<Vec<(SocketEntry, Arc<FilteredSocket>)>>,
+    /// Held while the generated sockets are brought up to date
+    per_key_sync: Mutex<()>,
+    /// Configuration the sockets were last built from, applied to
+    /// ephemeral sockets as well
+    config: RwLock<Config>,
+    /// Configured sockets taken down with [`Router::disable_tag`], which
+    /// reloads leave unbound
+    disabled: RwLock<BTreeSet<PathBuf>>,
+}
+
+impl Router {
+    pub fn new(agent: Agent) -> Self {
+        Self {
+            agent,
+            pins: None,
+            grants: Arc::new(GrantUses::in_memory()),
+            certificates: RwLock::new(None),
+            saved_stats: SavedStats::in_memory(),
+            lockdown: Arc::new(AtomicBool::new(false)),
+            handed_over: AtomicBool::new(false),
+            sockets: RwLock::new(Vec::new()),
+            ephemeral: RwLock::new(Vec::new()),
+            next_ephemeral: AtomicUsize::new(1),
+            per_key: RwLock::new(Vec::new()),
+            per_key_sync: Mutex::new(()),
+            config: RwLock::new(Config::default()),
+            disabled: RwLock::new(BTreeSet::new()),
+        }
+    }
+
+    /// Share pinned upstream keys with every socket added afterwards
+    pub fn with_pins(mut self, pins: KeyPins) -> Self {
+        self.pins = Some(Arc::new(pins));
+        self
+    }
+
+    /// Count grant uses in `grants` for every socket added afterwards
+    pub fn with_grants(mut self, grants: GrantUses) -> Self {
+        self.grants = Arc::new(grants);
+        self
+    }
+
+    /// Carry on from the counters in `saved` for sockets added afterwards,
+    /// and record them there
+    pub fn with_saved_stats(mut self, saved: SavedStats) -> Self {
+        self.saved_stats = saved;
+        self
+    }
+
+    /// Start from `config`, which ephemeral and generated sockets are
+    /// built with until sockets are added or reloaded
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.update_certificates(&config);
+        self.config = RwLock::new(config);
+        self
+    }
+
+    /// Start fetching certificates with the `[certificates]` settings of
+    /// `config` if they changed, returning whether they did
+    ///
+    /// Certificates already fetched are kept while the settings stay.
+    fn update_certificates(&self, config: &Config) -> bool {
+        let settings = config.effective().certificates;
+        let mut certificates = self.certificates.write().unwrap();
+        if certificates.as_ref().map(|store| store.config()) == settings.as_ref() {
+            return false;
+        }
+        *certificates = settings.map(|settings| Arc::new(CertificateStore::new(settings)));
+        true
+    }
+
+    /// A socket sharing the router's upstream, pins, grant uses and
+    /// panic mode
+    ///
+    /// Its counters carry on from the last ones recorded for its path;
+    /// ephemeral sockets are never recorded, so they start from zero.
+    fn new_socket(&self, entry: &SocketEntry, config: &Config) -> Result<Arc<FilteredSocket>> {
+        let socket = Arc::new(
+            FilteredSocket::new(entry, self.agent.clone(), config)?
+                .with_pins(self.pins.clone())
+                .with_grants(Arc::clone(&self.grants))
+                .with_lockdown(Arc::clone(&self.lockdown))
+                .with_certificates(self.certificates.read().unwrap().clone()),
+        );
+        if let Some(saved) = self.saved_stats.get(&entry.path) {
+            socket.stats().restore(&saved);
+        }
+        Ok(socket)
+    }
+
+    /// Build `socket` anew from `entry` and serve its clients with it from
+    /// now on, for settings only a new socket picks up
+    fn rebuild_socket(&self, socket: &FilteredSocket, entry: &SocketEntry, config: &Config) -> Result<Arc<FilteredSocket>> {
+        self.keep_stats(socket);
+        let rebuilt = self.new_socket(entry, config)?;
+        rebuilt.replace(socket)?;
+        Ok(rebuilt)
+    }
+
+    /// Record a socket's counters before it is unbound, so they carry on
+    /// if it comes back
+    fn keep_stats(&self, socket: &FilteredSocket) {
+        self.saved_stats.update(socket.path(), socket.stats().snapshot());
+    }
+
+    /// Write the counters of configured and generated sockets to the
+    /// saved statistics
+    pub fn save_stats(&self) -> Result<()> {
+        for socket in self.sockets.read().unwrap().iter() {
+            self.keep_stats(socket);
+        }
+        for (_, socket) in self.per_key.read().unwrap().iter() {
+            self.keep_stats(socket);
+        }
+        self.saved_stats.save()
+    }
+
+    /// Make every socket, including ones added later, list no keys and
+    /// refuse every request, then disconnect all clients and forget
+    /// cached approvals
+    ///
+    /// Returns how many client connections were closed. Lasts until
+    /// [`Router::resume`], across reloads.
+    pub fn panic(&self) -> usize {
+        self.lockdown.store(true, Ordering::SeqCst);
+        log::warn!("Panic mode: all sockets now refuse every request");
+        let mut closed = 0;
+        for socket in self.sockets() {
+            closed += socket.close_connections();
+            socket.flush_approvals();
+        }
+        closed
+    }
+
+    /// Leave panic mode, returning whether the router was in it
+    pub fn resume(&self) -> bool {
+        let was = self.lockdown.swap(false, Ordering::SeqCst);
+        if was {
+            log::warn!("Panic mode ended, sockets apply their policy again");
+        }
+        was
+    }
+
+    pub fn in_panic(&self) -> bool {
+        self.lockdown.load(Ordering::SeqCst)
+    }
+
+    /// Give the listening sockets of the configured sockets to a router
+    /// taking over, which this one should then exit for
+    ///
+    /// Generated and ephemeral sockets are not handed over: the new
+    /// router generates its own and does not know of the others.
+    pub fn hand_over(&self) -> Vec<(PathBuf, UnixListener)> {
+        self.handed_over.store(true, Ordering::SeqCst);
+        self.sockets.read().unwrap().iter()
+            .filter_map(|socket| Some((socket.path().clone(), socket.hand_over()?)))
+            .collect()
+    }
+
+    /// Whether the sockets were handed over and the router should exit
+    pub fn handed_over(&self) -> bool {
+        self.handed_over.load(Ordering::SeqCst)
+    }
+
+    /// Unbind the configured sockets tagged `tag` and disconnect their
+    /// clients, returning their names and how many connections were
+    /// closed
+    ///
+    /// They stay unbound across reloads until [`Router::enable_tag`].
+    pub fn disable_tag(&self, tag: &str) -> Result<(Vec<String>, usize)> {
+        let config = self.config();
+        let tagged: Vec<&SocketEntry> = config.sockets.iter().filter(|s| s.tags.iter().any(|t| t == tag)).collect();
+        if tagged.is_empty() {
+            anyhow::bail!("No configured socket is tagged {:?}", tag);
+        }
+
+        self.disabled.write().unwrap().extend(tagged.iter().map(|entry| entry.path.clone()));
+        let mut names = Vec::new();
+        let mut closed = 0;
+        self.sockets.write().unwrap().retain(|socket| {
+            if !tagged.iter().any(|entry| &entry.path == socket.path()) {
+                return true;
+            }
+            log::info!("{}: disabled by tag {:?}, unbinding", socket.name(), tag);
+            closed += socket.close_connections();
+            self.keep_stats(socket);
+            names.push(socket.name());
+            false
+        });
+        Ok((names, closed))
+    }
+
+    /// Bind the configured sockets tagged `tag` that were taken down by
+    /// [`Router::disable_tag`], returning their names
+    pub fn enable_tag(&self, tag: &str) -> Result<Vec<String>> {
+        let config = self.config();
+        let tagged: Vec<&SocketEntry> = config.sockets.iter().filter(|s| s.tags.iter().any(|t| t == tag)).collect();
+        if tagged.is_empty() {
+            anyhow::bail!("No configured socket is tagged {:?}", tag);
+        }
+
+        // Like reload, bind them all before anything changes. Sockets
+        // outside their active hours come up when their schedule starts
+        let now = SystemTime::now();
+        let mut added = Vec::new();
+        let mut waiting = Vec::new();
+        for entry in tagged.into_iter().filter(|entry| self.is_disabled(&entry.path)) {
+            if !config.socket_active(entry, now) {
+                waiting.push(&entry.path);
+                continue;
+            }
+            let socket = self.new_socket(entry, &config)?;
+            socket.listen()?;
+            added.push(socket);
+        }
+
+        let mut disabled = self.disabled.write().unwrap();
+        for path in waiting {
+            disabled.remove(path);
+        }
+        for socket in &added {
+            log::info!("{}: enabled by tag {:?}, listening on {:?}", socket.name(), tag, socket.path());
+            disabled.remove(socket.path());
+        }
+        let names = added.iter().map(|socket| socket.name()).collect();
+        self.sockets.write().unwrap().extend(added);
+        Ok(names)
+    }
+
+    /// Whether a configured socket is unbound by [`Router::disable_tag`]
+    pub fn is_disabled(&self, path: &Path) -> bool {
+        self.disabled.read().unwrap().contains(path)
+    }
+
+    /// Create, bind and start serving a filtered socket
+    pub async fn add_socket(&self, entry: &SocketEntry, config: &Config) -> Result<Arc<FilteredSocket>> {
+        let socket = self.new_socket(entry, config)?;
+        socket.start().await?;
+
+        self.sockets.write().unwrap().push(Arc::clone(&socket));
+        *self.config.write().unwrap() = config.clone();
+        Ok(socket)
+    }
+
+    /// Bind a socket that is not part of the configuration
+    ///
+    /// It outlives reloads and profile switches and is unbound after
+    /// `ttl`, by [`Router::remove_ephemeral`] or when the router stops.
+    /// Without a path it goes into the ephemeral socket directory.
+    pub fn add_ephemeral(self: &Arc<Self>, mut entry: SocketEntry, ttl: Option<Duration>) -> Result<Arc<FilteredSocket>> {
+        if entry.allowed.is_empty() && entry.rules.is_empty() {
+            anyhow::bail!("An ephemeral socket needs at least one allowed key");
+        }
+
+        let name = match &entry.name {
+            Some(name) => name.clone(),
+            None if entry.path.as_os_str().is_empty() => {
+                let taken = self.sockets().iter().map(|s| s.name()).collect::<Vec<_>>();
+                loop {
+                    let name = format!("ephemeral-{}", self.next_ephemeral.fetch_add(1, Ordering::Relaxed));
+                    if !taken.contains(&name) {
+                        break name;
+                    }
+                }
+            }
+            None => entry.name(),
+        };
+        if entry.path.as_os_str().is_empty() {
+            let dir = Config::ephemeral_socket_dir()?;
+            state::create_private_dir(&dir)?;
+            entry.path = dir.join(format!("{}.sock", name));
+        }
+        entry.name = Some(name.clone());
+
+        if self.sockets().iter().any(|s| s.name() == name) {
+            anyhow::bail!("A socket named {:?} already exists", name);
+        }
+        // Binding replaces whatever is at the path, so only take a free one
+        if entry.path.exists() {
+            anyhow::bail!("{:?} already exists", entry.path);
+        }
+
+        let config = self.config.read().unwrap().clone();
+        let socket = self.new_socket(&entry, &config)?;
+        socket.listen()?;
+
+        let expires = ttl.map(|ttl| (SystemTime::now() + ttl).duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+        self.ephemeral.write().unwrap().push(Ephemeral { socket: Arc::clone(&socket), expires });
+        log::info!("{}: ephemeral socket listening on {:?}", name, entry.path);
+
+        if let Some(ttl) = ttl {
+            let router = Arc::downgrade(self);
+            let socket = Arc::downgrade(&socket);
+            std::thread::spawn(move || {
+                std::thread::sleep(ttl);
+                if let (Some(router), Some(socket)) = (router.upgrade(), socket.upgrade()) {
+                    if router.take_ephemeral(|s| Arc::ptr_eq(s, &socket)).is_some() {
+                        let closed = socket.close_connections();
+                        log::info!("{}: ephemeral socket expired, unbinding and closing {} connection(s)", socket.name(), closed);
+                    }
+                }
+            });
+        }
+
+        Ok(socket)
+    }
+
+    /// Unbind an ephemeral socket by name or path
+    pub fn remove_ephemeral(&self, name_or_path: &str) -> Result<Arc<FilteredSocket>> {
+        let socket = self
+            .take_ephemeral(|s| s.name() == name_or_path || s.path().as_os_str() == name_or_path)
+            .with_context(|| {
+                if self.sockets.read().unwrap().iter().any(|s| s.name() == name_or_path || s.path().as_os_str() == name_or_path) {
+                    format!("Socket {:?} is configured, not ephemeral", name_or_path)
+                } else {
+                    format!("No ephemeral socket named {:?}", name_or_path)
+                }
+            })?;
+        let closed = socket.close_connections();
+        log::info!("{}: ephemeral socket removed, unbinding and closing {} connection(s)", socket.name(), closed);
+        Ok(socket)
+    }
+
+    fn take_ephemeral(&self, matches: impl Fn(&Arc<FilteredSocket>) -> bool) -> Option<Arc<FilteredSocket>> {
+        let mut ephemeral = self.ephemeral.write().unwrap();
+        let index = ephemeral.iter().position(|e| matches(&e.socket))?;
+        Some(ephemeral.remove(index).socket)
+    }
+
+    /// Unix time at which a socket expires, `None` for sockets without
+    /// an expiry or TTL
+    pub fn expires(&self, socket: &Arc<FilteredSocket>) -> Option<u64> {
+        if let Some(ephemeral) = self.ephemeral.read().unwrap().iter().find(|e| Arc::ptr_eq(&e.socket, socket)) {
+            return ephemeral.expires;
+        }
+        let config = self.config.read().unwrap();
+        let entry = config.sockets.iter().find(|s| &s.path == socket.path())?;
+        let expiry = config.socket_expiry(entry)?;
+        Some(expiry.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs())
+    }
+
+    /// Unbind configured sockets whose `expires` or `ttl` has passed
+    pub fn remove_expired(&self) {
+        let config = self.config.read().unwrap();
+        let expired = config.expired_sockets(SystemTime::now());
+        if expired.is_empty() {
+            return;
+        }
+        self.sockets.write().unwrap().retain(|socket| {
+            if !expired.iter().any(|entry| &entry.path == socket.path()) {
+                return true;
+            }
+            let closed = socket.close_connections();
+            log::info!("{}: expired, unbinding and closing {} connection(s)", socket.name(), closed);
+            self.keep_stats(socket);
+            false
+        });
+    }
+
+    /// Bind and unbind configured sockets as their `active` schedules
+    /// start and end
+    ///
+    /// Sockets are unbound rather than filtered outside their hours, so
+    /// clients fail to connect instead of finding an empty agent.
+    pub fn apply_schedules(&self) {
+        let config = self.config();
+        let now = SystemTime::now();
+        let expired = config.expired_sockets(now);
+        for entry in config.sockets.iter().filter(|entry| entry.active.is_some()) {
+            let bound = self.sockets.read().unwrap().iter().any(|socket| socket.path() == &entry.path);
+            let active = config.socket_active(entry, now);
+            if bound && !active {
+                self.sockets.write().unwrap().retain(|socket| {
+                    if socket.path() != &entry.path {
+                        return true;
+                    }
+                    let closed = socket.close_connections();
+                    log::info!("{}: outside its active hours, unbinding and closing {} connection(s)", socket.name(), closed);
+                    self.keep_stats(socket);
+                    false
+                });
+            } else if !bound && active && !self.is_disabled(&entry.path) && !expired.iter().any(|e| e.path == entry.path) {
+                match self.new_socket(entry, &config).and_then(|socket| socket.listen().map(|()| socket)) {
+                    Ok(socket) => {
+                        log::info!("{}: active hours started, listening on {:?}", socket.name(), entry.path);
+                        self.sockets.write().unwrap().push(socket);
+                    }
+                    Err(e) => log::error!("{}: failed to bind for its active hours: {:#}", entry.name(), e),
+                }
+            }
+        }
+    }
+
+    pub fn is_ephemeral(&self, socket: &Arc<FilteredSocket>) -> bool {
+        self.ephemeral.read().unwrap().iter().any(|e| Arc::ptr_eq(&e.socket, socket))
+    }
+
+    /// Connection to the upstream agent shared by all sockets
+    pub fn agent(&self) -> &Agent {
+        &self.agent
+    }
+
+    /// Configuration the configured sockets were last built from
+    pub fn config(&self) -> Config {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Configured sockets followed by generated and ephemeral ones
+    pub fn sockets(&self) -> Vec<Arc<FilteredSocket>> {
+        let mut sockets = self.sockets.read().unwrap().clone();
+        sockets.extend(self.per_key.read().unwrap().iter().map(|(_, socket)| Arc::clone(socket)));
+        sockets.extend(self.ephemeral.read().unwrap().iter().map(|e| Arc::clone(&e.socket)));
+        sockets
+    }
+
+    /// Open the upstream connections and build every socket's listing
+    /// ahead of the first client, returning how many keys upstream holds
+    pub fn warm(&self) -> Result<usize> {
+        let answer = self.agent.warm()?;
+        for socket in self.sockets() {
+            if let Err(e) = socket.warm_listing(&self.agent, &answer) {
+                log::debug!("{}: failed to warm listing: {:#}", socket.name(), e);
+            }
+        }
+        Ok(self.agent.last_seen().map_or(0, |snapshot| snapshot.keys.len()))
+    }
+
+    /// Remove sockets a crashed router left in its directories, returning
+    /// their paths
+    pub fn sweep_stale_sockets(&self) -> Result<Vec<PathBuf>> {
+        let candidates = crate::sweep::candidates(&self.config())?;
+        let serving: Vec<PathBuf> = self.sockets().iter().map(|socket| socket.path().clone()).collect();
+        Ok(crate::sweep::sweep(&candidates, &serving, false))
+    }
+
+    /// How often to bring the `per_key_sockets` up to date, if configured
+    pub fn per_key_interval(&self) -> Option<Duration> {
+        self.config.read().unwrap().per_key_sockets.as_ref().map(|per_key| per_key.interval)
+    }
+
+    /// Bind a socket for each upstream key that lacks one and unbind the
+    /// sockets of keys upstream no longer lists, as `per_key_sockets`
+    /// describes
+    ///
+    /// Does nothing if another sync is already under way. Keys that would
+    /// share a socket file, or a name with a configured socket, get none.
+    pub fn sync_per_key_sockets(&self) -> Result<()> {
+        let Ok(_syncing) = self.per_key_sync.try_lock() else {
+            return Ok(());
+        };
+        let config = self.config();
+        let Some(per_key) = &config.per_key_sockets else {
+            self.per_key.write().unwrap().clear();
+            return Ok(());
+        };
+        let keys = self.agent.list_keys().context("Failed to list upstream keys for per_key_sockets")?;
+
+        let taken: Vec<String> = self.sockets.read().unwrap().iter()
+            .chain(self.ephemeral.read().unwrap().iter().map(|e| &e.socket))
+            .flat_map(|socket| [socket.name(), socket.path().to_string_lossy().into_owned()])
+            .collect();
+        let mut wanted: Vec<SocketEntry> = Vec::new();
+        for key in &keys {
+            let path = per_key.socket_path(&key.fingerprint, &key.key_type, &key.comment);
+            let entry = SocketEntry {
+                name: path.file_stem().map(|stem| stem.to_string_lossy().into_owned()),
+                path,
+                allowed: vec![key.fingerprint.clone().into()],
+                ..Default::default()
+            };
+            let clash = wanted.iter().find(|other| other.path == entry.path || other.name == entry.name);
+            if let Some(other) = clash {
+                log::warn!(
+                    "per_key_sockets: {} and {} would both use {:?}, skipping the second; add {{fingerprint_slug}} to the pattern",
+                    other.allowed[0].key, key.fingerprint, entry.path
+                );
+            } else if taken.contains(&entry.name()) || taken.iter().any(|t| Path::new(t) == entry.path) {
+                log::warn!("per_key_sockets: {:?} is already a configured socket, skipping {}", entry.path, key.fingerprint);
+            } else {
+                wanted.push(entry);
+            }
+        }
+
+        let mut current = std::mem::take(&mut *self.per_key.write().unwrap());
+        current.retain(|(entry, socket)| {
+            let keep = wanted.iter().any(|w| w.path == entry.path && w.allowed == entry.allowed);
+            if !keep {
+                log::info!("{}: key no longer upstream, unbinding", socket.name());
+                self.keep_stats(socket);
+            }
+            keep
+        });
+        let mut result = Ok(());
+        for entry in wanted {
+            if current.iter().any(|(e, _)| e.path == entry.path) {
+                continue;
+            }
+            match self.new_socket(&entry, &config).and_then(|socket| socket.listen().map(|()| socket)) {
+                Ok(socket) => {
+                    log::info!("{}: new upstream key {}, listening on {:?}", socket.name(), entry.allowed[0].key, entry.path);
+                    current.push((entry, socket));
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+        *self.per_key.write().unwrap() = current;
+        result
+    }
+
+    /// Connections accepted plus requests handled on all sockets, or
+    /// `None` while a client is connected
+    ///
+    /// The router has been idle for as long as this stays the same.
+    pub fn activity(&self) -> Option<u64> {
+        let mut total = 0;
+        for socket in self.sockets() {
+            let stats = socket.stats().snapshot();
+            if stats.active > 0 {
+                return None;
+            }
+            total += stats.accepted + stats.rejected + stats.requests;
+        }
+        Some(total)
+    }
+
+    /// Switch to the sockets in `config`: rebuild the filters of sockets
+    /// it keeps, bind the ones it adds and unbind the ones it drops
+    ///
+    /// Added sockets are all bound before anything else changes, so an
+    /// entry that cannot be started leaves the running set as it was.
+    /// Ephemeral sockets are left alone.
+    pub fn reload(&self, config: &Config) -> Result<()> {
+        let current = self.sockets.read().unwrap().clone();
+        let ephemeral: Vec<_> = self.ephemeral.read().unwrap().iter().map(|e| Arc::clone(&e.socket)).collect();
+
+        // Disabled sockets stay down while they are still configured
+        self.disabled.write().unwrap().retain(|path| config.sockets.iter().any(|s| &s.path == path));
+        let disabled = self.disabled.read().unwrap().clone();
+
+        if self.update_certificates(config) {
+            let certificates = self.certificates.read().unwrap().clone();
+            let per_key = self.per_key.read().unwrap().iter().map(|(_, socket)| Arc::clone(socket)).collect::<Vec<_>>();
+            for socket in current.iter().chain(&ephemeral).chain(&per_key) {
+                socket.set_certificates(certificates.clone());
+            }
+        }
+
+        let now = SystemTime::now();
+        let mut added = Vec::new();
+        for entry in &config.sockets {
+            if current.iter().any(|s| s.path() == &entry.path) || disabled.contains(&entry.path) {
+                continue;
+            }
+            if !config.socket_active(entry, now) {
+                log::info!("{}: outside its active hours, not binding yet", entry.name());
+                continue;
+            }
+            if let Some(socket) = ephemeral.iter().find(|s| s.path() == &entry.path) {
+                anyhow::bail!("{:?} is in use by ephemeral socket {}", entry.path, socket.name());
+            }
+            let socket = self.new_socket(entry, config)?;
+            // Sockets bound so far are unbound again when `added` drops
+            socket.listen()?;
+            socket.warn_if_allow_all(entry, config);
+            added.push(socket);
+        }
+
+        let previous = self.config.read().unwrap().clone();
+        let mut sockets = Vec::new();
+        for socket in current {
+            let Some(entry) = config.sockets.iter().find(|s| &s.path == socket.path()) else {
+                log::info!("{}: removed from configuration, unbinding", socket.name());
+                self.keep_stats(&socket);
+                continue;
+            };
+            if !config.socket_active(entry, now) {
+                log::info!("{}: outside its active hours, unbinding", socket.name());
+                self.keep_stats(&socket);
+                continue;
+            }
+
+            let rebuild = previous.sockets.iter()
+                .find(|s| &s.path == socket.path())
+                .is_some_and(|old| previous.fixed_socket_settings(old) != config.fixed_socket_settings(entry));
+            if rebuild {
+                match self.rebuild_socket(&socket, entry, config) {
+                    Ok(rebuilt) => {
+                        log::info!("{}: settings changed, rebuilt the socket", rebuilt.name());
+                        rebuilt.warn_if_allow_all(entry, config);
+                        sockets.push(rebuilt);
+                    }
+                    Err(e) => {
+                        log::error!("{}: rebuild failed, keeping previous settings: {:#}", socket.name(), e);
+                        sockets.push(socket);
+                    }
+                }
+                continue;
+            }
+
+            match socket.reload(entry, config) {
+                Ok(()) => {
+                    log::info!("{}: reloaded filter", socket.name());
+                    socket.warn_if_allow_all(entry, config);
+                }
+                Err(e) => log::error!("{}: reload failed, keeping previous filter: {:#}", socket.name(), e),
+            }
+            sockets.push(socket);
+        }
+        for socket in &added {
+            log::info!("{}: added to configuration, listening on {:?}", socket.name(), socket.path());
+        }
+        sockets.extend(added);
+
+        *self.sockets.write().unwrap() = sockets;
+        *self.config.write().unwrap() = config.clone();
+
+        // Generated sockets follow the new settings; which ones exist is
+        // settled by the next sync
+        if config.per_key_sockets.is_none() {
+            self.per_key.write().unwrap().clear();
+        }
+        for (entry, socket) in self.per_key.read().unwrap().iter() {
+            if let Err(e) = socket.reload(entry, config) {
+                log::error!("{}: reload failed, keeping previous filter: {:#}", socket.name(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop tracking all sockets so their files are removed once idle
+    pub fn shutdown(&self) {
+        self.sockets.write().unwrap().clear();
+        self.per_key.write().unwrap().clear();
+        self.ephemeral.write().unwrap().clear();
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::{Context, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DAYS: [(&str, &str); 7] = [
+    ("mon", "monday"), ("tue", "tuesday"), ("wed", "wednesday"), ("thu", "thursday"),
+    ("fri", "friday"), ("sat", "saturday"), ("sun", "sunday"),
+];
+
+/// Minutes in a day, the end of a window that lasts until midnight
+const DAY: u16 = 24 * 60;
+
+/// When a socket is bound, e.g. `Mon-Fri 08:00-19:00`
+///
+/// Windows are separated by `;` and each is a list of days or day ranges,
+/// a time range, or both; a missing part means every day or all day. A
+/// window ending before it starts runs past midnight, so `Fri 22:00-02:00`
+/// covers the first two hours of Saturday.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    windows: Vec<Window>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Window {
+    /// Monday first
+    days: [bool; 7],
+    /// Minutes since midnight
+    start: u16,
+    end: u16,
+}
+
+/// A moment in local time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    /// 0 for Monday
+    pub weekday: usize,
+    /// Minutes since midnight
+    pub minute: u16,
+}
+
+impl LocalTime {
+    /// The local time in the system time zone
+    pub fn at(time: SystemTime) -> Self {
+        let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as libc::time_t;
+        let mut tm = std::mem::MaybeUninit::<libc::tm>::zeroed();
+        // SAFETY: localtime_r only writes to the struct it is given
+        let tm = unsafe {
+            libc::localtime_r(&secs, tm.as_mut_ptr());
+            tm.assume_init()
+        };
+        Self {
+            // tm_wday counts from Sunday
+            weekday: ((tm.tm_wday + 6) % 7) as usize,
+            minute: (tm.tm_hour * 60 + tm.tm_min) as u16,
+        }
+    }
+}
+
+impl Schedule {
+    pub fn parse(text: &str) -> Result<Self> {
+        let windows = text.split(';')
+            .map(str::trim)
+            .filter(|window| !window.is_empty())
+            .map(|window| Window::parse(window).with_context(|| format!("Invalid schedule {:?}", window)))
+            .collect::<Result<Vec<_>>>()?;
+        if windows.is_empty() {
+            anyhow::bail!("Empty schedule, expected e.g. \"Mon-Fri 08:00-19:00\"");
+        }
+        Ok(Self { windows })
+    }
+
+    /// Whether `time` falls in one of the windows
+    pub fn is_active(&self, time: LocalTime) -> bool {
+        self.windows.iter().any(|window| window.contains(time))
+    }
+
+    pub fn is_active_at(&self, time: SystemTime) -> bool {
+        self.is_active(LocalTime::at(time))
+    }
+}
+
+impl Window {
+    fn parse(text: &str) -> Result<Self> {
+        let mut window = Window { days: [true; 7], start: 0, end: DAY };
+        for part in text.split_whitespace() {
+            if let Some((start, end)) = part.split_once('-').filter(|(start, _)| start.contains(':')) {
+                window.start = parse_time(start)?;
+                window.end = parse_time(end)?;
+                if window.start == window.end || window.start == DAY {
+                    anyhow::bail!("{} is not a time range", part);
+                }
+            } else {
+                window.days = [false; 7];
+                for days in part.split(',') {
+                    let (first, last) = days.split_once('-').unwrap_or((days, days));
+                    let (first, last) = (parse_day(first)?, parse_day(last)?);
+                    // Ranges may wrap around the week, e.g. Sat-Mon
+                    let mut day = first;
+                    loop {
+                        window.days[day] = true;
+                        if day == last {
+                            break;
+                        }
+                        day = (day + 1) % 7;
+                    }
+                }
+            }
+        }
+        Ok(window)
+    }
+
+    fn contains(&self, time: LocalTime) -> bool {
+        if self.start < self.end {
+            return self.days[time.weekday] && (self.start..self.end).contains(&time.minute);
+        }
+        // Past midnight the window belongs to the day before
+        (self.days[time.weekday] && time.minute >= self.start)
+            || (self.days[(time.weekday + 6) % 7] && time.minute < self.end)
+    }
+}
+
+fn parse_day(text: &str) -> Result<usize> {
+    let name = text.to_ascii_lowercase();
+    DAYS.iter()
+        .position(|(short, long)| name == *short || name == *long)
+        .with_context(|| format!("Unknown day {:?}, expected e.g. Mon or Mon-Fri", text))
+}
+
+/// `HH:MM` as minutes since midnight, with `24:00` for the end of the day
+fn parse_time(text: &str) -> Result<u16> {
+    let parsed = text.split_once(':').and_then(|(hours, minutes)| {
+        let hours: u16 = hours.parse().ok()?;
+        let minutes: u16 = minutes.parse().ok().filter(|m| *m < 60 && minutes.len() == 2)?;
+        Some(hours * 60 + minutes).filter(|time| *time <= DAY)
+    });
+    parsed.with_context(|| format!("Invalid time {:?}, expected HH:MM", text))
+}
@@ -0,0 +1,115 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Bound;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// How often a waiting request checks whether it was given up
+const CANCEL_CHECK: Duration = Duration::from_millis(100);
+
+/// Hands the pooled upstream connections to requests of several sockets
+/// in turn, so one busy socket cannot starve the others
+///
+/// Requests of each socket wait in a queue of their own. A connection
+/// that comes free goes to the next socket with a waiting request, round
+/// robin, rather than to whichever request asked first: a CI job keeping
+/// fifty requests queued on one socket delays an interactive `ssh` on
+/// another by one request at most.
+pub struct Scheduler {
+    state: Mutex<State>,
+    turn: Condvar,
+}
+
+struct State {
+    /// Connections no request holds
+    free: usize,
+    /// Tickets of waiting requests by socket
+    queues: BTreeMap<String, VecDeque<u64>>,
+    /// Socket served last; the next turn goes to the one after it
+    last: Option<String>,
+    /// Tickets given a connection whose request has not woken up yet
+    granted: Vec<u64>,
+    next_ticket: u64,
+}
+
+/// A connection held until dropped
+pub struct Permit<'a> {
+    scheduler: &'a Scheduler,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.scheduler.release();
+    }
+}
+
+impl Scheduler {
+    pub fn new(connections: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                free: connections.max(1),
+                queues: BTreeMap::new(),
+                last: None,
+                granted: Vec::new(),
+                next_ticket: 0,
+            }),
+            turn: Condvar::new(),
+        }
+    }
+
+    /// Wait for a connection for a request of socket `queue`, or until
+    /// `cancelled` says its client is gone
+    pub fn acquire(&self, queue: &str, cancelled: impl Fn() -> bool) -> Option<Permit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        // Connections are only free while nobody waits
+        if state.free > 0 {
+            state.free -= 1;
+            state.last = Some(queue.to_string());
+            return Some(Permit { scheduler: self });
+        }
+
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.queues.entry(queue.to_string()).or_default().push_back(ticket);
+        loop {
+            if let Some(i) = state.granted.iter().position(|t| *t == ticket) {
+                state.granted.swap_remove(i);
+                return Some(Permit { scheduler: self });
+            }
+            if cancelled() {
+                let tickets = state.queues.get_mut(queue).expect("a waiting request has a queue");
+                tickets.retain(|t| *t != ticket);
+                if tickets.is_empty() {
+                    state.queues.remove(queue);
+                }
+                return None;
+            }
+            state = self.turn.wait_timeout(state, CANCEL_CHECK).unwrap().0;
+        }
+    }
+
+    /// Requests waiting for a connection
+    pub fn waiting(&self) -> usize {
+        self.state.lock().unwrap().queues.values().map(VecDeque::len).sum()
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        let after = state.last.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+        let next = state.queues.range::<str, _>((after, Bound::Unbounded)).next()
+            .or_else(|| state.queues.iter().next())
+            .map(|(queue, _)| queue.clone());
+        let Some(queue) = next else {
+            state.free += 1;
+            return;
+        };
+
+        let tickets = state.queues.get_mut(&queue).expect("queue was just found");
+        let ticket = tickets.pop_front().expect("queues are never left empty");
+        if tickets.is_empty() {
+            state.queues.remove(&queue);
+        }
+        state.granted.push(ticket);
+        state.last = Some(queue);
+        self.turn.notify_all();
+    }
+}
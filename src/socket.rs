@@ -1,95 +1,581 @@
-use crate::agent::{Agent, SshKey};
+use crate::agent::{self, Agent, SshKey};
+use crate::approval::{self, ApprovalRequest, Approver, DecisionCache, Notifier};
+use crate::audit::{AuditEvent, AuditLog};
+use crate::capture::Capture;
+use crate::certs::CertificateStore;
+use crate::container::ContainerPolicy;
+use crate::config::{expand_tilde, Config, ConfirmMethod, KeyMeta, Limits, Risk, RiskTier, SmartcardPolicy, SocketEntry, DenyResponse, DirPermissions, UnknownKeyPolicy, UpstreamAffinity};
+use crate::logging;
+use crate::context::{self, RequestContext};
+use crate::events::{self, Event};
+use crate::grants::GrantUses;
+use crate::identity;
+use crate::peer::{self, PeerCred};
+use crate::pinning::KeyPins;
+use crate::privacy;
+use crate::policy::{DenyReason, KeyFilter, MessageFilter};
+use crate::protocol::{self, UserAuth};
+use crate::rate::{SignRate, SignRateWindow};
+use crate::stats::SocketStats;
+use crate::telemetry::{self, SpanKind};
 use anyhow::{Context, Result};
-use std::collections::HashSet;
+use sha2::{Digest, Sha256};
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::io::{Read, Write};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::Semaphore;
-use tokio::task;
+use zeroize::Zeroizing;
+
+/// Listening sockets a router handed over on `run --takeover`, by path,
+/// until a socket for the path takes its own
+static INHERITED: Mutex<BTreeMap<PathBuf, UnixListener>> = Mutex::new(BTreeMap::new());
+
+/// Serve `listeners` handed over by another router instead of binding
+/// their paths anew, so clients never find them missing
+pub fn inherit(listeners: Vec<(PathBuf, UnixListener)>) {
+    INHERITED.lock().unwrap().extend(listeners);
+}
+
+/// Whether the socket at `path` was handed over and is waiting to be served
+pub fn is_inherited(path: &Path) -> bool {
+    INHERITED.lock().unwrap().contains_key(path)
+}
+
+/// Remove the files of inherited sockets no socket took, as when the new
+/// configuration dropped them, returning their paths
+pub fn remove_unused_inherited() -> Vec<PathBuf> {
+    let unused = std::mem::take(&mut *INHERITED.lock().unwrap());
+    unused.into_keys()
+        .inspect(|path| {
+            let _ = std::fs::remove_file(path);
+        })
+        .collect()
+}
 
 pub struct FilteredSocket {
     path: PathBuf,
-    allowed_fingerprints: HashSet<String>,
-    denied_fingerprints: HashSet<String>,
+    name: String,
+    /// What the socket is for, from its `description`
+    description: RwLock<Option<String>>,
+    log_target: String,
+    filter: RwLock<KeyFilter>,
+    /// `[keys]` notes by fingerprint, for audit records and prompts
+    key_meta: RwLock<BTreeMap<String, KeyMeta>>,
     agent: Agent,
+    capture: Option<Capture>,
+    audit: Option<AuditLog>,
+    approver: Option<Box<dyn Approver>>,
+    confirm_timeout: Duration,
+    decisions: Option<DecisionCache>,
+    sign_rate: Option<SignRateWindow>,
+    /// Asks about sign requests over `sign_rate`, which are denied without it
+    rate_approver: Option<Box<dyn Approver>>,
+    /// `[risk]` settings by the risk level of keys
+    tiers: BTreeMap<Risk, TierPolicy>,
+    pins: Option<Arc<KeyPins>>,
+    grants: Arc<GrantUses>,
+    /// Set by `panic`: list nothing and refuse everything until `resume`
+    lockdown: Arc<AtomicBool>,
+    /// Connected clients by connection id, so `panic` can close them
+    clients: Mutex<Vec<(u64, UnixStream)>>,
+    require_destination_constraints: bool,
+    smartcard: SmartcardPolicy,
+    smartcard_providers: Vec<PathBuf>,
+    /// Message types passed on to upstream, `None` for all
+    allowed_messages: RwLock<Option<MessageFilter>>,
+    allow_protocol_1: bool,
+    /// Refuses clients in containers, `None` to let them in
+    containers: Option<ContainerPolicy>,
+    dir_permissions: DirPermissions,
+    unknown_key_policy: UnknownKeyPolicy,
+    deny_response: DenyResponse,
+    upstream_affinity: UpstreamAffinity,
+    listing: Mutex<Option<CachedListing>>,
+    /// Fetches certificates to list after the keys they certify
+    certificates: RwLock<Option<Arc<CertificateStore>>>,
+    /// Device and inode of the socket file once bound
+    bound: Mutex<Option<(u64, u64)>>,
+    /// A handle on the listening socket, to pass to a router taking over
+    listener: Mutex<Option<UnixListener>>,
+    /// What the accept loop hands clients to, once listening
+    acceptor: Mutex<Option<Arc<RwLock<Acceptor>>>>,
+    /// Where `SSH_AUTH_SOCK` is written once bound
+    env_file: Mutex<Option<PathBuf>>,
+    limits: Limits,
+    stats: Arc<SocketStats>,
+}
+
+/// The socket an accept loop serves clients of, replaced when a reload
+/// rebuilds the socket on the same listener
+struct Acceptor {
+    socket: Weak<FilteredSocket>,
+    /// Limits concurrent connections to `max_connections`
+    semaphore: Arc<Semaphore>,
+}
+
+/// What a socket does with keys of one risk level, from the `[risk]`
+/// settings it does not set itself
+struct TierPolicy {
+    approver: Option<Box<dyn Approver>>,
+    /// `Some(None)` keeps sign decisions out of the audit trail
+    audit: Option<Option<AuditLog>>,
+    notifier: Option<Notifier>,
+}
+
+/// Outcome of checking a sign request
+enum SignCheck {
+    /// Answer the client with this failure
+    Deny(Vec<u8>),
+    /// Forward it upstream, having taken a use of `grant`
+    Forward { grant: Option<String> },
+}
+
+/// The identities answer last rebuilt for clients, reused while upstream
+/// keeps listing the same keys
+struct CachedListing {
+    /// SHA-256 of the upstream answer it was built from
+    upstream: [u8; 32],
+    /// Pin generation it was filtered against
+    pins: u64,
+    /// Grant uses generation it was filtered against
+    grants: u64,
+    /// Certificates generation it was built with
+    certificates: u64,
+    /// When the first exposed key becomes too old to expose
+    until: Option<SystemTime>,
+    response: Vec<u8>,
+    exposed: usize,
+    total: usize,
 }
 
 impl FilteredSocket {
-    pub fn new(
-        path: PathBuf,
-        allowed: Vec<String>,
-        denied: Vec<String>,
-        agent: Agent,
-    ) -> Self {
-        Self {
-            path,
-            allowed_fingerprints: allowed.into_iter().collect(),
-            denied_fingerprints: denied.into_iter().collect(),
-            agent,
+    pub fn new(entry: &SocketEntry, agent: Agent, config: &Config) -> Result<Self> {
+        let capture = match &entry.debug_capture {
+            Some(dir) => Some(Capture::new(dir.clone(), entry.capture_redact)?),
+            None => None,
+        };
+        let sinks = config.audit_sinks(entry);
+        let audit = match sinks.is_empty() {
+            true => None,
+            false => Some(AuditLog::new(&sinks).with_context(|| format!("Invalid audit setting for socket {:?}", entry.path))?),
+        };
+        logging::set_socket_level(&entry.name(), entry.log_level);
+
+        if let (Some(ConfirmMethod::Ntfy), Some(ntfy)) = (entry.confirm, &config.approval.ntfy) {
+            if ntfy.token.is_none() {
+                log::warn!(
+                    "{}: ntfy approvals without a token can be answered by anyone who can read topic {:?}",
+                    entry.name(), ntfy.topic
+                );
+            }
+        }
+        let approver = entry.confirm
+            .map(|method| approval::approver(method, &config.approval))
+            .transpose()
+            .with_context(|| format!("Invalid confirm setting for socket {:?}", entry.path))?;
+        let rate_approver = entry.sign_rate.as_ref()
+            .and_then(|limit| limit.confirm)
+            .map(|method| approval::approver(method, &config.approval))
+            .transpose()
+            .with_context(|| format!("Invalid sign_rate setting for socket {:?}", entry.path))?;
+        let tiers = config.risk.iter()
+            .map(|(risk, tier)| {
+                Self::tier_policy(entry, config, tier)
+                    .map(|policy| (*risk, policy))
+                    .with_context(|| format!("Invalid [risk.{}] setting for socket {:?}", risk.as_str(), entry.path))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            path: entry.path.clone(),
+            name: entry.name(),
+            description: RwLock::new(entry.description.clone()),
+            log_target: logging::socket_target(&entry.name()),
+            filter: RwLock::new(KeyFilter::from_entry(entry, config)?),
+            key_meta: RwLock::new(config.keys.clone()),
+            agent: agent.for_socket(&entry.name()),
+            capture,
+            audit,
+            approver,
+            confirm_timeout: entry.confirm_timeout.unwrap_or(approval::DEFAULT_TIMEOUT),
+            decisions: entry.confirm_cache.map(DecisionCache::new),
+            sign_rate: entry.sign_rate.as_ref().map(|limit| SignRateWindow::new(limit.max, limit.per)),
+            rate_approver,
+            tiers,
+            pins: None,
+            grants: Arc::new(GrantUses::in_memory()),
+            lockdown: Arc::new(AtomicBool::new(false)),
+            clients: Mutex::new(Vec::new()),
+            require_destination_constraints: entry.require_destination_constraints,
+            smartcard: entry.smartcard.unwrap_or_default(),
+            smartcard_providers: entry.smartcard_providers.iter().map(|p| expand_tilde(p)).collect(),
+            allowed_messages: RwLock::new(Self::message_filter(entry, config)?),
+            allow_protocol_1: entry.allow_protocol_1,
+            containers: ContainerPolicy::from_entry(entry)
+                .with_context(|| format!("Invalid container_images setting for socket {:?}", entry.path))?,
+            dir_permissions: entry.dir_permissions.unwrap_or_default(),
+            unknown_key_policy: entry.unknown_key_policy.unwrap_or_default(),
+            deny_response: entry.deny_response.unwrap_or_default(),
+            upstream_affinity: entry.upstream_affinity.unwrap_or_default(),
+            listing: Mutex::new(None),
+            certificates: RwLock::new(None),
+            bound: Mutex::new(None),
+            listener: Mutex::new(None),
+            acceptor: Mutex::new(None),
+            env_file: Mutex::new(entry.env_file.as_deref().map(expand_tilde)),
+            limits: config.limits.clone(),
+            stats: Arc::new(SocketStats::default()),
+        })
+    }
+
+    /// Check upstream keys against the pinned set shared by all sockets
+    pub fn with_pins(mut self, pins: Option<Arc<KeyPins>>) -> Self {
+        self.pins = pins;
+        self
+    }
+
+    /// Count uses of count-limited grants in `grants` instead of in memory
+    pub fn with_grants(mut self, grants: Arc<GrantUses>) -> Self {
+        self.grants = grants;
+        self
+    }
+
+    /// Refuse everything while `lockdown` is set, as the router's other
+    /// sockets do
+    pub fn with_lockdown(mut self, lockdown: Arc<AtomicBool>) -> Self {
+        self.lockdown = lockdown;
+        self
+    }
+
+    /// List certificates from `certificates`, shared by all sockets,
+    /// after the keys they certify
+    pub fn with_certificates(self, certificates: Option<Arc<CertificateStore>>) -> Self {
+        *self.certificates.write().unwrap() = certificates;
+        self
+    }
+
+    /// Replace the certificate store after a reload, rebuilding the
+    /// listing on the next request
+    pub fn set_certificates(&self, certificates: Option<Arc<CertificateStore>>) {
+        *self.certificates.write().unwrap() = certificates;
+        *self.listing.lock().unwrap() = None;
+    }
+
+    /// Disconnect every connected client, returning how many there were
+    pub fn close_connections(&self) -> usize {
+        let clients = self.clients.lock().unwrap();
+        for (_, stream) in clients.iter() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
         }
+        clients.len()
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn stats(&self) -> &Arc<SocketStats> {
+        &self.stats
+    }
+
+    /// Short name used in capture file names and log lines
+    pub fn name(&self) -> String {
+        self.name.clone()
     }
 
     fn is_key_allowed(&self, key: &SshKey) -> bool {
-        // If in denied list, reject
-        if self.denied_fingerprints.contains(&key.fingerprint) {
-            return false;
+        self.key_refusal(key).is_none()
+    }
+
+    /// Why the socket hides `key`, `None` if it exposes it
+    fn key_refusal(&self, key: &SshKey) -> Option<DenyReason> {
+        if let Some(reason) = self.filter.read().unwrap().evaluate(key).deny_reason() {
+            return Some(reason);
+        }
+        if self.pins.as_ref().is_some_and(|pins| !pins.allows(key)) {
+            return Some(DenyReason::Unpinned);
         }
+        if self.hidden_after(key).is_some_and(|time| time <= SystemTime::now()) {
+            return Some(DenyReason::KeyTooOld);
+        }
+        let grant = self.filter.read().unwrap().grant(key);
+        if grant.is_some_and(|(grant, max_uses)| self.grants.used(&self.name, &grant) >= max_uses) {
+            return Some(DenyReason::GrantUsedUp);
+        }
+        None
+    }
 
-        // If allowed list is empty, allow all (except denied)
-        if self.allowed_fingerprints.is_empty() {
-            return true;
+    /// Take a use of the count-limited grant exposing `key`, if any
+    fn take_grant_use(&self, key: &SshKey, context: &RequestContext) -> Result<Option<String>> {
+        let Some((grant, max_uses)) = self.filter.read().unwrap().grant(key) else {
+            return Ok(None);
+        };
+        match self.grants.try_use(&self.name, &grant, max_uses)? {
+            Some(used) if used == max_uses => {
+                log::info!(target: &self.log_target, "{} [{}]: grant {} used up ({} uses)", self.name, context.id, privacy::fingerprint(&grant), max_uses);
+            }
+            Some(used) => {
+                log::debug!(target: &self.log_target, "{} [{}]: grant {} used {} of {} times", self.name, context.id, privacy::fingerprint(&grant), used, max_uses);
+            }
+            None => anyhow::bail!("grant {} is used up", privacy::fingerprint(&grant)),
         }
+        Ok(Some(grant))
+    }
 
-        // Otherwise, must be in allowed list
-        self.allowed_fingerprints.contains(&key.fingerprint)
+    /// When a key becomes too old for the socket's `max_key_age`
+    fn hidden_after(&self, key: &SshKey) -> Option<SystemTime> {
+        let first_seen = self.pins.as_ref()?.first_seen(&key.fingerprint)?;
+        self.filter.read().unwrap().hidden_after(key, first_seen)
+    }
+
+    /// Whether the socket exposes every key that is not explicitly denied
+    pub fn is_allow_all(&self) -> bool {
+        self.filter.read().unwrap().is_allow_all()
+    }
+
+    /// Warn when the socket exposes every key only because no default
+    /// policy was chosen
+    pub fn warn_if_allow_all(&self, entry: &SocketEntry, config: &Config) {
+        if self.is_allow_all() && config.default_policy_is_implicit(entry) {
+            log::warn!(
+                "Socket {:?} exposes ALL upstream keys because its allow list is empty. \
+                 Add allowed keys, or set default = \"allow\" to silence this warning \
+                 or default = \"deny\" to expose nothing.",
+                self.path
+            );
+        }
     }
 
-    fn filter_identities_response(&self, response: &[u8]) -> Result<Vec<u8>> {
+    fn message_filter(entry: &SocketEntry, config: &Config) -> Result<Option<MessageFilter>> {
+        config.allowed_messages(entry)
+            .map(MessageFilter::new)
+            .transpose()
+            .with_context(|| format!("Invalid allowed_messages setting for socket {:?}", entry.path))
+    }
+
+    fn tier_policy(entry: &SocketEntry, config: &Config, tier: &RiskTier) -> Result<TierPolicy> {
+        let approver = match entry.confirm {
+            Some(_) => None,
+            None => tier.confirm.map(|method| approval::approver(method, &config.approval)).transpose()?,
+        };
+        let audit = match &tier.audit {
+            Some(_) if entry.audit.is_some() || entry.audit_file.is_some() => None,
+            Some(sinks) if sinks.is_empty() => Some(None),
+            Some(sinks) => Some(Some(AuditLog::new(sinks)?)),
+            None => None,
+        };
+        let notifier = tier.notify.map(|method| Notifier::new(method, &config.approval)).transpose()?;
+        Ok(TierPolicy { approver, audit, notifier })
+    }
+
+    /// Rebuild the key and message filters, re-reading `allowed_files`,
+    /// and apply the socket's log level
+    ///
+    /// The old filters stay active on error.
+    pub fn reload(&self, entry: &SocketEntry, config: &Config) -> Result<()> {
+        let filter = KeyFilter::from_entry(entry, config)?;
+        let messages = Self::message_filter(entry, config)?;
+        *self.filter.write().unwrap() = filter;
+        *self.allowed_messages.write().unwrap() = messages;
+        *self.key_meta.write().unwrap() = config.keys.clone();
+        *self.description.write().unwrap() = entry.description.clone();
+        *self.listing.lock().unwrap() = None;
+        logging::set_socket_level(&self.name, entry.log_level);
+
+        let env_file = entry.env_file.as_deref().map(expand_tilde);
+        let mut current = self.env_file.lock().unwrap();
+        if *current != env_file {
+            if let Some(old) = current.as_deref() {
+                self.remove_env_file(old);
+            }
+            if let (Some(path), true) = (&env_file, self.bound.lock().unwrap().is_some()) {
+                self.write_env_file(path)?;
+            }
+            *current = env_file;
+        }
+        Ok(())
+    }
+
+    /// Line written to `env_file`
+    fn env_line(&self) -> String {
+        format!("SSH_AUTH_SOCK={}\n", self.path.display())
+    }
+
+    fn write_env_file(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {:?}", parent))?;
+        }
+        // Renamed into place so a unit starting now never reads it empty
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, self.env_line())
+            .with_context(|| format!("Failed to write {:?}", tmp))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("Failed to write environment file {:?}", path))
+    }
+
+    /// Remove `path` unless something else has rewritten it since
+    fn remove_env_file(&self, path: &Path) {
+        if std::fs::read_to_string(path).is_ok_and(|content| content == self.env_line()) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    fn filter_identities_response(&self, agent: &Agent, response: Vec<u8>, context: &RequestContext) -> Result<Vec<u8>> {
         if response.len() < 5 || response[4] != 12 {
             // Not SSH_AGENT_IDENTITIES_ANSWER
-            return Ok(response.to_vec());
+            return Ok(response);
+        }
+
+        // Bursts of clients usually see the same upstream keys, so reuse the
+        // last rebuilt answer until upstream or the pinned set changes
+        let upstream = <[u8; 32]>::from(Sha256::digest(&response[4..]));
+        let pins = self.pins.as_ref().map_or(0, |pins| pins.generation());
+        let grants = self.grants.generation();
+        let certificates = self.certificates_generation();
+        if let Some(cached) = self.listing.lock().unwrap().as_ref()
+            .filter(|c| c.upstream == upstream && c.pins == pins && c.grants == grants && c.certificates == certificates)
+            .filter(|c| c.until.is_none_or(|until| until > SystemTime::now()))
+        {
+            agent.touch_last_seen();
+            self.audit(context, &AuditEvent::List { exposed: cached.exposed, total: cached.total });
+            return Ok(cached.response.clone());
+        }
+
+        // Filter the very listing being answered; asking upstream again
+        // could return a different set if a key was added in between
+        let (response, exposed, total) = self.rebuild_listing(agent, &response, upstream)?;
+        self.audit(context, &AuditEvent::List { exposed, total });
+        Ok(response)
+    }
+
+    /// Build this socket's listing from an identities answer upstream
+    /// sent elsewhere, so the first client to list is answered from the
+    /// cache
+    pub fn warm_listing(&self, agent: &Agent, response: &[u8]) -> Result<()> {
+        if response.len() < 5 || response[4] != 12 {
+            return Ok(());
         }
+        let upstream = <[u8; 32]>::from(Sha256::digest(&response[4..]));
+        self.rebuild_listing(agent, response, upstream)?;
+        Ok(())
+    }
 
-        // Get all keys from upstream
-        let all_keys = self.agent.list_keys()?;
-        let filtered_keys: Vec<&SshKey> = all_keys
+    /// Filter an identities answer into the one clients of this socket
+    /// get, caching it, and return it with the number of keys exposed and
+    /// listed upstream
+    fn rebuild_listing(&self, agent: &Agent, response: &[u8], upstream: [u8; 32]) -> Result<(Vec<u8>, usize, usize)> {
+        let all_keys = agent.identities_from_answer(&response[4..])?;
+        if let Some(pins) = &self.pins {
+            pins.observe(&all_keys);
+        }
+        let mut filtered_keys: Vec<&SshKey> = all_keys
             .iter()
             .filter(|k| self.is_key_allowed(k))
             .collect();
+        if filtered_keys.len() > self.limits.max_identities {
+            log::warn!(
+                target: &self.log_target,
+                "{}: {} identities exposed, only returning the first {}",
+                self.name, filtered_keys.len(), self.limits.max_identities
+            );
+            filtered_keys.truncate(self.limits.max_identities);
+        }
+        let exposed = filtered_keys.len();
+        let mut until = filtered_keys.iter().filter_map(|k| self.hidden_after(k)).min();
+
+        // Each key's certificate is listed right after it, and the listing
+        // rebuilt once one is due for a refresh or a new one was fetched
+        let certificates_generation = self.certificates_generation();
+        let mut certificates = Vec::new();
+        if let Some(store) = self.certificates.read().unwrap().clone() {
+            for key in &filtered_keys {
+                if let Some(cert) = store.certificate(key) {
+                    if let Some(at) = cert.refresh_at(store.config().refresh_before) {
+                        until = Some(until.map_or(at, |until| until.min(at)));
+                    }
+                    certificates.push(cert);
+                }
+            }
+        }
+        let mut identities: Vec<(&[u8], &str)> = Vec::with_capacity(filtered_keys.len() + certificates.len());
+        let mut certificates = certificates.iter().peekable();
+        for key in &filtered_keys {
+            identities.push((&key.blob, &key.comment));
+            if let Some(cert) = certificates.next_if(|cert| cert.key_blob == key.blob) {
+                identities.push((&cert.blob, &cert.comment));
+            }
+        }
+
+        // Rebuild response with filtered keys, in a buffer sized up front
+        // with room for the length prefix so nothing is copied twice
+        let size = 9 + identities.iter().map(|(blob, comment)| 8 + blob.len() + comment.len()).sum::<usize>();
+        let mut new_response = Vec::with_capacity(size);
+        new_response.extend_from_slice(&[0; 4]);
 
-        // Rebuild response with filtered keys
-        let mut new_response = Vec::new();
-        
         // Response type
         new_response.push(12); // SSH_AGENT_IDENTITIES_ANSWER
         
         // Number of keys
-        let num_keys = filtered_keys.len() as u32;
+        let num_keys = identities.len() as u32;
         new_response.extend_from_slice(&num_keys.to_be_bytes());
 
-        for key in filtered_keys {
+        for (blob, comment) in identities {
             // Key blob length
-            let blob_len = key.blob.len() as u32;
+            let blob_len = blob.len() as u32;
             new_response.extend_from_slice(&blob_len.to_be_bytes());
             
             // Key blob
-            new_response.extend_from_slice(&key.blob);
+            new_response.extend_from_slice(blob);
             
             // Comment length
-            let comment_len = key.comment.len() as u32;
+            let comment_len = comment.len() as u32;
             new_response.extend_from_slice(&comment_len.to_be_bytes());
             
             // Comment
-            new_response.extend_from_slice(key.comment.as_bytes());
+            new_response.extend_from_slice(comment.as_bytes());
         }
 
-        // Prepend total length
-        let total_len = new_response.len() as u32;
-        let mut final_response = total_len.to_be_bytes().to_vec();
-        final_response.extend_from_slice(&new_response);
+        // Fill in the length prefix
+        let total_len = (new_response.len() - 4) as u32;
+        new_response[..4].copy_from_slice(&total_len.to_be_bytes());
+
+        *self.listing.lock().unwrap() = Some(CachedListing {
+            upstream,
+            pins: self.pins.as_ref().map_or(0, |pins| pins.generation()),
+            grants: self.grants.generation(),
+            certificates: certificates_generation,
+            until,
+            response: new_response.clone(),
+            exposed,
+            total: all_keys.len(),
+        });
 
-        Ok(final_response)
+        Ok((new_response, exposed, all_keys.len()))
+    }
+
+    fn certificates_generation(&self) -> u64 {
+        self.certificates.read().unwrap().as_ref().map_or(0, |store| store.generation())
+    }
+
+    /// `request` signing with the key of a certificate this router
+    /// listed, `None` for any other request
+    fn uncertify_sign_request(&self, request: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+        let store = self.certificates.read().unwrap().clone()?;
+        let cert_blob = protocol::sign_request_key(request)?;
+        let key_blob = store.key_of(cert_blob)?;
+        let rest = &request[9 + cert_blob.len()..];
+        let mut message = Zeroizing::new(Vec::with_capacity(9 + key_blob.len() + rest.len()));
+        message.extend_from_slice(&((5 + key_blob.len() + rest.len()) as u32).to_be_bytes());
+        message.push(protocol::SSH_AGENTC_SIGN_REQUEST);
+        protocol::put_string(&mut message, &key_blob);
+        message.extend_from_slice(rest);
+        Some(message)
     }
 
     fn should_filter_request(&self, request: &[u8]) -> bool {
@@ -101,9 +587,9 @@ impl FilteredSocket {
         false
     }
 
-    fn filter_sign_request(&self, request: &[u8]) -> Result<Option<Vec<u8>>> {
+    fn filter_sign_request(&self, agent: &Agent, request: &[u8], context: &RequestContext) -> Result<SignCheck> {
         if request.len() < 9 {
-            return Ok(None);
+            return Ok(SignCheck::Deny(self.deny("malformed sign request")));
         }
 
         // Parse key blob from sign request
@@ -115,158 +601,860 @@ impl FilteredSocket {
         ]) as usize;
 
         if request.len() < 9 + blob_len {
-            return Ok(None);
+            return Ok(SignCheck::Deny(self.deny("malformed sign request")));
         }
 
         let blob = &request[9..9 + blob_len];
         
         // Get all keys and check if this blob is allowed
-        let all_keys = self.agent.list_keys()?;
+        let all_keys = self.upstream(agent.list_keys())?;
         for key in &all_keys {
             if key.blob == blob {
-                if !self.is_key_allowed(key) {
-                    // Return failure response
-                    let failure_response = vec![0, 0, 0, 1, 5]; // SSH_AGENT_FAILURE
-                    return Ok(Some(failure_response));
+                let refusal = match self.key_refusal(key) {
+                    Some(reason) => Some((reason, "denied by policy".to_string())),
+                    None => self.check_signed_data(key, request, context),
+                };
+                let permitted = refusal.is_none();
+                let over_rate = permitted && self.over_sign_rate(context);
+                let mut allowed = match (permitted, over_rate, &self.rate_approver) {
+                    (false, _, _) => false,
+                    (true, false, _) => self.confirm(key, context),
+                    // Cached approvals do not count once the rate is exceeded
+                    (true, true, Some(approver)) => self.ask(approver.as_ref(), key, context),
+                    (true, true, None) => false,
+                };
+                let (mut reason, mut message) = match (refusal, over_rate) {
+                    (Some(refusal), _) => refusal,
+                    (None, true) if self.rate_approver.is_none() => (DenyReason::RateLimit, "denied, the signing rate is exceeded".to_string()),
+                    (None, _) => (DenyReason::ConfirmationDeclined, "was not confirmed".to_string()),
+                };
+                let mut grant = None;
+                if allowed {
+                    // Another client may have used the grant up meanwhile
+                    match self.take_grant_use(key, context) {
+                        Ok(taken) => grant = taken,
+                        Err(e) => {
+                            log::info!(target: &self.log_target, "{} [{}]: {:#}", self.name, context.id, e);
+                            (allowed, reason, message) = (false, DenyReason::GrantUsedUp, "denied, its grant is used up".to_string());
+                        }
+                    }
+                }
+                let namespace = protocol::sign_request_data(request).and_then(protocol::sshsig_namespace);
+                let meta = self.key_meta(&key.fingerprint);
+                self.audit(context, &AuditEvent::Sign {
+                    fingerprint: &key.fingerprint,
+                    comment: &key.comment,
+                    key_meta: meta.as_ref(),
+                    namespace: namespace.as_deref(),
+                    allowed,
+                    reason: (!allowed).then_some(reason),
+                });
+                if !allowed {
+                    self.stats.sign_denied(&key.fingerprint);
+                    log::info!(target: &self.log_target, "{} [{}]: denied sign request for {}", self.name, context.id, privacy::fingerprint(&key.fingerprint));
+                    return Ok(SignCheck::Deny(self.deny(&format!("signing with {} {}", key.fingerprint, message))));
                 }
-                break;
+                self.stats.sign_allowed(&key.fingerprint);
+                self.notify(key, meta.as_ref(), context);
+                log::debug!(target: &self.log_target, "{} [{}]: sign request for {}", self.name, context.id, privacy::fingerprint(&key.fingerprint));
+                return Ok(SignCheck::Forward { grant });
             }
         }
 
-        Ok(None)
+        // A key the upstream agent does not list cannot be checked against
+        // the filter, so only forward it when the socket explicitly allows it
+        if self.unknown_key_policy == UnknownKeyPolicy::Forward {
+            log::warn!(target: &self.log_target, "{} [{}]: forwarding sign request for a key upstream does not list", self.name, context.id);
+            return Ok(SignCheck::Forward { grant: None });
+        }
+        let key = SshKey::from_key_blob(blob.to_vec(), String::new());
+        self.audit(context, &AuditEvent::Sign {
+            fingerprint: &key.fingerprint,
+            comment: &key.comment,
+            key_meta: None,
+            namespace: None,
+            allowed: false,
+            reason: Some(DenyReason::UnknownKey),
+        });
+        self.stats.sign_denied(&key.fingerprint);
+        log::info!(target: &self.log_target, "{} [{}]: denied sign request for a key upstream does not list", self.name, context.id);
+        Ok(SignCheck::Deny(self.deny("signing with a key upstream does not list denied by policy")))
+    }
+
+    /// Why a sign request may not make what it asks for, if the socket
+    /// binds `key` to host keys or SSHSIG namespaces
+    ///
+    /// A bound key signs logins only to its bound hosts and SSHSIG data
+    /// only in its bound namespaces, and nothing else.
+    fn check_signed_data(&self, key: &SshKey, request: &[u8], context: &RequestContext) -> Option<(DenyReason, String)> {
+        let (hosts, namespaces) = {
+            let filter = self.filter.read().unwrap();
+            (filter.bound_hosts(key).map(<[String]>::to_vec), filter.bound_namespaces(key).map(<[String]>::to_vec))
+        };
+        if hosts.is_none() && namespaces.is_none() {
+            return None;
+        }
+
+        let data = protocol::sign_request_data(request).unwrap_or_default();
+        if let Some(auth) = protocol::parse_userauth(data) {
+            return match hosts {
+                Some(hosts) => self.check_host(key, &hosts, &auth, context),
+                None => {
+                    log::info!(target: &self.log_target, "{} [{}]: {} is bound to SSHSIG namespaces, refusing to sign a login", self.name, context.id, privacy::fingerprint(&key.fingerprint));
+                    Some((DenyReason::NamespaceNotAllowed, "denied for logins, it only makes SSHSIG signatures".to_string()))
+                }
+            };
+        }
+        if let Some(namespace) = protocol::sshsig_namespace(data) {
+            return match namespaces {
+                Some(namespaces) if namespaces.contains(&namespace) => {
+                    log::debug!(target: &self.log_target, "{} [{}]: {} signs in bound namespace {:?}", self.name, context.id, privacy::fingerprint(&key.fingerprint), namespace);
+                    None
+                }
+                Some(_) => {
+                    log::info!(target: &self.log_target, "{} [{}]: {} is not bound to namespace {:?}", self.name, context.id, privacy::fingerprint(&key.fingerprint), namespace);
+                    Some((DenyReason::NamespaceNotAllowed, format!("denied in namespace {:?}", namespace)))
+                }
+                None => {
+                    log::info!(target: &self.log_target, "{} [{}]: {} is bound to hosts, refusing an SSHSIG signature", self.name, context.id, privacy::fingerprint(&key.fingerprint));
+                    Some((DenyReason::HostNotAllowed, "denied, it may only log in to its bound hosts".to_string()))
+                }
+            };
+        }
+        log::info!(target: &self.log_target, "{} [{}]: {} is bound to hosts or namespaces, refusing to sign other data", self.name, context.id, privacy::fingerprint(&key.fingerprint));
+        let reason = if hosts.is_some() { DenyReason::HostNotAllowed } else { DenyReason::NamespaceNotAllowed };
+        Some((reason, "denied for data that is neither a login nor an SSHSIG signature".to_string()))
+    }
+
+    /// Why a login may not go to the server it is for
+    ///
+    /// The server is taken from the host key ssh signs along with the
+    /// login, which the server checks, so logins without one are refused.
+    /// A session binding upstream accepted for the same session must name
+    /// the same host.
+    fn check_host(&self, key: &SshKey, hosts: &[String], auth: &UserAuth, context: &RequestContext) -> Option<(DenyReason, String)> {
+        let Some(host_key) = &auth.host_key else {
+            log::info!(target: &self.log_target, "{} [{}]: cannot tell which host {} logs in to", self.name, context.id, privacy::fingerprint(&key.fingerprint));
+            return Some((DenyReason::HostNotAllowed, "denied, the login does not name the host it is for".to_string()));
+        };
+        let bound = context.session_binds.iter().find(|bind| bind.session_id == auth.session_id);
+        if bound.is_some_and(|bind| &bind.host_key != host_key) {
+            log::warn!(target: &self.log_target, "{} [{}]: host key in the login does not match the session binding", self.name, context.id);
+            return Some((DenyReason::HostNotAllowed, "denied, the login names a different host than the session".to_string()));
+        }
+
+        let host = SshKey::from_key_blob(host_key.clone(), String::new()).fingerprint;
+        if hosts.contains(&host) {
+            log::debug!(target: &self.log_target, "{} [{}]: {} logs in to bound host {}", self.name, context.id, privacy::fingerprint(&key.fingerprint), host);
+            return None;
+        }
+        log::info!(target: &self.log_target, "{} [{}]: {} is not bound to host {}", self.name, context.id, privacy::fingerprint(&key.fingerprint), host);
+        Some((DenyReason::HostNotAllowed, format!("denied for host {}", host)))
+    }
+
+    /// Decode an added key's constraints for the log and audit trail, and
+    /// refuse it if the socket requires destination constraints it lacks
+    fn filter_add_identity(&self, request: &[u8], context: &RequestContext) -> Option<Vec<u8>> {
+        let added = match identity::parse_add_identity(request) {
+            Ok(added) => added,
+            Err(e) if self.require_destination_constraints => {
+                log::info!(target: &self.log_target, "{} [{}]: denied adding a key that cannot be checked: {:#}", self.name, context.id, e);
+                return Some(self.deny("adding a key that cannot be checked for destination constraints denied by policy"));
+            }
+            Err(e) => {
+                log::debug!(target: &self.log_target, "{} [{}]: forwarding a key that cannot be decoded: {:#}", self.name, context.id, e);
+                return None;
+            }
+        };
+
+        let destinations: Vec<String> = added.destinations().iter().map(|d| d.to_string()).collect();
+        let allowed = !self.require_destination_constraints || !destinations.is_empty();
+        self.audit(context, &AuditEvent::Add {
+            fingerprint: &added.key.fingerprint,
+            comment: &added.key.comment,
+            destinations: destinations.clone(),
+            allowed,
+            reason: (!allowed).then_some(DenyReason::MissingDestinationConstraints),
+        });
+
+        if !allowed {
+            log::info!(
+                target: &self.log_target,
+                "{} [{}]: denied adding {} without destination constraints",
+                self.name, context.id, privacy::fingerprint(&added.key.fingerprint)
+            );
+            return Some(self.deny(&format!(
+                "adding {} without destination constraints denied by policy",
+                added.key.fingerprint
+            )));
+        }
+        log::info!(
+            target: &self.log_target,
+            "{} [{}]: adding {} ({}){}",
+            self.name,
+            context.id,
+            added.key.fingerprint,
+            added.key.comment,
+            if destinations.is_empty() { String::new() } else { format!(" restricted to {}", destinations.join(", ")) }
+        );
+        None
+    }
+
+    /// Apply the socket's smartcard policy to loading or unloading a token,
+    /// which changes every key the token holds at once
+    fn filter_smartcard(&self, request: &[u8], context: &RequestContext) -> Option<Vec<u8>> {
+        let mut reader = protocol::Reader::new(request);
+        let provider = reader.read_u32()
+            .and_then(|_| reader.read_u8())
+            .and_then(|_| reader.read_string())
+            .map(|p| String::from_utf8_lossy(p).to_string());
+        let Ok(provider) = provider else {
+            return Some(self.deny("malformed smartcard request"));
+        };
+        let action = if protocol::message_type(request) == Some(protocol::SSH_AGENTC_REMOVE_SMARTCARD_KEY) {
+            "remove"
+        } else {
+            "add"
+        };
+
+        let reason = match self.smartcard {
+            SmartcardPolicy::Deny => Some(DenyReason::SmartcardDenied),
+            SmartcardPolicy::Allow if self.smartcard_providers.is_empty()
+                || self.smartcard_providers.iter().any(|p| p.as_os_str() == provider.as_str()) => None,
+            SmartcardPolicy::Allow => Some(DenyReason::ProviderNotAllowed),
+        };
+        let allowed = reason.is_none();
+        self.audit(context, &AuditEvent::Smartcard { action, provider: &provider, allowed, reason });
+
+        if !allowed {
+            log::info!(target: &self.log_target, "{} [{}]: denied smartcard {} for {:?}", self.name, context.id, action, provider);
+            return Some(self.deny(&format!("smartcard {} for {:?} denied by policy", action, provider)));
+        }
+        log::info!(target: &self.log_target, "{} [{}]: smartcard {} for {:?}", self.name, context.id, action, provider);
+        None
+    }
+
+    /// Ask the user to approve a sign request if the socket requires it
+    fn confirm(&self, key: &SshKey, context: &RequestContext) -> bool {
+        let meta = self.key_meta(&key.fingerprint);
+        let tier_approver = self.tier(meta.as_ref()).and_then(|tier| tier.approver.as_deref());
+        let Some(approver) = self.approver.as_deref().or(tier_approver) else {
+            return true;
+        };
+        if let Some(decisions) = &self.decisions {
+            if decisions.is_approved(&key.fingerprint, context.peer.pid) {
+                log::debug!(target: &self.log_target, "{} [{}]: reusing approval for {}", self.name, context.id, privacy::fingerprint(&key.fingerprint));
+                return true;
+            }
+        }
+
+        let approved = self.ask(approver, key, context);
+        if approved {
+            if let Some(decisions) = &self.decisions {
+                decisions.approve(&key.fingerprint, context.peer.pid);
+            }
+        }
+        approved
+    }
+
+    /// The `[risk]` settings for a key with `meta`
+    fn tier(&self, meta: Option<&KeyMeta>) -> Option<&TierPolicy> {
+        meta.and_then(|meta| meta.risk).and_then(|risk| self.tiers.get(&risk))
+    }
+
+    /// Tell the user `key` is being used if its risk level asks for it
+    ///
+    /// Notifications are sent in the background so they never hold up the
+    /// signature.
+    fn notify(&self, key: &SshKey, meta: Option<&KeyMeta>, context: &RequestContext) {
+        let Some(notifier) = self.tier(meta).and_then(|tier| tier.notifier.clone()) else {
+            return;
+        };
+        let description = self.description.read().unwrap().clone();
+        let summary = ApprovalRequest { context, key, meta, socket_description: description.as_deref() }.summary();
+        let (name, id, log_target) = (self.name.clone(), context.id.clone(), self.log_target.clone());
+        std::thread::spawn(move || {
+            if let Err(e) = notifier.notify(&summary) {
+                log::warn!(target: &log_target, "{} [{}]: {} notification failed: {:#}", name, id, notifier.name(), e);
+            }
+        });
+    }
+
+    /// The `[keys]` notes on the key with `fingerprint`
+    fn key_meta(&self, fingerprint: &str) -> Option<KeyMeta> {
+        self.key_meta.read().unwrap().get(fingerprint).cloned()
+    }
+
+    fn ask(&self, approver: &dyn Approver, key: &SshKey, context: &RequestContext) -> bool {
+        let meta = self.key_meta(&key.fingerprint);
+        let description = self.description.read().unwrap().clone();
+        let request = ApprovalRequest { context, key, meta: meta.as_ref(), socket_description: description.as_deref() };
+        match approver.approve(&request, self.confirm_timeout) {
+            Ok(approved) => approved,
+            Err(e) => {
+                log::warn!(
+                    target: &self.log_target,
+                    "{} [{}]: {} confirmation failed, denying: {:#}",
+                    self.name, context.id, approver.name(), e
+                );
+                false
+            }
+        }
+    }
+
+    /// Count a sign request against `sign_rate`, returning whether the
+    /// limit is exceeded
+    fn over_sign_rate(&self, context: &RequestContext) -> bool {
+        let Some(window) = &self.sign_rate else {
+            return false;
+        };
+        match window.record(Instant::now()) {
+            SignRate::Normal => false,
+            SignRate::Exceeded { started: false } => true,
+            SignRate::Exceeded { started: true } => {
+                let (max, per) = window.limit();
+                let action = match &self.rate_approver {
+                    Some(approver) => format!("confirming them with {}", approver.name()),
+                    None => "denying them".to_string(),
+                };
+                log::warn!(
+                    target: &self.log_target,
+                    "{} [{}]: more than {} sign requests in {}, {} until the rate drops",
+                    self.name, context.id, max, humantime::format_duration(per), action
+                );
+                true
+            }
+            SignRate::Dropped => {
+                log::info!(target: &self.log_target, "{} [{}]: signing rate back within its limit", self.name, context.id);
+                false
+            }
+        }
+    }
+
+    /// Forget cached approvals, returning how many were dropped
+    pub fn flush_approvals(&self) -> usize {
+        self.decisions.as_ref().map_or(0, DecisionCache::flush)
+    }
+
+    /// SSH_AGENT_SUCCESS carrying this socket's stats as a JSON string
+    fn stats_extension(&self) -> Result<Vec<u8>> {
+        let mut answer = serde_json::json!({
+            "socket": self.name,
+            "stats": self.stats.snapshot(),
+        });
+        if let Some(description) = self.description.read().unwrap().as_deref() {
+            answer["description"] = description.into();
+        }
+        let json = serde_json::to_vec(&answer)?;
+
+        let mut body = vec![protocol::SSH_AGENT_SUCCESS];
+        protocol::put_string(&mut body, &json);
+        Ok(protocol::frame(&body))
+    }
+
+    /// SSH_AGENT_SUCCESS listing the extensions a client can use here
+    ///
+    /// The router's own extensions come first, then those upstream
+    /// supports, which are forwarded unchanged. Upstream agents without
+    /// `query` contribute nothing.
+    fn query_extension(&self, agent: &Agent, request: &[u8]) -> Result<Vec<u8>> {
+        let mut names: Vec<String> = protocol::EXTENSIONS.iter().map(|name| name.to_string()).collect();
+
+        match self.upstream(agent.forward_request(request)) {
+            Ok(response) => {
+                for name in protocol::parse_query_response(&response).unwrap_or_default() {
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+            Err(e) => log::debug!(target: &self.log_target, "{}: upstream query failed: {:#}", self.name, e),
+        }
+
+        let mut body = vec![protocol::SSH_AGENT_SUCCESS];
+        for name in &names {
+            protocol::put_string(&mut body, name.as_bytes());
+        }
+        Ok(protocol::frame(&body))
+    }
+
+    /// Answer a request while the router is in panic mode: no keys are
+    /// listed and nothing reaches upstream
+    fn refuse_in_panic(&self, request: &[u8], context: &RequestContext) -> Vec<u8> {
+        let msg_type = protocol::message_type(request);
+        log::warn!(
+            target: &self.log_target,
+            "{} [{}]: refused {} from {}, the router is in panic mode",
+            self.name, context.id, msg_type.map_or("empty message", protocol::message_type_name), context.client()
+        );
+        if let Some(blob) = protocol::sign_request_key(request) {
+            let key = SshKey::from_key_blob(blob.to_vec(), String::new());
+            let meta = self.key_meta(&key.fingerprint);
+            self.audit(context, &AuditEvent::Sign {
+                fingerprint: &key.fingerprint,
+                comment: &key.comment,
+                key_meta: meta.as_ref(),
+                namespace: None,
+                allowed: false,
+                reason: Some(DenyReason::Panic),
+            });
+        }
+        if msg_type == Some(protocol::SSH_AGENTC_REQUEST_IDENTITIES) {
+            let mut body = vec![protocol::SSH_AGENT_IDENTITIES_ANSWER];
+            body.extend_from_slice(&0u32.to_be_bytes());
+            return protocol::frame(&body);
+        }
+        self.deny("denied, the router is in panic mode")
+    }
+
+    /// Answer a refused request, saying why if the socket is set to
+    fn deny(&self, reason: &str) -> Vec<u8> {
+        match self.deny_response {
+            DenyResponse::Failure => vec![0, 0, 0, 1, protocol::SSH_AGENT_FAILURE],
+            DenyResponse::ExtensionFailure => {
+                protocol::extension_failure(&format!("ssh-agent-router: {} on socket {}", reason, self.name))
+            }
+        }
+    }
+
+    /// Count failures talking to the upstream agent
+    fn upstream<T>(&self, result: Result<T>) -> Result<T> {
+        if result.as_ref().is_err_and(|e| !agent::is_client_gone(e)) {
+            self.stats.upstream_error();
+        }
+        result
+    }
+
+    fn audit(&self, context: &RequestContext, event: &AuditEvent) {
+        let upstream = agent::pinned_upstream();
+        let mut decision = serde_json::to_value(event).unwrap_or_default();
+        privacy::redact_record(&mut decision);
+        if let (Some(upstream), Some(object)) = (&upstream, decision.as_object_mut()) {
+            object.insert("upstream".to_string(), upstream.to_string_lossy().into());
+        }
+        events::publish(Event::Decision {
+            socket: self.name.clone(),
+            id: context.id.clone(),
+            decision,
+        });
+        // Sign decisions follow the key's risk level unless the socket has
+        // its own audit settings
+        let tier_audit = match event {
+            AuditEvent::Sign { key_meta: Some(meta), .. } => self.tier(Some(meta)).and_then(|tier| tier.audit.as_ref()),
+            _ => None,
+        };
+        if let Some(audit) = tier_audit.unwrap_or(&self.audit) {
+            if let Err(e) = audit.record(context, upstream.as_deref(), event) {
+                log::warn!(target: &self.log_target, "{}: {:#}", self.name, e);
+            }
+        }
+    }
+
+    /// Run one length-prefixed request through the filter pipeline
+    ///
+    /// Returns the length-prefixed response to send back to the client.
+    /// Requests can hold private keys being added and the data being
+    /// signed, and responses signatures; the router clears the buffers it
+    /// serves clients from once they are answered, and callers holding
+    /// them longer should do the same, as with [`zeroize::Zeroizing`].
+    pub fn process_request(&self, request: &[u8], context: &RequestContext) -> Result<Vec<u8>> {
+        self.process_request_via(&self.agent, request, context)
+    }
+
+    /// Like `process_request`, talking to upstream through `agent`
+    pub fn process_request_via(&self, agent: &Agent, request: &[u8], context: &RequestContext) -> Result<Vec<u8>> {
+        // Named by message type so latency can be broken down by it
+        let msg_type = protocol::message_type(request);
+        let mut span = telemetry::span(msg_type.map_or("empty message", protocol::message_type_name), SpanKind::Server);
+        span.attribute("ssh_agent_router.socket", &self.name);
+        span.attribute("ssh_agent_router.request_id", &context.id);
+
+        let _pin = agent.pin();
+        let result = self.filter_request(agent, request, context);
+        match &result {
+            Ok(response) => {
+                let response = protocol::message_type(response).map_or("empty message", protocol::message_type_name);
+                span.attribute("ssh_agent_router.response", response);
+            }
+            Err(e) => span.error(e),
+        }
+        result
+    }
+
+    fn filter_request(&self, agent: &Agent, request: &[u8], context: &RequestContext) -> Result<Vec<u8>> {
+        if self.lockdown.load(Ordering::SeqCst) {
+            return Ok(self.refuse_in_panic(request, context));
+        }
+
+        // An empty message has no type to filter on, and OpenSSH's agent
+        // would wait for one, holding up the shared upstream connection
+        if protocol::message_type(request).is_none() {
+            log::warn!(target: &self.log_target, "{} [{}]: refused an empty message", self.name, context.id);
+            return Ok(self.deny("empty message"));
+        }
+
+        // Extensions the router answers itself never reach upstream
+        match protocol::extension_name(request) {
+            Some(name) if name == protocol::STATS_EXTENSION.as_bytes() => return self.stats_extension(),
+            Some(name) if name == protocol::QUERY_EXTENSION.as_bytes() => return self.query_extension(agent, request),
+            _ => {}
+        }
+
+        if self.allowed_messages.read().unwrap().as_ref().is_some_and(|filter| !filter.permits(request)) {
+            let name = protocol::message_type(request).map_or("empty message", protocol::message_type_name);
+            let name = match protocol::extension_name(request) {
+                Some(extension) => format!("{} {}", name, String::from_utf8_lossy(extension)),
+                None => name.to_string(),
+            };
+            log::info!(target: &self.log_target, "{} [{}]: refused {}, not in allowed_messages", self.name, context.id, name);
+            return Ok(self.deny(&format!("{} denied by allowed_messages", name)));
+        }
+
+        // Protocol 1 identities would bypass the filter entirely
+        if let Some(msg_type) = protocol::message_type(request).filter(|t| protocol::is_protocol_1(*t)) {
+            if !self.allow_protocol_1 {
+                log::warn!(
+                    target: &self.log_target,
+                    "{} [{}]: rejected SSH protocol 1 request {}, set allow_protocol_1 to forward it",
+                    self.name, context.id, protocol::message_type_name(msg_type)
+                );
+                return Ok(self.deny(&format!("SSH protocol 1 request {} denied by policy", protocol::message_type_name(msg_type))));
+            }
+        }
+
+        // Upstream holds the keys, not the certificates listed with them,
+        // so signing with a certificate is checked and done as its key
+        let uncertified = self.uncertify_sign_request(request);
+        let request = uncertified.as_ref().map_or(request, |message| message.as_slice());
+
+        // Check if this is a list identities request
+        let is_list = protocol::message_type(request) == Some(protocol::SSH_AGENTC_REQUEST_IDENTITIES);
+
+        // Check if this is a sign request that needs filtering
+        let mut grant = None;
+        if self.should_filter_request(request) {
+            match self.filter_sign_request(agent, request, context)? {
+                SignCheck::Deny(failure) => return Ok(failure),
+                SignCheck::Forward { grant: taken } => grant = taken,
+            }
+        }
+
+        if matches!(
+            protocol::message_type(request),
+            Some(protocol::SSH_AGENTC_ADD_IDENTITY | protocol::SSH_AGENTC_ADD_ID_CONSTRAINED)
+        ) {
+            if let Some(failure) = self.filter_add_identity(request, context) {
+                return Ok(failure);
+            }
+        }
+
+        if matches!(
+            protocol::message_type(request),
+            Some(
+                protocol::SSH_AGENTC_ADD_SMARTCARD_KEY
+                    | protocol::SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED
+                    | protocol::SSH_AGENTC_REMOVE_SMARTCARD_KEY
+            )
+        ) {
+            if let Some(failure) = self.filter_smartcard(request, context) {
+                return Ok(failure);
+            }
+        }
+
+        // A panic while the request waited for approval still stops it
+        let response = match self.lockdown.load(Ordering::SeqCst) {
+            true => Ok(self.refuse_in_panic(request, context)),
+            false => self.upstream(agent.forward_request(request)),
+        };
+
+        // Only signatures upstream actually made use up a grant
+        if let Some(grant) = grant {
+            let signed = response.as_ref().is_ok_and(|r| protocol::message_type(r) == Some(protocol::SSH_AGENT_SIGN_RESPONSE));
+            if !signed {
+                if let Err(e) = self.grants.release(&self.name, &grant) {
+                    log::warn!(target: &self.log_target, "{}: failed to give back a use of grant {}: {:#}", self.name, grant, e);
+                }
+            }
+        }
+        let response = response?;
+
+        // Filter response if it's a list identities response
+        if is_list {
+            self.filter_identities_response(agent, response, context)
+        } else {
+            Ok(response)
+        }
     }
 
     fn handle_client(&self, mut stream: UnixStream) -> Result<()> {
-        // Maximum message size (1MB should be more than enough for SSH agent)
-        const MAX_MESSAGE_SIZE: u32 = 1024 * 1024;
-        
+        let peer = peer::peer_cred(&stream);
+        let connection = context::next_connection();
+        if let Some(refusal) = self.containers.as_ref().and_then(|policy| policy.refusal(peer.pid)) {
+            self.stats.connection_rejected();
+            log::warn!(target: &self.log_target, "{} [{}]: refused {}: {}", self.name, connection, privacy::client(&peer), refusal);
+            let context = RequestContext::new(&self.name, peer, &[]).with_id(connection, 0);
+            self.audit(&context, &AuditEvent::Connect {
+                detail: &refusal,
+                allowed: false,
+                reason: Some(DenyReason::Container),
+            });
+            return Ok(());
+        }
+        let mut requests = 0;
+        // Related requests (add, sign, remove) reach upstream on one
+        // connection, which closes when the client disconnects
+        let agent = match self.upstream_affinity {
+            UpstreamAffinity::Shared => self.agent.clone(),
+            UpstreamAffinity::PerConnection => self.agent.dedicated(),
+        };
+        log::debug!(
+            target: &self.log_target,
+            "{} [{}]: connection from {}",
+            self.name, connection, privacy::client(&peer)
+        );
+        events::publish(Event::Connected {
+            socket: self.name.clone(),
+            id: connection.to_string(),
+            client: privacy::client(&peer),
+        });
+        match stream.try_clone() {
+            Ok(clone) => self.clients.lock().unwrap().push((connection, clone)),
+            Err(e) => log::debug!(target: &self.log_target, "{} [{}]: cannot track connection: {}", self.name, connection, e),
+        }
+        let result = self.serve_client(&mut stream, agent, peer, connection, &mut requests);
+        self.clients.lock().unwrap().retain(|(id, _)| *id != connection);
+        events::publish(Event::Disconnected {
+            socket: self.name.clone(),
+            id: connection.to_string(),
+            requests,
+        });
+        result
+    }
+
+    fn serve_client(
+        &self,
+        stream: &mut UnixStream,
+        mut agent: Agent,
+        peer: PeerCred,
+        connection: u64,
+        requests: &mut u64,
+    ) -> Result<()> {
+        let max_message_size = self.limits.max_message_size;
+        let mut session_binds = Vec::new();
+        let mut dedicated = self.upstream_affinity == UpstreamAffinity::PerConnection;
+        stream.set_read_timeout(self.limits.per_conn_timeout)?;
+        stream.set_write_timeout(self.limits.per_conn_timeout)?;
+        let _watch = agent::watch_client(stream);
+
         loop {
             // Read request length
             let mut len_buf = [0u8; 4];
             match stream.read_exact(&mut len_buf) {
                 Ok(_) => {},
                 Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                    log::debug!(target: &self.log_target, "{}: closing idle client connection", self.name());
+                    break;
+                }
                 Err(e) => return Err(e.into()),
             }
 
             let msg_len = u32::from_be_bytes(len_buf);
             
             // Validate message size to prevent DoS
-            if msg_len > MAX_MESSAGE_SIZE {
-                eprintln!("Message too large: {} bytes (max: {})", msg_len, MAX_MESSAGE_SIZE);
+            if msg_len > max_message_size {
+                log::warn!(target: &self.log_target, "{}: message too large: {} bytes (max: {})", self.name(), msg_len, max_message_size);
                 return Err(anyhow::anyhow!("Message exceeds maximum size"));
             }
             
-            // Read request
-            let mut request = vec![0u8; msg_len as usize];
-            stream.read_exact(&mut request)?;
-
-            // Full request with length prefix
-            let mut full_request = len_buf.to_vec();
-            full_request.extend_from_slice(&request);
-
-            // Check if this is a list identities request
-            let is_list = !request.is_empty() && request[0] == 11;
-
-            // Check if this is a sign request that needs filtering
-            if self.should_filter_request(&full_request) {
-                if let Some(failure) = self.filter_sign_request(&full_request)? {
-                    stream.write_all(&failure)?;
-                    stream.flush()?;
-                    continue;
-                }
+            // Read the request after its length prefix, in one buffer that
+            // is cleared once answered: it may hold a private key being
+            // added or data being signed
+            let mut full_request = Zeroizing::new(vec![0u8; 4 + msg_len as usize]);
+            full_request[..4].copy_from_slice(&len_buf);
+            stream.read_exact(&mut full_request[4..])?;
+            self.stats.add_bytes_in(full_request.len());
+            self.stats.request();
+
+            *requests += 1;
+            let bind = protocol::parse_session_bind(&full_request);
+            // Upstream keeps binds per connection and refuses a second
+            // session's, so a client binding gets a connection of its own
+            if bind.is_some() && !dedicated {
+                log::debug!(target: &self.log_target, "{} [{}]: session bound, using a dedicated upstream connection", self.name, connection);
+                agent = agent.dedicated();
+                dedicated = true;
             }
+            let context = RequestContext::new(&self.name, peer, &full_request)
+                .with_id(connection, *requests)
+                .with_session_binds(&session_binds);
+            logging::trace_message(&self.name, &context.id, "<-", &full_request);
+            // Upstream's answer is passed on in the buffer it was read
+            // into, so clearing it here clears the signature it carries
+            let response = match self.process_request_via(&agent, &full_request, &context) {
+                Err(e) if agent::is_client_gone(&e) => {
+                    log::debug!(target: &self.log_target, "{} [{}]: client disconnected, dropped its request", self.name, context.id);
+                    break;
+                }
+                result => Zeroizing::new(result?),
+            };
+            logging::trace_message(&self.name, &context.id, "->", &response);
 
-            // Forward to upstream
-            let response = self.agent.forward_request(&full_request)?;
+            // Remember host bindings for later requests on this connection
+            // once upstream has checked the host's signature over them
+            if let Some(bind) = bind {
+                match response.get(4) {
+                    Some(&protocol::SSH_AGENT_SUCCESS) => session_binds.push(bind),
+                    _ => log::info!(target: &self.log_target, "{} [{}]: upstream refused the session binding, ignoring it", self.name, context.id),
+                }
+            }
 
-            // Filter response if it's a list identities response
-            let final_response = if is_list {
-                self.filter_identities_response(&response)?
-            } else {
-                response
-            };
+            if let Some(capture) = &self.capture {
+                if let Err(e) = capture.record(&self.name(), &full_request, &response) {
+                    log::warn!(target: &self.log_target, "{}: failed to capture request: {}", self.name(), e);
+                }
+            }
 
-            stream.write_all(&final_response)?;
+            stream.write_all(&response)?;
             stream.flush()?;
+            self.stats.add_bytes_out(response.len());
         }
 
         Ok(())
     }
 
-    pub async fn start(&self) -> Result<()> {
-        // Maximum concurrent connections per socket
-        const MAX_CONCURRENT_CONNECTIONS: usize = 100;
-        
+    /// Bind the socket file, replacing whatever is at the path
+    fn bind(&self) -> Result<UnixListener> {
         // Remove socket file if it exists
         if self.path.exists() {
             std::fs::remove_file(&self.path)
                 .with_context(|| format!("Failed to remove existing socket at {:?}", self.path))?;
         }
 
-        // Create parent directory if needed
+        // Create parent directory if needed, private to the user, and make
+        // sure no one else can replace the socket in it
         if let Some(parent) = self.path.parent() {
-            std::fs::create_dir_all(parent)
-                .with_context(|| format!("Failed to create directory {:?}", parent))?;
+            let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+            crate::state::create_private_dir(parent)?;
+            crate::state::check_private_dir(parent, self.dir_permissions)
+                .with_context(|| format!("Refusing to bind socket at {:?}", self.path))?;
         }
 
-        let listener = UnixListener::bind(&self.path)
-            .with_context(|| format!("Failed to bind socket at {:?}", self.path))?;
+        UnixListener::bind(&self.path)
+            .with_context(|| format!("Failed to bind socket at {:?}", self.path))
+    }
+
+    /// Give up the listening socket to another router, leaving the socket
+    /// and environment files in place when this one is dropped
+    pub fn hand_over(&self) -> Option<UnixListener> {
+        let listener = self.listener.lock().unwrap().take()?;
+        *self.bound.lock().unwrap() = None;
+        Some(listener)
+    }
+
+    /// Serve the clients of `old`, which stops listening, on its socket
+    /// file, as when a reload changed settings [`Self::reload`] cannot
+    /// apply to a running socket
+    ///
+    /// Clients already connected to `old` keep its settings until they
+    /// disconnect.
+    pub fn replace(self: &Arc<Self>, old: &FilteredSocket) -> Result<()> {
+        let acceptor = old.acceptor.lock().unwrap().take()
+            .with_context(|| format!("{} is not listening", old.name))?;
+        {
+            let mut acceptor = acceptor.write().unwrap();
+            acceptor.socket = Arc::downgrade(self);
+            if self.limits.max_connections != old.limits.max_connections {
+                acceptor.semaphore = Arc::new(Semaphore::new(self.limits.max_connections));
+            }
+        }
+        *self.acceptor.lock().unwrap() = Some(acceptor);
+        *self.listener.lock().unwrap() = old.listener.lock().unwrap().take();
+        // Keeps `old` from removing the socket file once dropped
+        *self.bound.lock().unwrap() = old.bound.lock().unwrap().take();
+
+        let env_file = self.env_file.lock().unwrap().clone();
+        if let Some(old_env) = old.env_file.lock().unwrap().take() {
+            if env_file.as_ref() != Some(&old_env) {
+                old.remove_env_file(&old_env);
+            }
+        }
+        if let Some(path) = env_file.as_deref() {
+            self.write_env_file(path)?;
+        }
+        Ok(())
+    }
+
+    pub async fn start(self: &Arc<Self>) -> Result<()> {
+        self.listen()
+    }
+
+    /// Bind the socket file and serve clients on a background thread
+    pub fn listen(self: &Arc<Self>) -> Result<()> {
+        let inherited = INHERITED.lock().unwrap().remove(&self.path);
+        let listener = match inherited {
+            Some(listener) => {
+                log::info!(target: &self.log_target, "{}: serving the socket handed over at {:?}", self.name, self.path);
+                listener
+            }
+            None => self.bind()?,
+        };
+        *self.bound.lock().unwrap() = std::fs::metadata(&self.path).ok().map(|m| (m.dev(), m.ino()));
+        *self.listener.lock().unwrap() = listener.try_clone().ok();
 
         println!("Listening on socket: {:?}", self.path);
+        if let Some(env_file) = self.env_file.lock().unwrap().as_deref() {
+            self.write_env_file(env_file)?;
+        }
 
-        // Clone what we need for the task
-        let path = self.path.clone();
-        let allowed = self.allowed_fingerprints.clone();
-        let denied = self.denied_fingerprints.clone();
-        let agent = self.agent.clone();
-        
-        // Semaphore to limit concurrent connections
-        let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_CONNECTIONS));
-        
-        // Channel to signal when the listener is ready
-        let (tx, rx) = tokio::sync::oneshot::channel();
+        // The listener only holds a weak reference so dropping the last
+        // handle still removes the socket file
+        let acceptor = Arc::new(RwLock::new(Acceptor {
+            socket: Arc::downgrade(self),
+            semaphore: Arc::new(Semaphore::new(self.limits.max_connections)),
+        }));
+        *self.acceptor.lock().unwrap() = Some(Arc::clone(&acceptor));
 
-        task::spawn_blocking(move || {
-            // Signal that we're ready
-            let _ = tx.send(());
-            
+        // A plain thread rather than spawn_blocking, which would keep the
+        // runtime from shutting down while accept() blocks
+        std::thread::spawn(move || {
             for stream in listener.incoming() {
                 match stream {
                     Ok(stream) => {
-                        let socket = FilteredSocket {
-                            path: path.clone(),
-                            allowed_fingerprints: allowed.clone(),
-                            denied_fingerprints: denied.clone(),
-                            agent: agent.clone(),
+                        let (socket, semaphore) = {
+                            let acceptor = acceptor.read().unwrap();
+                            (acceptor.socket.upgrade(), Arc::clone(&acceptor.semaphore))
+                        };
+                        let Some(socket) = socket else {
+                            break;
                         };
-                        
+
                         // Try to acquire a permit from the semaphore
-                        let sem_clone = semaphore.clone();
-                        match sem_clone.try_acquire_owned() {
+                        match semaphore.try_acquire_owned() {
                             Ok(permit) => {
                                 std::thread::spawn(move || {
                                     // Permit will be automatically released when dropped
                                     let _permit = permit;
+                                    socket.stats.connection_opened();
                                     if let Err(e) = socket.handle_client(stream) {
-                                        eprintln!("Error handling client: {}", e);
+                                        socket.stats.error();
+                                        log::error!(target: &socket.log_target, "{}: error handling client: {}", socket.name(), e);
                                     }
+                                    socket.stats.connection_closed();
                                 });
                             }
                             Err(_) => {
-                                eprintln!("Connection limit reached, rejecting connection");
+                                socket.stats.connection_rejected();
+                                log::warn!(target: &socket.log_target, "{}: connection limit reached, rejecting connection", socket.name());
                             }
                         }
                     }
                     Err(e) => {
-                        eprintln!("Connection error: {}", e);
+                        log::error!("Connection error: {}", e);
                     }
                 }
             }
         });
-        
-        // Wait for the listener to be ready
-        rx.await.context("Failed to start socket listener")?;
 
         Ok(())
     }
@@ -274,7 +1462,15 @@ impl FilteredSocket {
 
 impl Drop for FilteredSocket {
     fn drop(&mut self) {
-        // Clean up socket file
-        let _ = std::fs::remove_file(&self.path);
+        // Clean up the socket file, unless a socket that replaced this one
+        // on reload has bound the same path since
+        let bound = *self.bound.lock().unwrap();
+        let current = std::fs::metadata(&self.path).ok().map(|m| (m.dev(), m.ino()));
+        if bound.is_some() && bound == current {
+            let _ = std::fs::remove_file(&self.path);
+            if let Some(env_file) = self.env_file.lock().unwrap().as_deref() {
+                self.remove_env_file(env_file);
+            }
+        }
     }
 }
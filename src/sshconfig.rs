@@ -0,0 +1,72 @@
+use std::path::Path;
+
+/// Include file the `ssh-config` command installs blocks into, relative
+/// to `~/.ssh`
+pub const INCLUDE_FILE: &str = "config.d/ssh-agent-router.conf";
+
+/// An ssh_config block sending connections to `hosts` through the socket
+/// at `path`
+///
+/// `hosts` are ssh_config host patterns, separated by commas or spaces.
+/// The block is delimited by comments naming the socket so it can be
+/// replaced later.
+pub fn block(socket: &str, hosts: &str, path: &Path) -> String {
+    let patterns: Vec<&str> = hosts.split([',', ' ']).filter(|p| !p.is_empty()).collect();
+    let path = path.to_string_lossy();
+    let path = if path.contains(char::is_whitespace) { format!("\"{}\"", path) } else { path.into_owned() };
+    format!(
+        "{}\nHost {}\n    IdentityAgent {}\n{}\n",
+        begin_marker(socket), patterns.join(" "), path, end_marker(socket)
+    )
+}
+
+/// `content` with the block for `socket` replaced by `block`, or with
+/// `block` appended if it has none
+pub fn replace_block(content: &str, socket: &str, block: &str) -> String {
+    let (begin, end) = (begin_marker(socket), end_marker(socket));
+    let mut out = String::new();
+    let mut lines = content.lines();
+    let mut replaced = false;
+    while let Some(line) = lines.next() {
+        if line != begin {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+        // Skip the old block, keeping the new one in its place
+        for line in lines.by_ref() {
+            if line == end {
+                break;
+            }
+        }
+        if !replaced {
+            out.push_str(block);
+            replaced = true;
+        }
+    }
+    if !replaced {
+        if !out.is_empty() && !out.ends_with("\n\n") {
+            out.push('\n');
+        }
+        out.push_str(block);
+    }
+    out
+}
+
+/// Whether ssh_config `content` already includes the file `include`,
+/// given as written in an `Include` line
+pub fn includes(content: &str, include: &str) -> bool {
+    content.lines().any(|line| {
+        let mut words = line.split_whitespace();
+        words.next().is_some_and(|keyword| keyword.eq_ignore_ascii_case("include"))
+            && words.any(|word| word.trim_matches('"') == include)
+    })
+}
+
+fn begin_marker(socket: &str) -> String {
+    format!("# BEGIN ssh-agent-router socket {}", socket)
+}
+
+fn end_marker(socket: &str) -> String {
+    format!("# END ssh-agent-router socket {}", socket)
+}
@@ -0,0 +1,109 @@
+use crate::config::DirPermissions;
+use anyhow::{Context, Result};
+use std::fs::DirBuilder;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Subdirectory of each base directory the router's files go into
+const APP_DIR: &str = "ssh-agent-router";
+
+/// Files worth keeping and backing up, such as pinned keys and grant uses
+///
+/// `$XDG_DATA_HOME` or `~/.local/share` on Linux, `~/Library/Application
+/// Support` on macOS.
+pub fn data_dir() -> Result<PathBuf> {
+    Ok(dirs::data_dir().context("Failed to get data directory")?.join(APP_DIR))
+}
+
+/// Files kept across restarts that can be lost without harm, such as
+/// counters
+///
+/// `$XDG_STATE_HOME` or `~/.local/state` on Linux. macOS has no such
+/// directory, so it is the data directory there.
+pub fn state_dir() -> Result<PathBuf> {
+    Ok(dirs::state_dir()
+        .or_else(dirs::data_dir)
+        .context("Failed to get state directory")?
+        .join(APP_DIR))
+}
+
+/// Files that can be rebuilt at any time
+///
+/// `$XDG_CACHE_HOME` or `~/.cache` on Linux, `~/Library/Caches` on macOS.
+pub fn cache_dir() -> Result<PathBuf> {
+    Ok(dirs::cache_dir().context("Failed to get cache directory")?.join(APP_DIR))
+}
+
+/// Sockets and other files that only make sense while the router runs
+///
+/// `$XDG_RUNTIME_DIR`, or the cache directory where there is none, as on
+/// macOS.
+pub fn runtime_dir() -> Result<PathBuf> {
+    Ok(dirs::runtime_dir()
+        .or_else(dirs::cache_dir)
+        .context("Failed to get runtime directory")?
+        .join(APP_DIR))
+}
+
+/// Create `dir` and any missing parents, new ones accessible only by the
+/// user
+pub fn create_private_dir(dir: &Path) -> Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("Failed to create directory {:?}", dir))
+}
+
+/// Create the directory a file goes into, as [`create_private_dir`]
+pub fn create_parent_dir(file: &Path) -> Result<()> {
+    match file.parent() {
+        Some(parent) => create_private_dir(parent),
+        None => Ok(()),
+    }
+}
+
+/// Check that no one but the user, or root, can add or remove files in
+/// `dir`, as OpenSSH insists for `~/.ssh`, and act on a problem as
+/// `action` says
+///
+/// Anyone who can write to a socket's directory can swap the socket for
+/// their own and see what clients ask for.
+pub fn check_private_dir(dir: &Path, action: DirPermissions) -> Result<()> {
+    if action == DirPermissions::Ignore {
+        return Ok(());
+    }
+    let metadata = std::fs::metadata(dir).with_context(|| format!("Failed to read directory {:?}", dir))?;
+    // SAFETY: getuid cannot fail
+    let uid = unsafe { libc::getuid() };
+    let mode = metadata.mode() & 0o7777;
+    let problem = if metadata.uid() != uid && metadata.uid() != 0 {
+        format!("belongs to uid {}", metadata.uid())
+    } else if mode & 0o022 != 0 {
+        let who = if mode & 0o002 != 0 { "every user" } else { "its group" };
+        format!("is writable by {} (mode {:o})", who, mode & 0o777)
+    } else {
+        return Ok(());
+    };
+
+    // A sticky directory such as /tmp is shared by design, so it is never
+    // changed, nor is someone else's
+    let fixable = metadata.uid() == uid && mode & 0o1000 == 0;
+    match action {
+        DirPermissions::Warn => {
+            log::warn!("Directory {:?} {}, so other users could replace the sockets in it", dir, problem);
+            Ok(())
+        }
+        DirPermissions::Fix if fixable => {
+            std::fs::set_permissions(dir, std::fs::Permissions::from_mode(mode & !0o022))
+                .with_context(|| format!("Failed to change the permissions of {:?}", dir))?;
+            log::info!("Removed group and world write permission from {:?}", dir);
+            Ok(())
+        }
+        _ => anyhow::bail!(
+            "Directory {:?} {}, so other users could replace the socket; \
+             set dir_permissions = \"ignore\" for a directory shared on purpose",
+            dir, problem
+        ),
+    }
+}
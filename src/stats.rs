@@ -0,0 +1,224 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use crate::state;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Connection counters for a single socket
+///
+/// Shared between the connection threads and the control socket, so the
+/// counters are atomics that can be read without locking. Only the per-key
+/// sign tallies need a lock.
+#[derive(Debug, Default)]
+pub struct SocketStats {
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    active: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    errors: AtomicU64,
+    requests: AtomicU64,
+    upstream_errors: AtomicU64,
+    denied_signs: Mutex<BTreeMap<String, u64>>,
+    last_signed: Mutex<BTreeMap<String, u64>>,
+    last_denied: Mutex<Option<DeniedSign>>,
+}
+
+/// The most recent sign request refused on a socket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeniedSign {
+    pub fingerprint: String,
+    /// Unix time of the request
+    pub at: u64,
+}
+
+/// Point-in-time copy of `SocketStats`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    pub accepted: u64,
+    pub rejected: u64,
+    pub active: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub errors: u64,
+    #[serde(default)]
+    pub requests: u64,
+    #[serde(default)]
+    pub upstream_errors: u64,
+    /// Denied sign requests by key fingerprint
+    #[serde(default)]
+    pub denied_signs: BTreeMap<String, u64>,
+    /// Unix time of the last allowed sign request by key fingerprint
+    #[serde(default)]
+    pub last_signed: BTreeMap<String, u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_denied: Option<DeniedSign>,
+}
+
+impl SocketStats {
+    pub fn connection_opened(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// A connection was refused because the socket hit max_connections
+    pub fn connection_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An agent message was received from a client
+    pub fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Talking to the upstream agent failed
+    pub fn upstream_error(&self) {
+        self.upstream_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A sign request was refused by the filter
+    pub fn sign_denied(&self, fingerprint: &str) {
+        *self.denied_signs.lock().unwrap().entry(fingerprint.to_string()).or_default() += 1;
+        *self.last_denied.lock().unwrap() = Some(DeniedSign {
+            fingerprint: fingerprint.to_string(),
+            at: unix_now(),
+        });
+    }
+
+    /// A sign request was passed on to the upstream agent
+    pub fn sign_allowed(&self, fingerprint: &str) {
+        self.last_signed.lock().unwrap().insert(fingerprint.to_string(), unix_now());
+    }
+
+    /// Carry on from counters saved by an earlier run; `active` is left
+    /// alone since those connections are gone
+    pub fn restore(&self, saved: &StatsSnapshot) {
+        self.accepted.fetch_add(saved.accepted, Ordering::Relaxed);
+        self.rejected.fetch_add(saved.rejected, Ordering::Relaxed);
+        self.bytes_in.fetch_add(saved.bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(saved.bytes_out, Ordering::Relaxed);
+        self.errors.fetch_add(saved.errors, Ordering::Relaxed);
+        self.requests.fetch_add(saved.requests, Ordering::Relaxed);
+        self.upstream_errors.fetch_add(saved.upstream_errors, Ordering::Relaxed);
+        let mut denied_signs = self.denied_signs.lock().unwrap();
+        for (fingerprint, count) in &saved.denied_signs {
+            *denied_signs.entry(fingerprint.clone()).or_default() += count;
+        }
+        let mut last_signed = self.last_signed.lock().unwrap();
+        for (fingerprint, at) in &saved.last_signed {
+            let last = last_signed.entry(fingerprint.clone()).or_default();
+            *last = (*last).max(*at);
+        }
+        if let Some(saved) = &saved.last_denied {
+            let mut last_denied = self.last_denied.lock().unwrap();
+            if last_denied.as_ref().is_none_or(|last| saved.at > last.at) {
+                *last_denied = Some(saved.clone());
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            upstream_errors: self.upstream_errors.load(Ordering::Relaxed),
+            denied_signs: self.denied_signs.lock().unwrap().clone(),
+            last_signed: self.last_signed.lock().unwrap().clone(),
+            last_denied: self.last_denied.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl StatsSnapshot {
+    /// Total number of denied sign requests
+    pub fn denied_sign_count(&self) -> u64 {
+        self.denied_signs.values().sum()
+    }
+}
+
+/// Counters of the configured sockets by path, kept across restarts
+///
+/// Sockets built for a path carry on from its saved counters, and the
+/// router writes them back periodically and when it stops.
+pub struct SavedStats {
+    path: Option<PathBuf>,
+    sockets: Mutex<BTreeMap<PathBuf, StatsSnapshot>>,
+}
+
+impl SavedStats {
+    /// Load the counters saved in `path`, if there are any yet
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let sockets = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse saved statistics {:?}", path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read saved statistics {:?}", path)),
+        };
+        Ok(Self { path: Some(path), sockets: Mutex::new(sockets) })
+    }
+
+    /// Counters that are forgotten when the process exits
+    pub fn in_memory() -> Self {
+        Self { path: None, sockets: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The last counters recorded for the socket at `socket`
+    pub fn get(&self, socket: &Path) -> Option<StatsSnapshot> {
+        self.sockets.lock().unwrap().get(socket).cloned()
+    }
+
+    /// Record the current counters of the socket at `socket`
+    pub fn update(&self, socket: &Path, snapshot: StatsSnapshot) {
+        self.sockets.lock().unwrap().insert(socket.to_path_buf(), StatsSnapshot { active: 0, ..snapshot });
+    }
+
+    /// Write the recorded counters to the file
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        state::create_parent_dir(path)?;
+
+        // Written to a temporary file first so a crash never leaves half a file
+        let content = serde_json::to_string_pretty(&*self.sockets.lock().unwrap())?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content).with_context(|| format!("Failed to write statistics {:?}", tmp))?;
+        fs::rename(&tmp, path).with_context(|| format!("Failed to write statistics {:?}", path))
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,74 @@
+use crate::config::Config;
+use anyhow::{Context, Result};
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How often a running router looks for leftover sockets
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Where sockets left behind by a router that crashed can be: every file in
+/// the directories the router keeps to itself, and the configured socket
+/// paths
+///
+/// Only the exact configured paths are looked at, since their directories,
+/// such as `/tmp`, are often shared with other programs' sockets.
+pub fn candidates(config: &Config) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![Config::ephemeral_socket_dir()?];
+    if let Some(per_key) = &config.per_key_sockets {
+        dirs.push(crate::config::expand_tilde(&per_key.dir));
+    }
+
+    let mut paths: Vec<PathBuf> = config.sockets.iter().map(|socket| socket.path.clone()).collect();
+    for dir in dirs {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", dir)),
+        };
+        for entry in entries {
+            paths.push(entry?.path());
+        }
+    }
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
+/// Whether `path` is a socket of this user that nothing listens on
+///
+/// A socket is only dead when connecting is refused; one that accepts, or
+/// cannot be tried, such as for lack of permission, is left alone.
+pub fn is_dead_socket(path: &Path) -> bool {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return false;
+    };
+    // SAFETY: getuid cannot fail
+    let uid = unsafe { libc::getuid() };
+    if !metadata.file_type().is_socket() || metadata.uid() != uid {
+        return false;
+    }
+    matches!(UnixStream::connect(path), Err(e) if e.kind() == ErrorKind::ConnectionRefused)
+}
+
+/// Remove the dead sockets among `paths`, skipping those in `serving`,
+/// and return the ones removed, or with `dry_run` the ones that would be
+pub fn sweep(paths: &[PathBuf], serving: &[PathBuf], dry_run: bool) -> Vec<PathBuf> {
+    let mut removed = Vec::new();
+    for path in paths {
+        // Connecting to the router's own sockets would count as a client
+        if serving.contains(path) || !is_dead_socket(path) {
+            continue;
+        }
+        if !dry_run {
+            if let Err(e) = std::fs::remove_file(path) {
+                log::warn!("Failed to remove stale socket {:?}: {}", path, e);
+                continue;
+            }
+        }
+        removed.push(path.clone());
+    }
+    removed
+}
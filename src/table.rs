@@ -0,0 +1,131 @@
+use std::io::IsTerminal;
+
+/// Characters of a fingerprint's hash shown when it is shortened
+const SHORT_FINGERPRINT: usize = 12;
+
+/// How a table cell is highlighted when color is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Plain,
+    Bold,
+    Dim,
+    Green,
+    Yellow,
+    Red,
+}
+
+impl Style {
+    fn code(self) -> Option<&'static str> {
+        match self {
+            Style::Plain => None,
+            Style::Bold => Some("1"),
+            Style::Dim => Some("2"),
+            Style::Green => Some("32"),
+            Style::Yellow => Some("33"),
+            Style::Red => Some("31"),
+        }
+    }
+}
+
+/// Whether to color output: not with `--no-color`, not when `NO_COLOR` is
+/// set to anything, and only on a terminal
+pub fn use_color(no_color: bool) -> bool {
+    !no_color
+        && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+        && std::io::stdout().is_terminal()
+}
+
+/// `text` highlighted with `style` if `color` is on
+pub fn paint(text: &str, style: Style, color: bool) -> String {
+    match style.code().filter(|_| color) {
+        Some(code) => format!("\x1b[{}m{}\x1b[0m", code, text),
+        None => text.to_string(),
+    }
+}
+
+/// `SHA256:uZG6Jmeg+99C…` for a SHA256 or MD5 fingerprint, which is still
+/// enough to tell keys apart; anything else, such as a pattern, is left
+/// alone
+pub fn short_fingerprint(fingerprint: &str) -> String {
+    match fingerprint.split_once(':') {
+        Some((algorithm @ ("SHA256" | "MD5"), hash)) if hash.chars().count() > SHORT_FINGERPRINT => {
+            format!("{}:{}…", algorithm, hash.chars().take(SHORT_FINGERPRINT).collect::<String>())
+        }
+        _ => fingerprint.to_string(),
+    }
+}
+
+/// Columns `text` takes up on a terminal, where CJK characters such as
+/// Japanese headers are two columns wide
+pub fn width(text: &str) -> usize {
+    text.chars().map(|c| match c as u32 {
+        0x1100..=0x115F | 0x2E80..=0x303E | 0x3041..=0x33FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF | 0xAC00..=0xD7A3 | 0xF900..=0xFAFF | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60 | 0xFFE0..=0xFFE6 => 2,
+        _ => 1,
+    }).sum()
+}
+
+/// Rows printed in columns aligned to their widest cell
+pub struct Table {
+    header: Vec<String>,
+    rows: Vec<Vec<(String, Style)>>,
+    color: bool,
+}
+
+impl Table {
+    pub fn new(header: &[&str], color: bool) -> Self {
+        Self {
+            header: header.iter().map(|h| h.to_string()).collect(),
+            rows: Vec::new(),
+            color,
+        }
+    }
+
+    /// Add a row of plain cells
+    pub fn row<S: Into<String>>(&mut self, cells: impl IntoIterator<Item = S>) {
+        self.styled_row(cells.into_iter().map(|cell| (cell.into(), Style::Plain)));
+    }
+
+    /// Add a row of cells with their styles
+    pub fn styled_row<S: Into<String>>(&mut self, cells: impl IntoIterator<Item = (S, Style)>) {
+        self.rows.push(cells.into_iter().map(|(cell, style)| (cell.into(), style)).collect());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The table as lines of text, header first
+    ///
+    /// Widths count terminal columns of the text, so the escape codes
+    /// added for color do not upset the alignment.
+    pub fn render(&self) -> String {
+        let columns = self.rows.iter().map(Vec::len).chain([self.header.len()]).max().unwrap_or(0);
+        let mut widths = vec![0; columns];
+        let cells = self.header.iter().enumerate()
+            .chain(self.rows.iter().flat_map(|row| row.iter().map(|(text, _)| text).enumerate()));
+        for (i, text) in cells {
+            widths[i] = widths[i].max(width(text));
+        }
+
+        let header = self.header.iter().map(|h| (h.clone(), Style::Bold)).collect::<Vec<_>>();
+        let mut out = String::new();
+        for row in std::iter::once(&header).chain(&self.rows) {
+            let mut line = String::new();
+            for (i, (text, style)) in row.iter().enumerate() {
+                if i > 0 {
+                    line.push_str("  ");
+                }
+                line.push_str(&paint(text, *style, self.color));
+                // The last column is not padded, so lines do not end in spaces
+                if i + 1 < row.len() {
+                    line.push_str(&" ".repeat(widths[i] - width(text)));
+                }
+            }
+            out.push_str(line.trim_end());
+            out.push('\n');
+        }
+        out
+    }
+}
@@ -0,0 +1,262 @@
+/// What a span measures, as OTLP reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    /// Handling a client request
+    Server,
+    /// Waiting for the upstream agent
+    Client,
+}
+
+#[cfg(feature = "otel")]
+pub use otlp::{init, span, Span};
+
+#[cfg(not(feature = "otel"))]
+pub use disabled::{init, span, Span};
+
+/// Spans batched and sent to an OTLP/HTTP collector as JSON
+///
+/// Spans nest per thread: one started while another is open on the same
+/// thread becomes its child, which is how an upstream exchange ends up
+/// under the client request that caused it.
+#[cfg(feature = "otel")]
+mod otlp {
+    use super::SpanKind;
+    use crate::config::TelemetryConfig;
+    use anyhow::{Context, Result};
+    use serde_json::{json, Value};
+    use std::cell::RefCell;
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+    use std::sync::OnceLock;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    /// Spans queued for export before new ones are dropped
+    const QUEUE: usize = 4096;
+    /// Spans sent in one request
+    const BATCH: usize = 512;
+    /// How long a finished span may wait for its batch to fill
+    const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+    static EXPORTER: OnceLock<SyncSender<SpanData>> = OnceLock::new();
+
+    thread_local! {
+        /// Trace and span ID of the spans open on this thread, innermost last
+        static OPEN: RefCell<Vec<([u8; 16], [u8; 8])>> = const { RefCell::new(Vec::new()) };
+    }
+
+    struct SpanData {
+        trace_id: [u8; 16],
+        span_id: [u8; 8],
+        parent: Option<[u8; 8]>,
+        name: String,
+        kind: SpanKind,
+        start: SystemTime,
+        end: SystemTime,
+        attributes: Vec<(&'static str, String)>,
+        error: Option<String>,
+    }
+
+    /// An open span, ended and queued for export when dropped
+    pub struct Span {
+        data: Option<SpanData>,
+    }
+
+    /// Start a span, a no-op until [`init`] has run
+    pub fn span(name: &str, kind: SpanKind) -> Span {
+        if EXPORTER.get().is_none() {
+            return Span { data: None };
+        }
+
+        let mut span_id = [0u8; 8];
+        let mut trace_id = [0u8; 16];
+        if getrandom::getrandom(&mut span_id).is_err() {
+            return Span { data: None };
+        }
+        let parent = OPEN.with(|open| open.borrow().last().copied());
+        match parent {
+            Some((parent_trace, _)) => trace_id = parent_trace,
+            None => {
+                if getrandom::getrandom(&mut trace_id).is_err() {
+                    return Span { data: None };
+                }
+            }
+        }
+        OPEN.with(|open| open.borrow_mut().push((trace_id, span_id)));
+
+        let now = SystemTime::now();
+        Span {
+            data: Some(SpanData {
+                trace_id,
+                span_id,
+                parent: parent.map(|(_, id)| id),
+                name: name.to_string(),
+                kind,
+                start: now,
+                end: now,
+                attributes: Vec::new(),
+                error: None,
+            }),
+        }
+    }
+
+    impl Span {
+        pub fn attribute(&mut self, key: &'static str, value: impl ToString) {
+            if let Some(data) = &mut self.data {
+                data.attributes.push((key, value.to_string()));
+            }
+        }
+
+        /// Mark the span as failed
+        pub fn error(&mut self, error: &anyhow::Error) {
+            if let Some(data) = &mut self.data {
+                data.error = Some(format!("{:#}", error));
+            }
+        }
+    }
+
+    impl Drop for Span {
+        fn drop(&mut self) {
+            let Some(mut data) = self.data.take() else {
+                return;
+            };
+            OPEN.with(|open| {
+                let mut open = open.borrow_mut();
+                if let Some(i) = open.iter().rposition(|(_, id)| *id == data.span_id) {
+                    open.truncate(i);
+                }
+            });
+            data.end = SystemTime::now();
+            if let Some(exporter) = EXPORTER.get() {
+                // A full queue means the collector is behind; drop the span
+                let _ = exporter.try_send(data);
+            }
+        }
+    }
+
+    /// Start exporting spans to `config.otlp_endpoint`, if set
+    pub fn init(config: &TelemetryConfig) -> Result<()> {
+        let Some(endpoint) = &config.otlp_endpoint else {
+            return Ok(());
+        };
+        let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+        let (sender, receiver) = mpsc::sync_channel(QUEUE);
+        EXPORTER.set(sender).map_err(|_| anyhow::anyhow!("Telemetry is already initialized"))?;
+
+        let service = config.service_name.clone();
+        let headers = config.headers.clone();
+        std::thread::Builder::new()
+            .name("otlp-exporter".to_string())
+            .spawn(move || export(receiver, &url, &service, &headers))
+            .context("Failed to start the OTLP exporter")?;
+        log::info!("Exporting traces to {}", endpoint);
+        Ok(())
+    }
+
+    fn export(receiver: Receiver<SpanData>, url: &str, service: &str, headers: &std::collections::BTreeMap<String, String>) {
+        let mut batch = Vec::new();
+        let mut deadline = Instant::now() + FLUSH_INTERVAL;
+        loop {
+            let closed = match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(span) => {
+                    batch.push(span);
+                    if batch.len() < BATCH {
+                        continue;
+                    }
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+
+            if !batch.is_empty() {
+                let body = request_body(service, &batch);
+                let mut request = ureq::post(url)
+                    .set("Content-Type", "application/json")
+                    .timeout(Duration::from_secs(10));
+                for (name, value) in headers {
+                    request = request.set(name, value);
+                }
+                if let Err(e) = request.send_string(&body.to_string()) {
+                    log::debug!("Failed to export {} spans to {}: {}", batch.len(), url, e);
+                }
+                batch.clear();
+            }
+            if closed {
+                return;
+            }
+            deadline = Instant::now() + FLUSH_INTERVAL;
+        }
+    }
+
+    /// An OTLP `ExportTraceServiceRequest` in its JSON encoding
+    fn request_body(service: &str, spans: &[SpanData]) -> Value {
+        let spans: Vec<Value> = spans.iter().map(span_json).collect();
+        json!({
+            "resourceSpans": [{
+                "resource": { "attributes": [attribute("service.name", service)] },
+                "scopeSpans": [{
+                    "scope": { "name": "ssh-agent-router", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+
+    fn span_json(span: &SpanData) -> Value {
+        let mut value = json!({
+            "traceId": hex(&span.trace_id),
+            "spanId": hex(&span.span_id),
+            "name": span.name,
+            "kind": match span.kind { SpanKind::Server => 2, SpanKind::Client => 3 },
+            "startTimeUnixNano": unix_nanos(span.start),
+            "endTimeUnixNano": unix_nanos(span.end),
+            "attributes": span.attributes.iter().map(|(k, v)| attribute(k, v)).collect::<Vec<_>>(),
+        });
+        if let Some(parent) = &span.parent {
+            value["parentSpanId"] = json!(hex(parent));
+        }
+        if let Some(error) = &span.error {
+            value["status"] = json!({ "code": 2, "message": error });
+        }
+        value
+    }
+
+    fn attribute(key: &str, value: &str) -> Value {
+        json!({ "key": key, "value": { "stringValue": value } })
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// OTLP JSON carries 64-bit integers as strings
+    fn unix_nanos(time: SystemTime) -> String {
+        time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default().to_string()
+    }
+}
+
+/// Stand-ins when the router is built without the `otel` feature
+#[cfg(not(feature = "otel"))]
+mod disabled {
+    use super::SpanKind;
+    use crate::config::TelemetryConfig;
+    use anyhow::Result;
+
+    pub struct Span;
+
+    pub fn span(_name: &str, _kind: SpanKind) -> Span {
+        Span
+    }
+
+    impl Span {
+        pub fn attribute(&mut self, _key: &'static str, _value: impl ToString) {}
+
+        pub fn error(&mut self, _error: &anyhow::Error) {}
+    }
+
+    pub fn init(config: &TelemetryConfig) -> Result<()> {
+        if config.otlp_endpoint.is_some() {
+            log::warn!("Ignoring telemetry.otlp_endpoint, this build lacks the otel feature");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::collections::BTreeMap;
+
+/// Variables every config file can use, by name
+///
+/// `hostname` is the machine's name as `hostname` prints it, `user` the
+/// login name, `home` the home directory and `os` `linux` or `macos`.
+pub fn builtins() -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::new();
+    vars.insert("hostname".to_string(), hostname());
+    vars.insert("os".to_string(), std::env::consts::OS.to_string());
+    if let Some(user) = user() {
+        vars.insert("user".to_string(), user);
+    }
+    if let Some(home) = dirs::home_dir() {
+        vars.insert("home".to_string(), home.display().to_string());
+    }
+    vars
+}
+
+/// `$USER`, or the name of the user running the router where it is not
+/// set, as under some service managers
+fn user() -> Option<String> {
+    if let Some(user) = std::env::var("USER").ok().filter(|user| !user.is_empty()) {
+        return Some(user);
+    }
+    // SAFETY: getpwuid returns null or an entry valid until the next call,
+    // which is copied out right away
+    unsafe {
+        let entry = libc::getpwuid(libc::getuid());
+        if entry.is_null() {
+            return None;
+        }
+        Some(std::ffi::CStr::from_ptr((*entry).pw_name).to_string_lossy().into_owned())
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: gethostname writes at most `buf.len()` bytes into `buf`
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Resolve the templating in a parsed config file for this machine
+///
+/// Tables in `host_overrides` whose key is a glob matching the hostname,
+/// such as `host_overrides."laptop-*"`, are merged over the file in the
+/// order of their keys. Then `${name}` in any string is replaced by the
+/// variable of that name from `[vars]` or `builtins`, and `$$` by `$`.
+/// Values in `[vars]` can use the builtins, and a `[vars]` table in an
+/// override adds to or replaces those of the file.
+pub fn apply(table: &mut toml::Table, builtins: &BTreeMap<String, String>) -> Result<()> {
+    if let Some(overrides) = table.remove("host_overrides") {
+        let toml::Value::Table(overrides) = overrides else {
+            anyhow::bail!("host_overrides must be a table of tables, e.g. [host_overrides.\"laptop-*\"]");
+        };
+        let hostname = builtins.get("hostname").map(String::as_str).unwrap_or_default();
+        for (pattern, overlay) in overrides {
+            let toml::Value::Table(overlay) = overlay else {
+                anyhow::bail!("host_overrides.{:?} must be a table", pattern);
+            };
+            if overlay.contains_key("host_overrides") {
+                anyhow::bail!("host_overrides.{:?} cannot have host overrides of its own", pattern);
+            }
+            if glob_matches(&pattern, hostname)? {
+                log::debug!("Applying host_overrides.{:?} for host {:?}", pattern, hostname);
+                crate::config::merge_overlay(table, overlay);
+            }
+        }
+    }
+
+    let mut vars = builtins.clone();
+    if let Some(defined) = table.remove("vars") {
+        let toml::Value::Table(defined) = defined else {
+            anyhow::bail!("vars must be a table of strings");
+        };
+        for (name, value) in defined {
+            let toml::Value::String(value) = value else {
+                anyhow::bail!("vars.{} must be a string", name);
+            };
+            let value = interpolate(&value, builtins).with_context(|| format!("In vars.{}", name))?;
+            vars.insert(name, value);
+        }
+    }
+
+    for (key, value) in table.iter_mut() {
+        interpolate_value(value, key, &vars)?;
+    }
+    Ok(())
+}
+
+fn interpolate_value(value: &mut toml::Value, key: &str, vars: &BTreeMap<String, String>) -> Result<()> {
+    match value {
+        toml::Value::String(text) => {
+            *text = interpolate(text, vars).with_context(|| format!("In {}", key))?;
+        }
+        toml::Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                interpolate_value(item, &format!("{}[{}]", key, i), vars)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (name, item) in table.iter_mut() {
+                interpolate_value(item, &format!("{}.{}", key, name), vars)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// `text` with `${name}` replaced by the variable and `$$` by `$`
+pub fn interpolate(text: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let (name, after) = after.split_once('}')
+                .with_context(|| format!("Unclosed ${{ in {:?}", text))?;
+            let value = vars.get(name).with_context(|| {
+                let known = vars.keys().map(String::as_str).collect::<Vec<_>>().join(", ");
+                format!("Unknown variable ${{{}}}, expected one of {} or a name from [vars]", name, known)
+            })?;
+            out.push_str(value);
+            rest = after;
+        } else {
+            // A lone `$` is kept, as in `re:` patterns ending in `$`
+            out.push('$');
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn glob_matches(pattern: &str, text: &str) -> Result<bool> {
+    let regex = Regex::new(&crate::policy::glob_to_regex(pattern))
+        .with_context(|| format!("Invalid host pattern {:?}", pattern))?;
+    Ok(regex.is_match(text))
+}
@@ -0,0 +1,40 @@
+use serde::Serialize;
+
+/// What this binary is and was built with, as `version --json` reports
+/// it for the upgrade checker and bug reports
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Abbreviated git commit, absent when built outside a checkout
+    pub commit: Option<&'static str>,
+    /// UTC date of the build, `SOURCE_DATE_EPOCH` if set
+    pub build_date: &'static str,
+    /// Cargo features compiled in
+    pub features: Vec<&'static str>,
+    /// Agent protocol extensions the router answers itself
+    pub extensions: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        let mut features = Vec::new();
+        if cfg!(feature = "otel") {
+            features.push("otel");
+        }
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            commit: option_env!("SSH_AGENT_ROUTER_COMMIT"),
+            build_date: env!("SSH_AGENT_ROUTER_BUILD_DATE"),
+            features,
+            extensions: crate::protocol::EXTENSIONS.to_vec(),
+        }
+    }
+
+    /// One line such as `ssh-agent-router 0.1.0 (1a2b3c4d5e6f 2026-01-31)`
+    pub fn summary(&self) -> String {
+        match self.commit {
+            Some(commit) => format!("ssh-agent-router {} ({} {})", self.version, commit, self.build_date),
+            None => format!("ssh-agent-router {} ({})", self.version, self.build_date),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::Result;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How often to look again where the directory cannot be watched, such as
+/// while it does not exist itself
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Wait until `path` exists, for at most `timeout` or forever with `None`,
+/// returning whether it does
+///
+/// The parent directory is watched with inotify on Linux and kqueue on
+/// macOS, so the wait ends as soon as the file is created; elsewhere, and
+/// while the parent is missing, the path is checked twice a second.
+pub fn wait_for_path(path: &Path, timeout: Option<Duration>) -> Result<bool> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let remaining = || deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+    loop {
+        if path.exists() {
+            return Ok(true);
+        }
+        let wait = match remaining() {
+            Some(remaining) if remaining.is_zero() => return Ok(false),
+            Some(remaining) => remaining,
+            None => Duration::MAX,
+        };
+        let watched = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => DirWatch::new(parent).ok(),
+            _ => DirWatch::new(Path::new(".")).ok(),
+        };
+        match watched {
+            // Checked again after the watch is set up, so a file created
+            // in between is not missed
+            Some(_) if path.exists() => return Ok(true),
+            Some(watch) => watch.wait(wait.min(Duration::from_secs(60)))?,
+            None => std::thread::sleep(wait.min(POLL_INTERVAL)),
+        }
+    }
+}
+
+/// A watch on the entries of a directory, removed when dropped
+#[cfg(any(target_os = "linux", target_os = "android"))]
+struct DirWatch {
+    fd: libc::c_int,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl DirWatch {
+    fn new(dir: &Path) -> Result<Self> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+        // SAFETY: plain system calls; the descriptor is owned by the watch
+        // and closed when it drops
+        unsafe {
+            let fd = libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK);
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let watch = Self { fd };
+            let mask = libc::IN_CREATE | libc::IN_MOVED_TO;
+            if libc::inotify_add_watch(fd, dir.as_ptr(), mask) < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            Ok(watch)
+        }
+    }
+
+    /// Block until something changes in the directory or `timeout` passes
+    fn wait(&self, timeout: Duration) -> Result<()> {
+        let mut poll = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+        let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        // SAFETY: `poll` is valid for the call and the buffer is only read
+        // into up to its length
+        unsafe {
+            if libc::poll(&mut poll, 1, millis) < 0 {
+                let error = std::io::Error::last_os_error();
+                if error.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(error.into());
+                }
+            }
+            // Drain the events; which file changed is checked by the caller
+            let mut buf = [0u8; 4096];
+            while libc::read(self.fd, buf.as_mut_ptr().cast(), buf.len()) > 0 {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+struct DirWatch {
+    kq: libc::c_int,
+    dir: libc::c_int,
+}
+
+#[cfg(target_os = "macos")]
+impl DirWatch {
+    fn new(dir: &Path) -> Result<Self> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+        // SAFETY: plain system calls; both descriptors are owned by the
+        // watch and closed when it drops
+        unsafe {
+            let dir = libc::open(dir.as_ptr(), libc::O_EVTONLY | libc::O_CLOEXEC);
+            if dir < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let kq = libc::kqueue();
+            if kq < 0 {
+                let error = std::io::Error::last_os_error();
+                libc::close(dir);
+                return Err(error.into());
+            }
+            let watch = Self { kq, dir };
+            let change = libc::kevent {
+                ident: dir as libc::uintptr_t,
+                filter: libc::EVFILT_VNODE,
+                flags: libc::EV_ADD | libc::EV_CLEAR,
+                fflags: libc::NOTE_WRITE,
+                data: 0,
+                udata: std::ptr::null_mut(),
+            };
+            if libc::kevent(kq, &change, 1, std::ptr::null_mut(), 0, std::ptr::null()) < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            Ok(watch)
+        }
+    }
+
+    /// Block until something changes in the directory or `timeout` passes
+    fn wait(&self, timeout: Duration) -> Result<()> {
+        let timeout = libc::timespec {
+            tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as libc::c_long,
+        };
+        // SAFETY: `event` and `timeout` are valid for the call
+        unsafe {
+            let mut event: libc::kevent = std::mem::zeroed();
+            if libc::kevent(self.kq, std::ptr::null(), 0, &mut event, 1, &timeout) < 0 {
+                let error = std::io::Error::last_os_error();
+                if error.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(error.into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+impl Drop for DirWatch {
+    fn drop(&mut self) {
+        // SAFETY: the descriptors are owned by the watch
+        unsafe {
+            #[cfg(target_os = "macos")]
+            libc::close(self.dir);
+            #[cfg(target_os = "macos")]
+            libc::close(self.kq);
+            #[cfg(not(target_os = "macos"))]
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Without a way to watch, every directory is polled
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+struct DirWatch;
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+impl DirWatch {
+    fn new(_dir: &Path) -> Result<Self> {
+        anyhow::bail!("Directories cannot be watched on this system")
+    }
+
+    fn wait(&self, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
+}